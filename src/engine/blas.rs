@@ -1,6 +1,9 @@
 //! This module contains the necessary resources and systems to manage BLASes (used to accelerate ray intersections).
 
 use crate::engine::geometry::GeometryManager;
+use crate::engine::settings::{NEVRAccelConfig, NEVRTuning};
+use crate::engine::stats::NEVRStats;
+use crate::engine::status::{NEVRStatus, NEVRWarning};
 use crate::engine::voxel::{RenderVoxelType, VoxelType};
 use bevy::mesh::VertexFormat;
 use bevy::platform::collections::HashMap;
@@ -23,49 +26,153 @@ const MAX_COMPACTION_VERTICES_PER_FRAME: u32 = 400_000;
 pub struct BlasManager {
     blas: HashMap<AssetId<VoxelType>, Blas>,
     compaction_queue: VecDeque<(AssetId<VoxelType>, u32, bool)>,
+    // how many frames the BLASes of the types without visible blocks have been unused
+    unused_frames: HashMap<AssetId<VoxelType>, u32>,
 }
 
 impl BlasManager {
     pub fn get(&self, id: &AssetId<VoxelType>) -> Option<&Blas> {
         self.blas.get(id)
     }
+
+    /// The BLAS to trace for a block of the given type at `distance` from the camera, with the type it was built
+    /// from, check [VoxelType::with_lod].
+    ///
+    /// Falls back to the BLAS of the type itself while the one of the level isn't built yet.
+    pub fn get_lod(
+        &self,
+        geometry_manager: &GeometryManager,
+        id: &AssetId<VoxelType>,
+        distance: f32,
+    ) -> Option<(AssetId<VoxelType>, &Blas)> {
+        let lod = geometry_manager.lod_for_distance(id, distance);
+        self.blas
+            .get(&lod)
+            .map(|blas| (lod, blas))
+            .or_else(|| Some((*id, self.blas.get(id)?)))
+    }
+
+    fn remove(&mut self, id: &AssetId<VoxelType>) {
+        self.blas.remove(id);
+        self.unused_frames.remove(id);
+        self.compaction_queue
+            .retain(|(queued_id, _, _)| queued_id != id);
+    }
 }
 
+/// Builds the BLASes of the types used by visible blocks.
+///
+/// The BLAS of a type is freed [NEVRTuning::blas_eviction_frames] frames after the last visible block using it
+/// is gone and it's built again, from the geometry kept by [GeometryManager], when a block using it becomes
+/// visible.
+///
+/// The geometry of a type is built as a single BLAS however many voxels it has, the types with more triangles
+/// than the GPU allows in a BLAS ([bevy::render::settings::WgpuLimits::max_blas_primitive_count], at least
+/// 2^28 on the GPUs that support ray tracing) are skipped and reported with [NEVRWarning::TypeTooLarge].
+///
+/// The types whose materials let the light through (glass and thin films) are built as non-opaque geometry,
+/// the others as opaque geometry, which is faster to trace. Whether a type is opaque is checked when its
+/// geometry is built and again when its materials are uploaded or changed (e.g. a glass material loaded after
+/// the type was built), the BLAS is built again when it changes, check [GeometryManager::opacity_changed_types].
 pub fn prepare_blas(
     mut blas_manager: ResMut<BlasManager>,
     geometry_manager: Res<GeometryManager>,
+    tuning: Res<NEVRTuning>,
+    accel_config: Res<NEVRAccelConfig>,
     voxel_types: Res<ExtractedAssets<RenderVoxelType>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    status: Res<NEVRStatus>,
 ) {
     for id in &voxel_types.removed {
-        blas_manager.blas.remove(id);
+        blas_manager.remove(id);
     }
 
-    if voxel_types.extracted.is_empty() {
-        return;
+    let hidden_types = blas_manager
+        .blas
+        .keys()
+        .filter(|id| !geometry_manager.is_visible(id))
+        .copied()
+        .collect::<Vec<_>>();
+    blas_manager
+        .unused_frames
+        .retain(|id, _| hidden_types.contains(id));
+    for id in &hidden_types {
+        let frames = blas_manager.unused_frames.entry(*id).or_default();
+        *frames += 1;
+        if *frames > tuning.blas_eviction_frames {
+            blas_manager.remove(id);
+        }
     }
 
-    let blas_resources = voxel_types
-        .extracted
+    // the types whose geometry changed and the visible types that don't have a BLAS yet
+    let missing_types = geometry_manager
+        .visible_types()
+        .filter(|id| {
+            !blas_manager.blas.contains_key(*id) && !geometry_manager.rebuilt_types().contains(*id)
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    // the types whose BLAS has to switch between opaque and non-opaque geometry
+    let opacity_types = geometry_manager
+        .opacity_changed_types()
         .iter()
-        .map(|(id, _voxel_type)| {
-            let vertices = geometry_manager.get_geometry_vertices(id).unwrap();
-            let indices = geometry_manager.get_geometry_indices(id).unwrap();
+        .filter(|id| {
+            blas_manager.blas.contains_key(*id) && !geometry_manager.rebuilt_types().contains(*id)
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    let build_types = [
+        geometry_manager.rebuilt_types(),
+        &missing_types,
+        &opacity_types,
+    ]
+    .concat();
+    let max_primitives = render_device.limits().max_blas_primitive_count as u64;
 
+    let blas_resources = build_types
+        .iter()
+        .filter_map(|id| {
+            Some((
+                id,
+                geometry_manager.get_geometry_vertices(id)?,
+                geometry_manager.get_geometry_indices(id)?,
+            ))
+        })
+        .filter(|(_, _, indices)| {
+            // 4 bytes per int, 3 ints per triangle
+            let too_large = indices.size() / 12 > max_primitives;
+            if too_large {
+                status.report(NEVRWarning::TypeTooLarge);
+            }
+            !too_large
+        })
+        .map(|(id, vertices, indices)| {
+            // transient types are rebuilt too often for the compaction to pay off
+            let compact = !geometry_manager.is_transient(id);
             let (blas, blas_size) = allocate_blas(
                 vertices.size() as u32,
                 indices.size() as u32,
+                geometry_manager.is_opaque(id),
+                compact,
+                accel_config.flags(),
                 &render_device,
             );
+            blas_manager.remove(id);
             blas_manager.blas.insert(*id, blas);
-            blas_manager
-                .compaction_queue
-                .push_back((*id, blas_size.vertex_count, false));
+            if compact {
+                blas_manager
+                    .compaction_queue
+                    .push_back((*id, blas_size.vertex_count, false));
+            }
             (*id, vertices, indices, blas_size)
         })
         .collect::<Vec<_>>();
 
+    if blas_resources.is_empty() {
+        return;
+    }
+
     let build_entries = blas_resources
         .iter()
         .map(|(id, vertices, indices, blas_size)| {
@@ -93,7 +200,14 @@ pub fn prepare_blas(
     render_queue.submit([command_encoder.finish()]);
 }
 
-pub fn compact_blas(mut blas_manager: ResMut<BlasManager>, render_queue: Res<RenderQueue>) {
+pub fn compact_blas(
+    mut blas_manager: ResMut<BlasManager>,
+    render_queue: Res<RenderQueue>,
+    mut stats: ResMut<NEVRStats>,
+) {
+    stats.blas_count = blas_manager.blas.len() as u32;
+    stats.compaction_queue = blas_manager.compaction_queue.len() as u32;
+
     let queue_size = blas_manager.compaction_queue.len();
     let mut blocks_processed = 0;
     let mut vertices_processed = 0;
@@ -127,23 +241,33 @@ pub fn compact_blas(mut blas_manager: ResMut<BlasManager>, render_queue: Res<Ren
 fn allocate_blas(
     vertices_size: u32,
     indices_size: u32,
+    opaque: bool,
+    compact: bool,
+    mut flags: AccelerationStructureFlags,
     render_device: &RenderDevice,
 ) -> (Blas, BlasTriangleGeometrySizeDescriptor) {
     let blas_size = BlasTriangleGeometrySizeDescriptor {
         vertex_format: VertexFormat::Float32x3,
         // 3 floats in a vertex, 4 bytes in a float
-        vertex_count: vertices_size / 3 / 16,
+        vertex_count: vertices_size / 12,
         index_format: Some(IndexFormat::Uint32),
         // 4 bytes per int
         index_count: Some(indices_size / 4),
-        flags: AccelerationStructureGeometryFlags::OPAQUE,
+        flags: if opaque {
+            AccelerationStructureGeometryFlags::OPAQUE
+        } else {
+            AccelerationStructureGeometryFlags::NO_DUPLICATE_ANY_HIT_INVOCATION
+        },
     };
 
+    if compact {
+        flags |= AccelerationStructureFlags::ALLOW_COMPACTION;
+    }
+
     let blas = render_device.wgpu_device().create_blas(
         &CreateBlasDescriptor {
             label: None,
-            flags: AccelerationStructureFlags::PREFER_FAST_TRACE
-                | AccelerationStructureFlags::ALLOW_COMPACTION,
+            flags,
             update_mode: AccelerationStructureUpdateMode::Build,
         },
         BlasGeometrySizeDescriptors::Triangles {
@@ -1,9 +1,18 @@
 //! This module contains the camera needed to render voxels for NEVR.
 
+use crate::engine::settings::{NEVRAccumulationSubsteps, NEVRPaused, NEVRShadingMode, NEVRTuning};
+use crate::engine::skybox::VoxelSkybox;
+use crate::engine::voxel::VoxelMaterial;
 use bevy::camera::CameraMainTextureUsages;
+use bevy::camera::primitives::Aabb;
 use bevy::core_pipeline::core_3d::graph::Core3d;
+use bevy::ecs::change_detection::DetectChanges;
 use bevy::ecs::query::QueryItem;
-use bevy::prelude::{Camera, Camera2d, Component, Msaa, PerspectiveProjection, Projection};
+use bevy::prelude::{
+    Assets, Camera, Camera2d, Commands, Component, Entity, GlobalTransform, Mat4, Msaa,
+    PerspectiveProjection, Projection, Query, Ref, Reflect, ReflectComponent, ReflectDefault, Res,
+    Transform, Vec3, With,
+};
 use bevy::render::camera::CameraRenderGraph;
 use bevy::render::extract_component::ExtractComponent;
 use bevy::render::render_resource::encase::internal::{
@@ -11,7 +20,7 @@ use bevy::render::render_resource::encase::internal::{
 };
 use bevy::render::render_resource::encase::private::{Metadata, WriteInto};
 use bevy::render::render_resource::{ShaderType, TextureUsages};
-use bevy::render::view::{ColorGrading, Hdr};
+use bevy::render::view::{ColorGrading, ExtractedView, Hdr};
 use bytemuck::{Pod, Zeroable};
 use std::ops::Deref;
 
@@ -19,13 +28,59 @@ use std::ops::Deref;
 ///
 /// This camera enables HDR automatically (check Bevy's documentation for more information about HDR).
 ///
+/// The aspect ratio of the required [Projection] is only the initial value: Bevy updates it from the viewport
+/// of the camera when the camera is spawned and every time its target or viewport is resized, and the rays are
+/// generated from the resulting view matrices, so the image isn't stretched on windows that aren't 16:9.
+///
+/// When [Camera::viewport] is set only that region of the target is rendered, the rest is left untouched.
+///
+/// The `near` and `far` planes of the [Projection] clip the rays from the camera like they clip the rasterized
+/// geometry, so the blocks closer than `near` or farther than `far` aren't drawn and the depth matches the
+/// gizmos and the other overlays. Only the rays from the camera are clipped, the light bounces through the
+/// whole scene.
+///
+/// Many cameras can render into the same window: they're drawn in increasing [Camera::order] and every camera
+/// traces and denoises only its viewport, so a camera with a higher order and a smaller viewport is composited
+/// over the others. Every camera keeps its own accumulation, g-buffer and denoiser history, while the scene
+/// (the TLAS and the geometry) is shared and the levels of detail follow the closest camera.
+/// For example a picture-in-picture inset in the top-right corner of the window:
+/// ```rs
+/// fn setup(mut commands: Commands, window: Single<&Window>) {
+///     // the main view fills the window
+///     commands.spawn((
+///         VoxelCamera::default(),
+///         VoxelCamera::look_at(Vec3::new(8.0, 6.0, 8.0), Vec3::ZERO, Vec3::Y),
+///     ));
+///
+///     // the inset is drawn after the main view, over a quarter of the window
+///     let size = window.physical_size() / 4;
+///     commands.spawn((
+///         VoxelCamera::default(),
+///         Camera {
+///             order: 1,
+///             viewport: Some(Viewport {
+///                 physical_position: UVec2::new(window.physical_width() - size.x - 16, 16),
+///                 physical_size: size,
+///                 ..Default::default()
+///             }),
+///             ..Default::default()
+///         },
+///         VoxelCamera::look_at(Vec3::new(0.0, 20.0, 0.1), Vec3::ZERO, Vec3::Y),
+///     ));
+/// }
+/// ```
+/// Cameras with the same order and target are drawn in an unspecified order (Bevy warns about it), and the
+/// viewports are in physical pixels so they have to be updated when the window is resized.
+///
 /// Check the fields for more information.
-#[derive(Clone, Debug, Component)]
+#[derive(Clone, Debug, Component, Reflect)]
+#[reflect(Component, Default)]
 #[require(
     Camera,
     Camera2d::default(),
     Hdr,
     Msaa::Off,
+    VoxelAccumulation,
     ColorGrading::default(),
     CameraRenderGraph::new(Core3d),
     CameraMainTextureUsages(
@@ -51,9 +106,20 @@ pub struct VoxelCamera {
     pub focus_distance: f32,
     /// How many rays to shoot per pixel (samples per pixel).
     pub samples: u32,
-    /// The maximum number of bounces per ray (used only when hitting something).
-    pub bounces: u32,
-    /// Enable temporal accumulation to reduce noise using old frames (you have to reset the [bevy::diagnostic::FrameCount] when needed)
+    /// The maximum number of diffuse (lambertian) bounces per ray (used only when hitting something).
+    ///
+    /// A ray stops after this many diffuse bounces or after [VoxelCamera::specular_bounces] specular bounces,
+    /// and it never bounces more than the bigger of the two in total.
+    pub diffuse_bounces: u32,
+    /// The maximum number of specular (metallic, dielectric and thin film) bounces per ray (used only when
+    /// hitting something).
+    ///
+    /// Reflections and refractions usually need more bounces than diffuse lighting to look right.
+    pub specular_bounces: u32,
+    /// Enable temporal accumulation to reduce noise using old frames.
+    ///
+    /// The accumulation restarts when a [VoxelCamera] or its transform changes, check [update_accumulation].
+    /// Every camera keeps its own count of accumulated frames in [VoxelAccumulation].
     pub temporal_accumulation: bool,
 }
 
@@ -69,7 +135,8 @@ impl VoxelCamera {
             aperture,
             focus_distance,
             samples,
-            bounces,
+            diffuse_bounces: bounces,
+            specular_bounces: bounces,
             temporal_accumulation,
         }
     }
@@ -89,8 +156,19 @@ impl VoxelCamera {
         self
     }
 
+    /// Sets both [VoxelCamera::diffuse_bounces] and [VoxelCamera::specular_bounces].
     pub fn with_bounces(mut self, bounces: u32) -> Self {
-        self.bounces = bounces;
+        self.set_bounces(bounces);
+        self
+    }
+
+    pub fn with_diffuse_bounces(mut self, diffuse_bounces: u32) -> Self {
+        self.diffuse_bounces = diffuse_bounces;
+        self
+    }
+
+    pub fn with_specular_bounces(mut self, specular_bounces: u32) -> Self {
+        self.specular_bounces = specular_bounces;
         self
     }
 
@@ -98,6 +176,147 @@ impl VoxelCamera {
         self.temporal_accumulation = temporal_accumulation;
         self
     }
+
+    /// Sets the aperture in place, useful to change it at runtime (e.g. from a UI slider).
+    pub fn set_aperture(&mut self, aperture: f32) {
+        self.aperture = aperture;
+    }
+
+    /// Sets the focus distance in place, useful to change it at runtime (e.g. from a UI slider).
+    pub fn set_focus_distance(&mut self, focus_distance: f32) {
+        self.focus_distance = focus_distance;
+    }
+
+    /// Sets the samples per pixel in place, useful to change them at runtime (e.g. from a UI slider).
+    pub fn set_samples(&mut self, samples: u32) {
+        self.samples = samples;
+    }
+
+    /// Sets the maximum number of both diffuse and specular bounces in place, useful to change them at runtime
+    /// (e.g. from a UI slider).
+    pub fn set_bounces(&mut self, bounces: u32) {
+        self.diffuse_bounces = bounces;
+        self.specular_bounces = bounces;
+    }
+
+    /// Sets the maximum number of diffuse bounces in place.
+    pub fn set_diffuse_bounces(&mut self, diffuse_bounces: u32) {
+        self.diffuse_bounces = diffuse_bounces;
+    }
+
+    /// Sets the maximum number of specular bounces in place.
+    pub fn set_specular_bounces(&mut self, specular_bounces: u32) {
+        self.specular_bounces = specular_bounces;
+    }
+
+    /// Returns the transform of a camera at `eye` looking at `target`, with `up` pointing up on the screen.
+    ///
+    /// Like every Bevy camera a [VoxelCamera] looks along the negative Z axis of its [Transform], with a
+    /// right-handed Y-up world, this places it without building the rotation by hand:
+    /// ```rs
+    /// let eye = Vec3::new(-4.0, 3.0, 6.0);
+    /// let target = Vec3::ZERO;
+    /// commands.spawn((
+    ///     VoxelCamera::default().with_focus_distance(eye.distance(target)),
+    ///     VoxelCamera::look_at(eye, target, Vec3::Y),
+    /// ));
+    /// ```
+    ///
+    /// When `eye` and `target` are the same point the camera looks along the negative Z axis, when `up` is
+    /// parallel to the view direction any direction perpendicular to it is used instead.
+    pub fn look_at(eye: Vec3, target: Vec3, up: Vec3) -> Transform {
+        Transform::from_translation(eye).looking_at(target, up)
+    }
+
+    /// Returns a transform that looks at the center of `aabb` from above and to the side, far enough for the
+    /// whole box to fit in a vertical field of view of `fov` radians, and focuses the camera on the center.
+    ///
+    /// The default projection of [VoxelCamera] has a field of view of 90 degrees. Use it with
+    /// [scene_bounds](crate::scene_bounds) to see the whole scene:
+    /// ```rs
+    /// if let Some(bounds) = nevr::scene_bounds(world) {
+    ///     *transform = camera.frame_scene(&bounds, 90.0f32.to_radians());
+    /// }
+    /// ```
+    pub fn frame_scene(&mut self, aabb: &Aabb, fov: f32) -> Transform {
+        let center = Vec3::from(aabb.center);
+        // the sphere around the box fits in the view from every direction
+        let radius = aabb.half_extents.length().max(0.001);
+        let distance = radius / (fov.clamp(0.01, 3.0) * 0.5).sin();
+
+        self.focus_distance = distance;
+        Transform::from_translation(center + Vec3::new(1.0, 0.75, 1.0).normalize() * distance)
+            .looking_at(center, Vec3::Y)
+    }
+}
+
+/// The number of frames accumulated by a [VoxelCamera], added automatically to every camera.
+///
+/// Every camera has its own count, so moving one camera doesn't restart the accumulation of the others.
+#[derive(Clone, Copy, Debug, Default, Component)]
+pub struct VoxelAccumulation {
+    frames: u32,
+}
+
+impl VoxelAccumulation {
+    /// How many frames were accumulated since the last restart, 0 in the first frame after it. Every substep
+    /// of [NEVRAccumulationSubsteps] counts as a frame.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Restarts the accumulation in the next frame, useful when something else than the camera changes the
+    /// image (e.g. a block that moved).
+    pub fn reset(&mut self) {
+        self.frames = 0;
+    }
+}
+
+/// Advances the [VoxelAccumulation] of every [VoxelCamera], restarting it when the camera, its transform or its
+/// [Projection] changes, when rendering is resumed after [NEVRPaused], when the [NEVRShadingMode] or the
+/// [NEVRAccumulationSubsteps] change or in every frame while a [VoxelMaterialModel::Flipbook](crate::engine::voxel::VoxelMaterialModel::Flipbook)
+/// material or a scrolling [VoxelSkyboxLayer](crate::engine::skybox::VoxelSkyboxLayer) exists.
+pub fn update_accumulation(
+    mut cameras: Query<(
+        Ref<VoxelCamera>,
+        Ref<GlobalTransform>,
+        Option<Ref<Projection>>,
+        &mut VoxelAccumulation,
+    )>,
+    paused: Res<NEVRPaused>,
+    shading_mode: Res<NEVRShadingMode>,
+    substeps: Res<NEVRAccumulationSubsteps>,
+    materials: Res<Assets<VoxelMaterial>>,
+    skybox: Option<Res<VoxelSkybox>>,
+) {
+    if paused.0 {
+        return;
+    }
+
+    // the frames of the flipbooks and the scrolling skybox layers change the lighting over time
+    let animated = materials.iter().any(|(_, material)| material.is_flipbook())
+        || skybox.is_some_and(|skybox| skybox.is_animated());
+
+    for (camera, transform, projection, mut accumulation) in &mut cameras {
+        if animated
+            || paused.is_changed()
+            || shading_mode.is_changed()
+            || substeps.is_changed()
+            || camera.is_changed()
+            || transform.is_changed()
+            || projection.is_some_and(|projection| projection.is_changed())
+        {
+            accumulation.frames = 0;
+        } else {
+            // the substeps of the last frame were all accumulated, a single one without the temporal accumulation
+            let traced = if camera.temporal_accumulation {
+                substeps.0.max(1)
+            } else {
+                1
+            };
+            accumulation.frames = accumulation.frames.saturating_add(traced);
+        }
+    }
 }
 
 impl Default for VoxelCamera {
@@ -107,12 +326,22 @@ impl Default for VoxelCamera {
 }
 
 impl ExtractComponent for VoxelCamera {
-    type QueryData = &'static VoxelCamera;
+    type QueryData = (
+        &'static VoxelCamera,
+        &'static VoxelAccumulation,
+        Option<&'static Projection>,
+    );
     type QueryFilter = ();
     type Out = RayCamera;
 
-    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
-        Some(RayCamera::from(item))
+    fn extract_component(
+        (camera, accumulation, projection): QueryItem<'_, '_, Self::QueryData>,
+    ) -> Option<Self::Out> {
+        let mut camera = RayCamera::from(camera).with_accumulated_frames(accumulation.frames);
+        if let Some(projection) = projection {
+            camera = camera.with_clipping(projection);
+        }
+        Some(camera)
     }
 }
 
@@ -122,8 +351,85 @@ pub struct RayCamera {
     aperture: f32,
     focus_distance: f32,
     samples: u32,
-    bounces: u32,
+    diffuse_bounces: u32,
+    specular_bounces: u32,
     temporal_accumulation: u32,
+    seed: u32,
+    terminator_softness: f32,
+    accumulated_frames: u32,
+    shading_mode: u32,
+    near: f32,
+    far: f32,
+}
+
+impl RayCamera {
+    /// Sets the seed mixed into the random numbers of this view, check [crate::engine::settings::NEVRSeed].
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets the number of frames accumulated by this view, check [VoxelAccumulation].
+    pub fn with_accumulated_frames(mut self, accumulated_frames: u32) -> Self {
+        self.accumulated_frames = accumulated_frames;
+        self
+    }
+
+    pub fn focus_distance(&self) -> f32 {
+        self.focus_distance
+    }
+
+    /// How many frames the accumulation of this view holds before the current one, 0 when the temporal
+    /// accumulation is disabled.
+    pub fn accumulated_frames(&self) -> u32 {
+        if self.temporal_accumulation > 0 {
+            self.accumulated_frames
+        } else {
+            0
+        }
+    }
+
+    /// How many substeps this view traces in a frame, 1 when the temporal accumulation is disabled, check
+    /// [NEVRAccumulationSubsteps].
+    pub fn substeps(&self, substeps: &NEVRAccumulationSubsteps) -> u32 {
+        if self.temporal_accumulation > 0 {
+            substeps.0.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Sets the tuning parameters used by this view, check [NEVRTuning].
+    pub fn with_tuning(mut self, tuning: &NEVRTuning) -> Self {
+        self.terminator_softness = tuning.terminator_softness.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the distances the rays from the camera are clipped at from the near and far planes of `projection`.
+    ///
+    /// Custom projections don't expose their planes, the defaults of [VoxelCamera] are kept for them, like for
+    /// the projections whose far plane isn't past the near one. The near plane of orthographic projections
+    /// (0.0 by default) is kept at least 0.001, or the rays would hit the surface they start from.
+    pub fn with_clipping(mut self, projection: &Projection) -> Self {
+        let (near, far) = match projection {
+            Projection::Perspective(perspective) => (perspective.near, perspective.far),
+            Projection::Orthographic(orthographic) => {
+                (orthographic.near.max(0.001), orthographic.far)
+            }
+            Projection::Custom(_) => return self,
+        };
+        if far > near {
+            self.near = near;
+            self.far = far;
+        }
+        self
+    }
+
+    /// Sets how this view is shaded, check [NEVRShadingMode].
+    pub fn with_shading_mode(mut self, shading_mode: NEVRShadingMode) -> Self {
+        self.shading_mode = shading_mode as u32;
+        self
+    }
 }
 
 impl<C: Deref<Target = VoxelCamera>> From<C> for RayCamera {
@@ -132,8 +438,15 @@ impl<C: Deref<Target = VoxelCamera>> From<C> for RayCamera {
             aperture: camera.aperture,
             focus_distance: camera.focus_distance,
             samples: camera.samples,
-            bounces: camera.bounces,
+            diffuse_bounces: camera.diffuse_bounces,
+            specular_bounces: camera.specular_bounces,
             temporal_accumulation: if camera.temporal_accumulation { 1 } else { 0 },
+            seed: 0,
+            terminator_softness: 1.0,
+            accumulated_frames: 0,
+            shading_mode: NEVRShadingMode::Full as u32,
+            near: 0.001,
+            far: 10000.0,
         }
     }
 }
@@ -143,7 +456,7 @@ impl ShaderType for RayCamera {
     const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
         alignment: AlignmentValue::new(4),
         has_uniform_min_alignment: false,
-        min_size: SizeValue::new(20),
+        min_size: SizeValue::new(48),
         is_pod: false,
         extra: (),
     };
@@ -157,7 +470,174 @@ impl WriteInto for RayCamera {
         writer.write(&self.aperture.to_le_bytes());
         writer.write(&self.focus_distance.to_le_bytes());
         writer.write(&self.samples.to_le_bytes());
-        writer.write(&self.bounces.to_le_bytes());
+        writer.write(&self.diffuse_bounces.to_le_bytes());
+        writer.write(&self.specular_bounces.to_le_bytes());
         writer.write(&self.temporal_accumulation.to_le_bytes());
+        writer.write(&self.seed.to_le_bytes());
+        writer.write(&self.terminator_softness.to_le_bytes());
+        writer.write(&self.accumulated_frames.to_le_bytes());
+        writer.write(&self.shading_mode.to_le_bytes());
+        writer.write(&self.near.to_le_bytes());
+        writer.write(&self.far.to_le_bytes());
+    }
+}
+
+/// The view-projection matrices of a view in the render world, used to compute the motion vectors.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PreviousRayView {
+    /// The clip-from-world matrix of the last frame, the same as the current one in the first frame.
+    pub clip_from_world: Mat4,
+    current_clip_from_world: Mat4,
+}
+
+/// Moves the view-projection matrix of the last frame into [PreviousRayView] for every view.
+pub fn prepare_previous_views(
+    mut commands: Commands,
+    mut views: Query<(Entity, &ExtractedView, Option<&mut PreviousRayView>), With<RayCamera>>,
+) {
+    for (entity, view, previous_view) in &mut views {
+        let clip_from_world = view
+            .clip_from_world
+            .unwrap_or_else(|| view.clip_from_view * view.world_from_view.to_matrix().inverse());
+
+        match previous_view {
+            Some(mut previous_view) => {
+                previous_view.clip_from_world = previous_view.current_clip_from_world;
+                previous_view.current_clip_from_world = clip_from_world;
+            }
+            None => {
+                commands.entity(entity).insert(PreviousRayView {
+                    clip_from_world,
+                    current_clip_from_world: clip_from_world,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::{OrthographicProjection, Schedule, World};
+
+    // a world with a camera and the systems that update its accumulation
+    fn accumulation_world() -> (World, Schedule, Entity) {
+        let mut world = World::new();
+        world.init_resource::<NEVRPaused>();
+        world.init_resource::<NEVRShadingMode>();
+        world.init_resource::<NEVRAccumulationSubsteps>();
+        world.init_resource::<Assets<VoxelMaterial>>();
+        // the accumulation and the transform are required by the camera
+        let camera = world.spawn(VoxelCamera::default()).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_accumulation);
+        (world, schedule, camera)
+    }
+
+    fn accumulated_frames(world: &World, camera: Entity) -> u32 {
+        world.get::<VoxelAccumulation>(camera).unwrap().frames()
+    }
+
+    #[test]
+    fn moving_a_camera_restarts_only_its_accumulation() {
+        let (mut world, mut schedule, first) = accumulation_world();
+        let second = world.spawn(VoxelCamera::default()).id();
+
+        for moved in [first, second] {
+            for _ in 0..3 {
+                schedule.run(&mut world);
+            }
+            let still = if moved == first { second } else { first };
+            let frames = accumulated_frames(&world, still);
+
+            *world.get_mut::<GlobalTransform>(moved).unwrap() =
+                GlobalTransform::from_xyz(1.0, 0.0, 0.0);
+            schedule.run(&mut world);
+            assert_eq!(accumulated_frames(&world, moved), 0);
+            assert_eq!(accumulated_frames(&world, still), frames + 1);
+        }
+    }
+
+    #[test]
+    fn setters_restart_the_accumulation() {
+        let (mut world, mut schedule, camera) = accumulation_world();
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(accumulated_frames(&world, camera), 2);
+
+        world.get_mut::<VoxelCamera>(camera).unwrap().set_samples(4);
+        schedule.run(&mut world);
+        assert_eq!(accumulated_frames(&world, camera), 0);
+        assert_eq!(world.get::<VoxelCamera>(camera).unwrap().samples, 4);
+    }
+
+    #[test]
+    fn substeps_count_only_with_temporal_accumulation() {
+        let (mut world, mut schedule, camera) = accumulation_world();
+        world.insert_resource(NEVRAccumulationSubsteps(4));
+        let temporal = world
+            .spawn(VoxelCamera::default().with_temporal_accumulation(true))
+            .id();
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(accumulated_frames(&world, camera), 2);
+        assert_eq!(accumulated_frames(&world, temporal), 8);
+    }
+
+    #[test]
+    fn tuning_clamps_terminator_softness() {
+        let camera = RayCamera::from(&VoxelCamera::default());
+
+        let tuning = NEVRTuning {
+            terminator_softness: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(camera.with_tuning(&tuning).terminator_softness, 1.0);
+
+        let tuning = NEVRTuning {
+            terminator_softness: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(camera.with_tuning(&tuning).terminator_softness, 0.0);
+    }
+
+    #[test]
+    fn look_at_faces_the_target() {
+        let transform = VoxelCamera::look_at(Vec3::Z * 5.0, Vec3::ZERO, Vec3::Y);
+        assert_eq!(transform.translation, Vec3::Z * 5.0);
+        assert!(transform.forward().abs_diff_eq(Vec3::NEG_Z, 1e-6));
+        assert!(transform.up().abs_diff_eq(Vec3::Y, 1e-6));
+    }
+
+    #[test]
+    fn clipping_follows_the_projection() {
+        let camera = RayCamera::from(&VoxelCamera::default());
+        let perspective = Projection::Perspective(PerspectiveProjection {
+            near: 0.5,
+            far: 100.0,
+            ..Default::default()
+        });
+        let clipped = camera.with_clipping(&perspective);
+        assert_eq!((clipped.near, clipped.far), (0.5, 100.0));
+
+        let orthographic = Projection::Orthographic(OrthographicProjection {
+            near: 0.0,
+            far: 50.0,
+            ..OrthographicProjection::default_3d()
+        });
+        let clipped = camera.with_clipping(&orthographic);
+        assert_eq!((clipped.near, clipped.far), (0.001, 50.0));
+
+        // a far plane before the near one keeps the defaults
+        let inverted = Projection::Perspective(PerspectiveProjection {
+            near: 10.0,
+            far: 1.0,
+            ..Default::default()
+        });
+        let clipped = camera.with_clipping(&inverted);
+        assert_eq!((clipped.near, clipped.far), (camera.near, camera.far));
     }
 }
@@ -0,0 +1,82 @@
+//! This module contains the capabilities of the GPU used by NEVR.
+
+use bevy::prelude::Resource;
+use bevy::render::render_resource::{TextureFormat, TextureFormatFeatureFlags};
+use bevy::render::renderer::{RenderAdapter, RenderDevice};
+use bevy::render::settings::WgpuFeatures;
+
+/// Which features of NEVR the GPU supports, check [crate::NEVRPlugin::available_modes].
+///
+/// It is available in both the main and the render world once [crate::NEVRPlugin] is finished, so it can be
+/// used to show the supported modes or to pick quality presets before spawning a camera:
+/// ```rs
+/// fn show_capabilities(capabilities: Res<NEVRCapabilities>) {
+///     let supported = if capabilities.hardware_ray_tracing { "Yes" } else { "No" };
+///     println!("Hardware ray tracing: {supported}");
+/// }
+/// ```
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NEVRCapabilities {
+    /// The GPU can build acceleration structures.
+    pub acceleration_structures: bool,
+    /// The GPU supports ray queries in shaders.
+    pub ray_query: bool,
+    /// The GPU supports everything needed by hardware ray tracing, NEVR renders only when this is true.
+    pub hardware_ray_tracing: bool,
+    /// The GPU supports timestamp queries, used to measure the time spent on the GPU.
+    pub timestamps: bool,
+    /// The format of the HDR textures written by the shaders (output, accumulation, denoiser and focus plane).
+    ///
+    /// The accumulation is read and written by the same shader, which WebGPU doesn't guarantee for
+    /// `Rgba16Float`: it needs the `TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES` feature and an adapter that
+    /// supports it. When it's `None` NEVR reports it and doesn't render. `Rgba32Float` isn't used as a fallback
+    /// since it has the same restriction.
+    pub hdr_storage_format: Option<TextureFormat>,
+    /// Whether the software ray tracing fallback is used.
+    ///
+    /// The software path doesn't exist yet, so this is always false and nothing is rendered without
+    /// hardware ray tracing.
+    pub software_fallback: bool,
+}
+
+impl NEVRCapabilities {
+    pub fn from_features(features: WgpuFeatures) -> Self {
+        let acceleration_structures =
+            features.contains(WgpuFeatures::EXPERIMENTAL_RAY_TRACING_ACCELERATION_STRUCTURE);
+        let ray_query = features.contains(WgpuFeatures::EXPERIMENTAL_RAY_QUERY);
+
+        Self {
+            acceleration_structures,
+            ray_query,
+            hardware_ray_tracing: acceleration_structures && ray_query,
+            timestamps: features.contains(WgpuFeatures::TIMESTAMP_QUERY),
+            hdr_storage_format: None,
+            software_fallback: false,
+        }
+    }
+
+    /// Whether NEVR renders on this GPU: it needs hardware ray tracing and an [NEVRCapabilities::hdr_storage_format].
+    pub fn is_supported(&self) -> bool {
+        self.hardware_ray_tracing && self.hdr_storage_format.is_some()
+    }
+
+    /// Like [NEVRCapabilities::from_features], but the texture formats are checked on the adapter too.
+    pub fn from_device(render_device: &RenderDevice, render_adapter: &RenderAdapter) -> Self {
+        let features = render_device.features();
+        let format = TextureFormat::Rgba16Float;
+        let format_features =
+            if features.contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+                render_adapter.get_texture_format_features(format)
+            } else {
+                format.guaranteed_format_features(features)
+            };
+
+        Self {
+            hdr_storage_format: format_features
+                .flags
+                .contains(TextureFormatFeatureFlags::STORAGE_READ_WRITE)
+                .then_some(format),
+            ..Self::from_features(features)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! This module contains the chunk loader used to stream blocks around the cameras.
+
+use crate::engine::camera::VoxelCamera;
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::prelude::{
+    ChildSpawnerCommands, Commands, Component, Entity, GlobalTransform, IVec3, Query, ResMut,
+    Resource, Transform, Vec3, Visibility, With,
+};
+
+/// A chunk spawned by [NEVRChunkLoader], every [crate::engine::voxel::VoxelBlock] generated for the chunk
+/// is spawned as a child of this entity.
+#[derive(Component, Debug, Clone, Copy)]
+#[require(Transform, Visibility::Inherited)]
+pub struct VoxelChunk(pub IVec3);
+
+/// Function used to generate the content of a chunk.
+///
+/// It receives the coordinates of the chunk and spawns its blocks as children of the chunk's entity,
+/// the blocks' [Transform]s are relative to the origin of the chunk.
+pub type ChunkGenerator = Box<dyn Fn(IVec3, &mut ChildSpawnerCommands) + Send + Sync>;
+
+/// Streams chunks of blocks based on the position of the [VoxelCamera]s.
+///
+/// Every frame, the chunks within `view_distance` (in chunks) from any camera are generated through the
+/// provided [ChunkGenerator] and the chunks that are too far away are despawned, so only the nearby
+/// chunks contribute to the TLAS.
+/// ```rs
+/// commands.insert_resource(NEVRChunkLoader::new(16.0, 4, move |chunk, parent| {
+///     let position = Vec3::new(0.0, 0.0, 0.0);
+///     parent.spawn((VoxelBlock::new(voxel_type.clone()), Transform::from_translation(position)));
+/// }));
+/// ```
+///
+/// **Note:** the BLAS of a [crate::engine::voxel::VoxelType] is built only the first time the type is extracted,
+/// so reuse the same handles across chunks: creating new types while generating a chunk forces a BLAS build
+/// every time a camera crosses a chunk boundary, which may cause stutters.
+#[derive(Resource)]
+pub struct NEVRChunkLoader {
+    /// The size of a chunk in world units (chunks are cubes).
+    pub chunk_size: f32,
+    /// How many chunks to load around a camera.
+    pub view_distance: u32,
+    generator: ChunkGenerator,
+    loaded: HashMap<IVec3, Entity>,
+}
+
+impl NEVRChunkLoader {
+    pub fn new(
+        chunk_size: f32,
+        view_distance: u32,
+        generator: impl Fn(IVec3, &mut ChildSpawnerCommands) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            chunk_size,
+            view_distance,
+            generator: Box::new(generator),
+            loaded: HashMap::default(),
+        }
+    }
+
+    /// Returns the coordinates of the chunk containing the position.
+    pub fn chunk_at(&self, position: Vec3) -> IVec3 {
+        (position / self.chunk_size).floor().as_ivec3()
+    }
+
+    /// Returns the entity of a loaded chunk.
+    pub fn get(&self, chunk: IVec3) -> Option<Entity> {
+        self.loaded.get(&chunk).cloned()
+    }
+
+    /// Returns how many chunks are currently loaded.
+    pub fn loaded_count(&self) -> usize {
+        self.loaded.len()
+    }
+
+    fn in_range(&self, chunk: IVec3, center: IVec3, distance: u32) -> bool {
+        let distance = distance as i32;
+        (chunk - center).length_squared() <= distance * distance
+    }
+}
+
+/// Spawns the chunks near the cameras and despawns the ones out of range.
+pub fn update_chunks(
+    mut chunk_loader: ResMut<NEVRChunkLoader>,
+    cameras: Query<&GlobalTransform, With<VoxelCamera>>,
+    mut commands: Commands,
+) {
+    let centers = cameras
+        .iter()
+        .map(|transform| chunk_loader.chunk_at(transform.translation()))
+        .collect::<Vec<_>>();
+
+    let distance = chunk_loader.view_distance as i32;
+    let mut required: HashSet<IVec3> = HashSet::default();
+
+    for center in &centers {
+        for x in -distance..=distance {
+            for y in -distance..=distance {
+                for z in -distance..=distance {
+                    let chunk = *center + IVec3::new(x, y, z);
+                    if chunk_loader.in_range(chunk, *center, chunk_loader.view_distance) {
+                        required.insert(chunk);
+                    }
+                }
+            }
+        }
+    }
+
+    // chunks are unloaded one chunk further than they are loaded to avoid spawning and despawning
+    // the same chunk when a camera moves back and forth on a chunk boundary
+    let unload_distance = chunk_loader.view_distance + 1;
+    let to_unload = chunk_loader
+        .loaded
+        .keys()
+        .filter(|chunk| {
+            !centers
+                .iter()
+                .any(|center| chunk_loader.in_range(**chunk, *center, unload_distance))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for chunk in to_unload {
+        if let Some(entity) = chunk_loader.loaded.remove(&chunk) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for chunk in required {
+        if chunk_loader.loaded.contains_key(&chunk) {
+            continue;
+        }
+
+        let origin = chunk.as_vec3() * chunk_loader.chunk_size;
+        let generator = &chunk_loader.generator;
+        let entity = commands
+            .spawn((VoxelChunk(chunk), Transform::from_translation(origin)))
+            .with_children(|parent| generator(chunk, parent))
+            .id();
+
+        chunk_loader.loaded.insert(chunk, entity);
+    }
+}
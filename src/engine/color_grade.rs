@@ -0,0 +1,248 @@
+//! Color grading module.
+
+use crate::engine::camera::RayCamera;
+use crate::engine::capabilities::NEVRCapabilities;
+use bevy::app::App;
+use bevy::asset::{embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::FullscreenShader;
+use bevy::core_pipeline::core_3d::graph::{Core3d, Node3d};
+use bevy::ecs::query::QueryItem;
+use bevy::prelude::{
+    Commands, Component, Entity, FromWorld, Handle, Image, IntoScheduleConfigs, Plugin, Query, Res,
+    ResMut, Resource, With, World,
+};
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+};
+use bevy::render::render_resource::binding_types::{sampler, texture_2d, texture_3d};
+use bevy::render::render_resource::{
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
+    ColorTargetState, ColorWrites, FilterMode, FragmentState, LoadOp, Operations, PipelineCache,
+    RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor, Sampler,
+    SamplerBindingType, SamplerDescriptor, ShaderStages, SpecializedRenderPipeline,
+    SpecializedRenderPipelines, StoreOp, TextureFormat, TextureSampleType,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice};
+use bevy::render::texture::GpuImage;
+use bevy::render::view::ViewTarget;
+use bevy::render::{Render, RenderApp, RenderSystems};
+use bevy::shader::Shader;
+
+/// Color grades the final image with a 3D lookup table (LUT).
+///
+/// The LUT must be a 3D texture (usually 32x32x32, like the ones converted from `.cube` files) where the red
+/// channel grows along x, the green channel along y and the blue channel along z. It is sampled with the
+/// sRGB-encoded color, which is the space `.cube` LUTs are authored in, and the result is decoded back to linear.
+///
+/// The grade is applied after exposure and tonemapping, so the LUT always receives colors between 0.0 and 1.0.
+/// It is off by default, insert this resource to enable it and remove it to disable it:
+/// ```rs
+/// commands.insert_resource(NEVRColorGrade(asset_server.load("luts/warm.ktx2")));
+/// ```
+#[derive(Resource, ExtractResource, Clone, Debug)]
+pub struct NEVRColorGrade(pub Handle<Image>);
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct ColorGradeLabel;
+
+/// The plugin which adds the color grading pass, check [NEVRColorGrade].
+///
+/// This is enabled by default when using [nevr::NEVRPlugin].
+pub struct ColorGradePlugin;
+
+impl Plugin for ColorGradePlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "shaders/color_grade.wgsl");
+
+        app.add_plugins(ExtractResourcePlugin::<NEVRColorGrade>::default());
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        render_app
+            .init_resource::<ColorGradePipeline>()
+            .init_resource::<SpecializedRenderPipelines<ColorGradePipeline>>()
+            .add_systems(
+                Render,
+                prepare_color_grade_pipelines.in_set(RenderSystems::Prepare),
+            )
+            .add_render_graph_node::<ViewNodeRunner<ColorGradeNode>>(Core3d, ColorGradeLabel)
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::Tonemapping,
+                    ColorGradeLabel,
+                    Node3d::EndMainPassPostProcessing,
+                ),
+            );
+    }
+}
+
+#[derive(Resource)]
+pub struct ColorGradePipeline {
+    bind_group_layout: BindGroupLayout,
+    screen_sampler: Sampler,
+    lut_sampler: Sampler,
+    fullscreen_shader: FullscreenShader,
+    fragment_shader: Handle<Shader>,
+}
+
+impl FromWorld for ColorGradePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let bind_group_layout = render_device.create_bind_group_layout(
+            "voxel_color_grade_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    // Screen texture
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    // Screen sampler
+                    sampler(SamplerBindingType::NonFiltering),
+                    // LUT texture
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    // LUT sampler
+                    sampler(SamplerBindingType::Filtering),
+                ),
+            ),
+        );
+
+        let screen_sampler = render_device.create_sampler(&SamplerDescriptor::default());
+        let lut_sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("voxel_color_grade_lut_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            bind_group_layout,
+            screen_sampler,
+            lut_sampler,
+            fullscreen_shader: world.resource::<FullscreenShader>().clone(),
+            fragment_shader: load_embedded_asset!(world, "shaders/color_grade.wgsl"),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for ColorGradePipeline {
+    type Key = TextureFormat;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("voxel_color_grade_pipeline".into()),
+            layout: vec![self.bind_group_layout.clone()],
+            vertex: self.fullscreen_shader.to_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.fragment_shader.clone(),
+                targets: vec![Some(ColorTargetState {
+                    format: key,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ViewColorGradePipeline(CachedRenderPipelineId);
+
+fn prepare_color_grade_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<ColorGradePipeline>>,
+    color_grade_pipeline: Res<ColorGradePipeline>,
+    views: Query<(Entity, &ViewTarget), With<RayCamera>>,
+) {
+    for (entity, view_target) in &views {
+        let pipeline = pipelines.specialize(
+            &pipeline_cache,
+            &color_grade_pipeline,
+            view_target.main_texture_format(),
+        );
+
+        commands
+            .entity(entity)
+            .insert(ViewColorGradePipeline(pipeline));
+    }
+}
+
+#[derive(Default)]
+pub struct ColorGradeNode;
+
+impl ViewNode for ColorGradeNode {
+    type ViewQuery = (&'static ViewTarget, &'static ViewColorGradePipeline);
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (view_target, view_pipeline): QueryItem<'w, '_, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let Some(color_grade) = world.get_resource::<NEVRColorGrade>() else {
+            return Ok(());
+        };
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let color_grade_pipeline = world.resource::<ColorGradePipeline>();
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(view_pipeline.0) else {
+            return Ok(());
+        };
+        // the LUT may still be loading
+        let Some(lut) = gpu_images.get(color_grade.0.id()) else {
+            return Ok(());
+        };
+
+        let post_process = view_target.post_process_write();
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "voxel_color_grade_bind_group",
+            &color_grade_pipeline.bind_group_layout,
+            &BindGroupEntries::sequential((
+                post_process.source,
+                &color_grade_pipeline.screen_sampler,
+                &lut.texture_view,
+                &color_grade_pipeline.lut_sampler,
+            )),
+        );
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("voxel_color_grade"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: post_process.destination,
+                depth_slice: None,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Default::default()),
+                    store: StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_render_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+//! This module contains the estimate of how converged the accumulated image of a camera is.
+
+use crate::VoxelViewTarget;
+use crate::engine::camera::RayCamera;
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::denoiser::DenoiserLabel;
+use crate::engine::node::NEVRNodeLabel;
+use crate::engine::settings::{NEVRAccumulationSubsteps, NEVRDebugView};
+use crate::engine::status::{NEVRStatus, NEVRWarning};
+use bevy::app::App;
+use bevy::asset::{RenderAssetUsages, embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::core_3d::graph::Core3d;
+use bevy::ecs::change_detection::DetectChangesMut;
+use bevy::ecs::observer::On;
+use bevy::ecs::query::QueryItem;
+use bevy::prelude::{
+    AssetId, Assets, ChildOf, Commands, Component, Entity, FromWorld, Handle, Plugin, Query,
+    ResMut, Update, Vec4, World,
+};
+use bevy::render::RenderApp;
+use bevy::render::camera::ExtractedCamera;
+use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+};
+use bevy::render::render_resource::binding_types::{
+    storage_buffer_sized, texture_storage_2d, uniform_buffer,
+};
+use bevy::render::render_resource::{
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BufferUsages,
+    CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache,
+    ShaderStages, StorageTextureAccess, TextureFormat, UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::storage::{GpuShaderStorageBuffer, ShaderStorageBuffer};
+use std::num::NonZeroU64;
+
+// the sum of the variances, the number of pixels, the mean variance and the padding
+const CONVERGENCE_SIZE: usize = 16;
+// where the mean variance starts in the buffer
+const MEAN_VARIANCE_OFFSET: usize = 8;
+// the side of the region covered by a workgroup of the reduction, 8 invocations reading one pixel every 4, it
+// must match the shader
+const PIXELS_PER_WORKGROUP: u32 = 8 * 4;
+
+/// Estimates how converged the accumulated image of a camera is, e.g. to show a progress bar while rendering
+/// until the image is clean.
+///
+/// Add it to the entity of a [crate::engine::camera::VoxelCamera] with the temporal accumulation enabled:
+/// ```rs
+/// commands.spawn((VoxelCamera::default().with_temporal_accumulation(true), NEVRConvergence::default()));
+/// ```
+///
+/// Every frame the ray tracer measures how much the new samples differ from the accumulated color of every
+/// pixel, and the mean variance of the accumulated image (relative to its luminance) is computed on the GPU
+/// over a downsampled copy of the image. The variance is read back from the GPU, so it lags a couple of frames
+/// behind what is rendered.
+///
+/// [NEVRConvergence::progress] compares it with `noise`, the noise of a clean image: since the noise of the
+/// accumulation halves every four times the frames, the progress grows about linearly with the rendering time
+/// and reaches 1.0 when the image is clean. The progress restarts with the accumulation and stays near 0.0 when
+/// the temporal accumulation is disabled.
+#[derive(Component, Clone, Debug)]
+pub struct NEVRConvergence {
+    /// The relative noise (the standard deviation of the accumulated colors divided by their luminance) of an
+    /// image considered clean. Defaults to 0.02.
+    pub noise: f32,
+    variance: Option<f32>,
+    buffer: Option<Handle<ShaderStorageBuffer>>,
+}
+
+impl NEVRConvergence {
+    pub fn new(noise: f32) -> Self {
+        Self {
+            noise,
+            variance: None,
+            buffer: None,
+        }
+    }
+
+    pub fn with_noise(mut self, noise: f32) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    /// The last mean relative variance of the accumulated image read from the GPU, [None] until the first one
+    /// is read.
+    pub fn variance(&self) -> Option<f32> {
+        self.variance
+    }
+
+    /// How close the accumulated image is to being clean, from 0.0 to 1.0, [None] until the first variance is
+    /// read.
+    pub fn progress(&self) -> Option<f32> {
+        let variance = self.variance?;
+        if variance <= 0.0 {
+            return Some(1.0);
+        }
+
+        Some((self.noise * self.noise / variance).clamp(0.0, 1.0))
+    }
+}
+
+impl Default for NEVRConvergence {
+    fn default() -> Self {
+        Self::new(0.02)
+    }
+}
+
+/// Used in the rendering phase to compute the variance of a view.
+#[derive(Component, Clone, Debug)]
+pub struct RenderConvergence {
+    pub buffer: AssetId<ShaderStorageBuffer>,
+}
+
+impl ExtractComponent for NEVRConvergence {
+    type QueryData = &'static NEVRConvergence;
+    type QueryFilter = ();
+    type Out = RenderConvergence;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        Some(RenderConvergence {
+            buffer: item.buffer.as_ref()?.id(),
+        })
+    }
+}
+
+/// Entity reading back the variance of a camera.
+#[derive(Component)]
+pub struct ConvergenceReadback {
+    camera: Entity,
+}
+
+/// Creates the buffers used to compute the variances and removes the unused ones.
+pub fn prepare_convergence(
+    mut cameras: Query<(Entity, &mut NEVRConvergence)>,
+    readbacks: Query<(Entity, &ConvergenceReadback)>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    for (entity, readback) in &readbacks {
+        if !cameras.contains(readback.camera) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (camera, mut convergence) in &mut cameras {
+        if convergence.buffer.is_some() {
+            continue;
+        }
+
+        // the mean starts as NaN, so nothing is read until the first variance is computed
+        let mut data = vec![0; CONVERGENCE_SIZE];
+        data[MEAN_VARIANCE_OFFSET..MEAN_VARIANCE_OFFSET + 4]
+            .copy_from_slice(&f32::NAN.to_le_bytes());
+        let mut buffer = ShaderStorageBuffer::new(&data, RenderAssetUsages::RENDER_WORLD);
+        buffer.buffer_description.usage |= BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let buffer = buffers.add(buffer);
+
+        commands
+            .spawn((
+                Readback::buffer(buffer.clone()),
+                ConvergenceReadback { camera },
+                ChildOf(camera),
+            ))
+            .observe(read_convergence);
+
+        convergence.buffer = Some(buffer);
+    }
+}
+
+fn read_convergence(
+    event: On<ReadbackComplete>,
+    readbacks: Query<&ConvergenceReadback>,
+    mut cameras: Query<&mut NEVRConvergence>,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    let Ok(mut convergence) = cameras.get_mut(readback.camera) else {
+        return;
+    };
+    let Some(bytes) = event
+        .data
+        .get(MEAN_VARIANCE_OFFSET..)
+        .and_then(|bytes| bytes.first_chunk::<4>())
+    else {
+        return;
+    };
+
+    let variance = f32::from_le_bytes(*bytes);
+    if variance.is_finite() {
+        convergence.bypass_change_detection().variance = Some(variance);
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct ConvergenceLabel;
+
+/// The plugin which adds the convergence estimate, check [NEVRConvergence].
+///
+/// This is enabled by default when using [nevr::NEVRPlugin].
+pub struct ConvergencePlugin;
+
+impl Plugin for ConvergencePlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "shaders/convergence.wgsl");
+
+        app.add_plugins(ExtractComponentPlugin::<NEVRConvergence>::default())
+            .add_systems(Update, prepare_convergence);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<ConvergenceNode>>(Core3d, ConvergenceLabel)
+            .add_render_graph_edges(Core3d, (NEVRNodeLabel, ConvergenceLabel, DenoiserLabel));
+    }
+}
+
+pub struct ConvergenceNode {
+    sum_pipeline: CachedComputePipelineId,
+    average_pipeline: CachedComputePipelineId,
+    binding_layout: BindGroupLayout,
+}
+
+impl FromWorld for ConvergenceNode {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let binding_layout = render_device.create_bind_group_layout(
+            "voxel_convergence_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // Accumulation
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Convergence
+                    storage_buffer_sized(false, NonZeroU64::new(CONVERGENCE_SIZE as u64)),
+                    // Accumulated frames
+                    uniform_buffer::<Vec4>(false),
+                ),
+            ),
+        );
+
+        let sum_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_convergence_sum_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/convergence.wgsl"),
+            entry_point: Some("sum_variance".into()),
+            ..Default::default()
+        });
+
+        let average_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_convergence_average_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/convergence.wgsl"),
+            entry_point: Some("average_variance".into()),
+            ..Default::default()
+        });
+
+        Self {
+            sum_pipeline,
+            average_pipeline,
+            binding_layout,
+        }
+    }
+}
+
+impl ViewNode for ConvergenceNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static RayCamera,
+        &'static VoxelViewTarget,
+        &'static RenderConvergence,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, ray_camera, voxel_view_target, convergence): QueryItem<'w, '_, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        // the accumulation isn't written while the heatmap is shown
+        if *world.resource::<NEVRDebugView>() == NEVRDebugView::Heatmap {
+            return Ok(());
+        }
+
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let storage_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+        let status = world.resource::<NEVRStatus>();
+
+        let (Some(sum_pipeline), Some(average_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(self.sum_pipeline),
+            pipeline_cache.get_compute_pipeline(self.average_pipeline),
+        ) else {
+            return Ok(());
+        };
+        let Some(viewport) = &camera.physical_viewport_size else {
+            status.report(NEVRWarning::MissingViewport);
+            return Ok(());
+        };
+        // the buffer is created in the main world, it may not be on the GPU yet
+        let Some(buffer) = storage_buffers.get(convergence.buffer) else {
+            return Ok(());
+        };
+
+        // the frames accumulated before the last substep of this frame
+        let substeps = ray_camera.substeps(world.resource::<NEVRAccumulationSubsteps>());
+        let mut frames_uniform = UniformBuffer::from(Vec4::new(
+            (ray_camera.accumulated_frames() + substeps - 1) as f32,
+            0.0,
+            0.0,
+            0.0,
+        ));
+        frames_uniform.write_buffer(render_device, render_queue);
+
+        let bind_group = render_device.create_bind_group(
+            "voxel_bindings_convergence",
+            &self.binding_layout,
+            &BindGroupEntries::sequential((
+                &voxel_view_target.accumulation.default_view,
+                buffer.buffer.as_entire_binding(),
+                frames_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_convergence"),
+            timestamp_writes: None,
+        });
+
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.set_pipeline(sum_pipeline);
+        pass.dispatch_workgroups(
+            viewport.x.div_ceil(PIXELS_PER_WORKGROUP),
+            viewport.y.div_ceil(PIXELS_PER_WORKGROUP),
+            1,
+        );
+        // averages the variances and clears the sums for the next frame
+        pass.set_pipeline(average_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Debug UI module, enabled by the `egui` feature.
+
+use crate::engine::camera::VoxelCamera;
+use crate::engine::denoiser::{
+    NEVRFireflyFilter, VoxelDenoiser, VoxelDenoiserDetail, VoxelTemporalDenoiser,
+};
+use crate::engine::light::VoxelLight;
+use bevy::app::App;
+use bevy::math::Vec4;
+use bevy::prelude::{Entity, Mut, Plugin, Query, ResMut};
+use bevy_egui::{EguiContexts, EguiPlugin, EguiPrimaryContextPass, egui};
+use std::num::NonZeroU32;
+
+/// The plugin which adds a window to tweak the renderer at runtime.
+///
+/// The window has sliders for every [VoxelCamera] (samples, diffuse and specular bounces, aperture and focus
+/// distance), for [VoxelLight] (direction, intensity, ambient light and sky color) and a selector for
+/// [VoxelDenoiser] with its [VoxelDenoiserDetail], [VoxelTemporalDenoiser] and [NEVRFireflyFilter].
+///
+/// Add it with [crate::NEVRPlugin::debug_ui] or after [crate::NEVRPlugin] when needed:
+/// ```rs
+/// app.add_plugins((NEVRPlugin::default(), NEVRDebugUiPlugin));
+/// ```
+/// [EguiPlugin] is added too if the app doesn't have it already.
+pub struct NEVRDebugUiPlugin;
+
+impl Plugin for NEVRDebugUiPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin::default());
+        }
+
+        app.add_systems(EguiPrimaryContextPass, debug_ui);
+    }
+}
+
+// the values are copied out and written back only when a widget changes them, otherwise the components would be
+// marked as changed every frame and the temporal accumulation would never converge
+fn debug_ui(
+    mut contexts: EguiContexts,
+    mut cameras: Query<(Entity, &mut VoxelCamera)>,
+    mut light: ResMut<VoxelLight>,
+    mut denoiser: ResMut<VoxelDenoiser>,
+    mut detail: ResMut<VoxelDenoiserDetail>,
+    mut temporal: ResMut<VoxelTemporalDenoiser>,
+    mut firefly: ResMut<NEVRFireflyFilter>,
+) {
+    let Ok(ctx) = contexts.ctx_mut() else {
+        return;
+    };
+
+    egui::Window::new("NEVR").show(ctx, |ui| {
+        for (entity, camera) in &mut cameras {
+            ui.collapsing(format!("Camera {entity}"), |ui| camera_ui(ui, camera));
+        }
+
+        ui.collapsing("Light", |ui| light_ui(ui, light.reborrow()));
+        ui.collapsing("Denoiser", |ui| {
+            denoiser_ui(ui, denoiser.reborrow(), detail.reborrow());
+            temporal_denoiser_ui(ui, temporal.reborrow());
+            firefly_filter_ui(ui, firefly.reborrow());
+        });
+    });
+}
+
+fn camera_ui(ui: &mut egui::Ui, mut camera: Mut<VoxelCamera>) {
+    let mut samples = camera.samples;
+    let mut diffuse_bounces = camera.diffuse_bounces;
+    let mut specular_bounces = camera.specular_bounces;
+    let mut aperture = camera.aperture;
+    let mut focus_distance = camera.focus_distance;
+
+    let changed = ui
+        .add(egui::Slider::new(&mut samples, 1..=64).text("Samples"))
+        .changed()
+        | ui.add(egui::Slider::new(&mut diffuse_bounces, 0..=16).text("Diffuse bounces"))
+            .changed()
+        | ui.add(egui::Slider::new(&mut specular_bounces, 0..=16).text("Specular bounces"))
+            .changed()
+        | ui.add(egui::Slider::new(&mut aperture, 0.0..=1.0).text("Aperture"))
+            .changed()
+        | ui.add(
+            egui::Slider::new(&mut focus_distance, 0.01..=100.0)
+                .logarithmic(true)
+                .text("Focus distance"),
+        )
+        .changed();
+
+    if changed {
+        camera.set_samples(samples);
+        camera.set_diffuse_bounces(diffuse_bounces);
+        camera.set_specular_bounces(specular_bounces);
+        camera.set_aperture(aperture);
+        camera.set_focus_distance(focus_distance);
+    }
+}
+
+fn light_ui(ui: &mut egui::Ui, mut light: Mut<VoxelLight>) {
+    let mut direction = light.direction();
+    let mut intensity = light.intensity();
+    let mut ambient = light.ambient();
+    let sky_color = light.sky_color();
+    let mut sky_color = [sky_color.x, sky_color.y, sky_color.z];
+
+    let changed = ui
+        .add(egui::Slider::new(&mut direction.x, -1.0..=1.0).text("Direction x"))
+        .changed()
+        | ui.add(egui::Slider::new(&mut direction.y, -1.0..=1.0).text("Direction y"))
+            .changed()
+        | ui.add(egui::Slider::new(&mut direction.z, -1.0..=1.0).text("Direction z"))
+            .changed()
+        | ui.add(egui::Slider::new(&mut intensity, 0.0..=10.0).text("Intensity"))
+            .changed()
+        | ui.add(egui::Slider::new(&mut ambient, 0.0..=1.0).text("Ambient"))
+            .changed()
+        | ui.horizontal(|ui| {
+            ui.label("Sky color");
+            ui.color_edit_button_rgb(&mut sky_color).changed()
+        })
+        .inner;
+
+    if changed {
+        // a zero direction would make the shaders produce NaNs
+        if direction.truncate().length_squared() > 0.0 {
+            light.set_direction(direction);
+        }
+        light.set_intensity(intensity);
+        light.set_ambient(ambient);
+        light.set_sky_color(Vec4::new(sky_color[0], sky_color[1], sky_color[2], 1.0));
+    }
+}
+
+fn denoiser_ui(
+    ui: &mut egui::Ui,
+    mut denoiser: Mut<VoxelDenoiser>,
+    mut detail: Mut<VoxelDenoiserDetail>,
+) {
+    let mut selected = *denoiser;
+
+    egui::ComboBox::from_label("Denoiser")
+        .selected_text(match selected {
+            VoxelDenoiser::None => "None",
+            VoxelDenoiser::Simple => "Simple",
+            VoxelDenoiser::ATrous(_) => "À-Trous",
+            VoxelDenoiser::Split(_) => "Split",
+        })
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut selected, VoxelDenoiser::None, "None");
+            ui.selectable_value(&mut selected, VoxelDenoiser::Simple, "Simple");
+            if ui
+                .selectable_label(matches!(selected, VoxelDenoiser::ATrous(_)), "À-Trous")
+                .clicked()
+                && !matches!(selected, VoxelDenoiser::ATrous(_))
+            {
+                selected = VoxelDenoiser::ATrous(NonZeroU32::new(16).unwrap());
+            }
+            if ui
+                .selectable_label(matches!(selected, VoxelDenoiser::Split(_)), "Split")
+                .clicked()
+                && !matches!(selected, VoxelDenoiser::Split(_))
+            {
+                selected = VoxelDenoiser::Split(NonZeroU32::new(16).unwrap());
+            }
+        });
+
+    match selected {
+        VoxelDenoiser::ATrous(filter_size) => {
+            let mut filter_size = filter_size.get();
+            ui.add(egui::Slider::new(&mut filter_size, 1..=64).text("Filter size"));
+            selected = VoxelDenoiser::ATrous(NonZeroU32::new(filter_size).unwrap());
+        }
+        VoxelDenoiser::Split(filter_size) => {
+            let mut filter_size = filter_size.get();
+            ui.add(egui::Slider::new(&mut filter_size, 1..=64).text("Filter size"));
+            selected = VoxelDenoiser::Split(NonZeroU32::new(filter_size).unwrap());
+        }
+        _ => {}
+    }
+
+    if selected != *denoiser {
+        *denoiser = selected;
+    }
+
+    let mut strength = detail.0;
+    if ui
+        .add(egui::Slider::new(&mut strength, 0.0..=1.0).text("Detail"))
+        .changed()
+    {
+        detail.0 = strength;
+    }
+}
+
+fn temporal_denoiser_ui(ui: &mut egui::Ui, mut temporal: Mut<VoxelTemporalDenoiser>) {
+    let mut settings = *temporal;
+
+    ui.checkbox(&mut settings.enabled, "Temporal");
+    if settings.enabled {
+        ui.add(egui::Slider::new(&mut settings.feedback, 0.0..=1.0).text("Feedback"));
+        ui.add(egui::Slider::new(&mut settings.history_clamp, 0.0..=1.0).text("History clamp"));
+    }
+
+    if settings != *temporal {
+        *temporal = settings;
+    }
+}
+
+fn firefly_filter_ui(ui: &mut egui::Ui, mut firefly: Mut<NEVRFireflyFilter>) {
+    let mut settings = *firefly;
+
+    ui.checkbox(&mut settings.enabled, "Firefly filter");
+    if settings.enabled {
+        ui.add(egui::Slider::new(&mut settings.strength, 0.0..=1.0).text("Strength"));
+    }
+
+    if settings != *firefly {
+        *firefly = settings;
+    }
+}
@@ -1,12 +1,18 @@
 //! Denoiser module.
 
-use crate::engine::node::NEVRNodeLabel;
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::node::{NEVRFragmentLabel, NEVRNodeLabel};
+use crate::engine::settings::NEVRDebugView;
+use crate::engine::status::{NEVRPipelines, NEVRStatus, NEVRWarning};
 use crate::{VoxelGBuffer, VoxelViewTarget};
 use bevy::app::App;
 use bevy::asset::{embedded_asset, load_embedded_asset};
-use bevy::core_pipeline::core_3d::graph::{Core3d, Node3d};
+use bevy::core_pipeline::core_3d::graph::Core3d;
 use bevy::ecs::query::QueryItem;
-use bevy::prelude::{FromWorld, Plugin, Resource, UVec2, World};
+use bevy::image::ToExtents;
+use bevy::prelude::{
+    FromWorld, Plugin, Reflect, ReflectDefault, ReflectResource, Resource, UVec2, Vec2, World,
+};
 use bevy::render::RenderApp;
 use bevy::render::camera::ExtractedCamera;
 use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
@@ -16,10 +22,12 @@ use bevy::render::render_graph::{
 use bevy::render::render_resource::binding_types::{texture_storage_2d, uniform_buffer};
 use bevy::render::render_resource::{
     BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingResource,
-    CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache,
-    ShaderStages, StorageTextureAccess, TextureFormat, TextureView, UniformBuffer,
+    CachedComputePipelineId, CommandEncoder, ComputePassDescriptor, ComputePipelineDescriptor,
+    Origin3d, PipelineCache, ShaderStages, StorageTextureAccess, TexelCopyTextureInfo,
+    TextureAspect, TextureFormat, TextureView, UniformBuffer,
 };
 use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::texture::CachedTexture;
 use bevy::render::view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms};
 use std::num::NonZeroU32;
 
@@ -34,12 +42,14 @@ pub struct DenoiserLabel;
 /// - None: No denoiser.
 /// - Simple: The simplest and fastest denoiser, decent quality.
 /// - ATrous: A bit more sophisticated, fast, good quality
+/// - Split: The ATrous filter on the diffuse light only, keeps reflections and refractions sharp
 ///
 /// Defaults to [VoxelDenoiser::None].
 ///
 /// **Note:** By changing the samples count in [crate::engine::camera::VoxelCamera] the resulted denoised
 /// image may vary by a lot.
-#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug, Default, PartialEq)]
+#[reflect(Resource, Default)]
 pub enum VoxelDenoiser {
     /// Doesn't enable the denoiser pass.
     #[default]
@@ -53,6 +63,139 @@ pub enum VoxelDenoiser {
     /// Params:
     /// - filter_size: how big should be the largest filter.
     ATrous(NonZeroU32),
+    /// Runs the same filter of [VoxelDenoiser::ATrous] on the diffuse light only.
+    ///
+    /// The ray tracer writes the light of the specular paths and emissive surfaces in its own texture
+    /// ([VoxelGBuffer::specular]), accumulated like the image; the filter runs on the rest of the light and a final
+    /// pass adds the specular light back, unfiltered. Reflections, refractions and lights stay crisp even where
+    /// a pixel mixes them with diffuse light, which [VoxelDenoiser::ATrous] can only estimate from
+    /// [VoxelGBuffer::detail]. [VoxelDenoiserDetail] scales how much of the specular light is kept sharp.
+    ///
+    /// It costs one more pass and texture than [VoxelDenoiser::ATrous].
+    ///
+    /// Params:
+    /// - filter_size: how big should be the largest filter.
+    Split(NonZeroU32),
+}
+
+/// How much the denoisers keep the detail of reflections, refractions and emissive surfaces, from 0.0 to 1.0.
+/// Defaults to 1.0.
+///
+/// The ray tracer writes, for every pixel, how much of its light comes from specular paths (mirrors, glass,
+/// seen directly or through other specular surfaces) and emissive surfaces in [VoxelGBuffer::detail]. The
+/// denoisers take that part out of the image, filter the rest and add it back unfiltered, so sharp highlights,
+/// reflections and the edges of lights aren't smeared like the diffuse lighting; 0.0 filters everything.
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug, PartialEq)]
+#[reflect(Resource, Default)]
+pub struct VoxelDenoiserDetail(pub f32);
+
+impl Default for VoxelDenoiserDetail {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// A temporal denoiser, run before [VoxelDenoiser]: every pixel is blended with the result of the last frame
+/// at the same point, found with the [VoxelGBuffer::motion_vectors], so it keeps reducing the noise while the
+/// camera and the blocks move (unlike the temporal accumulation of
+/// [VoxelCamera](crate::engine::camera::VoxelCamera), which restarts).
+///
+/// The history of a pixel is clamped to the colors around it in the current frame, like in temporal
+/// anti-aliasing: what was behind a moving object doesn't survive in the history, so moving objects don't
+/// leave a trail.
+///
+/// Disabled by default:
+/// ```rs
+/// commands.insert_resource(VoxelTemporalDenoiser::default().with_feedback(0.9));
+/// ```
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug, PartialEq)]
+#[reflect(Resource, Default)]
+pub struct VoxelTemporalDenoiser {
+    /// Enables the temporal denoiser, [VoxelTemporalDenoiser::default] is disabled and the other constructors
+    /// enable it.
+    pub enabled: bool,
+    /// How much of the history is kept every frame, from 0.0 to 1.0. Defaults to 0.9.
+    ///
+    /// Higher values remove more noise but the lighting takes longer to follow the changes.
+    pub feedback: f32,
+    /// How strongly the history is clamped to the colors around the pixel, from 0.0 to 1.0. Defaults to 1.0.
+    ///
+    /// Lower values keep more of the history and remove more noise, but moving objects leave a trail.
+    pub history_clamp: f32,
+}
+
+impl VoxelTemporalDenoiser {
+    pub fn new(feedback: f32, history_clamp: f32) -> Self {
+        Self {
+            enabled: true,
+            feedback,
+            history_clamp,
+        }
+    }
+
+    pub fn with_feedback(mut self, feedback: f32) -> Self {
+        self.enabled = true;
+        self.feedback = feedback;
+        self
+    }
+
+    pub fn with_history_clamp(mut self, history_clamp: f32) -> Self {
+        self.enabled = true;
+        self.history_clamp = history_clamp;
+        self
+    }
+}
+
+impl Default for VoxelTemporalDenoiser {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            feedback: 0.9,
+            history_clamp: 1.0,
+        }
+    }
+}
+
+/// A filter against fireflies (single pixels much brighter than the ones around them, e.g. a path that found a
+/// small emitter by chance), run on the rendered image before [VoxelTemporalDenoiser] and [VoxelDenoiser].
+///
+/// Every channel of a pixel is clamped between the darkest and the brightest of its 8 neighbors, so an
+/// isolated firefly (or black pixel) takes the color of its surroundings instead of being smeared over them by
+/// the denoisers, while edges and gradients are left untouched. The image accumulated by the camera isn't
+/// changed, only what the denoisers read.
+///
+/// Disabled by default:
+/// ```rs
+/// commands.insert_resource(NEVRFireflyFilter::new(1.0));
+/// ```
+///
+/// **Note:** Details a pixel wide (like the sun disk or distant lights seen from afar) are clamped like
+/// fireflies, lower the strength if they fade.
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug, PartialEq)]
+#[reflect(Resource, Default)]
+pub struct NEVRFireflyFilter {
+    /// Enables the filter, [NEVRFireflyFilter::default] is disabled and [NEVRFireflyFilter::new] enables it.
+    pub enabled: bool,
+    /// How much of the clamped color replaces the pixel, from 0.0 to 1.0. Defaults to 1.0.
+    pub strength: f32,
+}
+
+impl NEVRFireflyFilter {
+    pub fn new(strength: f32) -> Self {
+        Self {
+            enabled: true,
+            strength,
+        }
+    }
+}
+
+impl Default for NEVRFireflyFilter {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            strength: 1.0,
+        }
+    }
 }
 
 /// The plugin which adds a denoiser for the rendered image.
@@ -64,20 +207,37 @@ impl Plugin for DenoiserPlugin {
     fn build(&self, app: &mut App) {
         embedded_asset!(app, "shaders/simple_denoiser.wgsl");
         embedded_asset!(app, "shaders/a_trous.wgsl");
+        embedded_asset!(app, "shaders/heatmap.wgsl");
+        embedded_asset!(app, "shaders/temporal_denoiser.wgsl");
+        embedded_asset!(app, "shaders/firefly_filter.wgsl");
 
         app.add_plugins(ExtractResourcePlugin::<VoxelDenoiser>::default())
-            .init_resource::<VoxelDenoiser>();
+            .add_plugins(ExtractResourcePlugin::<VoxelDenoiserDetail>::default())
+            .add_plugins(ExtractResourcePlugin::<VoxelTemporalDenoiser>::default())
+            .add_plugins(ExtractResourcePlugin::<NEVRFireflyFilter>::default())
+            .init_resource::<VoxelDenoiser>()
+            .init_resource::<VoxelDenoiserDetail>()
+            .init_resource::<VoxelTemporalDenoiser>()
+            .init_resource::<NEVRFireflyFilter>()
+            .register_type::<VoxelDenoiser>()
+            .register_type::<VoxelDenoiserDetail>()
+            .register_type::<VoxelTemporalDenoiser>()
+            .register_type::<NEVRFireflyFilter>();
     }
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
 
         render_app
             .add_render_graph_node::<ViewNodeRunner<DenoiserNode>>(Core3d, DenoiserLabel)
-            .add_render_graph_edges(
-                Core3d,
-                (NEVRNodeLabel, DenoiserLabel, Node3d::MainOpaquePass),
-            );
+            .add_render_graph_edges(Core3d, (NEVRNodeLabel, DenoiserLabel, NEVRFragmentLabel));
     }
 }
 
@@ -85,47 +245,180 @@ pub struct DenoiserNode {
     simple_pipeline: CachedComputePipelineId,
     simple_binding_layout: BindGroupLayout,
 
+    heatmap_pipeline: CachedComputePipelineId,
+
     a_trous_pipeline: CachedComputePipelineId,
     a_trous_binding_layouts: [BindGroupLayout; 2],
+
+    // the a_trous pipeline with the SPLIT shader def, it uses the second layout of the a_trous pipeline
+    split_pipeline: CachedComputePipelineId,
+    split_combine_pipeline: CachedComputePipelineId,
+    split_binding_layout: BindGroupLayout,
+
+    temporal_pipeline: CachedComputePipelineId,
+    temporal_binding_layout: BindGroupLayout,
+
+    firefly_pipeline: CachedComputePipelineId,
+    firefly_binding_layout: BindGroupLayout,
 }
 
 impl DenoiserNode {
+    /// Clamps the fireflies of the image into `firefly`.
+    fn firefly_pipeline(
+        &self,
+        render_context: &mut RenderContext,
+        render_queue: &RenderQueue,
+        pipeline_cache: &PipelineCache,
+        view_input: &TextureView,
+        view_uniforms: BindingResource,
+        view_uniform_offset: u32,
+        viewport: &UVec2,
+        firefly: &CachedTexture,
+        settings: &NEVRFireflyFilter,
+    ) -> bool {
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.firefly_pipeline) else {
+            return false;
+        };
+
+        let mut strength_uniform = UniformBuffer::from(settings.strength.clamp(0.0, 1.0));
+        strength_uniform.write_buffer(render_context.render_device(), render_queue);
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "voxel_bindings_firefly_filter",
+            &self.firefly_binding_layout,
+            &BindGroupEntries::sequential((
+                view_uniforms,
+                view_input,
+                &firefly.default_view,
+                strength_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_raytracing_firefly_filter"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[view_uniform_offset]);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+
+        true
+    }
+
+    /// Blends the image with the history into `temporal` and copies the result to `history` for the next frame.
+    fn temporal_pipeline(
+        &self,
+        render_context: &mut RenderContext,
+        render_queue: &RenderQueue,
+        pipeline_cache: &PipelineCache,
+        view_input: &TextureView,
+        view_uniforms: BindingResource,
+        view_uniform_offset: u32,
+        viewport: &UVec2,
+        g_buffer: &VoxelGBuffer,
+        history: &CachedTexture,
+        temporal: &CachedTexture,
+        settings: &VoxelTemporalDenoiser,
+    ) -> bool {
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.temporal_pipeline) else {
+            return false;
+        };
+
+        let mut settings_uniform = UniformBuffer::from(Vec2::new(
+            settings.feedback.clamp(0.0, 1.0),
+            settings.history_clamp.clamp(0.0, 1.0),
+        ));
+        settings_uniform.write_buffer(render_context.render_device(), render_queue);
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "voxel_bindings_temporal_denoiser",
+            &self.temporal_binding_layout,
+            &BindGroupEntries::sequential((
+                view_uniforms,
+                view_input,
+                &history.default_view,
+                &g_buffer.motion_vectors.default_view,
+                &temporal.default_view,
+                settings_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_raytracing_temporal_denoiser"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[view_uniform_offset]);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+
+        drop(pass);
+
+        command_encoder.copy_texture_to_texture(
+            temporal.texture.as_image_copy(),
+            history.texture.as_image_copy(),
+            viewport.to_extents(),
+        );
+
+        true
+    }
+
     fn none_pipeline(
         &self,
         render_context: &mut RenderContext,
         view_output: &TextureView,
         view_input: &TextureView,
+        output_offset: UVec2,
+        viewport: &UVec2,
     ) {
-        let command_encoder = render_context.command_encoder();
-        command_encoder.copy_texture_to_texture(
+        copy_to_output(
+            render_context.command_encoder(),
             view_input.texture().as_image_copy(),
-            view_output.texture().as_image_copy(),
-            view_output.texture().size(),
+            view_output,
+            output_offset,
+            viewport,
         );
     }
 
     fn simple_pipeline(
         &self,
         render_context: &mut RenderContext,
+        render_queue: &RenderQueue,
         pipeline_cache: &PipelineCache,
         view_output: &TextureView,
         view_input: &TextureView,
         view_uniforms: BindingResource,
         view_uniform_offset: u32,
+        output_offset: UVec2,
         viewport: &UVec2,
+        g_buffer: &VoxelGBuffer,
+        detail_strength: f32,
     ) {
         let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.simple_pipeline) else {
-            eprintln!(
-                "{:?}",
-                pipeline_cache.get_compute_pipeline_state(self.simple_pipeline)
-            );
             return;
         };
 
+        let mut offset_uniform = UniformBuffer::from(output_offset);
+        offset_uniform.write_buffer(render_context.render_device(), render_queue);
+        let mut detail_uniform = UniformBuffer::from(detail_strength);
+        detail_uniform.write_buffer(render_context.render_device(), render_queue);
+
         let denoise_bind_group = render_context.render_device().create_bind_group(
             "voxel_bindings_simple_denoiser",
             &self.simple_binding_layout,
-            &BindGroupEntries::sequential((view_output, view_input, view_uniforms)),
+            &BindGroupEntries::sequential((
+                view_output,
+                view_input,
+                view_uniforms,
+                offset_uniform.binding().unwrap(),
+                &g_buffer.detail.default_view,
+                detail_uniform.binding().unwrap(),
+            )),
         );
 
         let command_encoder = render_context.command_encoder();
@@ -140,6 +433,56 @@ impl DenoiserNode {
         pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
     }
 
+    /// Maps the work written by the raytracing pipeline with [NEVRDebugView::Heatmap] to a color ramp.
+    /// It uses the same bindings of the simple denoiser.
+    fn heatmap_pipeline(
+        &self,
+        render_context: &mut RenderContext,
+        render_queue: &RenderQueue,
+        pipeline_cache: &PipelineCache,
+        view_output: &TextureView,
+        view_input: &TextureView,
+        view_uniforms: BindingResource,
+        view_uniform_offset: u32,
+        output_offset: UVec2,
+        viewport: &UVec2,
+        g_buffer: &VoxelGBuffer,
+        detail_strength: f32,
+    ) {
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.heatmap_pipeline) else {
+            return;
+        };
+
+        let mut offset_uniform = UniformBuffer::from(output_offset);
+        offset_uniform.write_buffer(render_context.render_device(), render_queue);
+        let mut detail_uniform = UniformBuffer::from(detail_strength);
+        detail_uniform.write_buffer(render_context.render_device(), render_queue);
+
+        let heatmap_bind_group = render_context.render_device().create_bind_group(
+            "voxel_bindings_heatmap",
+            &self.simple_binding_layout,
+            &BindGroupEntries::sequential((
+                view_output,
+                view_input,
+                view_uniforms,
+                offset_uniform.binding().unwrap(),
+                &g_buffer.detail.default_view,
+                detail_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_raytracing_heatmap"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &heatmap_bind_group, &[view_uniform_offset]);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+    }
+
     fn a_trous_pipeline(
         &self,
         render_context: &mut RenderContext,
@@ -150,26 +493,53 @@ impl DenoiserNode {
         view_input: &TextureView,
         view_uniforms: BindingResource,
         view_uniform_offset: u32,
+        output_offset: UVec2,
         viewport: &UVec2,
         g_buffer: &VoxelGBuffer,
         size: u32,
+        detail_strength: f32,
+        split: bool,
     ) {
-        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.a_trous_pipeline) else {
-            eprintln!(
-                "{:?}",
-                pipeline_cache.get_compute_pipeline_state(self.a_trous_pipeline)
-            );
+        let (pipeline_id, binding_layout, sharp_light) = if split {
+            (
+                self.split_pipeline,
+                &self.split_binding_layout,
+                &g_buffer.specular.default_view,
+            )
+        } else {
+            (
+                self.a_trous_pipeline,
+                &self.a_trous_binding_layouts[0],
+                &g_buffer.detail.default_view,
+            )
+        };
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(pipeline_id) else {
             return;
         };
+        let combine_pipeline = if split {
+            let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.split_combine_pipeline)
+            else {
+                return;
+            };
+            Some(pipeline)
+        } else {
+            None
+        };
+
+        let mut detail_uniform = UniformBuffer::from(detail_strength);
+        detail_uniform.write_buffer(render_device, render_queue);
 
         let denoise_bind_group = render_context.render_device().create_bind_group(
             "voxel_bindings_a_trous_denoiser",
-            &self.a_trous_binding_layouts[0],
+            binding_layout,
             &BindGroupEntries::sequential((
                 view_uniforms,
                 &g_buffer.albedo.default_view,
                 &g_buffer.normal.default_view,
                 &g_buffer.world_position.default_view,
+                sharp_light,
+                view_input,
+                detail_uniform.binding().unwrap(),
             )),
         );
 
@@ -218,21 +588,67 @@ impl DenoiserNode {
             index += 1;
         }
 
+        // the last texture is left for the combined image
+        if let Some(combine_pipeline) = combine_pipeline {
+            let mut filter_uniform = UniformBuffer::from(i);
+            filter_uniform.write_buffer(render_device, render_queue);
+
+            let combine_bind_group = render_device.create_bind_group(
+                "voxel_bindings_split_combine_denoiser",
+                &self.a_trous_binding_layouts[1],
+                &BindGroupEntries::sequential((
+                    filter_uniform.binding().unwrap(),
+                    &g_buffer.secondary_textures[index].default_view,
+                    &g_buffer.secondary_textures[index - 1].default_view,
+                )),
+            );
+
+            pass.set_pipeline(combine_pipeline);
+            pass.set_bind_group(1, &combine_bind_group, &[]);
+            pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+        }
+
         drop(pass);
 
-        command_encoder.copy_texture_to_texture(
+        copy_to_output(
+            command_encoder,
             g_buffer
                 .secondary_textures
                 .last()
                 .unwrap()
                 .texture
                 .as_image_copy(),
-            view_output.texture().as_image_copy(),
-            view_output.texture().size(),
+            view_output,
+            output_offset,
+            viewport,
         );
     }
 }
 
+// copies a viewport-sized texture into the output, which may be larger when the camera has a viewport
+fn copy_to_output(
+    command_encoder: &mut CommandEncoder,
+    input: TexelCopyTextureInfo,
+    view_output: &TextureView,
+    output_offset: UVec2,
+    viewport: &UVec2,
+) {
+    command_encoder.copy_texture_to_texture(
+        input,
+        TexelCopyTextureInfo {
+            texture: view_output.texture(),
+            mip_level: 0,
+            origin: Origin3d {
+                x: output_offset.x,
+                y: output_offset.y,
+                z: 0,
+            },
+            aspect: TextureAspect::All,
+        },
+        viewport.to_extents(),
+    );
+}
+
 impl FromWorld for DenoiserNode {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
@@ -249,6 +665,12 @@ impl FromWorld for DenoiserNode {
                     texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
                     // View
                     uniform_buffer::<ViewUniform>(true),
+                    // Output offset
+                    uniform_buffer::<UVec2>(false),
+                    // Detail
+                    texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::ReadOnly),
+                    // Detail strength
+                    uniform_buffer::<f32>(false),
                 ),
             ),
         );
@@ -266,6 +688,12 @@ impl FromWorld for DenoiserNode {
                     texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
                     // World position
                     texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Detail
+                    texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::ReadOnly),
+                    // Noisy image, to restore the detail
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Detail strength
+                    uniform_buffer::<f32>(false),
                 ),
             ),
         );
@@ -292,6 +720,65 @@ impl FromWorld for DenoiserNode {
             ..Default::default()
         });
 
+        let heatmap_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_heatmap_pipeline".into()),
+            layout: vec![simple_binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/heatmap.wgsl"),
+            ..Default::default()
+        });
+
+        let temporal_binding_layout = render_device.create_bind_group_layout(
+            "voxel_temporal_denoiser_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // View input
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // History
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Motion vectors
+                    texture_storage_2d(TextureFormat::Rg32Float, StorageTextureAccess::ReadOnly),
+                    // View output
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::WriteOnly),
+                    // Feedback and history clamp
+                    uniform_buffer::<Vec2>(false),
+                ),
+            ),
+        );
+
+        let temporal_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_temporal_denoiser_pipeline".into()),
+            layout: vec![temporal_binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/temporal_denoiser.wgsl"),
+            ..Default::default()
+        });
+
+        let firefly_binding_layout = render_device.create_bind_group_layout(
+            "voxel_firefly_filter_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // View input
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // View output
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::WriteOnly),
+                    // Strength
+                    uniform_buffer::<f32>(false),
+                ),
+            ),
+        );
+
+        let firefly_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_firefly_filter_pipeline".into()),
+            layout: vec![firefly_binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/firefly_filter.wgsl"),
+            ..Default::default()
+        });
+
         let a_trous_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
             label: Some("voxel_a_trous_denoiser_pipeline".into()),
             layout: vec![
@@ -302,15 +789,85 @@ impl FromWorld for DenoiserNode {
             ..Default::default()
         });
 
+        let split_binding_layout = render_device.create_bind_group_layout(
+            "voxel_split_denoiser_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // Albedo
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Normal
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // World position
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Specular
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Noisy image, unused
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Detail strength
+                    uniform_buffer::<f32>(false),
+                ),
+            ),
+        );
+
+        let split_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_split_denoiser_pipeline".into()),
+            layout: vec![
+                split_binding_layout.clone(),
+                a_trous_filter_a_trous_binding_layout.clone(),
+            ],
+            shader: load_embedded_asset!(world, "shaders/a_trous.wgsl"),
+            shader_defs: vec!["SPLIT".into()],
+            ..Default::default()
+        });
+
+        let split_combine_pipeline =
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("voxel_split_combine_denoiser_pipeline".into()),
+                layout: vec![
+                    split_binding_layout.clone(),
+                    a_trous_filter_a_trous_binding_layout.clone(),
+                ],
+                shader: load_embedded_asset!(world, "shaders/a_trous.wgsl"),
+                shader_defs: vec!["SPLIT".into()],
+                entry_point: Some("combine".into()),
+                ..Default::default()
+            });
+
+        // the pipelines are compiled once their shaders are loaded, the node skips the frames until then
+        world.get_resource_or_init::<NEVRPipelines>().track([
+            simple_pipeline,
+            heatmap_pipeline,
+            a_trous_pipeline,
+            split_pipeline,
+            split_combine_pipeline,
+            temporal_pipeline,
+            firefly_pipeline,
+        ]);
+
         Self {
             simple_pipeline,
             simple_binding_layout,
 
+            heatmap_pipeline,
+
             a_trous_pipeline,
             a_trous_binding_layouts: [
                 a_trous_binding_layout,
                 a_trous_filter_a_trous_binding_layout,
             ],
+
+            split_pipeline,
+            split_combine_pipeline,
+            split_binding_layout,
+
+            temporal_pipeline,
+            temporal_binding_layout,
+
+            firefly_pipeline,
+            firefly_binding_layout,
         }
     }
 }
@@ -338,46 +895,152 @@ impl ViewNode for DenoiserNode {
         let render_device = world.resource::<RenderDevice>();
         let render_queue = world.resource::<RenderQueue>();
         let voxel_denoiser = world.resource::<VoxelDenoiser>();
+        let detail_strength = world.resource::<VoxelDenoiserDetail>().0.clamp(0.0, 1.0);
         let pipeline_cache = world.resource::<PipelineCache>();
         let view_uniforms = world.resource::<ViewUniforms>();
+        let status = world.resource::<NEVRStatus>();
 
         let Some(viewport) = &camera.physical_viewport_size else {
-            eprintln!("no viewport size");
+            status.report(NEVRWarning::MissingViewport);
             return Ok(());
         };
+        // there's nothing to filter in a degenerate viewport
+        if viewport.x == 0 || viewport.y == 0 {
+            return Ok(());
+        }
 
         let Some(view_uniforms) = view_uniforms.uniforms.binding() else {
-            eprintln!("no view uniforms");
+            status.report(NEVRWarning::MissingViewUniforms);
             return Ok(());
         };
 
+        // with NEVRNodeMode::Fragment the denoised image is drawn into the view target by the fragment pipeline,
+        // otherwise it's written directly where the viewport of the camera is
+        let (view_output, output_offset) = match &voxel_view_target.composite {
+            Some(composite) => (composite.default_view.clone(), UVec2::ZERO),
+            None => (
+                TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
+                camera
+                    .viewport
+                    .as_ref()
+                    .map_or(UVec2::ZERO, |viewport| viewport.physical_position),
+            ),
+        };
+
+        if *world.resource::<NEVRDebugView>() == NEVRDebugView::Heatmap {
+            self.heatmap_pipeline(
+                render_context,
+                render_queue,
+                pipeline_cache,
+                &view_output,
+                &voxel_view_target.output.default_view,
+                view_uniforms,
+                view_uniform_offset.offset,
+                output_offset,
+                viewport,
+                g_buffer,
+                detail_strength,
+            );
+            return Ok(());
+        }
+
+        // the denoisers filter the image without its fireflies, when the filter is enabled
+        let firefly_filter = world.resource::<NEVRFireflyFilter>();
+        let mut view_input = &voxel_view_target.output.default_view;
+        if let Some(firefly) = &voxel_view_target.firefly {
+            if firefly_filter.enabled
+                && self.firefly_pipeline(
+                    render_context,
+                    render_queue,
+                    pipeline_cache,
+                    view_input,
+                    view_uniforms.clone(),
+                    view_uniform_offset.offset,
+                    viewport,
+                    firefly,
+                    firefly_filter,
+                )
+            {
+                view_input = &firefly.default_view;
+            }
+        }
+
+        let temporal_denoiser = world.resource::<VoxelTemporalDenoiser>();
+        // the other denoisers filter the result of the temporal one, or the image when it isn't enabled
+        if let (Some(history), Some(temporal)) =
+            (&voxel_view_target.history, &voxel_view_target.temporal)
+        {
+            if temporal_denoiser.enabled
+                && self.temporal_pipeline(
+                    render_context,
+                    render_queue,
+                    pipeline_cache,
+                    view_input,
+                    view_uniforms.clone(),
+                    view_uniform_offset.offset,
+                    viewport,
+                    g_buffer,
+                    history,
+                    temporal,
+                    temporal_denoiser,
+                )
+            {
+                view_input = &temporal.default_view;
+            }
+        }
+
         match voxel_denoiser {
             VoxelDenoiser::None => self.none_pipeline(
                 render_context,
-                &TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
-                &voxel_view_target.output.default_view,
+                &view_output,
+                view_input,
+                output_offset,
+                viewport,
             ),
             VoxelDenoiser::Simple => self.simple_pipeline(
                 render_context,
+                render_queue,
                 pipeline_cache,
-                &TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
-                &voxel_view_target.output.default_view,
+                &view_output,
+                view_input,
                 view_uniforms,
                 view_uniform_offset.offset,
+                output_offset,
                 viewport,
+                g_buffer,
+                detail_strength,
             ),
             VoxelDenoiser::ATrous(size) => self.a_trous_pipeline(
                 render_context,
                 render_device,
                 render_queue,
                 pipeline_cache,
-                &TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
-                &voxel_view_target.output.default_view,
+                &view_output,
+                view_input,
+                view_uniforms,
+                view_uniform_offset.offset,
+                output_offset,
+                viewport,
+                &g_buffer,
+                size.get(),
+                detail_strength,
+                false,
+            ),
+            VoxelDenoiser::Split(size) => self.a_trous_pipeline(
+                render_context,
+                render_device,
+                render_queue,
+                pipeline_cache,
+                &view_output,
+                view_input,
                 view_uniforms,
                 view_uniform_offset.offset,
+                output_offset,
                 viewport,
                 &g_buffer,
                 size.get(),
+                detail_strength,
+                true,
             ),
         }
 
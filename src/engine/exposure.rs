@@ -0,0 +1,387 @@
+//! This module contains the auto-exposure used to adapt the brightness of the image to the brightness of
+//! the scene.
+
+use crate::VoxelViewTarget;
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::denoiser::DenoiserLabel;
+use crate::engine::node::NEVRNodeLabel;
+use crate::engine::settings::NEVRDebugView;
+use crate::engine::status::{NEVRStatus, NEVRWarning};
+use bevy::app::App;
+use bevy::asset::{RenderAssetUsages, embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::core_3d::graph::Core3d;
+use bevy::ecs::change_detection::DetectChangesMut;
+use bevy::ecs::observer::On;
+use bevy::ecs::query::QueryItem;
+use bevy::prelude::{
+    AssetId, Assets, ChildOf, Commands, Component, Entity, FromWorld, Handle, IntoScheduleConfigs,
+    Plugin, Query, Res, ResMut, Time, Update, Vec4, World,
+};
+use bevy::render::RenderApp;
+use bevy::render::camera::ExtractedCamera;
+use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+};
+use bevy::render::render_resource::binding_types::{
+    storage_buffer_sized, texture_storage_2d, uniform_buffer,
+};
+use bevy::render::render_resource::{
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BufferUsages,
+    CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache,
+    ShaderStages, StorageTextureAccess, TextureFormat, UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::storage::{GpuShaderStorageBuffer, ShaderStorageBuffer};
+use bevy::render::view::ColorGrading;
+use std::num::NonZeroU64;
+
+// the histogram has a bin for every invocation of a 8x8 workgroup
+const HISTOGRAM_BINS: usize = 64;
+// the bins followed by the average EV
+const HISTOGRAM_SIZE: usize = HISTOGRAM_BINS * 4 + 4;
+
+/// Adapts the exposure of a camera to the brightness of what it sees, like the eye does moving from a dark room
+/// to the daylight.
+///
+/// Add it to the entity of a [crate::engine::camera::VoxelCamera]:
+/// ```rs
+/// commands.spawn((VoxelCamera::default(), NEVRAutoExposure::default()));
+/// ```
+///
+/// Every frame a histogram of the luminance of the rendered image is built on the GPU and averaged, ignoring the
+/// darkest and the brightest pixels, then the exposure of [ColorGrading] (applied by the tonemapping) is moved
+/// towards the exposure that maps the average to middle gray. The average is read back from the GPU, so the
+/// exposure lags a couple of frames behind what is rendered.
+///
+/// **Note:** the exposure of [ColorGrading] is overwritten, change it through the range of this component instead.
+#[derive(Component, Clone, Debug)]
+pub struct NEVRAutoExposure {
+    /// The darkest average luminance the exposure adapts to, in EV (the log2 of the luminance). Darker scenes
+    /// are exposed as if they had this luminance. Defaults to -8.0.
+    pub min_ev: f32,
+    /// The brightest average luminance the exposure adapts to, in EV. Brighter scenes are exposed as if they
+    /// had this luminance. Defaults to 8.0.
+    pub max_ev: f32,
+    /// How fast the exposure reaches the exposure of the scene, higher is faster. Defaults to 2.0.
+    pub speed: f32,
+    average_ev: Option<f32>,
+    buffer: Option<Handle<ShaderStorageBuffer>>,
+}
+
+impl NEVRAutoExposure {
+    /// The luminance the average of the scene is mapped to.
+    pub const MIDDLE_GRAY: f32 = 0.18;
+
+    pub fn new(min_ev: f32, max_ev: f32, speed: f32) -> Self {
+        Self {
+            min_ev,
+            max_ev,
+            speed,
+            average_ev: None,
+            buffer: None,
+        }
+    }
+
+    pub fn with_min_ev(mut self, min_ev: f32) -> Self {
+        self.min_ev = min_ev;
+        self
+    }
+
+    pub fn with_max_ev(mut self, max_ev: f32) -> Self {
+        self.max_ev = max_ev;
+        self
+    }
+
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// The last average luminance (in EV) read from the GPU, [None] until the first histogram is read.
+    pub fn average_ev(&self) -> Option<f32> {
+        self.average_ev
+    }
+}
+
+impl Default for NEVRAutoExposure {
+    fn default() -> Self {
+        Self::new(-8.0, 8.0, 2.0)
+    }
+}
+
+/// Used in the rendering phase to build the histogram of a view.
+#[derive(Component, Clone, Debug)]
+pub struct RenderAutoExposure {
+    pub buffer: AssetId<ShaderStorageBuffer>,
+    pub min_ev: f32,
+    pub max_ev: f32,
+}
+
+impl ExtractComponent for NEVRAutoExposure {
+    type QueryData = &'static NEVRAutoExposure;
+    type QueryFilter = ();
+    type Out = RenderAutoExposure;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        // an empty range would divide by zero in the shader
+        Some(RenderAutoExposure {
+            buffer: item.buffer.as_ref()?.id(),
+            min_ev: item.min_ev,
+            max_ev: item.max_ev.max(item.min_ev + 0.001),
+        })
+    }
+}
+
+/// Entity reading back the average luminance of a camera.
+#[derive(Component)]
+pub struct AutoExposureReadback {
+    camera: Entity,
+}
+
+/// Creates the buffers used to build the histograms and removes the unused ones.
+pub fn prepare_auto_exposure(
+    mut cameras: Query<(Entity, &mut NEVRAutoExposure)>,
+    readbacks: Query<(Entity, &AutoExposureReadback)>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    for (entity, readback) in &readbacks {
+        if !cameras.contains(readback.camera) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (camera, mut auto_exposure) in &mut cameras {
+        if auto_exposure.buffer.is_some() {
+            continue;
+        }
+
+        // the average starts as NaN, so nothing is read until the first histogram is averaged
+        let mut data = vec![0; HISTOGRAM_SIZE];
+        data[HISTOGRAM_BINS * 4..].copy_from_slice(&f32::NAN.to_le_bytes());
+        let mut buffer = ShaderStorageBuffer::new(&data, RenderAssetUsages::RENDER_WORLD);
+        buffer.buffer_description.usage |= BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let buffer = buffers.add(buffer);
+
+        commands
+            .spawn((
+                Readback::buffer(buffer.clone()),
+                AutoExposureReadback { camera },
+                ChildOf(camera),
+            ))
+            .observe(read_auto_exposure);
+
+        auto_exposure.buffer = Some(buffer);
+    }
+}
+
+fn read_auto_exposure(
+    event: On<ReadbackComplete>,
+    readbacks: Query<&AutoExposureReadback>,
+    mut cameras: Query<&mut NEVRAutoExposure>,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    let Ok(mut auto_exposure) = cameras.get_mut(readback.camera) else {
+        return;
+    };
+    let Some(bytes) = event
+        .data
+        .get(HISTOGRAM_BINS * 4..HISTOGRAM_SIZE)
+        .and_then(|bytes| bytes.first_chunk::<4>())
+    else {
+        return;
+    };
+
+    let average_ev = f32::from_le_bytes(*bytes);
+    if average_ev.is_finite() {
+        auto_exposure.bypass_change_detection().average_ev = Some(average_ev);
+    }
+}
+
+/// Moves the exposure of the cameras towards the exposure of what they see.
+pub fn update_auto_exposure(
+    time: Res<Time>,
+    mut cameras: Query<(&mut ColorGrading, &NEVRAutoExposure)>,
+) {
+    for (mut color_grading, auto_exposure) in &mut cameras {
+        let Some(average_ev) = auto_exposure.average_ev else {
+            continue;
+        };
+
+        let average_ev = average_ev.clamp(auto_exposure.min_ev, auto_exposure.max_ev);
+        let target = NEVRAutoExposure::MIDDLE_GRAY.log2() - average_ev;
+        let current = color_grading.global.exposure;
+        if current == target {
+            continue;
+        }
+
+        let factor = 1.0 - (-auto_exposure.speed.max(0.0) * time.delta_secs()).exp();
+        color_grading.global.exposure = current + (target - current) * factor;
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct AutoExposureLabel;
+
+/// The plugin which adds the auto-exposure, check [NEVRAutoExposure].
+///
+/// This is enabled by default when using [nevr::NEVRPlugin].
+pub struct AutoExposurePlugin;
+
+impl Plugin for AutoExposurePlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "shaders/auto_exposure.wgsl");
+
+        app.add_plugins(ExtractComponentPlugin::<NEVRAutoExposure>::default())
+            .add_systems(
+                Update,
+                (prepare_auto_exposure, update_auto_exposure).chain(),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<AutoExposureNode>>(Core3d, AutoExposureLabel)
+            .add_render_graph_edges(Core3d, (NEVRNodeLabel, AutoExposureLabel, DenoiserLabel));
+    }
+}
+
+pub struct AutoExposureNode {
+    histogram_pipeline: CachedComputePipelineId,
+    average_pipeline: CachedComputePipelineId,
+    binding_layout: BindGroupLayout,
+}
+
+impl FromWorld for AutoExposureNode {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let binding_layout = render_device.create_bind_group_layout(
+            "voxel_auto_exposure_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View input
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Histogram
+                    storage_buffer_sized(false, NonZeroU64::new(HISTOGRAM_SIZE as u64)),
+                    // EV range
+                    uniform_buffer::<Vec4>(false),
+                ),
+            ),
+        );
+
+        let histogram_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_auto_exposure_histogram_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/auto_exposure.wgsl"),
+            entry_point: Some("build_histogram".into()),
+            ..Default::default()
+        });
+
+        let average_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_auto_exposure_average_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/auto_exposure.wgsl"),
+            entry_point: Some("average_histogram".into()),
+            ..Default::default()
+        });
+
+        Self {
+            histogram_pipeline,
+            average_pipeline,
+            binding_layout,
+        }
+    }
+}
+
+impl ViewNode for AutoExposureNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static VoxelViewTarget,
+        &'static RenderAutoExposure,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, voxel_view_target, auto_exposure): QueryItem<'w, '_, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        // the output contains the work of the pixels instead of their color
+        if *world.resource::<NEVRDebugView>() == NEVRDebugView::Heatmap {
+            return Ok(());
+        }
+
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let storage_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+        let status = world.resource::<NEVRStatus>();
+
+        let (Some(histogram_pipeline), Some(average_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(self.histogram_pipeline),
+            pipeline_cache.get_compute_pipeline(self.average_pipeline),
+        ) else {
+            return Ok(());
+        };
+        let Some(viewport) = &camera.physical_viewport_size else {
+            status.report(NEVRWarning::MissingViewport);
+            return Ok(());
+        };
+        // the buffer is created in the main world, it may not be on the GPU yet
+        let Some(histogram) = storage_buffers.get(auto_exposure.buffer) else {
+            return Ok(());
+        };
+
+        let mut range_uniform = UniformBuffer::from(Vec4::new(
+            auto_exposure.min_ev,
+            auto_exposure.max_ev,
+            0.0,
+            0.0,
+        ));
+        range_uniform.write_buffer(render_device, render_queue);
+
+        let bind_group = render_device.create_bind_group(
+            "voxel_bindings_auto_exposure",
+            &self.binding_layout,
+            &BindGroupEntries::sequential((
+                &voxel_view_target.output.default_view,
+                histogram.buffer.as_entire_binding(),
+                range_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_auto_exposure"),
+            timestamp_writes: None,
+        });
+
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.set_pipeline(histogram_pipeline);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+        // averages the histogram and clears it for the next frame
+        pass.set_pipeline(average_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+
+        Ok(())
+    }
+}
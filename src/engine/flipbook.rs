@@ -0,0 +1,32 @@
+//! Flipbook module, the animated textures of the [VoxelMaterialModel::Flipbook] materials.
+//!
+//! [VoxelMaterialModel::Flipbook]: crate::engine::voxel::VoxelMaterialModel::Flipbook
+
+use bevy::prelude::{Handle, Image, Resource};
+use bevy::render::extract_resource::ExtractResource;
+
+/// The frames of every flipbook material, as the layers of a single 2D array texture.
+///
+/// Every [VoxelMaterial::new_flipbook] uses a range of layers of the image, so screens and fires can share
+/// the same texture. The frames are projected on the faces of the blocks like the other textures, the image
+/// can be reinterpreted as an array when it is loaded:
+/// ```rs
+/// let mut image = images.get_mut(&handle).unwrap();
+/// image.reinterpret_stacked_2d_as_array(8);
+/// commands.insert_resource(VoxelFlipbook::new(handle));
+/// ```
+///
+/// Without this resource (or while the image is loading) the flipbook materials emit their plain color.
+///
+/// [VoxelMaterial::new_flipbook]: crate::engine::voxel::VoxelMaterial::new_flipbook
+#[derive(Resource, ExtractResource, Clone, Debug)]
+pub struct VoxelFlipbook {
+    /// The 2D array image with the frames.
+    pub image: Handle<Image>,
+}
+
+impl VoxelFlipbook {
+    pub fn new(image: Handle<Image>) -> Self {
+        Self { image }
+    }
+}
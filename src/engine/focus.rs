@@ -0,0 +1,407 @@
+//! This module contains the auto-focus used to keep the depth of field of a camera on what it's looking at,
+//! and the overlay showing where the focus is.
+
+use crate::engine::camera::{RayCamera, VoxelCamera};
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::denoiser::DenoiserLabel;
+use crate::engine::node::NEVRFragmentLabel;
+use crate::engine::status::{NEVRStatus, NEVRWarning};
+use crate::{VoxelGBuffer, VoxelViewTarget};
+use bevy::app::App;
+use bevy::asset::{RenderAssetUsages, embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::core_3d::graph::Core3d;
+use bevy::ecs::change_detection::DetectChangesMut;
+use bevy::ecs::observer::On;
+use bevy::ecs::query::QueryItem;
+use bevy::prelude::{
+    AssetId, Assets, Camera, ChildOf, ColorToComponents, Commands, Component, Entity, FromWorld,
+    GlobalTransform, Handle, LinearRgba, Plugin, Query, Res, ResMut, Resource, Time, UVec2, Vec2,
+    Vec4, World,
+};
+use bevy::render::RenderApp;
+use bevy::render::camera::ExtractedCamera;
+use bevy::render::extract_component::ExtractComponent;
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_graph::{
+    NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+};
+use bevy::render::render_resource::binding_types::{texture_storage_2d, uniform_buffer};
+use bevy::render::render_resource::{
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BufferUsages,
+    CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache,
+    ShaderStages, StorageTextureAccess, TextureFormat, TextureView, UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::storage::ShaderStorageBuffer;
+use bevy::render::view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms};
+
+/// Automatically sets [VoxelCamera::focus_distance] to the distance of what is under a point of the screen.
+///
+/// Add it to the entity of a [VoxelCamera]:
+/// ```rs
+/// commands.spawn((VoxelCamera::default().with_aperture(0.1), VoxelAutoFocus::default()));
+/// ```
+///
+/// The distance is read back from the GPU, so the focus lags a couple of frames behind what is rendered.
+/// The focus distance is changed smoothly and only when the difference is bigger than `threshold`,
+/// so the camera isn't marked as changed every frame.
+#[derive(Component, Clone, Debug)]
+pub struct VoxelAutoFocus {
+    /// The point of the viewport to focus, in normalized coordinates ((0, 0) is the top-left corner and
+    /// (1, 1) is the bottom-right corner). Defaults to the center of the viewport.
+    pub point: Vec2,
+    /// How fast the focus distance reaches the distance of the focused point, higher is faster. Defaults to 8.0.
+    pub speed: f32,
+    /// The minimum difference between the current and the focused distance to update the camera. Defaults to 0.01.
+    pub threshold: f32,
+    depth: Option<f32>,
+    buffer: Option<Handle<ShaderStorageBuffer>>,
+}
+
+impl VoxelAutoFocus {
+    pub fn new(point: Vec2, speed: f32, threshold: f32) -> Self {
+        Self {
+            point,
+            speed,
+            threshold,
+            depth: None,
+            buffer: None,
+        }
+    }
+
+    pub fn with_point(mut self, point: Vec2) -> Self {
+        self.point = point;
+        self
+    }
+
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// The last view-space depth read from the GPU, [None] if nothing was under the focused point.
+    pub fn depth(&self) -> Option<f32> {
+        self.depth
+    }
+}
+
+impl Default for VoxelAutoFocus {
+    fn default() -> Self {
+        Self::new(Vec2::splat(0.5), 8.0, 0.01)
+    }
+}
+
+/// Used in the rendering phase to copy the depth of the focused point.
+#[derive(Component, Clone, Debug)]
+pub struct RenderAutoFocus {
+    pub buffer: AssetId<ShaderStorageBuffer>,
+    pub point: Vec2,
+}
+
+impl ExtractComponent for VoxelAutoFocus {
+    type QueryData = &'static VoxelAutoFocus;
+    type QueryFilter = ();
+    type Out = RenderAutoFocus;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        Some(RenderAutoFocus {
+            buffer: item.buffer.as_ref()?.id(),
+            point: item.point,
+        })
+    }
+}
+
+/// Entity reading back the focused depth of a camera.
+#[derive(Component)]
+pub struct AutoFocusReadback {
+    camera: Entity,
+}
+
+/// Creates the buffers used to read back the focused depth and removes the unused ones.
+pub fn prepare_auto_focus(
+    mut cameras: Query<(Entity, &mut VoxelAutoFocus)>,
+    readbacks: Query<(Entity, &AutoFocusReadback)>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    for (entity, readback) in &readbacks {
+        if !cameras.contains(readback.camera) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (camera, mut auto_focus) in &mut cameras {
+        if auto_focus.buffer.is_some() {
+            continue;
+        }
+
+        let mut buffer = ShaderStorageBuffer::with_size(4, RenderAssetUsages::RENDER_WORLD);
+        buffer.buffer_description.usage |= BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let buffer = buffers.add(buffer);
+
+        commands
+            .spawn((
+                Readback::buffer(buffer.clone()),
+                AutoFocusReadback { camera },
+                ChildOf(camera),
+            ))
+            .observe(read_auto_focus);
+
+        auto_focus.buffer = Some(buffer);
+    }
+}
+
+fn read_auto_focus(
+    event: On<ReadbackComplete>,
+    readbacks: Query<&AutoFocusReadback>,
+    mut cameras: Query<&mut VoxelAutoFocus>,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    let Ok(mut auto_focus) = cameras.get_mut(readback.camera) else {
+        return;
+    };
+    let Some(bytes) = event.data.first_chunk::<4>() else {
+        return;
+    };
+
+    let depth = f32::from_le_bytes(*bytes);
+    auto_focus.bypass_change_detection().depth = (depth > 0.0).then_some(depth);
+}
+
+/// Moves the focus distance of the cameras towards the focused point.
+pub fn update_auto_focus(
+    time: Res<Time>,
+    mut cameras: Query<(&mut VoxelCamera, &VoxelAutoFocus, &Camera, &GlobalTransform)>,
+) {
+    for (mut voxel_camera, auto_focus, camera, transform) in &mut cameras {
+        let Some(depth) = auto_focus.depth else {
+            continue;
+        };
+
+        // the depth is measured along the view direction, but the focus distance is measured along the ray
+        let distance = camera
+            .logical_viewport_size()
+            .and_then(|size| {
+                camera
+                    .viewport_to_world(transform, auto_focus.point * size)
+                    .ok()
+            })
+            .map(|ray| depth / ray.direction.dot(*transform.forward()).max(0.001))
+            .unwrap_or(depth);
+
+        let current = voxel_camera.focus_distance;
+        if (distance - current).abs() <= auto_focus.threshold {
+            continue;
+        }
+
+        let factor = 1.0 - (-auto_focus.speed * time.delta_secs()).exp();
+        voxel_camera.focus_distance = current + (distance - current) * factor;
+    }
+}
+
+/// Tints the pixels near the focus distance of the cameras, to see where the in-focus band of the depth of field is.
+///
+/// It is off by default, insert this resource to enable it and remove it to disable it:
+/// ```rs
+/// commands.insert_resource(NEVRShowFocusPlane::default());
+/// ```
+///
+/// The tint fades out with the distance from the focus distance, so the band is smooth even across a single pixel.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug)]
+pub struct NEVRShowFocusPlane {
+    /// The color of the tint, its alpha is the strength of the tint. Defaults to a semi-transparent magenta.
+    pub color: LinearRgba,
+    /// How far from the focus distance the pixels are tinted, in world units. Defaults to 0.1.
+    pub band: f32,
+}
+
+impl Default for NEVRShowFocusPlane {
+    fn default() -> Self {
+        Self {
+            color: LinearRgba::new(1.0, 0.0, 1.0, 0.5),
+            band: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct FocusPlaneLabel;
+
+/// The plugin which adds the focus plane overlay, check [NEVRShowFocusPlane].
+///
+/// This is enabled by default when using [nevr::NEVRPlugin].
+pub struct FocusPlanePlugin;
+
+impl Plugin for FocusPlanePlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "shaders/focus_plane.wgsl");
+
+        app.add_plugins(ExtractResourcePlugin::<NEVRShowFocusPlane>::default());
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<FocusPlaneNode>>(Core3d, FocusPlaneLabel)
+            .add_render_graph_edges(Core3d, (DenoiserLabel, FocusPlaneLabel, NEVRFragmentLabel));
+    }
+}
+
+pub struct FocusPlaneNode {
+    pipeline: CachedComputePipelineId,
+    binding_layout: BindGroupLayout,
+}
+
+impl FromWorld for FocusPlaneNode {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let binding_layout = render_device.create_bind_group_layout(
+            "voxel_focus_plane_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View output
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadWrite),
+                    // World position
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadOnly),
+                    // Depth
+                    texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::ReadOnly),
+                    // Color
+                    uniform_buffer::<Vec4>(false),
+                    // Focus distance and band
+                    uniform_buffer::<Vec4>(false),
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // Output offset
+                    uniform_buffer::<UVec2>(false),
+                ),
+            ),
+        );
+
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_focus_plane_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/focus_plane.wgsl"),
+            ..Default::default()
+        });
+
+        Self {
+            pipeline,
+            binding_layout,
+        }
+    }
+}
+
+impl ViewNode for FocusPlaneNode {
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ExtractedCamera,
+        &'static ViewUniformOffset,
+        &'static RayCamera,
+        &'static VoxelViewTarget,
+        &'static VoxelGBuffer,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (view_target, camera, view_uniform_offset, ray_camera, voxel_view_target, g_buffer): QueryItem<
+            'w,
+            '_,
+            Self::ViewQuery,
+        >,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let Some(focus_plane) = world.get_resource::<NEVRShowFocusPlane>() else {
+            return Ok(());
+        };
+
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let view_uniforms = world.resource::<ViewUniforms>();
+        let status = world.resource::<NEVRStatus>();
+
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.pipeline) else {
+            return Ok(());
+        };
+        let Some(viewport) = &camera.physical_viewport_size else {
+            status.report(NEVRWarning::MissingViewport);
+            return Ok(());
+        };
+        let Some(view_uniforms) = view_uniforms.uniforms.binding() else {
+            status.report(NEVRWarning::MissingViewUniforms);
+            return Ok(());
+        };
+
+        // the overlay goes where the denoiser wrote the image
+        let (view_output, output_offset) = match &voxel_view_target.composite {
+            Some(composite) => (composite.default_view.clone(), UVec2::ZERO),
+            None => (
+                TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
+                camera
+                    .viewport
+                    .as_ref()
+                    .map_or(UVec2::ZERO, |viewport| viewport.physical_position),
+            ),
+        };
+
+        let mut color_uniform = UniformBuffer::from(focus_plane.color.to_vec4());
+        color_uniform.write_buffer(render_device, render_queue);
+        let mut focus_uniform = UniformBuffer::from(Vec4::new(
+            ray_camera.focus_distance(),
+            focus_plane.band.max(0.0001),
+            0.0,
+            0.0,
+        ));
+        focus_uniform.write_buffer(render_device, render_queue);
+        let mut offset_uniform = UniformBuffer::from(output_offset);
+        offset_uniform.write_buffer(render_device, render_queue);
+
+        let bind_group = render_device.create_bind_group(
+            "voxel_bindings_focus_plane",
+            &self.binding_layout,
+            &BindGroupEntries::sequential((
+                &view_output,
+                &g_buffer.world_position.default_view,
+                &g_buffer.depth.default_view,
+                color_uniform.binding().unwrap(),
+                focus_uniform.binding().unwrap(),
+                view_uniforms,
+                offset_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_focus_plane"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[view_uniform_offset.offset]);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+
+        Ok(())
+    }
+}
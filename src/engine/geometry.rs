@@ -1,20 +1,31 @@
 //! This module contains resources and systems used in the rendering phase.
 
 use crate::ToBytes;
-use crate::engine::voxel::{RenderVoxelType, VoxelMaterial, VoxelType};
-use bevy::platform::collections::HashMap;
-use bevy::prelude::{AssetId, Res, ResMut, Resource, Transform, UVec4, Vec3};
+use crate::engine::settings::NEVRTuning;
+use crate::engine::stats::NEVRStats;
+use crate::engine::status::{NEVRStatus, NEVRWarning};
+use crate::engine::voxel::{
+    RelativeVoxel, RenderVoxelBlock, RenderVoxelBlockInstances, RenderVoxelType, VoxelMaterial,
+    VoxelShape, VoxelType,
+};
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::prelude::{
+    AssetId, Color, IVec3, InheritedVisibility, Query, Res, ResMut, Resource, Transform, UVec4,
+    Vec3,
+};
 use bevy::render::render_asset::ExtractedAssets;
 use bevy::render::render_resource::encase::internal::{
     AlignmentValue, BufferMut, WriteInto, Writer,
 };
 use bevy::render::render_resource::encase::private::{Metadata, SizeValue};
 use bevy::render::render_resource::{
-    Buffer, BufferInitDescriptor, BufferUsages, BufferVec, ShaderSize, ShaderType,
+    Buffer, BufferInitDescriptor, BufferUsages, BufferVec, RawBufferVec, ShaderSize, ShaderType,
 };
 use bevy::render::renderer::{RenderDevice, RenderQueue};
 use bytemuck::{Pod, Zeroable};
 use itertools::Itertools;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::LazyLock;
 
 #[rustfmt::skip]
 pub const VERTICES: [f32; 72] = [
@@ -94,6 +105,46 @@ pub const NORMALS: [f32; 72] = [
     0.0, 0.0, -1.0,
 ];
 
+/// Tangents of the cube faces, the fourth component is the handedness of the bitangent.
+#[rustfmt::skip]
+pub const TANGENTS: [f32; 96] = [
+    // LEFT
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+
+    // BOTTOM
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+
+    // FORWARD
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+
+    // RIGHT
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+    0.0, 0.0, 1.0, 1.0,
+
+    // TOP
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+
+    // BACKWARD
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+    1.0, 0.0, 0.0, 1.0,
+];
+
 #[rustfmt::skip]
 pub const INDICES: [u32; 36] = [
     // LEFT
@@ -121,20 +172,146 @@ pub const INDICES: [u32; 36] = [
     23, 21, 22,
 ];
 
+/// The triangles every voxel of a [VoxelShape] is made of, inside the 1x1x1 cell of the voxel.
+pub struct VoxelMesh {
+    /// The positions of the vertices, 3 floats each.
+    pub vertices: Vec<f32>,
+    /// The vertices of the triangles, 3 indices each.
+    pub indices: Vec<u32>,
+    /// The normals of the vertices, 3 floats each.
+    pub normals: Vec<f32>,
+    /// The tangents of the vertices, 4 floats each, check [TANGENTS].
+    pub tangents: Vec<f32>,
+}
+
+impl VoxelMesh {
+    /// The mesh of a shape, built the first time it's used.
+    pub fn of(shape: VoxelShape) -> &'static VoxelMesh {
+        static CUBE: LazyLock<VoxelMesh> = LazyLock::new(|| VoxelMesh {
+            vertices: VERTICES.to_vec(),
+            indices: INDICES.to_vec(),
+            normals: NORMALS.to_vec(),
+            tangents: TANGENTS.to_vec(),
+        });
+        static SPHERE: LazyLock<VoxelMesh> = LazyLock::new(|| VoxelMesh::icosphere(1));
+
+        match shape {
+            VoxelShape::Cube => &CUBE,
+            VoxelShape::Sphere => &SPHERE,
+        }
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.vertices.len() as u32 / 3
+    }
+
+    pub fn triangle_count(&self) -> u32 {
+        self.indices.len() as u32 / 3
+    }
+
+    // an icosahedron with every triangle split in 4 `subdivisions` times, 20 * 4^subdivisions triangles
+    fn icosphere(subdivisions: u32) -> Self {
+        let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+        let mut directions = [
+            [-1.0, t, 0.0],
+            [1.0, t, 0.0],
+            [-1.0, -t, 0.0],
+            [1.0, -t, 0.0],
+            [0.0, -1.0, t],
+            [0.0, 1.0, t],
+            [0.0, -1.0, -t],
+            [0.0, 1.0, -t],
+            [t, 0.0, -1.0],
+            [t, 0.0, 1.0],
+            [-t, 0.0, -1.0],
+            [-t, 0.0, 1.0],
+        ]
+        .map(|direction| Vec3::from_array(direction).normalize())
+        .to_vec();
+        #[rustfmt::skip]
+        let mut triangles = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+
+        for _ in 0..subdivisions {
+            // the vertices on the middle of the edges, shared by the two triangles of every edge
+            let mut middles = HashMap::<(u32, u32), u32>::default();
+            let mut middle = |a: u32, b: u32, directions: &mut Vec<Vec3>| {
+                *middles.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    directions.push((directions[a as usize] + directions[b as usize]).normalize());
+                    directions.len() as u32 - 1
+                })
+            };
+
+            triangles = triangles
+                .into_iter()
+                .flat_map(|[a, b, c]| {
+                    let ab = middle(a, b, &mut directions);
+                    let bc = middle(b, c, &mut directions);
+                    let ca = middle(c, a, &mut directions);
+                    [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+                })
+                .collect();
+        }
+
+        let mut mesh = VoxelMesh {
+            vertices: Vec::with_capacity(directions.len() * 3),
+            indices: triangles.into_iter().flatten().collect(),
+            normals: Vec::with_capacity(directions.len() * 3),
+            tangents: Vec::with_capacity(directions.len() * 4),
+        };
+        for direction in directions {
+            // a sphere with a diameter of 1 in the middle of the cell
+            let position = direction * 0.5 + 0.5;
+            // any direction on the surface works, the poles pick another one
+            let tangent = if direction.y.abs() > 0.999 {
+                Vec3::X
+            } else {
+                Vec3::Y.cross(direction).normalize()
+            };
+
+            mesh.vertices.extend(position.to_array());
+            mesh.normals.extend(direction.to_array());
+            mesh.tangents.extend(tangent.extend(1.0).to_array());
+        }
+
+        mesh
+    }
+}
+
 /// Struct used to store the indices used for a geometry in the shader
+///
+/// The shader finds the object of a TLAS instance through the instance's custom index.
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct RenderObject {
     pub index: u32,
     pub material_id: u32,
+    /// Multiplier for the texture coordinates of the block, defaults to 1.0.
+    pub uv_scale: f32,
+    /// Offset of the slice of the material palette used by the object, [RenderObject::NO_MATERIAL_REMAP] when it
+    /// uses the materials of its type, check [VoxelMaterialRemap](crate::engine::voxel::VoxelMaterialRemap).
+    pub material_remap: u32,
+    /// Check [VoxelBlockCustomData](crate::engine::voxel::VoxelBlockCustomData).
+    pub custom_data: [f32; 4],
+    /// Check [VoxelLightChannels](crate::engine::voxel::VoxelLightChannels).
+    pub light_channels: u32,
+}
+
+impl RenderObject {
+    /// The material remap of the objects that use the materials of their type.
+    pub const NO_MATERIAL_REMAP: u32 = u32::MAX;
 }
 
 impl ShaderType for RenderObject {
     type ExtraMetadata = ();
     const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
-        alignment: AlignmentValue::new(4),
+        alignment: AlignmentValue::new(16),
         has_uniform_min_alignment: false,
-        min_size: SizeValue::new(8),
+        min_size: SizeValue::new(48),
         is_pod: false,
         extra: (),
     };
@@ -147,25 +324,57 @@ impl WriteInto for RenderObject {
     {
         writer.write_slice(&self.index.to_le_bytes());
         writer.write_slice(&self.material_id.to_le_bytes());
+        writer.write_slice(&self.uv_scale.to_le_bytes());
+        writer.write_slice(&self.material_remap.to_le_bytes());
+        for value in self.custom_data {
+            writer.write_slice(&value.to_le_bytes());
+        }
+        writer.write_slice(&self.light_channels.to_le_bytes());
+        // padding
+        writer.write_slice(&[0; 12]);
     }
 }
 
 impl ShaderSize for RenderObject {}
 
 /// Manages the buffers for all voxels in the scene.
+///
+/// The geometry of a [VoxelType] is built only once a visible block uses it, until then the type waits in
+/// the pending types.
 #[derive(Resource)]
 pub struct GeometryManager {
     geometries_vertices: HashMap<AssetId<VoxelType>, Buffer>,
     geometries_indices: HashMap<AssetId<VoxelType>, Buffer>,
 
+    pending_types: HashMap<AssetId<VoxelType>, VoxelType>,
+    visible_types: HashSet<AssetId<VoxelType>>,
+    rebuilt_types: Vec<AssetId<VoxelType>>,
+    // the fingerprint of the voxels every type was built from, to skip the types extracted again unchanged
+    fingerprints: HashMap<AssetId<VoxelType>, u64>,
+    transient_types: HashSet<AssetId<VoxelType>>,
+    // the types with materials that let the light through, built as non-opaque geometry
+    non_opaque_types: HashSet<AssetId<VoxelType>>,
+    // the materials used by the voxels of every built type, to check its opacity again when they change
+    type_materials: HashMap<AssetId<VoxelType>, HashSet<AssetId<VoxelMaterial>>>,
+    // the built types whose opacity changed in this frame because of their materials
+    opacity_changed_types: Vec<AssetId<VoxelType>>,
+    // the levels of detail of every type, sorted by distance
+    lods: HashMap<AssetId<VoxelType>, Vec<(AssetId<VoxelType>, f32)>>,
+    geometry_written: bool,
+
     added_types: Vec<AssetId<VoxelType>>,
     added_materials: Vec<AssetId<VoxelMaterial>>,
 
-    vertices: BufferVec<f32>,
-    indices: BufferVec<UVec4>,
+    vertices: RawBufferVec<f32>,
+    indices: RawBufferVec<UVec4>,
     normals: BufferVec<f32>,
+    tangents: BufferVec<f32>,
     materials: BufferVec<VoxelMaterial>,
-    material_map: BufferVec<u32>,
+    material_values: Vec<VoxelMaterial>,
+    material_map: RawBufferVec<u32>,
+    // the triangles (indices in material_map) drawn with the fallback material because their material wasn't
+    // uploaded yet, they're patched as soon as it is
+    missing_materials: HashMap<AssetId<VoxelMaterial>, Vec<u32>>,
 
     object_map: HashMap<AssetId<VoxelType>, u32>,
     index_map: Vec<u32>,
@@ -173,6 +382,12 @@ pub struct GeometryManager {
 }
 
 impl GeometryManager {
+    /// The index of the material used by the voxels whose material isn't on the GPU: it wasn't loaded yet
+    /// when their type was built, it was removed or it's over the limit of the materials. It's a magenta
+    /// [VoxelMaterialModel::Lambertian](crate::engine::voxel::VoxelMaterialModel::Lambertian) material, so
+    /// the missing materials stand out instead of borrowing the look of another one.
+    pub const FALLBACK_MATERIAL: u32 = 0;
+
     pub fn get_geometry_vertices(&self, id: &AssetId<VoxelType>) -> Option<&Buffer> {
         self.geometries_vertices.get(id)
     }
@@ -181,11 +396,64 @@ impl GeometryManager {
         self.geometries_indices.get(id)
     }
 
-    pub fn vertices(&self) -> &BufferVec<f32> {
+    /// Whether at least one visible block or instance uses the type, or a type it's a level of detail of, in this
+    /// frame.
+    pub fn is_visible(&self, id: &AssetId<VoxelType>) -> bool {
+        self.visible_types.contains(id)
+    }
+
+    /// The types used by at least one visible block or instance in this frame, with their levels of detail.
+    pub fn visible_types(&self) -> impl Iterator<Item = &AssetId<VoxelType>> {
+        self.visible_types.iter()
+    }
+
+    /// The types whose geometry buffers were (re)built in this frame.
+    pub fn rebuilt_types(&self) -> &[AssetId<VoxelType>] {
+        &self.rebuilt_types
+    }
+
+    /// Whether the geometry buffers were ever written, they are empty until the first visible type is built.
+    pub fn has_geometry(&self) -> bool {
+        self.geometry_written
+    }
+
+    /// The type to trace for a block of the given type at `distance` from the camera, check [VoxelType::with_lod].
+    pub fn lod_for_distance(&self, id: &AssetId<VoxelType>, distance: f32) -> AssetId<VoxelType> {
+        self.lods
+            .get(id)
+            .and_then(|lods| {
+                lods.iter()
+                    .rev()
+                    .find(|(_, lod_distance)| distance >= *lod_distance)
+            })
+            .map_or(*id, |(lod, _)| *lod)
+    }
+
+    /// Whether the type was marked with [VoxelType::transient].
+    pub fn is_transient(&self, id: &AssetId<VoxelType>) -> bool {
+        self.transient_types.contains(id)
+    }
+
+    /// Whether none of the materials of the type let the light through (check
+    /// [VoxelMaterial::transmits_light]), so its BLAS can be built as opaque. It's checked again when the
+    /// materials used by the type are uploaded or changed.
+    pub fn is_opaque(&self, id: &AssetId<VoxelType>) -> bool {
+        !self.non_opaque_types.contains(id)
+    }
+
+    /// The built types which became opaque or stopped being opaque in this frame because one of their materials
+    /// was uploaded or changed, their BLAS is built again.
+    pub fn opacity_changed_types(&self) -> &[AssetId<VoxelType>] {
+        &self.opacity_changed_types
+    }
+
+    /// The vertices of all the objects, a vec4 each (w is always 1.0).
+    pub fn vertices(&self) -> &RawBufferVec<f32> {
         &self.vertices
     }
 
-    pub fn indices(&self) -> &BufferVec<UVec4> {
+    /// The triangles of all the objects, the global indices of their three vertices (w is unused).
+    pub fn indices(&self) -> &RawBufferVec<UVec4> {
         &self.indices
     }
 
@@ -193,11 +461,15 @@ impl GeometryManager {
         &self.normals
     }
 
+    pub fn tangents(&self) -> &BufferVec<f32> {
+        &self.tangents
+    }
+
     pub fn materials(&self) -> &BufferVec<VoxelMaterial> {
         &self.materials
     }
 
-    pub fn material_map(&self) -> &BufferVec<u32> {
+    pub fn material_map(&self) -> &RawBufferVec<u32> {
         &self.material_map
     }
 
@@ -214,6 +486,19 @@ impl GeometryManager {
         self.material_index_map.get(object_id as usize).cloned()
     }
 
+    /// The number of materials uploaded to the shader, with [GeometryManager::FALLBACK_MATERIAL].
+    pub fn material_count(&self) -> u32 {
+        self.material_values.len() as u32
+    }
+
+    // whether some of the uploaded materials let the light through
+    fn uploaded_transmit_light(&self, materials: &HashSet<AssetId<VoxelMaterial>>) -> bool {
+        transmits_light(materials, |material| {
+            self.index_of_material(material)
+                .and_then(|index| self.material_values.get(index as usize))
+        })
+    }
+
     pub fn index_of_material(&self, id: &AssetId<VoxelMaterial>) -> Option<u32> {
         for (i, material_id) in self.added_materials.iter().enumerate() {
             if material_id == id {
@@ -231,59 +516,186 @@ impl Default for GeometryManager {
             geometries_vertices: HashMap::default(),
             geometries_indices: HashMap::default(),
 
+            pending_types: HashMap::default(),
+            visible_types: HashSet::default(),
+            rebuilt_types: vec![],
+            transient_types: HashSet::default(),
+            non_opaque_types: HashSet::default(),
+            type_materials: HashMap::default(),
+            opacity_changed_types: vec![],
+            lods: HashMap::default(),
+            geometry_written: false,
+
             added_types: vec![],
-            added_materials: vec![],
+            // the slot of the fallback material, no asset has the invalid id
+            added_materials: vec![AssetId::invalid()],
 
-            vertices: BufferVec::new(BufferUsages::STORAGE),
-            indices: BufferVec::new(BufferUsages::STORAGE),
+            vertices: RawBufferVec::new(BufferUsages::STORAGE),
+            indices: RawBufferVec::new(BufferUsages::STORAGE),
             normals: BufferVec::new(BufferUsages::STORAGE),
+            tangents: BufferVec::new(BufferUsages::STORAGE),
             materials: BufferVec::new(BufferUsages::STORAGE),
-            material_map: BufferVec::new(BufferUsages::STORAGE),
+            material_values: vec![VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 1.0))],
+            material_map: RawBufferVec::new(BufferUsages::STORAGE),
+            missing_materials: HashMap::default(),
 
             object_map: HashMap::default(),
             index_map: vec![],
             material_index_map: vec![],
+            fingerprints: HashMap::default(),
         }
     }
 }
 
 // TODO: a refactor may soon be necessary
 /// Extracts all necessary data to copy in buffers.
+///
+/// Types without visible blocks are kept pending and built as soon as a block using them becomes visible.
+/// Types extracted again with the same voxels keep their buffers, so the frames where no type is added or
+/// changed don't create any buffer, check [NEVRStats::geometry_rebuilds].
+/// Types whose voxels changed are appended again to the global buffers with the same object ID, their old
+/// geometry is left unused there.
 pub fn prepare_geometry(
     mut geometry_manager: ResMut<GeometryManager>,
     voxel_types: Res<ExtractedAssets<RenderVoxelType>>,
+    blocks: Query<(&RenderVoxelBlock, &InheritedVisibility)>,
+    instances: Query<(&RenderVoxelBlockInstances, &InheritedVisibility)>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    status: Res<NEVRStatus>,
+    mut stats: ResMut<NEVRStats>,
 ) {
+    geometry_manager.rebuilt_types.clear();
+
     for id in &voxel_types.removed {
         geometry_manager.geometries_vertices.remove(id);
         geometry_manager.geometries_indices.remove(id);
+        geometry_manager.fingerprints.remove(id);
+        geometry_manager.pending_types.remove(id);
+        geometry_manager.transient_types.remove(id);
+        geometry_manager.non_opaque_types.remove(id);
+        geometry_manager.type_materials.remove(id);
+        geometry_manager.lods.remove(id);
     }
 
+    for (id, voxel_type) in &voxel_types.extracted {
+        if voxel_type.is_transient() {
+            geometry_manager.transient_types.insert(*id);
+        } else {
+            geometry_manager.transient_types.remove(id);
+        }
+
+        let mut lods = voxel_type
+            .lods()
+            .iter()
+            .map(|lod| (lod.voxel_type.id(), lod.distance))
+            .collect::<Vec<_>>();
+        lods.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        if lods.is_empty() {
+            geometry_manager.lods.remove(id);
+        } else {
+            geometry_manager.lods.insert(*id, lods);
+        }
+
+        geometry_manager
+            .pending_types
+            .insert(*id, voxel_type.clone());
+    }
+
+    geometry_manager.visible_types = blocks
+        .iter()
+        .filter(|(_, visible)| **visible != InheritedVisibility::HIDDEN)
+        .map(|(block, _)| block.voxel_type)
+        .chain(
+            instances
+                .iter()
+                .filter(|(instances, visible)| {
+                    **visible != InheritedVisibility::HIDDEN && !instances.transforms.is_empty()
+                })
+                .map(|(instances, _)| instances.voxel_type),
+        )
+        .collect();
+    // the levels of detail of the visible types are built too, so a block can switch level at any time
+    let lod_types = geometry_manager
+        .visible_types
+        .iter()
+        .filter_map(|id| geometry_manager.lods.get(id))
+        .flatten()
+        .map(|(lod, _)| *lod)
+        .collect::<Vec<_>>();
+    geometry_manager.visible_types.extend(lod_types);
+
+    let ready_types = geometry_manager
+        .visible_types
+        .iter()
+        .filter(|id| geometry_manager.pending_types.contains_key(*id))
+        .copied()
+        .collect::<Vec<_>>();
+
     let mut new_additions = false;
     let mut global_offset = geometry_manager.indices.len() as u32;
+    // every vertex is a vec4
+    let mut global_vertex_offset = geometry_manager.vertices.len() as u32 / 4;
+
+    for id in &ready_types {
+        let voxel_type = geometry_manager.pending_types.remove(id).unwrap();
+        // the asset was modified without changing its voxels (e.g. a level of detail was added), the buffers
+        // and the BLAS built from them are still valid
+        let fingerprint = geometry_fingerprint(&voxel_type);
+        if geometry_manager.fingerprints.get(id) == Some(&fingerprint)
+            && geometry_manager.geometries_vertices.contains_key(id)
+        {
+            continue;
+        }
+        geometry_manager.fingerprints.insert(*id, fingerprint);
+
+        if voxel_type.validate() != (0, 0) {
+            status.report(NEVRWarning::InvalidVoxels);
+        }
 
-    for (id, voxel_type) in &voxel_types.extracted {
         let size = 1.0 / voxel_type.size() as f32;
         let voxels = voxel_type.voxels();
-        let mut vertices = Vec::with_capacity((VERTICES.len() + VERTICES.len() / 3) * voxels.len());
-        let mut indices = Vec::with_capacity(INDICES.len() * voxels.len());
+        let mesh = VoxelMesh::of(voxel_type.shape());
+        let mut vertices = Vec::with_capacity(mesh.vertices.len() * voxels.len());
+        let mut indices = Vec::with_capacity(mesh.indices.len() * voxels.len());
         let mut offset = 0;
         // divided by 4 because in the shader we use a vec4 for indices
 
-        let added = geometry_manager.added_types.contains(id);
-        new_additions |= !added;
-
-        // if not still added, object_id will be used to reference the object's data
-        // and global_offset is the first set of geometry's indices
-        if !added {
-            let object_id = geometry_manager.added_types.len() as u32;
-            let material_offset = geometry_manager.material_map.len() as u32;
+        new_additions = true;
+
+        // 3 floats for every vertex of every voxel, the normals of the mesh are used when they're not smoothed
+        let smooth_normals = (voxel_type.smooth_normals()
+            && voxel_type.shape() == VoxelShape::Cube)
+            .then(|| smooth_cube_normals(voxels, mesh));
+
+        let materials = voxels
+            .iter()
+            .map(|voxel| voxel.material.id())
+            .collect::<HashSet<_>>();
+        if geometry_manager.uploaded_transmit_light(&materials) {
+            geometry_manager.non_opaque_types.insert(*id);
+        } else {
+            geometry_manager.non_opaque_types.remove(id);
+        }
+        geometry_manager.type_materials.insert(*id, materials);
+
+        // the object ID references the object's data and global_offset is the first set of geometry's indices.
+        // A type whose voxels changed keeps its object ID and its geometry is appended again, the old one is left
+        // unused in the global buffers
+        let material_offset = geometry_manager.material_map.len() as u32;
+        match geometry_manager.get_object_id(id) {
+            Some(object_id) => {
+                geometry_manager.index_map[object_id as usize] = global_offset;
+                geometry_manager.material_index_map[object_id as usize] = material_offset;
+            }
+            None => {
+                let object_id = geometry_manager.added_types.len() as u32;
 
-            geometry_manager.added_types.push(*id);
-            geometry_manager.object_map.insert(*id, object_id);
-            geometry_manager.index_map.push(global_offset);
-            geometry_manager.material_index_map.push(material_offset);
+                geometry_manager.added_types.push(*id);
+                geometry_manager.object_map.insert(*id, object_id);
+                geometry_manager.index_map.push(global_offset);
+                geometry_manager.material_index_map.push(material_offset);
+            }
         }
 
         for voxel in voxels {
@@ -291,7 +703,7 @@ pub fn prepare_geometry(
             let transform =
                 Transform::from_scale(Vec3::new(size, size, size)).with_translation(position);
 
-            let chunks = VERTICES.iter().chunks(3);
+            let chunks = mesh.vertices.iter().chunks(3);
 
             for vec in chunks.into_iter() {
                 let vec = vec.collect_array::<3>().unwrap();
@@ -300,51 +712,64 @@ pub fn prepare_geometry(
                 vertices.push(vertex.y);
                 vertices.push(vertex.z);
 
-                if !added {
-                    geometry_manager.vertices.push(vertex.x);
-                    geometry_manager.vertices.push(vertex.y);
-                    geometry_manager.vertices.push(vertex.z);
-                    geometry_manager.vertices.push(1.0);
-                }
+                geometry_manager.vertices.push(vertex.x);
+                geometry_manager.vertices.push(vertex.y);
+                geometry_manager.vertices.push(vertex.z);
+                geometry_manager.vertices.push(1.0);
             }
 
-            let chunks = INDICES.iter().chunks(3);
+            let chunks = mesh.indices.iter().chunks(3);
 
             for index in chunks.into_iter() {
                 let indices_array = index.collect_array::<3>().unwrap();
-                indices.push(indices_array[0] + offset * (VERTICES.len() as u32 / 3));
-                indices.push(indices_array[1] + offset * (VERTICES.len() as u32 / 3));
-                indices.push(indices_array[2] + offset * (VERTICES.len() as u32 / 3));
-
-                if !added {
-                    let material_id = geometry_manager
-                        .index_of_material(&voxel.material.id())
-                        .unwrap();
-
-                    geometry_manager.material_map.push(material_id);
-
-                    let index_offset = offset * (VERTICES.len() as u32 / 3) + global_offset * 2;
-                    let index = UVec4::new(
-                        indices_array[0] + index_offset,
-                        indices_array[1] + index_offset,
-                        indices_array[2] + index_offset,
-                        0,
-                    );
-                    geometry_manager.indices.push(index);
-                }
+                indices.push(indices_array[0] + offset * mesh.vertex_count());
+                indices.push(indices_array[1] + offset * mesh.vertex_count());
+                indices.push(indices_array[2] + offset * mesh.vertex_count());
+
+                // the materials that aren't loaded yet or are over NEVRTuning::max_materials aren't
+                // uploaded, the fallback one is used until prepare_materials uploads them
+                let material_id = match geometry_manager.index_of_material(&voxel.material.id()) {
+                    Some(material_id) => material_id,
+                    None => {
+                        let triangle = geometry_manager.material_map.len() as u32;
+                        geometry_manager
+                            .missing_materials
+                            .entry(voxel.material.id())
+                            .or_default()
+                            .push(triangle);
+                        GeometryManager::FALLBACK_MATERIAL
+                    }
+                };
+
+                geometry_manager.material_map.push(material_id);
+
+                let index_offset = offset * mesh.vertex_count() + global_vertex_offset;
+                let index = UVec4::new(
+                    indices_array[0] + index_offset,
+                    indices_array[1] + index_offset,
+                    indices_array[2] + index_offset,
+                    0,
+                );
+                geometry_manager.indices.push(index);
             }
 
-            if !added {
-                let chunks = NORMALS.iter().chunks(3);
-
-                for normal in chunks.into_iter() {
-                    let normal_array = normal.collect_array::<3>().unwrap();
-
-                    geometry_manager.normals.push(*normal_array[0]);
-                    geometry_manager.normals.push(*normal_array[1]);
-                    geometry_manager.normals.push(*normal_array[2]);
-                    geometry_manager.normals.push(1.0);
+            let normals = match &smooth_normals {
+                Some(normals) => {
+                    let voxel_normals = mesh.normals.len();
+                    &normals[offset as usize * voxel_normals..][..voxel_normals]
                 }
+                None => &mesh.normals[..],
+            };
+
+            for normal_array in normals.chunks(3) {
+                geometry_manager.normals.push(normal_array[0]);
+                geometry_manager.normals.push(normal_array[1]);
+                geometry_manager.normals.push(normal_array[2]);
+                geometry_manager.normals.push(1.0);
+            }
+
+            for tangent in &mesh.tangents {
+                geometry_manager.tangents.push(*tangent);
             }
 
             offset += 1;
@@ -362,50 +787,370 @@ pub fn prepare_geometry(
             contents: indices.to_bytes(),
         });
 
-        if new_additions {
-            geometry_manager
-                .vertices
-                .write_buffer(&render_device, &render_queue);
-            geometry_manager
-                .indices
-                .write_buffer(&render_device, &render_queue);
-            geometry_manager
-                .normals
-                .write_buffer(&render_device, &render_queue);
-            geometry_manager
-                .material_map
-                .write_buffer(&render_device, &render_queue);
-        }
-
         geometry_manager.geometries_vertices.insert(*id, vertices);
         geometry_manager.geometries_indices.insert(*id, indices);
+        geometry_manager.rebuilt_types.push(*id);
+
+        global_offset += voxels.len() as u32 * mesh.triangle_count();
+        global_vertex_offset += voxels.len() as u32 * mesh.vertex_count();
+    }
+
+    // the global buffers are written once, after all the new and changed types of this frame were appended
+    if new_additions {
+        geometry_manager
+            .vertices
+            .write_buffer(&render_device, &render_queue);
+        geometry_manager
+            .indices
+            .write_buffer(&render_device, &render_queue);
+        geometry_manager
+            .normals
+            .write_buffer(&render_device, &render_queue);
+        geometry_manager
+            .tangents
+            .write_buffer(&render_device, &render_queue);
+        geometry_manager
+            .material_map
+            .write_buffer(&render_device, &render_queue);
+        geometry_manager.geometry_written = true;
+    }
+    stats.geometry_rebuilds = geometry_manager.rebuilt_types.len() as u32;
+
+    // every index is a vec4 with the three vertices of a triangle
+    stats.triangles = geometry_manager.indices.len() as u32;
+}
+
+// the directions of the faces of a cube, a bit each in the masks of smooth_cube_normals
+const FACE_DIRECTIONS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+// the normals of the vertices of the cubes of a type (3 floats for every vertex of every voxel, in the order of the
+// voxels), averaged with the visible faces touching the same corner, check VoxelType::with_smooth_normals
+fn smooth_cube_normals(voxels: &[RelativeVoxel], mesh: &VoxelMesh) -> Vec<f32> {
+    let cells = voxels
+        .iter()
+        .map(|voxel| voxel.position.floor().as_ivec3())
+        .collect::<HashSet<_>>();
+    let corners_and_normals = mesh
+        .vertices
+        .chunks(3)
+        .zip(mesh.normals.chunks(3))
+        .map(|(vertex, normal)| {
+            (
+                Vec3::from_slice(vertex).as_ivec3(),
+                Vec3::from_slice(normal).as_ivec3(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let face_bit = |normal: IVec3| {
+        let index = FACE_DIRECTIONS
+            .iter()
+            .position(|direction| *direction == normal);
+        1u8 << index.unwrap_or_default()
+    };
 
-        global_offset += voxels.len() as u32 * INDICES.len() as u32 / 3;
+    // the faces between two voxels are hidden, they don't bend the normals around them
+    let mut corners = HashMap::<IVec3, u8>::default();
+    for voxel in voxels {
+        let cell = voxel.position.floor().as_ivec3();
+        for (corner, normal) in &corners_and_normals {
+            if !cells.contains(&(cell + *normal)) {
+                *corners.entry(cell + *corner).or_default() |= face_bit(*normal);
+            }
+        }
+    }
+
+    let mut normals = Vec::with_capacity(voxels.len() * mesh.normals.len());
+    for voxel in voxels {
+        let cell = voxel.position.floor().as_ivec3();
+        for (corner, normal) in &corners_and_normals {
+            let mask = corners.get(&(cell + *corner)).copied().unwrap_or_default();
+            let smooth_normal = if cells.contains(&(cell + *normal)) {
+                normal.as_vec3()
+            } else {
+                // the opposite faces (e.g. two voxels touching on an edge) would cancel out
+                FACE_DIRECTIONS
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, direction)| mask & (1 << i) != 0 && **direction != -*normal)
+                    .map(|(_, direction)| direction.as_vec3())
+                    .sum::<Vec3>()
+                    .normalize()
+            };
+            normals.extend(smooth_normal.to_array());
+        }
     }
+
+    normals
+}
+
+// whether some of the materials let the light through, `material` finds the uploaded values of a material and
+// the ones not uploaded are opaque
+fn transmits_light<'a>(
+    materials: &HashSet<AssetId<VoxelMaterial>>,
+    material: impl Fn(&AssetId<VoxelMaterial>) -> Option<&'a VoxelMaterial>,
+) -> bool {
+    materials
+        .iter()
+        .filter_map(material)
+        .any(VoxelMaterial::transmits_light)
+}
+
+// a hash of everything the geometry of a type is built from
+fn geometry_fingerprint(voxel_type: &VoxelType) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    voxel_type.size().hash(&mut hasher);
+    voxel_type.shape().hash(&mut hasher);
+    voxel_type.smooth_normals().hash(&mut hasher);
+    voxel_type.voxels().len().hash(&mut hasher);
+    for voxel in voxel_type.voxels() {
+        voxel
+            .position
+            .to_array()
+            .map(f32::to_bits)
+            .hash(&mut hasher);
+        voxel.material.id().hash(&mut hasher);
+    }
+    hasher.finish()
 }
 
 /// Prepare materials used for rendering
+///
+/// Modified materials are updated in place, so changing a material through [bevy::prelude::Assets] only
+/// uploads the materials again and doesn't rebuild any geometry. The voxels built before their material was
+/// loaded switch from [GeometryManager::FALLBACK_MATERIAL] to it once it's uploaded.
 pub fn prepare_materials(
     mut geometry_manager: ResMut<GeometryManager>,
     materials: Res<ExtractedAssets<VoxelMaterial>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    tuning: Res<NEVRTuning>,
+    status: Res<NEVRStatus>,
+    mut stats: ResMut<NEVRStats>,
 ) {
-    let mut new_additions = false;
+    // the GPU validation fails when the buffer is bigger than a storage binding can be, the materials over the
+    // limit are skipped instead
+    let mut material_limit = render_device.limits().max_storage_buffer_binding_size
+        / VoxelMaterial::min_size().get() as u32;
+    if tuning.max_materials > 0 {
+        material_limit = material_limit.min(tuning.max_materials);
+    }
+    if geometry_manager.material_count() as u64 * 10 > material_limit as u64 * 9 {
+        status.report(NEVRWarning::MaterialsNearLimit);
+    }
+    geometry_manager.opacity_changed_types.clear();
 
-    for (id, material) in &materials.extracted {
-        let added = geometry_manager.added_materials.contains(id);
-        new_additions |= !added;
+    // the buffer is written at least once for the fallback material
+    if materials.extracted.is_empty()
+        && materials.removed.is_empty()
+        && geometry_manager.materials.buffer().is_some()
+    {
+        return;
+    }
 
-        if !added {
-            geometry_manager.added_materials.push(*id);
-            geometry_manager.materials.push(*material);
+    // the voxels of the removed materials keep their slot, which shows the fallback until the material is added
+    // again
+    for id in &materials.removed {
+        if let Some(index) = geometry_manager.index_of_material(id) {
+            geometry_manager.material_values[index as usize] =
+                geometry_manager.material_values[GeometryManager::FALLBACK_MATERIAL as usize];
         }
     }
 
-    if new_additions {
+    let mut material_map_changed = false;
+    for (id, material) in &materials.extracted {
+        match geometry_manager.index_of_material(id) {
+            Some(index) => geometry_manager.material_values[index as usize] = *material,
+            None if geometry_manager.material_count() >= material_limit => {
+                status.report(NEVRWarning::TooManyMaterials);
+            }
+            None => {
+                let index = geometry_manager.material_count();
+                geometry_manager.added_materials.push(*id);
+                geometry_manager.material_values.push(*material);
+
+                // the types built while the material was loading point to the fallback material
+                if let Some(triangles) = geometry_manager.missing_materials.remove(id) {
+                    for triangle in triangles {
+                        geometry_manager.material_map.set(triangle, index);
+                    }
+                    material_map_changed = true;
+                }
+            }
+        }
+    }
+    if material_map_changed {
         geometry_manager
-            .materials
+            .material_map
             .write_buffer(&render_device, &render_queue);
     }
+    // the fallback material isn't counted
+    stats.materials = geometry_manager.material_count() - 1;
+
+    // the types built before their materials were loaded, or whose materials changed model, may have to switch
+    // between opaque and non-opaque geometry
+    let changed_materials = materials
+        .extracted
+        .iter()
+        .map(|(id, _)| *id)
+        .chain(materials.removed.iter().copied())
+        .collect::<HashSet<_>>();
+    let opacity_changed_types = geometry_manager
+        .type_materials
+        .iter()
+        .filter(|(_, materials)| !materials.is_disjoint(&changed_materials))
+        .filter(|(id, materials)| {
+            geometry_manager.uploaded_transmit_light(materials) == geometry_manager.is_opaque(id)
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in &opacity_changed_types {
+        if !geometry_manager.non_opaque_types.remove(id) {
+            geometry_manager.non_opaque_types.insert(*id);
+        }
+    }
+    geometry_manager.opacity_changed_types = opacity_changed_types;
+
+    // BufferVec can't overwrite single values, the buffer is reused as long as no material was added
+    let geometry_manager = geometry_manager.as_mut();
+    geometry_manager.materials.clear();
+    for material in &geometry_manager.material_values {
+        geometry_manager.materials.push(*material);
+    }
+    geometry_manager
+        .materials
+        .write_buffer(&render_device, &render_queue);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::{Assets, Handle};
+
+    // two voxels of different materials in a type of size 4
+    fn voxels() -> (Vec<RelativeVoxel>, Assets<VoxelMaterial>) {
+        let mut materials = Assets::<VoxelMaterial>::default();
+        let voxels = [Color::WHITE, Color::BLACK]
+            .into_iter()
+            .zip([IVec3::ZERO, IVec3::new(1, 2, 3)])
+            .map(|(color, position)| {
+                RelativeVoxel::at(
+                    position,
+                    materials.add(VoxelMaterial::new_lambertian(color)),
+                )
+            })
+            .collect();
+        (voxels, materials)
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let (voxels, _materials) = voxels();
+        let fingerprint = geometry_fingerprint(&VoxelType::new(4, voxels.clone()));
+        assert_eq!(
+            geometry_fingerprint(&VoxelType::new(4, voxels.clone())),
+            fingerprint
+        );
+        // the levels of detail and the transient hint don't change the geometry
+        let with_lod = VoxelType::new(4, voxels)
+            .with_lod(Handle::default(), 10.0)
+            .transient();
+        assert_eq!(geometry_fingerprint(&with_lod), fingerprint);
+    }
+
+    #[test]
+    fn fingerprint_follows_the_geometry() {
+        let (voxels, _materials) = voxels();
+        let fingerprint = geometry_fingerprint(&VoxelType::new(4, voxels.clone()));
+
+        let mut moved = voxels.clone();
+        moved[1].position.x += 1.0;
+        let mut swapped = voxels.clone();
+        swapped.swap(0, 1);
+        let mut recolored = voxels.clone();
+        recolored[1].material = voxels[0].material.clone();
+        let changed = [
+            VoxelType::new(8, voxels.clone()),
+            VoxelType::new(4, voxels.clone()).with_shape(VoxelShape::Sphere),
+            VoxelType::new(4, voxels.clone()).with_smooth_normals(true),
+            VoxelType::new(4, voxels[..1].to_vec()),
+            VoxelType::new(4, moved),
+            VoxelType::new(4, swapped),
+            VoxelType::new(4, recolored),
+        ];
+        for voxel_type in &changed {
+            assert_ne!(geometry_fingerprint(voxel_type), fingerprint);
+        }
+    }
+
+    #[test]
+    fn glass_and_thin_films_transmit_light() {
+        let mut materials = Assets::<VoxelMaterial>::default();
+        let mut type_materials = |material: VoxelMaterial| {
+            HashSet::from_iter([
+                materials
+                    .add(VoxelMaterial::new_lambertian(Color::WHITE))
+                    .id(),
+                materials.add(material).id(),
+            ])
+        };
+        let opaque = type_materials(VoxelMaterial::new_metallic(Color::WHITE, 0.1));
+        let glass = type_materials(VoxelMaterial::from_ior(VoxelMaterial::IOR_GLASS));
+        let thin_film = type_materials(VoxelMaterial::new_thin_film(Color::WHITE, 400.0, 1.33));
+
+        let uploaded = |material: &AssetId<VoxelMaterial>| materials.get(*material);
+        assert!(!transmits_light(&opaque, uploaded));
+        assert!(transmits_light(&glass, uploaded));
+        assert!(transmits_light(&thin_film, uploaded));
+        // the materials that aren't uploaded yet count as opaque
+        assert!(!transmits_light(&glass, |_| None));
+    }
+
+    // the smooth normal of every vertex of every voxel
+    fn smooth_normals(cells: &[IVec3]) -> Vec<Vec3> {
+        let voxels = cells
+            .iter()
+            .map(|cell| RelativeVoxel::at(*cell, Handle::default()))
+            .collect::<Vec<_>>();
+        smooth_cube_normals(&voxels, VoxelMesh::of(VoxelShape::Cube))
+            .chunks(3)
+            .map(Vec3::from_slice)
+            .collect()
+    }
+
+    #[test]
+    fn lone_voxel_has_corner_normals() {
+        let mesh = VoxelMesh::of(VoxelShape::Cube);
+        let normals = smooth_normals(&[IVec3::ZERO]);
+        assert_eq!(normals.len() * 3, mesh.normals.len());
+
+        // every corner touches three faces, its normal points out of the corner
+        for (normal, vertex) in normals.iter().zip(mesh.vertices.chunks(3)) {
+            let corner = (Vec3::from_slice(vertex) - Vec3::splat(0.5)).normalize();
+            assert!(normal.abs_diff_eq(corner, 1e-5), "{normal} at {vertex:?}");
+        }
+    }
+
+    #[test]
+    fn voxels_touching_on_an_edge_stay_finite() {
+        let normals = smooth_normals(&[IVec3::ZERO, IVec3::new(1, 1, 0)]);
+        let mesh = VoxelMesh::of(VoxelShape::Cube);
+        assert_eq!(normals.len() * 3, mesh.normals.len() * 2);
+
+        // on the shared edge the opposite faces of the two voxels would cancel out
+        for normal in &normals {
+            assert!(normal.is_finite(), "{normal}");
+            assert!((normal.length() - 1.0).abs() < 1e-5, "{normal}");
+        }
+        // each face keeps facing out of its voxel
+        for (normal, face) in normals.iter().zip(mesh.normals.chunks(3).cycle()) {
+            assert!(normal.dot(Vec3::from_slice(face)) > 0.0);
+        }
+    }
 }
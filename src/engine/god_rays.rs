@@ -0,0 +1,285 @@
+//! This module contains the screen-space light scattering (god rays) of the sun.
+
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::denoiser::DenoiserLabel;
+use crate::engine::focus::FocusPlaneLabel;
+use crate::engine::light::RenderVoxelLight;
+use crate::engine::node::NEVRFragmentLabel;
+use crate::engine::status::{NEVRStatus, NEVRWarning};
+use crate::{VoxelGBuffer, VoxelViewTarget};
+use bevy::app::App;
+use bevy::asset::{embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::core_3d::graph::Core3d;
+use bevy::ecs::query::QueryItem;
+use bevy::prelude::{FromWorld, Plugin, Resource, UVec2, Vec3, Vec4, World};
+use bevy::render::RenderApp;
+use bevy::render::camera::ExtractedCamera;
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::render::render_graph::{
+    NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+};
+use bevy::render::render_resource::binding_types::{texture_storage_2d, uniform_buffer};
+use bevy::render::render_resource::{
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId,
+    ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache, ShaderStages,
+    StorageTextureAccess, TextureFormat, TextureView, UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms};
+
+/// Adds shafts of light (god rays) around the sun when it's partially hidden by the blocks.
+///
+/// Every pixel walks toward the position of the sun on the screen and counts how many of the pixels along the
+/// way show the sky, the light of the sun (tinted by [crate::engine::light::VoxelLight::sun_color] and scaled
+/// by the light intensity) is then added to the image after the denoiser. It is off by default, insert this
+/// resource to enable it and remove it to disable it:
+/// ```rs
+/// commands.insert_resource(NEVRGodRays::default());
+/// ```
+///
+/// **Note:** The effect only knows what is on the screen, which is the usual trade-off of screen-space god
+/// rays:
+/// - There are no rays when the sun is behind the camera, and they fade out as the sun leaves the screen.
+/// - Blocks outside of the view don't cast shafts, even if they are between the sun and the camera.
+/// - Every surface that was hit counts as an occluder, glass and thin films included.
+/// - The light is added on top of the image instead of being traced through a medium, so it doesn't respect
+///   the shadows of the scene (shafts may go through walls that are in front of the sky).
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq)]
+pub struct NEVRGodRays {
+    /// How much light is scattered toward the camera. Defaults to 0.3.
+    pub intensity: f32,
+    /// How much every step away from the pixel counts less than the previous one, from 0.0 to 1.0, higher
+    /// values make longer shafts. Defaults to 0.97.
+    pub decay: f32,
+    /// How many pixels are read between every pixel and the sun, more samples make smoother shafts but cost
+    /// more. Defaults to 64.
+    pub samples: u32,
+}
+
+impl NEVRGodRays {
+    pub fn new(intensity: f32, decay: f32, samples: u32) -> Self {
+        Self {
+            intensity,
+            decay,
+            samples,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_decay(mut self, decay: f32) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    pub fn with_samples(mut self, samples: u32) -> Self {
+        self.samples = samples;
+        self
+    }
+}
+
+impl Default for NEVRGodRays {
+    fn default() -> Self {
+        Self {
+            intensity: 0.3,
+            decay: 0.97,
+            samples: 64,
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct GodRaysLabel;
+
+/// The plugin which adds the god rays pass, check [NEVRGodRays].
+///
+/// This is enabled by default when using [nevr::NEVRPlugin].
+pub struct GodRaysPlugin;
+
+impl Plugin for GodRaysPlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "shaders/god_rays.wgsl");
+
+        app.add_plugins(ExtractResourcePlugin::<NEVRGodRays>::default());
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        // the focus plane overlay stays on top of the shafts
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<GodRaysNode>>(Core3d, GodRaysLabel)
+            .add_render_graph_edges(Core3d, (DenoiserLabel, GodRaysLabel, NEVRFragmentLabel))
+            .add_render_graph_edge(Core3d, GodRaysLabel, FocusPlaneLabel);
+    }
+}
+
+pub struct GodRaysNode {
+    pipeline: CachedComputePipelineId,
+    binding_layout: BindGroupLayout,
+}
+
+impl FromWorld for GodRaysNode {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let binding_layout = render_device.create_bind_group_layout(
+            "voxel_god_rays_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // View output
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadWrite),
+                    // Depth
+                    texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::ReadOnly),
+                    // Sun color
+                    uniform_buffer::<Vec4>(false),
+                    // Sun direction
+                    uniform_buffer::<Vec4>(false),
+                    // Decay and samples
+                    uniform_buffer::<Vec4>(false),
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // Output offset
+                    uniform_buffer::<UVec2>(false),
+                ),
+            ),
+        );
+
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_god_rays_pipeline".into()),
+            layout: vec![binding_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/god_rays.wgsl"),
+            ..Default::default()
+        });
+
+        Self {
+            pipeline,
+            binding_layout,
+        }
+    }
+}
+
+impl ViewNode for GodRaysNode {
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ExtractedCamera,
+        &'static ViewUniformOffset,
+        &'static VoxelViewTarget,
+        &'static VoxelGBuffer,
+        Option<&'static RenderVoxelLight>,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (view_target, camera, view_uniform_offset, voxel_view_target, g_buffer, light_override): QueryItem<
+            'w,
+            '_,
+            Self::ViewQuery,
+        >,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let Some(god_rays) = world.get_resource::<NEVRGodRays>() else {
+            return Ok(());
+        };
+        if god_rays.samples == 0 || god_rays.intensity <= 0.0 {
+            return Ok(());
+        }
+
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let view_uniforms = world.resource::<ViewUniforms>();
+        let status = world.resource::<NEVRStatus>();
+        let light = light_override.unwrap_or_else(|| world.resource::<RenderVoxelLight>());
+
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(self.pipeline) else {
+            return Ok(());
+        };
+        let Some(viewport) = &camera.physical_viewport_size else {
+            status.report(NEVRWarning::MissingViewport);
+            return Ok(());
+        };
+        let Some(view_uniforms) = view_uniforms.uniforms.binding() else {
+            status.report(NEVRWarning::MissingViewUniforms);
+            return Ok(());
+        };
+
+        // the shafts go where the denoiser wrote the image
+        let (view_output, output_offset) = match &voxel_view_target.composite {
+            Some(composite) => (composite.default_view.clone(), UVec2::ZERO),
+            None => (
+                TextureView::from(view_target.get_unsampled_color_attachment().view.clone()),
+                camera
+                    .viewport
+                    .as_ref()
+                    .map_or(UVec2::ZERO, |viewport| viewport.physical_position),
+            ),
+        };
+
+        // only the tint of the sun disk is used, its brightness is meant for the disk and would blow out the
+        // image, the light intensity scales the shafts instead
+        let sun_radiance = Vec3::from_slice(&light.sun[..3]);
+        let sun_tint = if sun_radiance.max_element() > 0.0 {
+            sun_radiance / sun_radiance.max_element()
+        } else {
+            Vec3::ONE
+        };
+        let sun_color = sun_tint * light.ambient[1] * god_rays.intensity;
+        let sun_direction = -Vec3::from_slice(&light.direction[..3]).normalize_or_zero();
+
+        let mut color_uniform = UniformBuffer::from(sun_color.extend(0.0));
+        color_uniform.write_buffer(render_device, render_queue);
+        let mut direction_uniform = UniformBuffer::from(sun_direction.extend(0.0));
+        direction_uniform.write_buffer(render_device, render_queue);
+        let mut settings_uniform = UniformBuffer::from(Vec4::new(
+            god_rays.decay.clamp(0.0, 1.0),
+            god_rays.samples as f32,
+            0.0,
+            0.0,
+        ));
+        settings_uniform.write_buffer(render_device, render_queue);
+        let mut offset_uniform = UniformBuffer::from(output_offset);
+        offset_uniform.write_buffer(render_device, render_queue);
+
+        let bind_group = render_device.create_bind_group(
+            "voxel_bindings_god_rays",
+            &self.binding_layout,
+            &BindGroupEntries::sequential((
+                &view_output,
+                &g_buffer.depth.default_view,
+                color_uniform.binding().unwrap(),
+                direction_uniform.binding().unwrap(),
+                settings_uniform.binding().unwrap(),
+                view_uniforms,
+                offset_uniform.binding().unwrap(),
+            )),
+        );
+
+        let command_encoder = render_context.command_encoder();
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_god_rays"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[view_uniform_offset.offset]);
+        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+
+        Ok(())
+    }
+}
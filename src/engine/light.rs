@@ -2,8 +2,11 @@
 //! like sky color and ambient light
 
 use crate::ToBytes;
-use bevy::math::Vec4;
-use bevy::prelude::Resource;
+use bevy::asset::AssetId;
+use bevy::ecs::query::QueryItem;
+use bevy::math::{Mat4, Vec3, Vec4};
+use bevy::prelude::{Component, Handle, Image, Reflect, ReflectDefault, ReflectResource, Resource};
+use bevy::render::extract_component::ExtractComponent;
 use bevy::render::extract_resource::ExtractResource;
 use bevy::render::render_resource::ShaderType;
 use bevy::render::render_resource::encase::internal::{
@@ -12,7 +15,8 @@ use bevy::render::render_resource::encase::internal::{
 use bevy::render::render_resource::encase::private::{Metadata, SizeValue};
 
 /// Used for ambient light, directional light and its intensity, and the sky color.
-#[derive(Resource, Clone)]
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource, Default)]
 pub struct VoxelLight {
     /// Ambient light and light intensity, i.e. the minimum light in the scene. Defaults to (0.03, 1.0)
     pub(crate) ambient: Vec4,
@@ -20,9 +24,55 @@ pub struct VoxelLight {
     pub direction: Vec4,
     /// The color of the sky, it's used in reflections, global illuminations, etc...
     pub sky_color: Vec4,
+    /// The color of the sun disk visible in the sky. Defaults to (1.0, 0.95, 0.85).
+    pub sun_color: Vec4,
+    /// The brightness of the sun disk visible in the sky, 0.0 hides it. Defaults to 20.0.
+    ///
+    /// It only changes how the sun looks in the background and in reflections, the light it casts is controlled
+    /// by the light intensity.
+    pub sun_intensity: f32,
+    /// The angular radius of the sun in radians, used both for the visible disk and for the softness of the shadows.
+    /// Defaults to 0.00925 (about the size of the real sun).
+    pub sun_angular_radius: f32,
+    /// Whether the sun disk is drawn on top of [crate::engine::skybox::VoxelSkybox]. Defaults to false, since
+    /// skybox textures usually already contain a sun.
+    pub sun_in_skybox: bool,
+    /// The minimum radiance returned by the sky (or the skybox) to the rays that already bounced. Defaults to
+    /// zero.
+    ///
+    /// This is a cheat to reduce noise: with a dark sky and no emissive blocks the indirect light of interiors
+    /// is almost zero and the few paths that reach a bright spot make the image very noisy, a small floor gives
+    /// them a faint fill. The background seen directly by the camera isn't changed, unlike the ambient light which
+    /// is also scaled by the light intensity.
+    pub min_indirect: Vec3,
+    /// A texture projected along the light (a "cookie" or "gobo"), e.g. the shadow of a window or a stylized
+    /// pattern. Defaults to none, which leaves the light uniform.
+    ///
+    /// The direct light of the sun is multiplied by the texel the hit point is projected on, check
+    /// [VoxelLight::with_cookie].
+    pub cookie: Option<Handle<Image>>,
+    /// Transforms a world position into the texture coordinates of [VoxelLight::cookie] in x and y, z and w are
+    /// ignored. Defaults to identity.
+    ///
+    /// The coordinates outside of 0..1 follow the address mode of the sampler of the image.
+    pub cookie_projection: Mat4,
 }
 
 impl VoxelLight {
+    /// Projects a cookie along the light, `cookie_projection` moves a world position to the texture coordinates
+    /// of the image:
+    /// ```rs
+    /// // the image covers 10x10 units and is projected along the light direction
+    /// let direction = light.direction().truncate();
+    /// let projection = Mat4::from_scale(Vec3::splat(0.1)) * Mat4::look_to_rh(Vec3::ZERO, direction, Vec3::Z);
+    /// light = light.with_cookie(asset_server.load("window.png"), projection);
+    /// ```
+    pub fn with_cookie(mut self, cookie: Handle<Image>, cookie_projection: Mat4) -> Self {
+        self.cookie = Some(cookie);
+        self.cookie_projection = cookie_projection;
+        self
+    }
+
     pub fn ambient(&self) -> f32 {
         self.ambient.x
     }
@@ -54,6 +104,59 @@ impl VoxelLight {
     pub fn set_sky_color(&mut self, sky_color: Vec4) {
         self.sky_color = sky_color;
     }
+
+    /// Sets the direction of the light from the position of the sun in the sky, e.g. for a day-night cycle.
+    ///
+    /// `elevation` is the angle of the sun over the horizon in radians: 0.0 is on the horizon (the light is
+    /// horizontal), `FRAC_PI_2` is straight up (like [Vec4::NEG_Y], the default) and negative values are below
+    /// the horizon. `azimuth` is the angle around the vertical axis in radians, 0.0 puts the sun towards -Z and
+    /// `FRAC_PI_2` towards +X:
+    /// ```rs
+    /// fn day_night_cycle(time: Res<Time>, mut light: ResMut<VoxelLight>) {
+    ///     // a full day every 2 minutes, rising in the east
+    ///     let angle = time.elapsed_secs() / 120.0 * TAU;
+    ///     light.set_sun(angle.sin() * FRAC_PI_2, FRAC_PI_2);
+    /// }
+    /// ```
+    /// Check [VoxelLight::set_sun_with_daylight] to change the colors with the time of day too.
+    pub fn set_sun(&mut self, elevation: f32, azimuth: f32) {
+        let (sin_elevation, cos_elevation) = elevation.sin_cos();
+        let (sin_azimuth, cos_azimuth) = azimuth.sin_cos();
+        let to_sun = Vec3::new(
+            cos_elevation * sin_azimuth,
+            sin_elevation,
+            -cos_elevation * cos_azimuth,
+        );
+        self.direction = (-to_sun).extend(0.0);
+    }
+
+    /// Like [VoxelLight::set_sun], and also changes the light with the time of day:
+    /// - the intensity goes from 1.0 when the sun is higher than about 15 degrees down to 0.0 on the horizon;
+    /// - the sun disk and the sky turn warmer as the sun gets lower than about 20 degrees;
+    /// - the sky fades to a dark night blue once the sun is about 10 degrees under the horizon.
+    ///
+    /// It replaces the intensity, the sky color and the sun color set before, the ambient light is kept so
+    /// the nights aren't completely black.
+    pub fn set_sun_with_daylight(&mut self, elevation: f32, azimuth: f32) {
+        const NOON_SUN: Vec3 = Vec3::new(1.0, 0.95, 0.85);
+        const SUNSET_SUN: Vec3 = Vec3::new(1.0, 0.45, 0.2);
+        const NOON_SKY: Vec3 = Vec3::new(0.5, 0.7, 1.0);
+        const SUNSET_SKY: Vec3 = Vec3::new(0.9, 0.55, 0.4);
+        const NIGHT_SKY: Vec3 = Vec3::new(0.02, 0.03, 0.06);
+
+        self.set_sun(elevation, azimuth);
+
+        // 0.0 at sunset, 1.0 once the sun is 20 degrees high
+        let day = (elevation / 20.0f32.to_radians()).clamp(0.0, 1.0);
+        // 0.0 at sunset, 1.0 once the sun is 10 degrees under the horizon
+        let night = (-elevation / 10.0f32.to_radians()).clamp(0.0, 1.0);
+
+        let sun_color = SUNSET_SUN.lerp(NOON_SUN, day);
+        let sky_color = SUNSET_SKY.lerp(NOON_SKY, day).lerp(NIGHT_SKY, night);
+        self.sun_color = sun_color.extend(1.0);
+        self.sky_color = sky_color.extend(1.0);
+        self.set_intensity((elevation.sin() * 4.0).clamp(0.0, 1.0));
+    }
 }
 
 impl Default for VoxelLight {
@@ -62,26 +165,79 @@ impl Default for VoxelLight {
             ambient: Vec4::new(0.03, 1.0, 0.03, 1.0),
             direction: Vec4::NEG_Y,
             sky_color: Vec4::new(0.5, 0.7, 1.0, 1.0),
+            sun_color: Vec4::new(1.0, 0.95, 0.85, 1.0),
+            sun_intensity: 20.0,
+            sun_angular_radius: 0.00925,
+            sun_in_skybox: false,
+            min_indirect: Vec3::ZERO,
+            cookie: None,
+            cookie_projection: Mat4::IDENTITY,
         }
     }
 }
 
-#[derive(Resource, Default)]
+/// Overrides the global [VoxelLight] for a single camera.
+///
+/// Add it to an entity with a [crate::engine::camera::VoxelCamera] to render that view with its own lighting
+/// (e.g. a neutral studio light in an editor viewport), cameras without it use the [VoxelLight] resource:
+/// ```rs
+/// let mut studio_light = VoxelLight::default();
+/// studio_light.set_sky_color(Vec4::new(0.8, 0.8, 0.8, 1.0));
+/// commands.spawn((VoxelCamera::default(), VoxelLightOverride(studio_light)));
+/// ```
+#[derive(Component, Clone)]
+pub struct VoxelLightOverride(pub VoxelLight);
+
+impl ExtractComponent for VoxelLightOverride {
+    type QueryData = &'static VoxelLightOverride;
+    type QueryFilter = ();
+    type Out = RenderVoxelLight;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        Some(RenderVoxelLight::from(&item.0))
+    }
+}
+
+/// Used in the rendering phase, both as the global light and as the light of a single view.
+#[derive(Resource, Component, Default)]
 pub struct RenderVoxelLight {
     pub ambient: [f32; 4],
     pub direction: [f32; 4],
     pub sky_color: [f32; 4],
+    /// The radiance of the sun disk in rgb and the angular radius of the sun in w.
+    pub sun: [f32; 4],
+    /// The minimum radiance of the sky for indirect rays in rgb, w is unused.
+    pub min_indirect: [f32; 4],
+    pub cookie_projection: [f32; 16],
+    pub sun_in_skybox: u32,
+    /// The image of [VoxelLight::cookie], bound by the node and not written in the uniform.
+    pub cookie: Option<AssetId<Image>>,
+}
+
+impl From<&VoxelLight> for RenderVoxelLight {
+    fn from(light: &VoxelLight) -> Self {
+        let sun_radiance = light.sun_color.truncate() * light.sun_intensity.max(0.0);
+
+        Self {
+            ambient: light.ambient.to_array(),
+            direction: light.direction.to_array(),
+            sky_color: light.sky_color.to_array(),
+            sun: sun_radiance
+                .extend(light.sun_angular_radius.max(0.0))
+                .to_array(),
+            min_indirect: light.min_indirect.max(Vec3::ZERO).extend(0.0).to_array(),
+            cookie_projection: light.cookie_projection.to_cols_array(),
+            sun_in_skybox: light.sun_in_skybox.into(),
+            cookie: light.cookie.as_ref().map(Handle::id),
+        }
+    }
 }
 
 impl ExtractResource for RenderVoxelLight {
     type Source = VoxelLight;
 
     fn extract_resource(source: &Self::Source) -> Self {
-        Self {
-            ambient: source.ambient.to_array(),
-            direction: source.direction.to_array(),
-            sky_color: source.sky_color.to_array(),
-        }
+        Self::from(source)
     }
 }
 
@@ -90,7 +246,7 @@ impl ShaderType for RenderVoxelLight {
     const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
         alignment: AlignmentValue::new(16),
         has_uniform_min_alignment: false,
-        min_size: SizeValue::new(48),
+        min_size: SizeValue::new(160),
         is_pod: false,
         extra: (),
     };
@@ -104,5 +260,38 @@ impl WriteInto for RenderVoxelLight {
         writer.write_slice(self.ambient.to_bytes());
         writer.write_slice(self.direction.to_bytes());
         writer.write_slice(self.sky_color.to_bytes());
+        writer.write_slice(self.sun.to_bytes());
+        writer.write_slice(self.min_indirect.to_bytes());
+        writer.write_slice(self.cookie_projection.to_bytes());
+        writer.write_slice(&self.sun_in_skybox.to_le_bytes());
+        writer.write_slice(&u32::from(self.cookie.is_some()).to_le_bytes());
+        writer.write_slice(&[0; 8]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn sun_on_the_horizon_is_horizontal() {
+        let mut light = VoxelLight::default();
+        for azimuth in [0.0, FRAC_PI_2, 2.0] {
+            light.set_sun(0.0, azimuth);
+            assert!(light.direction.y.abs() < 1e-6);
+            assert!((light.direction.truncate().length() - 1.0).abs() < 1e-6);
+        }
+
+        // towards -Z, the light goes towards +Z
+        light.set_sun(0.0, 0.0);
+        assert!(light.direction.abs_diff_eq(Vec4::Z, 1e-6));
+    }
+
+    #[test]
+    fn sun_straight_up_points_down() {
+        let mut light = VoxelLight::default();
+        light.set_sun(FRAC_PI_2, 1.0);
+        assert!(light.direction.abs_diff_eq(Vec4::NEG_Y, 1e-6));
     }
 }
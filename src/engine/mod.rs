@@ -5,9 +5,27 @@
 
 pub mod blas;
 pub mod camera;
+pub mod capabilities;
+pub mod chunk;
+pub mod color_grade;
+pub mod convergence;
+#[cfg(feature = "egui")]
+pub mod debug_ui;
 pub mod denoiser;
+pub mod exposure;
+pub mod flipbook;
+pub mod focus;
 pub mod geometry;
+pub mod god_rays;
 pub mod light;
 pub mod node;
+pub mod probes;
+pub mod readback;
+pub mod reference;
+pub mod settings;
 pub mod skybox;
+pub mod stats;
+pub mod status;
+pub mod tween;
+pub mod vox;
 pub mod voxel;
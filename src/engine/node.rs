@@ -1,44 +1,161 @@
 //! This module contains the renderer code.
 
-use crate::engine::camera::RayCamera;
+use crate::engine::camera::{PreviousRayView, RayCamera};
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::flipbook::VoxelFlipbook;
+use crate::engine::focus::RenderAutoFocus;
 use crate::engine::light::RenderVoxelLight;
-use crate::engine::skybox::VoxelSkybox;
+use crate::engine::probes::ProbeVolume;
+use crate::engine::readback::{
+    RenderContinuousReadback, RenderDepthReadback, padded_bytes_per_row, padded_depth_bytes_per_row,
+};
+use crate::engine::settings::{
+    NEVRAccumulationSubsteps, NEVRDebugView, NEVRPaused, NEVRSeed, NEVRShadingMode, NEVRTuning,
+};
+use crate::engine::skybox::{RenderSkyboxLayers, SkyboxDistribution, VoxelSkybox};
+use crate::engine::status::{NEVRPipelines, NEVRStatus, NEVRWarning};
 use crate::{VoxelBindings, VoxelGBuffer, VoxelViewTarget};
 use bevy::app::App;
 use bevy::asset::{embedded_asset, load_embedded_asset};
+use bevy::core_pipeline::FullscreenShader;
+use bevy::core_pipeline::core_3d::CORE_3D_DEPTH_FORMAT;
 use bevy::core_pipeline::core_3d::graph::{Core3d, Node3d};
 use bevy::ecs::query::QueryItem;
-use bevy::prelude::{FromWorld, Plugin, World};
-use bevy::render::RenderApp;
+use bevy::image::ToExtents;
+use bevy::prelude::{
+    Commands, Component, Entity, FromWorld, Handle, Has, IntoScheduleConfigs, Mat3, Msaa, Plugin,
+    Query, Res, ResMut, Resource, Time, UVec2, With, World,
+};
 use bevy::render::camera::ExtractedCamera;
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::render::globals::GlobalsBuffer;
 use bevy::render::render_asset::RenderAssets;
 use bevy::render::render_graph::{
     NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
 };
+use bevy::render::render_resource::binding_types::{
+    acceleration_structure, texture_2d, uniform_buffer,
+};
 use bevy::render::render_resource::{
-    BindGroupEntries, CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor,
-    DynamicUniformBuffer, PipelineCache,
+    BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId,
+    CachedRenderPipelineId, ColorTargetState, ColorWrites, CommandEncoder,
+    CommandEncoderDescriptor, CompareFunction, ComputePassDescriptor, ComputePipelineDescriptor,
+    DepthBiasState, DepthStencilState, DynamicUniformBuffer, Extent3d, FragmentState,
+    MultisampleState, Origin3d, PipelineCache, RenderPassDescriptor, RenderPipelineDescriptor,
+    ShaderStages, SpecializedRenderPipeline, SpecializedRenderPipelines, StencilState, StoreOp,
+    TexelCopyBufferInfo, TexelCopyBufferLayout, TexelCopyTextureInfo, TextureAspect, TextureFormat,
+    TextureSampleType,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::storage::GpuShaderStorageBuffer;
+use bevy::render::texture::{FallbackImage, GpuImage};
+use bevy::render::view::{
+    ViewDepthTexture, ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms,
 };
-use bevy::render::renderer::{RenderContext, RenderQueue};
-use bevy::render::texture::GpuImage;
-use bevy::render::view::{ViewUniformOffset, ViewUniforms};
-use bevy::shader::ShaderDefVal;
+use bevy::render::{Render, RenderApp, RenderSystems};
+use bevy::shader::{Shader, ShaderDefVal};
+
+/// Describes how the ray traced image is written to the view target.
+///
+/// Defaults to [NEVRNodeMode::Compute].
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq)]
+pub enum NEVRNodeMode {
+    /// The image is copied (or written by the denoiser) directly into the view target.
+    ///
+    /// This is the fastest mode but it doesn't support MSAA and doesn't write the depth of the view.
+    #[default]
+    Compute,
+    /// The image is drawn into the view target by a fragment shader, which traces the primary rays again with
+    /// ray queries to write the depth of every sample.
+    ///
+    /// This renders into a standard render attachment, so rasterized geometry and UI drawn by Bevy's 3D passes
+    /// is depth tested against the voxels and MSAA works as usual. To use it, add [bevy::prelude::Camera3d]
+    /// and the [Msaa] you want to the entity of the [crate::engine::camera::VoxelCamera].
+    Fragment,
+}
+
+/// Replaces the embedded ray tracing shader with a custom one, e.g. to change the integrator without forking
+/// NEVR.
+///
+/// It is read once when [crate::NEVRPlugin] is finished, so it must be inserted before adding the plugin:
+/// ```rs
+/// let shader = app.world().resource::<AssetServer>().load("shaders/my_integrator.wgsl");
+/// app.insert_resource(NEVRShaderOverride(shader))
+///     .add_plugins(NEVRPlugin::default());
+/// ```
+///
+/// The shader is used with the same pipeline layout as the embedded one, so it must follow its contract (the
+/// embedded `shaders/raytracing.wgsl` is the reference for the exact types):
+/// - The entry point is a compute shader called `main` with a workgroup size of 8x8x1, dispatched once per
+///   tile of the view (check [crate::engine::settings::NEVRTuning::max_workgroups_per_dispatch]).
+/// - Group 0 is the scene: the TLAS, the objects, the indices, the vertices, the normals, the tangents, the
+///   materials, the material map, the previous transforms, the material palette, the baked probes and their
+///   grid, in this order. The probes are baked by the embedded shader even when it's overridden.
+/// - Group 1 is the view: the camera, the output, the light, the view, the accumulation, the previous view, the
+///   tile offset, the light cookie and its sampler, the flipbook frames and their sampler and the globals.
+/// - Group 2 is the g-buffer: albedo, normal, world position, depth, object id, motion vectors, detail and
+///   specular, which are read by the denoisers, the readbacks and the other passes.
+/// - Group 3 is the skybox (the cubemap, its sampler, the sRGB flag, the distribution, the layers and the
+///   cubemaps of the layers), it is bound only when the `SKYBOX` shader def is set.
+/// - The `HEATMAP` shader def is set when [crate::engine::settings::NEVRDebugView::Heatmap] is shown.
+/// - The types with glass or thin film materials are built as non-opaque geometry, the ray queries have to use
+///   `RAY_FLAG_FORCE_OPAQUE` (or confirm their candidate intersections) to hit them.
+/// - The materials are 48 bytes each: the diffuse color, the texture, the fuzziness, the refraction index, the
+///   material model, the strength and the roughness of the clear coat and 8 bytes of padding.
+///
+/// The bindings that the shader doesn't use can be left out. A shader that doesn't compile is reported by
+/// [NEVRWarning::InvalidShader] and nothing is rendered, a shader whose bindings don't match the layout fails
+/// the validation of wgpu.
+#[derive(Resource, Clone, Debug)]
+pub struct NEVRShaderOverride(pub Handle<Shader>);
 
 pub struct NEVRNodeRender;
 
 impl Plugin for NEVRNodeRender {
     fn build(&self, app: &mut App) {
         embedded_asset!(app, "shaders/raytracing.wgsl");
+        embedded_asset!(app, "shaders/fragment.wgsl");
+        embedded_asset!(app, "shaders/skybox_distribution.wgsl");
+
+        app.add_plugins(ExtractResourcePlugin::<NEVRNodeMode>::default())
+            .init_resource::<NEVRNodeMode>();
     }
 
     fn finish(&self, app: &mut App) {
+        let shader_override = app.world().get_resource::<NEVRShaderOverride>().cloned();
         let render_app = app.sub_app_mut(RenderApp);
+        // the nodes of NEVRPlugin aren't registered on unsupported GPUs, the edges would point to nothing
+        if !render_app
+            .world()
+            .resource::<NEVRCapabilities>()
+            .is_supported()
+        {
+            return;
+        }
+
+        // the node reads it when it's created
+        if let Some(shader_override) = shader_override {
+            render_app.insert_resource(shader_override);
+        }
 
         render_app
+            .init_resource::<SkyboxDistribution>()
+            .init_resource::<NEVRFragmentPipeline>()
+            .init_resource::<SpecializedRenderPipelines<NEVRFragmentPipeline>>()
+            .add_systems(
+                Render,
+                prepare_fragment_pipelines.in_set(RenderSystems::Prepare),
+            )
             .add_render_graph_node::<ViewNodeRunner<NEVRNode>>(Core3d, NEVRNodeLabel)
+            .add_render_graph_node::<ViewNodeRunner<NEVRFragmentNode>>(Core3d, NEVRFragmentLabel)
             .add_render_graph_edges(
                 Core3d,
-                (Node3d::StartMainPass, NEVRNodeLabel, Node3d::MainOpaquePass),
+                (
+                    Node3d::StartMainPass,
+                    NEVRNodeLabel,
+                    NEVRFragmentLabel,
+                    Node3d::MainOpaquePass,
+                ),
             );
     }
 }
@@ -46,35 +163,114 @@ impl Plugin for NEVRNodeRender {
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub struct NEVRNodeLabel;
 
+// splits the workgroups of a dispatch in tiles (origin and size) of at most max_workgroups, 0 doesn't split them
+fn dispatch_tiles(workgroups: UVec2, max_workgroups: u32) -> Vec<(UVec2, UVec2)> {
+    if max_workgroups == 0 || workgroups.x * workgroups.y <= max_workgroups {
+        return vec![(UVec2::ZERO, workgroups)];
+    }
+
+    // whole rows when they fit, to keep the tiles as wide as possible
+    let tile_size = UVec2::new(
+        workgroups.x.min(max_workgroups),
+        (max_workgroups / workgroups.x).max(1),
+    );
+
+    let mut tiles = vec![];
+    for y in (0..workgroups.y).step_by(tile_size.y as usize) {
+        for x in (0..workgroups.x).step_by(tile_size.x as usize) {
+            let origin = UVec2::new(x, y);
+            tiles.push((origin, tile_size.min(workgroups - origin)));
+        }
+    }
+
+    tiles
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct NEVRFragmentLabel;
+
 pub struct NEVRNode {
     pipeline: CachedComputePipelineId,
     skybox_pipeline: CachedComputePipelineId,
+    heatmap_pipeline: CachedComputePipelineId,
+    skybox_heatmap_pipeline: CachedComputePipelineId,
+    bake_pipeline: CachedComputePipelineId,
+    skybox_bake_pipeline: CachedComputePipelineId,
 }
 
 impl FromWorld for NEVRNode {
     fn from_world(world: &mut World) -> Self {
         let pipeline_cache = world.resource::<PipelineCache>();
         let voxel_bindings = world.resource::<VoxelBindings>();
+        let shader = match world.get_resource::<NEVRShaderOverride>() {
+            Some(shader_override) => shader_override.0.clone(),
+            None => load_embedded_asset!(world, "shaders/raytracing.wgsl"),
+        };
 
-        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some("voxel_raytracing_pipeline".into()),
-            layout: voxel_bindings.bind_group_layouts[..3].to_vec(),
-            shader: load_embedded_asset!(world, "shaders/raytracing.wgsl"),
-            ..Default::default()
-        });
+        let queue_pipeline = |skybox: bool, heatmap: bool| {
+            let mut shader_defs = vec![];
+            if skybox {
+                shader_defs.push(ShaderDefVal::Bool("SKYBOX".into(), true));
+            }
+            if heatmap {
+                shader_defs.push(ShaderDefVal::Bool("HEATMAP".into(), true));
+            }
 
-        let skybox_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some("voxel_raytracing_pipeline".into()),
-            layout: voxel_bindings.bind_group_layouts[..].to_vec(),
-            shader: load_embedded_asset!(world, "shaders/raytracing.wgsl"),
-            shader_defs: vec![ShaderDefVal::Bool("SKYBOX".into(), true)],
-            ..Default::default()
-        });
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("voxel_raytracing_pipeline".into()),
+                layout: if skybox {
+                    voxel_bindings.bind_group_layouts[..].to_vec()
+                } else {
+                    voxel_bindings.bind_group_layouts[..3].to_vec()
+                },
+                shader: shader.clone(),
+                shader_defs,
+                ..Default::default()
+            })
+        };
 
-        Self {
-            pipeline,
-            skybox_pipeline,
-        }
+        // the probes are always baked by the embedded shader, the overrides don't have to implement it
+        let embedded_shader = load_embedded_asset!(world, "shaders/raytracing.wgsl");
+        let queue_bake_pipeline = |skybox: bool| {
+            let mut shader_defs = vec![];
+            if skybox {
+                shader_defs.push(ShaderDefVal::Bool("SKYBOX".into(), true));
+            }
+
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("voxel_bake_probes_pipeline".into()),
+                layout: if skybox {
+                    voxel_bindings.bind_group_layouts[..].to_vec()
+                } else {
+                    voxel_bindings.bind_group_layouts[..3].to_vec()
+                },
+                shader: embedded_shader.clone(),
+                shader_defs,
+                entry_point: Some("bake_probes".into()),
+                ..Default::default()
+            })
+        };
+
+        let node = Self {
+            pipeline: queue_pipeline(false, false),
+            skybox_pipeline: queue_pipeline(true, false),
+            heatmap_pipeline: queue_pipeline(false, true),
+            skybox_heatmap_pipeline: queue_pipeline(true, true),
+            bake_pipeline: queue_bake_pipeline(false),
+            skybox_bake_pipeline: queue_bake_pipeline(true),
+        };
+
+        // the pipelines are compiled once the shader is loaded, the node skips the frames until then
+        world.get_resource_or_init::<NEVRPipelines>().track([
+            node.pipeline,
+            node.skybox_pipeline,
+            node.heatmap_pipeline,
+            node.skybox_heatmap_pipeline,
+            node.bake_pipeline,
+            node.skybox_bake_pipeline,
+        ]);
+
+        node
     }
 }
 
@@ -85,58 +281,127 @@ impl ViewNode for NEVRNode {
         &'static ViewUniformOffset,
         &'static VoxelViewTarget,
         &'static VoxelGBuffer,
+        &'static PreviousRayView,
+        Option<&'static RenderVoxelLight>,
+        Option<&'static RenderAutoFocus>,
+        Option<&'static RenderContinuousReadback>,
+        Option<&'static RenderDepthReadback>,
     );
 
     fn run<'w>(
         &self,
         _graph: &mut RenderGraphContext,
         render_context: &mut RenderContext<'w>,
-        (extracted_camera, camera, view_uniform_offset, voxel_view_target, g_buffer): QueryItem<
-            'w,
-            '_,
-            Self::ViewQuery,
-        >,
+        (
+            extracted_camera,
+            camera,
+            view_uniform_offset,
+            voxel_view_target,
+            g_buffer,
+            previous_view,
+            light_override,
+            auto_focus,
+            continuous_readback,
+            depth_readback,
+        ): QueryItem<'w, '_, Self::ViewQuery>,
         world: &'w World,
     ) -> Result<(), NodeRunError> {
+        // the output keeps the last traced frame
+        if world.resource::<NEVRPaused>().0 {
+            return Ok(());
+        }
+
         let pipeline_cache = world.resource::<PipelineCache>();
         let voxel_bindings = world.resource::<VoxelBindings>();
         let render_queue = world.resource::<RenderQueue>();
         let view_uniforms = world.resource::<ViewUniforms>();
-        let voxel_light = world.resource::<RenderVoxelLight>();
+        let voxel_light = light_override.unwrap_or_else(|| world.resource::<RenderVoxelLight>());
         let optional_skybox = world.get_resource::<VoxelSkybox>();
+        let status = world.resource::<NEVRStatus>();
+        let seed = world.resource::<NEVRSeed>();
+        let tuning = world.resource::<NEVRTuning>();
 
-        let pipeline_id = if optional_skybox.is_some() {
-            self.skybox_pipeline
-        } else {
-            self.pipeline
+        let heatmap = *world.resource::<NEVRDebugView>() == NEVRDebugView::Heatmap;
+
+        let pipeline_id = match (optional_skybox.is_some(), heatmap) {
+            (false, false) => self.pipeline,
+            (true, false) => self.skybox_pipeline,
+            (false, true) => self.heatmap_pipeline,
+            (true, true) => self.skybox_heatmap_pipeline,
         };
 
+        // still compiling during the warm-up, the errors are reported by check_pipelines
         let Some(pipeline) = pipeline_cache.get_compute_pipeline(pipeline_id) else {
-            eprintln!(
-                "{:?}",
-                pipeline_cache.get_compute_pipeline_state(pipeline_id)
-            );
             return Ok(());
         };
         let Some(viewport) = &extracted_camera.physical_viewport_size else {
-            eprintln!("no viewport size");
+            status.report(NEVRWarning::MissingViewport);
             return Ok(());
         };
+        // there's nothing to trace in a degenerate viewport
+        if viewport.x == 0 || viewport.y == 0 {
+            return Ok(());
+        }
         let Some(bind_group) = &voxel_bindings.bind_group else {
-            eprintln!("no bind group");
+            status.report(NEVRWarning::MissingBindGroup);
             return Ok(());
         };
         let Some(view_uniforms) = view_uniforms.uniforms.binding() else {
-            eprintln!("no view uniforms");
+            status.report(NEVRWarning::MissingViewUniforms);
             return Ok(());
         };
 
+        // every substep adds its samples to the accumulation, with its own random numbers
+        let substeps = camera.substeps(world.resource::<NEVRAccumulationSubsteps>());
         let mut camera_uniform = DynamicUniformBuffer::default();
-        camera_uniform.push(camera);
+        let camera_offsets = (0..substeps)
+            .map(|substep| {
+                camera_uniform.push(
+                    &camera
+                        .with_seed(seed.0 ^ substep.wrapping_mul(0x9e37_79b9))
+                        .with_accumulated_frames(camera.accumulated_frames() + substep)
+                        .with_tuning(tuning)
+                        .with_shading_mode(*world.resource::<NEVRShadingMode>()),
+                )
+            })
+            .collect::<Vec<_>>();
         camera_uniform.write_buffer(render_context.render_device(), render_queue);
         let mut light_uniform = DynamicUniformBuffer::default();
         light_uniform.push(voxel_light);
         light_uniform.write_buffer(render_context.render_device(), render_queue);
+        let mut previous_view_uniform = DynamicUniformBuffer::default();
+        previous_view_uniform.push(&previous_view.clip_from_world);
+        previous_view_uniform.write_buffer(render_context.render_device(), render_queue);
+
+        let tiles = dispatch_tiles(
+            UVec2::new(viewport.x.div_ceil(8), viewport.y.div_ceil(8)),
+            tuning.max_workgroups_per_dispatch,
+        );
+        let mut tile_offset_uniform = DynamicUniformBuffer::default();
+        let tile_offsets = tiles
+            .iter()
+            // 8 pixels per workgroup
+            .map(|(origin, _)| tile_offset_uniform.push(&(origin * 8)))
+            .collect::<Vec<_>>();
+        tile_offset_uniform.write_buffer(render_context.render_device(), render_queue);
+
+        // without a cookie (or while it's loading) a white image keeps the light uniform
+        let fallback_image = world.resource::<FallbackImage>();
+        let cookie = voxel_light
+            .cookie
+            .and_then(|cookie| world.resource::<RenderAssets<GpuImage>>().get(cookie))
+            .unwrap_or(&fallback_image.d2);
+        let flipbook = world
+            .get_resource::<VoxelFlipbook>()
+            .and_then(|flipbook| {
+                world
+                    .resource::<RenderAssets<GpuImage>>()
+                    .get(flipbook.image.id())
+            })
+            .unwrap_or(&fallback_image.d2_array);
+        let Some(globals) = world.resource::<GlobalsBuffer>().buffer.binding() else {
+            return Ok(());
+        };
 
         let camera_bind_group = render_context.render_device().create_bind_group(
             "voxel_bindings_camera",
@@ -147,6 +412,13 @@ impl ViewNode for NEVRNode {
                 light_uniform.binding().unwrap(),
                 view_uniforms.clone(),
                 &voxel_view_target.accumulation.default_view,
+                previous_view_uniform.binding().unwrap(),
+                tile_offset_uniform.binding().unwrap(),
+                &cookie.texture_view,
+                &cookie.sampler,
+                &flipbook.texture_view,
+                &flipbook.sampler,
+                globals,
             )),
         );
 
@@ -157,40 +429,467 @@ impl ViewNode for NEVRNode {
                 &g_buffer.albedo.default_view,
                 &g_buffer.normal.default_view,
                 &g_buffer.world_position.default_view,
+                &g_buffer.depth.default_view,
+                &g_buffer.object_id.default_view,
+                &g_buffer.motion_vectors.default_view,
+                &g_buffer.detail.default_view,
+                &g_buffer.specular.default_view,
             )),
         );
 
         let optional_skybox_bind_group = if let Some(skybox) = optional_skybox {
             let gpu_images = world.resource::<RenderAssets<GpuImage>>();
-            let Some(image) = gpu_images.get(skybox.0.id()) else {
-                eprintln!("no skybox image found");
+            let Some(image) = gpu_images.get(skybox.image.id()) else {
+                status.report(NEVRWarning::MissingSkyboxImage);
+                return Ok(());
+            };
+
+            let mut decode_srgb_uniform = DynamicUniformBuffer::default();
+            decode_srgb_uniform.push(&u32::from(skybox.decode_srgb(image.texture_format)));
+            decode_srgb_uniform.write_buffer(render_context.render_device(), render_queue);
+
+            // the layers that are still loading are skipped, the unused slots are bound to the fallback cubemap
+            // and ignored by the shader
+            let seconds = world.resource::<Time>().elapsed_secs();
+            let mut layers = RenderSkyboxLayers::default();
+            let mut layer_images = [&fallback_image.cube; VoxelSkybox::MAX_LAYERS];
+            for (i, layer) in skybox
+                .layers
+                .iter()
+                .take(VoxelSkybox::MAX_LAYERS)
+                .enumerate()
+            {
+                let Some(layer_image) = gpu_images.get(layer.image.id()) else {
+                    continue;
+                };
+
+                layers.from_world[i] = Mat3::from_quat(layer.rotation_at(seconds).inverse());
+                layers.flags[i] = [
+                    layer.blend as u32,
+                    u32::from(layer.decode_srgb(layer_image.texture_format)),
+                    1,
+                    0,
+                ];
+                layer_images[i] = layer_image;
+            }
+            let mut layers_uniform = DynamicUniformBuffer::default();
+            layers_uniform.push(&layers);
+            layers_uniform.write_buffer(render_context.render_device(), render_queue);
+
+            let distribution = world.resource::<SkyboxDistribution>();
+            let Some(distribution_pipeline) =
+                pipeline_cache.get_compute_pipeline(distribution.pipeline)
+            else {
                 return Ok(());
             };
 
+            // the skybox image can change at any time, so the distribution is rebuilt before every trace, it's a
+            // single workgroup over a small grid
+            let distribution_bind_group = render_context.render_device().create_bind_group(
+                "voxel_skybox_distribution_bind_group",
+                &distribution.bind_group_layout,
+                &BindGroupEntries::sequential((
+                    &image.texture_view,
+                    &image.sampler,
+                    decode_srgb_uniform.binding().unwrap(),
+                    distribution.buffer.as_entire_binding(),
+                )),
+            );
+
+            {
+                let mut pass =
+                    render_context
+                        .command_encoder()
+                        .begin_compute_pass(&ComputePassDescriptor {
+                            label: Some("voxel_skybox_distribution"),
+                            timestamp_writes: None,
+                        });
+                pass.set_pipeline(distribution_pipeline);
+                pass.set_bind_group(0, &distribution_bind_group, &[]);
+                pass.dispatch_workgroups(1, 1, 1);
+            }
+
             Some(render_context.render_device().create_bind_group(
                 "voxel_bindings_skybox",
                 &voxel_bindings.bind_group_layouts[3],
-                &BindGroupEntries::sequential((&image.texture_view, &image.sampler)),
+                &BindGroupEntries::sequential((
+                    &image.texture_view,
+                    &image.sampler,
+                    decode_srgb_uniform.binding().unwrap(),
+                    distribution.buffer.as_entire_binding(),
+                    layers_uniform.binding().unwrap(),
+                    &layer_images[0].texture_view,
+                    &layer_images[1].texture_view,
+                    &layer_images[2].texture_view,
+                )),
             ))
         } else {
             None
         };
 
+        let trace = |command_encoder: &mut CommandEncoder,
+                     camera_offset: u32,
+                     tile_offset: u32,
+                     size: UVec2| {
+            let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("voxel_raytracing"),
+                timestamp_writes: None,
+            });
+
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_bind_group(
+                1,
+                &camera_bind_group,
+                &[camera_offset, view_uniform_offset.offset, tile_offset],
+            );
+            pass.set_bind_group(2, &g_buffer_bind_group, &[]);
+            if let Some(skybox_bind_group) = optional_skybox_bind_group.as_ref() {
+                pass.set_bind_group(3, skybox_bind_group, &[]);
+            }
+            pass.dispatch_workgroups(size.x, size.y, 1);
+        };
+
+        // a pass of the probes is baked before the first view traced in the frame, so it already uses it
+        let probe_volume = world.resource::<ProbeVolume>();
+        let bake_pipeline = if optional_skybox_bind_group.is_some() {
+            self.skybox_bake_pipeline
+        } else {
+            self.bake_pipeline
+        };
+        if probe_volume.pending_probes() > 0 {
+            if let Some(bake_pipeline) = pipeline_cache.get_compute_pipeline(bake_pipeline) {
+                if probe_volume.claim_bake() {
+                    let mut pass = render_context.command_encoder().begin_compute_pass(
+                        &ComputePassDescriptor {
+                            label: Some("voxel_bake_probes"),
+                            timestamp_writes: None,
+                        },
+                    );
+
+                    pass.set_pipeline(bake_pipeline);
+                    pass.set_bind_group(0, bind_group, &[]);
+                    pass.set_bind_group(
+                        1,
+                        &camera_bind_group,
+                        &[
+                            camera_offsets[0],
+                            view_uniform_offset.offset,
+                            tile_offsets[0],
+                        ],
+                    );
+                    pass.set_bind_group(2, &g_buffer_bind_group, &[]);
+                    if let Some(skybox_bind_group) = optional_skybox_bind_group.as_ref() {
+                        pass.set_bind_group(3, skybox_bind_group, &[]);
+                    }
+                    // 64 directions per workgroup, 6 for every probe
+                    pass.dispatch_workgroups(
+                        (probe_volume.pending_probes() * 6).div_ceil(64),
+                        1,
+                        1,
+                    );
+                }
+            }
+        }
+
+        for camera_offset in camera_offsets {
+            if let [(_, size)] = tiles[..] {
+                trace(
+                    render_context.command_encoder(),
+                    camera_offset,
+                    tile_offsets[0],
+                    size,
+                );
+            } else {
+                // every tile is submitted on its own so that the driver doesn't see a single long submission,
+                // the uniforms were written before, so the tiles still run after them
+                for ((_, size), tile_offset) in tiles.iter().zip(&tile_offsets) {
+                    let mut command_encoder = render_context
+                        .render_device()
+                        .create_command_encoder(&CommandEncoderDescriptor {
+                            label: Some("voxel_raytracing_tile"),
+                        });
+                    trace(&mut command_encoder, camera_offset, *tile_offset, *size);
+                    render_queue.submit([command_encoder.finish()]);
+                }
+            }
+        }
+
         let command_encoder = render_context.command_encoder();
 
-        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("voxel_raytracing"),
+        if let Some(auto_focus) = auto_focus {
+            let storage_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+
+            if let Some(focus_buffer) = storage_buffers.get(auto_focus.buffer) {
+                let pixel = (auto_focus.point * viewport.as_vec2())
+                    .as_uvec2()
+                    .min(viewport.saturating_sub(UVec2::ONE));
+
+                command_encoder.copy_texture_to_buffer(
+                    TexelCopyTextureInfo {
+                        texture: &g_buffer.depth.texture,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: pixel.x,
+                            y: pixel.y,
+                            z: 0,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    TexelCopyBufferInfo {
+                        buffer: &focus_buffer.buffer,
+                        layout: TexelCopyBufferLayout {
+                            offset: 0,
+                            bytes_per_row: None,
+                            rows_per_image: None,
+                        },
+                    },
+                    Extent3d {
+                        width: 1,
+                        height: 1,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        if let Some(continuous_readback) = continuous_readback {
+            let storage_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+
+            // the buffer is recreated in the main world after a resize, skip the frames where the sizes don't match
+            if let Some(readback_buffer) = storage_buffers
+                .get(continuous_readback.buffer)
+                .filter(|_| continuous_readback.size == *viewport)
+            {
+                command_encoder.copy_texture_to_buffer(
+                    voxel_view_target.output.texture.as_image_copy(),
+                    TexelCopyBufferInfo {
+                        buffer: &readback_buffer.buffer,
+                        layout: TexelCopyBufferLayout {
+                            offset: 0,
+                            bytes_per_row: Some(padded_bytes_per_row(viewport.x) as u32),
+                            rows_per_image: None,
+                        },
+                    },
+                    viewport.to_extents(),
+                );
+            }
+        }
+
+        if let Some(depth_readback) = depth_readback {
+            let storage_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+
+            if let Some(readback_buffer) = storage_buffers
+                .get(depth_readback.buffer)
+                .filter(|_| depth_readback.size == *viewport)
+            {
+                command_encoder.copy_texture_to_buffer(
+                    g_buffer.depth.texture.as_image_copy(),
+                    TexelCopyBufferInfo {
+                        buffer: &readback_buffer.buffer,
+                        layout: TexelCopyBufferLayout {
+                            offset: 0,
+                            bytes_per_row: Some(padded_depth_bytes_per_row(viewport.x) as u32),
+                            rows_per_image: None,
+                        },
+                    },
+                    viewport.to_extents(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The pipeline used by [NEVRNodeMode::Fragment].
+#[derive(Resource)]
+pub struct NEVRFragmentPipeline {
+    bind_group_layout: BindGroupLayout,
+    fullscreen_shader: FullscreenShader,
+    fragment_shader: Handle<Shader>,
+}
+
+impl FromWorld for NEVRFragmentPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let bind_group_layout = render_device.create_bind_group_layout(
+            "voxel_fragment_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    // TLAS
+                    acceleration_structure(),
+                    // View
+                    uniform_buffer::<ViewUniform>(true),
+                    // Composite
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                ),
+            ),
+        );
+
+        Self {
+            bind_group_layout,
+            fullscreen_shader: world.resource::<FullscreenShader>().clone(),
+            fragment_shader: load_embedded_asset!(world, "shaders/fragment.wgsl"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct NEVRFragmentPipelineKey {
+    format: TextureFormat,
+    samples: u32,
+    depth: bool,
+}
+
+impl SpecializedRenderPipeline for NEVRFragmentPipeline {
+    type Key = NEVRFragmentPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = Vec::new();
+        if key.depth {
+            shader_defs.push("DEPTH".into());
+        }
+
+        RenderPipelineDescriptor {
+            label: Some("voxel_fragment_pipeline".into()),
+            layout: vec![self.bind_group_layout.clone()],
+            vertex: self.fullscreen_shader.to_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.fragment_shader.clone(),
+                shader_defs,
+                targets: vec![Some(ColorTargetState {
+                    format: key.format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+                ..Default::default()
+            }),
+            depth_stencil: key.depth.then_some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Always,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: key.samples,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// The fragment pipeline used by a view when using [NEVRNodeMode::Fragment].
+#[derive(Component)]
+pub struct ViewNEVRFragmentPipeline(CachedRenderPipelineId);
+
+fn prepare_fragment_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<NEVRFragmentPipeline>>,
+    fragment_pipeline: Res<NEVRFragmentPipeline>,
+    node_mode: Res<NEVRNodeMode>,
+    views: Query<(Entity, &ViewTarget, &Msaa, Has<ViewDepthTexture>), With<RayCamera>>,
+) {
+    if *node_mode != NEVRNodeMode::Fragment {
+        return;
+    }
+
+    for (entity, view_target, msaa, depth) in &views {
+        let key = NEVRFragmentPipelineKey {
+            format: view_target.main_texture_format(),
+            samples: msaa.samples(),
+            depth,
+        };
+        let pipeline = pipelines.specialize(&pipeline_cache, &fragment_pipeline, key);
+
+        commands
+            .entity(entity)
+            .insert(ViewNEVRFragmentPipeline(pipeline));
+    }
+}
+
+/// Draws the ray traced image into the view target when using [NEVRNodeMode::Fragment].
+#[derive(Default)]
+pub struct NEVRFragmentNode;
+
+impl ViewNode for NEVRFragmentNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewUniformOffset,
+        &'static VoxelViewTarget,
+        &'static ViewNEVRFragmentPipeline,
+        Option<&'static ViewDepthTexture>,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (
+            extracted_camera,
+            view_target,
+            view_uniform_offset,
+            voxel_view_target,
+            view_pipeline,
+            depth,
+        ): QueryItem<'w, '_, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        if *world.resource::<NEVRNodeMode>() != NEVRNodeMode::Fragment {
+            return Ok(());
+        }
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let voxel_bindings = world.resource::<VoxelBindings>();
+        let fragment_pipeline = world.resource::<NEVRFragmentPipeline>();
+        let view_uniforms = world.resource::<ViewUniforms>();
+        let status = world.resource::<NEVRStatus>();
+
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(view_pipeline.0) else {
+            return Ok(());
+        };
+        let Some(tlas) = &voxel_bindings.tlas else {
+            status.report(NEVRWarning::MissingBindGroup);
+            return Ok(());
+        };
+        let Some(view_uniforms) = view_uniforms.uniforms.binding() else {
+            status.report(NEVRWarning::MissingViewUniforms);
+            return Ok(());
+        };
+        let Some(composite) = &voxel_view_target.composite else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "voxel_fragment_bind_group",
+            &fragment_pipeline.bind_group_layout,
+            &BindGroupEntries::sequential((
+                tlas.as_binding(),
+                view_uniforms,
+                &composite.default_view,
+            )),
+        );
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("voxel_fragment"),
+            color_attachments: &[Some(view_target.get_color_attachment())],
+            depth_stencil_attachment: depth.map(|depth| depth.get_attachment(StoreOp::Store)),
             timestamp_writes: None,
+            occlusion_query_set: None,
         });
 
-        pass.set_pipeline(pipeline);
-        pass.set_bind_group(0, bind_group, &[]);
-        pass.set_bind_group(1, &camera_bind_group, &[view_uniform_offset.offset]);
-        pass.set_bind_group(2, &g_buffer_bind_group, &[]);
-        if let Some(skybox_bind_group) = optional_skybox_bind_group.as_ref() {
-            pass.set_bind_group(3, skybox_bind_group, &[]);
+        if let Some(viewport) = &extracted_camera.viewport {
+            pass.set_camera_viewport(viewport);
         }
-        pass.dispatch_workgroups(viewport.x.div_ceil(8), viewport.y.div_ceil(8), 1);
+        pass.set_render_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[view_uniform_offset.offset]);
+        pass.draw(0..3, 0..1);
 
         Ok(())
     }
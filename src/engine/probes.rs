@@ -0,0 +1,236 @@
+//! This module contains the irradiance probes baked for static scenes.
+
+use crate::ToBytes;
+use crate::engine::stats::NEVRStats;
+use bevy::camera::primitives::Aabb;
+use bevy::ecs::change_detection::DetectChanges;
+use bevy::prelude::{Commands, FromWorld, Res, ResMut, Resource, UVec3, Vec3, World};
+use bevy::render::Extract;
+use bevy::render::render_resource::encase::internal::{
+    AlignmentValue, BufferMut, WriteInto, Writer,
+};
+use bevy::render::render_resource::encase::private::{Metadata, SizeValue};
+use bevy::render::render_resource::{
+    Buffer, BufferDescriptor, BufferUsages, ShaderType, UniformBuffer,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Bakes a grid of irradiance probes, so that [NEVRShadingMode::BakedProbes] can take the indirect light of the
+/// last diffuse bounce from them instead of tracing the rest of the path.
+///
+/// Every probe stores the light coming from the 6 directions of the axes (an ambient cube), path traced with
+/// the same bounces as the [crate::engine::camera::VoxelCamera]. The surfaces blend the 8 probes around them,
+/// so with a dense enough grid a single diffuse bounce looks close to many of them at a fraction of the cost:
+/// ```rs
+/// let bounds = nevr::scene_bounds(world).unwrap();
+/// world.insert_resource(NEVRBakeProbes::new(UVec3::new(16, 8, 16), bounds));
+/// world.insert_resource(NEVRShadingMode::BakedProbes);
+/// ```
+///
+/// The probes are baked over several frames ([NEVRBakeProbes::SAMPLES_PER_FRAME] samples at a time, so a bake
+/// doesn't stall the GPU) and they're used while they're being baked, [NEVRStats::probe_samples] tells how
+/// many samples were baked so far. They're freed when the resource is removed and baked again from scratch
+/// when it's changed.
+///
+/// **Note:** the probes are a trade-off for static scenes:
+/// - They aren't updated when the blocks, the materials or the light change, change the resource to bake them
+///   again.
+/// - The light between two probes is interpolated, details smaller than a cell of the grid are lost and the
+///   probes inside solid blocks (or behind thin walls) darken or leak the light around them.
+///
+/// [NEVRShadingMode::BakedProbes]: crate::engine::settings::NEVRShadingMode::BakedProbes
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct NEVRBakeProbes {
+    /// How many probes are placed on every axis, at the centers of the cells of the grid.
+    pub grid: UVec3,
+    /// The box the probes are spread in, usually [crate::scene_bounds]. The surfaces outside of it use the
+    /// closest probes.
+    pub bounds: Aabb,
+    /// How many paths are traced for every direction of every probe. Defaults to 256.
+    pub samples: u32,
+}
+
+impl NEVRBakeProbes {
+    /// The samples baked in a frame.
+    pub const SAMPLES_PER_FRAME: u32 = 16;
+
+    pub fn new(grid: UVec3, bounds: Aabb) -> Self {
+        Self {
+            grid,
+            bounds,
+            samples: 256,
+        }
+    }
+
+    pub fn with_samples(mut self, samples: u32) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// The number of probes in the grid.
+    pub fn probe_count(&self) -> u32 {
+        self.grid
+            .x
+            .saturating_mul(self.grid.y)
+            .saturating_mul(self.grid.z)
+    }
+}
+
+/// The grid of the probes as seen by the shader.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderProbeGrid {
+    /// The probes on every axis, w is the samples baked so far (0 when there are no probes).
+    pub size: [u32; 4],
+    /// The corner of the bounds with the lowest coordinates, w is unused.
+    pub min: [f32; 4],
+    /// The corner of the bounds with the highest coordinates, w is unused.
+    pub max: [f32; 4],
+    /// The samples baked before this frame and the samples baked in this frame, z and w are unused.
+    pub bake: [u32; 4],
+}
+
+impl ShaderType for RenderProbeGrid {
+    type ExtraMetadata = ();
+    const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
+        alignment: AlignmentValue::new(16),
+        has_uniform_min_alignment: false,
+        min_size: SizeValue::new(64),
+        is_pod: false,
+        extra: (),
+    };
+}
+
+impl WriteInto for RenderProbeGrid {
+    fn write_into<B>(&self, writer: &mut Writer<B>)
+    where
+        B: BufferMut,
+    {
+        writer.write_slice(self.size.to_bytes());
+        writer.write_slice(self.min.to_bytes());
+        writer.write_slice(self.max.to_bytes());
+        writer.write_slice(self.bake.to_bytes());
+    }
+}
+
+// the colors of the 6 directions of a probe, a vec4 each
+const PROBE_SIZE: u64 = 6 * 16;
+
+/// The probes of [NEVRBakeProbes] on the GPU, bound with the scene. It lives in the render world.
+#[derive(Resource)]
+pub struct ProbeVolume {
+    /// The ambient cubes of the probes, a single unused probe when there is nothing baked.
+    pub buffer: Buffer,
+    pub grid: UniformBuffer<RenderProbeGrid>,
+    bake: Option<NEVRBakeProbes>,
+    // the probes in the buffer, 0 when the grid is empty or too big
+    probe_count: u32,
+    // counted by the node once it dispatched a pass, so a frame that isn't traced doesn't count
+    baked_samples: AtomicU32,
+}
+
+impl FromWorld for ProbeVolume {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            buffer: create_probe_buffer(render_device, 1),
+            grid: UniformBuffer::default(),
+            bake: None,
+            probe_count: 0,
+            baked_samples: AtomicU32::new(0),
+        }
+    }
+}
+
+impl ProbeVolume {
+    /// The probes baked in this frame, 0 when there is nothing to bake.
+    pub fn pending_probes(&self) -> u32 {
+        if self.grid.get().bake[1] == 0 {
+            return 0;
+        }
+        self.probe_count
+    }
+
+    /// Claims the bake of this frame, only the first view that claims it bakes it.
+    pub fn claim_bake(&self) -> bool {
+        let [baked, samples, ..] = self.grid.get().bake;
+        samples > 0
+            && self
+                .baked_samples
+                .compare_exchange(baked, baked + samples, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    }
+}
+
+/// Copies [NEVRBakeProbes] to the render world, and removes it from there when it's removed so the probes are
+/// freed.
+pub fn extract_bake_probes(mut commands: Commands, bake: Extract<Option<Res<NEVRBakeProbes>>>) {
+    match bake.as_ref() {
+        Some(bake) if bake.is_changed() => commands.insert_resource(**bake),
+        Some(_) => {}
+        None => commands.remove_resource::<NEVRBakeProbes>(),
+    }
+}
+
+fn create_probe_buffer(render_device: &RenderDevice, probe_count: u32) -> Buffer {
+    render_device.create_buffer(&BufferDescriptor {
+        label: Some("voxel_probes"),
+        size: probe_count.max(1) as u64 * PROBE_SIZE,
+        usage: BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+/// Creates the probes when [NEVRBakeProbes] changes and writes the grid of the pass of this frame.
+pub fn prepare_probes(
+    bake: Option<Res<NEVRBakeProbes>>,
+    mut volume: ResMut<ProbeVolume>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut stats: ResMut<NEVRStats>,
+) {
+    let bake = bake.map(|bake| *bake);
+    if volume.bake != bake {
+        let limits = render_device.limits();
+        let mut probe_count = bake.map_or(0, |bake| bake.probe_count());
+        // a workgroup bakes 64 directions, which are 6 for every probe
+        let too_big = probe_count as u64 * PROBE_SIZE
+            > limits.max_storage_buffer_binding_size as u64
+            || (probe_count as u64 * 6).div_ceil(64)
+                > limits.max_compute_workgroups_per_dimension as u64;
+        if too_big {
+            eprintln!(
+                "NEVRBakeProbes has {probe_count} probes, which is more than the GPU can hold, use a smaller grid"
+            );
+            probe_count = 0;
+        }
+
+        volume.buffer = create_probe_buffer(&render_device, probe_count);
+        volume.bake = bake;
+        volume.probe_count = probe_count;
+        *volume.baked_samples.get_mut() = 0;
+    }
+
+    let baked = *volume.baked_samples.get_mut();
+    let grid = match volume.bake {
+        Some(bake) if volume.probe_count > 0 => RenderProbeGrid {
+            size: [bake.grid.x, bake.grid.y, bake.grid.z, baked],
+            min: Vec3::from(bake.bounds.min()).extend(0.0).to_array(),
+            max: Vec3::from(bake.bounds.max()).extend(0.0).to_array(),
+            bake: [
+                baked,
+                bake.samples
+                    .saturating_sub(baked)
+                    .min(NEVRBakeProbes::SAMPLES_PER_FRAME),
+                0,
+                0,
+            ],
+        },
+        _ => RenderProbeGrid::default(),
+    };
+    stats.probe_samples = grid.size[3];
+
+    volume.grid.set(grid);
+    volume.grid.write_buffer(&render_device, &render_queue);
+}
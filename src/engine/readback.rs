@@ -0,0 +1,371 @@
+//! This module contains the continuous readback used to copy the rendered HDR image to the CPU every frame,
+//! and the depth readback used to copy the depth of a frame to the CPU on request.
+
+use crate::engine::camera::VoxelCamera;
+use bevy::asset::RenderAssetUsages;
+use bevy::ecs::observer::On;
+use bevy::ecs::query::QueryItem;
+use bevy::image::Image;
+use bevy::prelude::{
+    AssetId, Assets, Camera, ChildOf, Commands, Component, Entity, Handle, Query, ResMut, UVec2,
+    With,
+};
+use bevy::render::extract_component::ExtractComponent;
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_resource::{BufferUsages, Extent3d, TextureDimension, TextureFormat};
+use bevy::render::renderer::RenderDevice;
+use bevy::render::storage::ShaderStorageBuffer;
+
+/// Size in bytes of a pixel of the HDR output (`Rgba16Float`).
+const PIXEL_SIZE: usize = 8;
+/// Size in bytes of a pixel of the depth (`R32Float`).
+const DEPTH_PIXEL_SIZE: usize = 4;
+
+/// Copies the HDR output of a [VoxelCamera] (before tonemapping) into an [Image] every frame.
+///
+/// Add it to the entity of a [VoxelCamera]:
+/// ```rs
+/// let image = images.add(Image::default());
+/// commands.spawn((VoxelCamera::default(), NEVRContinuousReadback::new(image.clone())));
+/// ```
+///
+/// The image is resized to the physical viewport of the camera and uses the `Rgba16Float` format.
+///
+/// The readback is asynchronous so the GPU is never stalled: the image is updated 2 to 3 frames after the
+/// frame it contains was rendered, and it keeps the last completed frame until a new one arrives.
+///
+/// Every camera with a readback uses a GPU buffer with the size of its viewport (with rows padded to 256 bytes),
+/// plus one staging buffer of the same size for every frame in flight and the CPU copy in the image: a 1080p
+/// viewport needs around 16 MB for each of them.
+#[derive(Component, Clone, Debug)]
+pub struct NEVRContinuousReadback {
+    /// The image updated with the rendered frames.
+    pub image: Handle<Image>,
+    buffer: Option<Handle<ShaderStorageBuffer>>,
+    size: UVec2,
+}
+
+impl NEVRContinuousReadback {
+    pub fn new(image: Handle<Image>) -> Self {
+        Self {
+            image,
+            buffer: None,
+            size: UVec2::ZERO,
+        }
+    }
+}
+
+/// Used in the rendering phase to copy the output of a view.
+#[derive(Component, Clone, Debug)]
+pub struct RenderContinuousReadback {
+    pub buffer: AssetId<ShaderStorageBuffer>,
+    pub size: UVec2,
+}
+
+impl ExtractComponent for NEVRContinuousReadback {
+    type QueryData = &'static NEVRContinuousReadback;
+    type QueryFilter = ();
+    type Out = RenderContinuousReadback;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        Some(RenderContinuousReadback {
+            buffer: item.buffer.as_ref()?.id(),
+            size: item.size,
+        })
+    }
+}
+
+/// Entity reading back the output of a camera.
+#[derive(Component)]
+pub struct ContinuousReadback {
+    camera: Entity,
+    size: UVec2,
+}
+
+/// Creates the buffers used to read back the output of the cameras, recreating them when the viewport is resized.
+pub fn prepare_continuous_readback(
+    mut cameras: Query<(Entity, &Camera, &mut NEVRContinuousReadback), With<VoxelCamera>>,
+    readbacks: Query<(Entity, &ContinuousReadback)>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    for (entity, readback) in &readbacks {
+        let outdated = cameras
+            .get(readback.camera)
+            .is_ok_and(|(_, _, continuous)| continuous.size != readback.size);
+
+        if outdated || !cameras.contains(readback.camera) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (camera, camera_component, mut continuous) in &mut cameras {
+        let Some(size) = camera_component.physical_viewport_size() else {
+            continue;
+        };
+        if continuous.buffer.is_some() && continuous.size == size {
+            continue;
+        }
+
+        if let Some(old_buffer) = continuous.buffer.take() {
+            buffers.remove(&old_buffer);
+        }
+
+        let mut buffer = ShaderStorageBuffer::with_size(
+            padded_bytes_per_row(size.x) * size.y as usize,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        buffer.buffer_description.usage |= BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let buffer = buffers.add(buffer);
+
+        commands
+            .spawn((
+                Readback::buffer(buffer.clone()),
+                ContinuousReadback { camera, size },
+                ChildOf(camera),
+            ))
+            .observe(read_continuous_readback);
+
+        continuous.buffer = Some(buffer);
+        continuous.size = size;
+    }
+}
+
+fn read_continuous_readback(
+    event: On<ReadbackComplete>,
+    readbacks: Query<&ContinuousReadback>,
+    cameras: Query<&NEVRContinuousReadback>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    let Ok(continuous) = cameras.get(readback.camera) else {
+        return;
+    };
+    let Some(image) = images.get_mut(&continuous.image) else {
+        return;
+    };
+
+    let size = readback.size;
+    let row_size = size.x as usize * PIXEL_SIZE;
+    let padded_row_size = padded_bytes_per_row(size.x);
+    if event.data.len() < padded_row_size * size.y as usize {
+        return;
+    }
+
+    let extent = Extent3d {
+        width: size.x,
+        height: size.y,
+        depth_or_array_layers: 1,
+    };
+    if image.texture_descriptor.format != TextureFormat::Rgba16Float
+        || image.texture_descriptor.size != extent
+    {
+        *image = Image::new_fill(
+            extent,
+            TextureDimension::D2,
+            &[0; PIXEL_SIZE],
+            TextureFormat::Rgba16Float,
+            RenderAssetUsages::default(),
+        );
+    }
+
+    let data = image.data.get_or_insert_default();
+    data.clear();
+    for row in event.data.chunks_exact(padded_row_size) {
+        data.extend_from_slice(&row[..row_size]);
+    }
+}
+
+/// Size in bytes of a row of the readback buffer, rounded up to the alignment required by copies.
+pub fn padded_bytes_per_row(width: u32) -> usize {
+    RenderDevice::align_copy_bytes_per_row(width as usize * PIXEL_SIZE)
+}
+
+/// Size in bytes of a row of the depth readback buffer, rounded up to the alignment required by copies.
+pub fn padded_depth_bytes_per_row(width: u32) -> usize {
+    RenderDevice::align_copy_bytes_per_row(width as usize * DEPTH_PIXEL_SIZE)
+}
+
+/// Copies the depth of a frame of a [VoxelCamera] into an [Image] on request.
+///
+/// Add it to the entity of a [VoxelCamera] to read the depth of the next frame, and call
+/// [NEVRDepthReadback::request] to read it again later:
+/// ```rs
+/// let image = images.add(Image::default());
+/// commands.spawn((VoxelCamera::default(), NEVRDepthReadback::new(image.clone())));
+/// ```
+///
+/// The image is resized to the physical viewport of the camera and uses the `R32Float` format: every pixel is the
+/// linear view-space depth of the primary hit (the distance from the camera plane, not from the camera),
+/// 0.0 where nothing was hit. It's the same data as [crate::VoxelGBuffer::depth].
+///
+/// The readback is asynchronous so the GPU is never stalled: the image is updated 2 to 3 frames after the
+/// request, check [NEVRDepthReadback::is_pending]. The GPU buffers are freed once the depth arrives.
+#[derive(Component, Clone, Debug)]
+pub struct NEVRDepthReadback {
+    /// The image updated with the depth.
+    pub image: Handle<Image>,
+    requested: bool,
+    buffer: Option<Handle<ShaderStorageBuffer>>,
+    size: UVec2,
+}
+
+impl NEVRDepthReadback {
+    /// Creates a readback which reads the depth of the next frame.
+    pub fn new(image: Handle<Image>) -> Self {
+        Self {
+            image,
+            requested: true,
+            buffer: None,
+            size: UVec2::ZERO,
+        }
+    }
+
+    /// Reads the depth of the next frame again, does nothing if a readback is already pending.
+    pub fn request(&mut self) {
+        self.requested = true;
+    }
+
+    /// Whether the depth was requested and hasn't arrived in the image yet.
+    pub fn is_pending(&self) -> bool {
+        self.requested
+    }
+}
+
+/// Used in the rendering phase to copy the depth of a view.
+#[derive(Component, Clone, Debug)]
+pub struct RenderDepthReadback {
+    pub buffer: AssetId<ShaderStorageBuffer>,
+    pub size: UVec2,
+}
+
+impl ExtractComponent for NEVRDepthReadback {
+    type QueryData = &'static NEVRDepthReadback;
+    type QueryFilter = ();
+    type Out = RenderDepthReadback;
+
+    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self::Out> {
+        Some(RenderDepthReadback {
+            buffer: item.buffer.as_ref()?.id(),
+            size: item.size,
+        })
+    }
+}
+
+/// Entity reading back the depth of a camera.
+#[derive(Component)]
+pub struct DepthReadback {
+    camera: Entity,
+    size: UVec2,
+}
+
+/// Creates the buffers used to read back the depth of the cameras with a pending request, recreating them when
+/// the viewport is resized.
+pub fn prepare_depth_readback(
+    mut cameras: Query<(Entity, &Camera, &mut NEVRDepthReadback), With<VoxelCamera>>,
+    readbacks: Query<(Entity, &DepthReadback)>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    for (entity, readback) in &readbacks {
+        let outdated = cameras
+            .get(readback.camera)
+            .is_ok_and(|(_, _, depth)| depth.size != readback.size);
+
+        if outdated || !cameras.contains(readback.camera) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (camera, camera_component, mut depth) in &mut cameras {
+        if !depth.requested {
+            continue;
+        }
+        let Some(size) = camera_component.physical_viewport_size() else {
+            continue;
+        };
+        if depth.buffer.is_some() && depth.size == size {
+            continue;
+        }
+
+        if let Some(old_buffer) = depth.buffer.take() {
+            buffers.remove(&old_buffer);
+        }
+
+        let mut buffer = ShaderStorageBuffer::with_size(
+            padded_depth_bytes_per_row(size.x) * size.y as usize,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        buffer.buffer_description.usage |= BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let buffer = buffers.add(buffer);
+
+        commands
+            .spawn((
+                Readback::buffer(buffer.clone()),
+                DepthReadback { camera, size },
+                ChildOf(camera),
+            ))
+            .observe(read_depth_readback);
+
+        depth.buffer = Some(buffer);
+        depth.size = size;
+    }
+}
+
+fn read_depth_readback(
+    event: On<ReadbackComplete>,
+    readbacks: Query<&DepthReadback>,
+    mut cameras: Query<&mut NEVRDepthReadback>,
+    mut images: ResMut<Assets<Image>>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    mut commands: Commands,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    let Ok(mut depth) = cameras.get_mut(readback.camera) else {
+        return;
+    };
+    let Some(image) = images.get_mut(&depth.image) else {
+        return;
+    };
+
+    let size = readback.size;
+    let row_size = size.x as usize * DEPTH_PIXEL_SIZE;
+    let padded_row_size = padded_depth_bytes_per_row(size.x);
+    if event.data.len() < padded_row_size * size.y as usize {
+        return;
+    }
+
+    let extent = Extent3d {
+        width: size.x,
+        height: size.y,
+        depth_or_array_layers: 1,
+    };
+    if image.texture_descriptor.format != TextureFormat::R32Float
+        || image.texture_descriptor.size != extent
+    {
+        *image = Image::new_fill(
+            extent,
+            TextureDimension::D2,
+            &[0; DEPTH_PIXEL_SIZE],
+            TextureFormat::R32Float,
+            RenderAssetUsages::default(),
+        );
+    }
+
+    let data = image.data.get_or_insert_default();
+    data.clear();
+    for row in event.data.chunks_exact(padded_row_size) {
+        data.extend_from_slice(&row[..row_size]);
+    }
+
+    // a single frame was requested, the buffers aren't needed anymore
+    commands.entity(event.entity).despawn();
+    if let Some(buffer) = depth.buffer.take() {
+        buffers.remove(&buffer);
+    }
+    depth.requested = false;
+}
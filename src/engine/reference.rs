@@ -0,0 +1,152 @@
+//! This module contains the reference render mode, used to render noise-free images to compare against.
+
+use crate::engine::camera::{VoxelAccumulation, VoxelCamera};
+use crate::engine::denoiser::VoxelDenoiser;
+use crate::engine::settings::NEVRPaused;
+use bevy::ecs::change_detection::DetectChanges;
+use bevy::platform::collections::HashSet;
+use bevy::prelude::{Commands, Entity, Event, Local, Query, Res, ResMut, Resource};
+use bevy::winit::WinitSettings;
+
+/// Renders reference (ground-truth) images by accumulating many samples per pixel without a denoiser, to
+/// compare the denoisers against or to generate golden images in scripts.
+///
+/// While this resource exists:
+/// - The [VoxelDenoiser] is set to [VoxelDenoiser::None], the previous denoiser is restored when the resource
+///   is removed.
+/// - The app is updated continuously even when the window is idle or unfocused ([WinitSettings::game]), the
+///   previous settings are restored when the resource is removed.
+/// - The temporal accumulation of every [VoxelCamera] is enabled (it stays enabled after the resource is
+///   removed).
+///
+/// Once a camera accumulated `target_samples` samples per pixel a [ReferenceRenderComplete] is triggered for
+/// it, and once every camera is done rendering is paused through [NEVRPaused] so the image stays the same
+/// until the resource is removed or changed, which restarts the render:
+/// ```rs
+/// commands.insert_resource(NEVRReferenceRender::new(4096));
+/// commands.spawn((VoxelCamera::default(), NEVRContinuousReadback::new(image.clone())));
+///
+/// app.add_observer(|complete: On<ReferenceRenderComplete>| println!("{} is done", complete.camera));
+/// ```
+///
+/// **Note:** the [crate::engine::readback::NEVRContinuousReadback] lags 2 to 3 frames behind the rendering,
+/// since rendering is paused at the end the image it holds a few frames after the event is the reference.
+/// Scenes with [VoxelMaterialModel::Flipbook](crate::engine::voxel::VoxelMaterialModel::Flipbook) materials or
+/// scrolling [VoxelSkyboxLayer](crate::engine::skybox::VoxelSkyboxLayer)s restart the accumulation every frame
+/// and never complete.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NEVRReferenceRender {
+    /// How many samples per pixel every camera accumulates, the samples of a frame are
+    /// [VoxelCamera::samples] (times the [NEVRAccumulationSubsteps]) so the frames needed are rounded up.
+    ///
+    /// [NEVRAccumulationSubsteps]: crate::engine::settings::NEVRAccumulationSubsteps
+    pub target_samples: u32,
+}
+
+impl NEVRReferenceRender {
+    pub fn new(target_samples: u32) -> Self {
+        Self { target_samples }
+    }
+}
+
+/// Triggered once a [VoxelCamera] accumulated the samples of [NEVRReferenceRender].
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ReferenceRenderComplete {
+    /// The entity of the camera.
+    pub camera: Entity,
+    /// How many samples per pixel were accumulated.
+    pub samples: u32,
+}
+
+/// What the reference render changed, to restore it when the render ends.
+#[derive(Default)]
+pub struct ReferenceRenderState {
+    previous_denoiser: Option<VoxelDenoiser>,
+    previous_winit_settings: Option<WinitSettings>,
+    completed: HashSet<Entity>,
+    paused: bool,
+}
+
+/// Drives the [NEVRReferenceRender], it runs after the accumulation of the cameras is updated.
+pub fn update_reference_render(
+    mut commands: Commands,
+    reference: Option<Res<NEVRReferenceRender>>,
+    mut denoiser: ResMut<VoxelDenoiser>,
+    mut paused: ResMut<NEVRPaused>,
+    winit_settings: Option<ResMut<WinitSettings>>,
+    mut cameras: Query<(Entity, &mut VoxelCamera, &VoxelAccumulation)>,
+    mut state: Local<ReferenceRenderState>,
+) {
+    let Some(reference) = reference else {
+        if let Some(previous_denoiser) = state.previous_denoiser.take() {
+            *denoiser = previous_denoiser;
+        }
+        if let (Some(previous), Some(mut winit_settings)) =
+            (state.previous_winit_settings.take(), winit_settings)
+        {
+            *winit_settings = previous;
+        }
+        if state.paused {
+            paused.0 = false;
+        }
+        state.completed.clear();
+        state.paused = false;
+        return;
+    };
+
+    // a new target restarts the render
+    if reference.is_changed() {
+        state.completed.clear();
+        if state.paused {
+            paused.0 = false;
+            state.paused = false;
+        }
+    }
+
+    if state.previous_denoiser.is_none() {
+        state.previous_denoiser = Some(*denoiser);
+    }
+    if *denoiser != VoxelDenoiser::None {
+        *denoiser = VoxelDenoiser::None;
+    }
+    if let Some(mut winit_settings) = winit_settings {
+        if state.previous_winit_settings.is_none() {
+            state.previous_winit_settings = Some(winit_settings.clone());
+            *winit_settings = WinitSettings::game();
+        }
+    }
+
+    if state.paused {
+        return;
+    }
+
+    let mut all_completed = true;
+    for (entity, mut camera, accumulation) in &mut cameras {
+        // enabling the accumulation restarts it, the camera is counted from the next frame
+        if !camera.temporal_accumulation {
+            camera.temporal_accumulation = true;
+            all_completed = false;
+            continue;
+        }
+        if state.completed.contains(&entity) {
+            continue;
+        }
+
+        // the frames accumulated before this one, which are already rendered
+        let samples = accumulation.frames().saturating_mul(camera.samples);
+        if samples >= reference.target_samples {
+            state.completed.insert(entity);
+            commands.trigger(ReferenceRenderComplete {
+                camera: entity,
+                samples,
+            });
+        } else {
+            all_completed = false;
+        }
+    }
+
+    if all_completed && !state.completed.is_empty() {
+        paused.0 = true;
+        state.paused = true;
+    }
+}
@@ -0,0 +1,189 @@
+//! This module contains the global settings of the renderer.
+
+use bevy::prelude::Resource;
+use bevy::render::extract_resource::ExtractResource;
+use bevy::render::render_resource::AccelerationStructureFlags;
+
+/// Seed mixed into the random numbers used for rendering.
+///
+/// The renderer is deterministic: the same seed, frame count and scene always produce the same image,
+/// which is useful for regression tests and offline renders. Change the seed to get a different (but still
+/// reproducible) noise pattern. Defaults to 0.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NEVRSeed(pub u32);
+
+/// How many times every [VoxelCamera] traces its samples and adds them to its accumulation in a single frame,
+/// to converge faster on static scenes. Defaults to 1, 0 is the same as 1.
+///
+/// Every substep is a dispatch of its own (split in tiles like the others, check
+/// [NEVRTuning::max_workgroups_per_dispatch]) that traces [VoxelCamera::samples] samples per pixel, so with
+/// 4 substeps the image is as converged after 10 frames as it would be after 40:
+/// ```rs
+/// // an offline render of a static scene, the framerate doesn't matter
+/// commands.insert_resource(NEVRAccumulationSubsteps(8));
+/// ```
+///
+/// **Note:** a frame takes about as many times longer to trace as the substeps, which lowers the framerate
+/// and delays the response to the input: keep it at 1 for interactive views. Only the cameras with the
+/// temporal accumulation enabled trace the substeps (the others would overwrite the same image), the
+/// [VoxelAccumulation] of the camera counts every substep as an accumulated frame and restarts when this
+/// changes.
+///
+/// [VoxelCamera]: crate::engine::camera::VoxelCamera
+/// [VoxelCamera::samples]: crate::engine::camera::VoxelCamera::samples
+/// [VoxelAccumulation]: crate::engine::camera::VoxelAccumulation
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NEVRAccumulationSubsteps(pub u32);
+
+impl Default for NEVRAccumulationSubsteps {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+/// Tuning parameters of the renderer.
+///
+/// The defaults are a good fit for most scenes, change them only to fix specific artifacts.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq)]
+pub struct NEVRTuning {
+    /// How much the shadow terminator (the dark bands near the edge between lit and unlit surfaces at grazing
+    /// light angles) is softened, from 0.0 (disabled) to 1.0. Defaults to 1.0.
+    pub terminator_softness: f32,
+    /// The maximum number of workgroups (8x8 pixels each) traced by a single dispatch, 0 traces the whole
+    /// view at once. Defaults to 0.
+    ///
+    /// With big resolutions and many samples a single dispatch may take longer than the GPU watchdog of the
+    /// operating system allows (TDR), which resets the driver. When set, the view is traced in tiles submitted
+    /// one after the other, e.g. 2048 traces about a 512x256 pixels region at a time.
+    /// Every tile adds a submission, so this slightly increases the overhead of a frame.
+    pub max_workgroups_per_dispatch: u32,
+    /// The maximum number of blocks and instances in the scene, 0 uses the limit of the GPU. Defaults to 0.
+    ///
+    /// The TLAS can't hold more instances than the GPU allows: the blocks over the limit aren't rendered and
+    /// [NEVRWarning::TooManyInstances] is reported. The limit of the GPU is always respected, even when this is
+    /// bigger.
+    ///
+    /// [NEVRWarning::TooManyInstances]: crate::engine::status::NEVRWarning::TooManyInstances
+    pub max_instances: u32,
+    /// The maximum number of different [VoxelMaterial]s, 0 uses the limit of the GPU. Defaults to 0.
+    ///
+    /// All the materials live in a single storage buffer, which can't be bigger than the GPU allows.
+    /// [NEVRWarning::MaterialsNearLimit] is reported once 90% of the limit is used, the materials over it aren't
+    /// uploaded (the voxels using them are drawn with a magenta fallback material) and
+    /// [NEVRWarning::TooManyMaterials] is reported. The limit of the GPU is always respected, even when this is
+    /// bigger.
+    ///
+    /// **Note:** a material over the limit is skipped until it's changed again, raising the limit doesn't
+    /// add it back by itself.
+    ///
+    /// [VoxelMaterial]: crate::engine::voxel::VoxelMaterial
+    /// [NEVRWarning::MaterialsNearLimit]: crate::engine::status::NEVRWarning::MaterialsNearLimit
+    /// [NEVRWarning::TooManyMaterials]: crate::engine::status::NEVRWarning::TooManyMaterials
+    pub max_materials: u32,
+    /// How many frames the BLAS of a type is kept after the last visible block using it is hidden or
+    /// despawned. Defaults to 60.
+    ///
+    /// Blocks that are hidden and shown again (or streamed out and back in) within this time reuse the BLAS
+    /// instead of building it again, the BLASes of the types that aren't used anymore are freed afterwards so
+    /// long-running apps don't keep growing the GPU memory. 0 frees them right away.
+    pub blas_eviction_frames: u32,
+}
+
+impl Default for NEVRTuning {
+    fn default() -> Self {
+        Self {
+            terminator_softness: 1.0,
+            max_workgroups_per_dispatch: 0,
+            max_instances: 0,
+            max_materials: 0,
+            blas_eviction_frames: 60,
+        }
+    }
+}
+
+/// How the acceleration structures (the BLASes of the types and the TLAS of the scene) are built.
+///
+/// It lives only in the render world and can't be changed after the app starts, set it with
+/// [crate::NEVRPlugin::accel_config].
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NEVRAccelConfig {
+    /// Whether the acceleration structures favor the speed of the rays over the speed of the builds. Defaults
+    /// to true.
+    ///
+    /// Scenes where most of the blocks are edited or streamed every frame may render faster with false.
+    pub prefer_fast_trace: bool,
+    /// Whether the acceleration structures use less memory at the cost of speed. Defaults to false.
+    pub low_memory: bool,
+}
+
+impl NEVRAccelConfig {
+    /// The build flags of the acceleration structures.
+    pub fn flags(&self) -> AccelerationStructureFlags {
+        let mut flags = if self.prefer_fast_trace {
+            AccelerationStructureFlags::PREFER_FAST_TRACE
+        } else {
+            AccelerationStructureFlags::PREFER_FAST_BUILD
+        };
+        if self.low_memory {
+            flags |= AccelerationStructureFlags::LOW_MEMORY;
+        }
+
+        flags
+    }
+}
+
+impl Default for NEVRAccelConfig {
+    fn default() -> Self {
+        Self {
+            prefer_fast_trace: true,
+            low_memory: false,
+        }
+    }
+}
+
+/// Debug views that replace the rendered image to inspect the renderer.
+///
+/// Defaults to [NEVRDebugView::None].
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NEVRDebugView {
+    /// The rendered image.
+    #[default]
+    None,
+    /// Every pixel shows how many rays it traced, relative to the most it could trace with the samples and
+    /// bounces of the camera: blue pixels are cheap, red pixels hit the bounce limit on every sample.
+    /// Useful to find the materials and areas that blow up the cost of the frame.
+    ///
+    /// The denoiser is skipped while this view is active.
+    Heatmap,
+}
+
+/// How the scene is shaded.
+///
+/// Defaults to [NEVRShadingMode::Full].
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NEVRShadingMode {
+    /// The full path tracer, with the bounces of [VoxelCamera](crate::engine::camera::VoxelCamera).
+    #[default]
+    Full = 0,
+    /// Only the surfaces seen by the camera are shaded: the sun light, darkened by a single short shadow ray
+    /// that finds the occluders close to the surface (about one unit away), plus the ambient light.
+    ///
+    /// There are no reflections, refractions or indirect lighting, and far objects don't cast shadows. Much
+    /// cheaper and almost noise-free, for stylized looks or slower GPUs.
+    ContactOnly = 1,
+    /// The full path tracer, but the last diffuse bounce of every path takes the indirect light from the
+    /// probes baked by [NEVRBakeProbes] instead of tracing on, so fewer diffuse bounces give a similar light.
+    ///
+    /// Without baked probes it's the same as [NEVRShadingMode::Full].
+    ///
+    /// [NEVRBakeProbes]: crate::engine::probes::NEVRBakeProbes
+    BakedProbes = 2,
+}
+
+/// Freezes the rendering on the last frame when true.
+///
+/// While paused the scene isn't traced anymore (the denoiser keeps showing the last image) and changes to the
+/// cameras, lights and blocks are ignored; the temporal accumulation restarts when rendering is resumed.
+/// Defaults to false.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NEVRPaused(pub bool);
@@ -10,7 +10,19 @@ const WORLD_POSITION_WEIGHT: f32 = 0.25;
 @group(0) @binding(0) var<uniform> view: View;
 @group(0) @binding(1) var albedo_texture: texture_storage_2d<rgba16float, read>;
 @group(0) @binding(2) var normal_texture: texture_storage_2d<rgba16float, read>;
+// positions are relative to the camera, only their differences are used so that's the same as world positions
 @group(0) @binding(3) var world_position_texture: texture_storage_2d<rgba16float, read>;
+#ifdef SPLIT
+// the light of the specular paths and emissive surfaces, accumulated like the image, it's taken out of the image by
+// the first pass, the next passes only filter the diffuse light and the combine pass adds it back
+@group(0) @binding(4) var specular_texture: texture_storage_2d<rgba16float, read>;
+#else
+// how much of every pixel comes from specular paths and emissive surfaces, that part is taken out of the noisy image
+// before filtering and added back after, so every pass gets the filtered diffuse light plus the sharp light
+@group(0) @binding(4) var detail_texture: texture_storage_2d<r32float, read>;
+#endif
+@group(0) @binding(5) var noisy_texture: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(6) var<uniform> detail_strength: f32;
 
 @group(1) @binding(0) var<uniform> step_width: u32;
 @group(1) @binding(1) var view_output: texture_storage_2d<rgba16float, write>;
@@ -24,7 +36,7 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
 
     var color_weight = COLOR_WEIGHT;
     let kernel = array(3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
-    let current_color = textureLoad(view_input, global_id.xy).rgb;
+    let current_color = diffuse_color(global_id.xy);
     let current_albedo = textureLoad(albedo_texture, global_id.xy).rgb;
     let current_normal = textureLoad(normal_texture, global_id.xy).rgb;
     let current_world_position = textureLoad(world_position_texture, global_id.xy).rgb;
@@ -40,7 +52,7 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
                 vec2i(view.viewport.zw) - vec2i(1)
             ));
 
-            let color = textureLoad(view_input, uv).rgb;
+            let color = diffuse_color(uv);
             let d_c = current_color - color;
             let dist_color = dot(d_c, d_c);
             let c_w = min(exp(-(dist_color) / color_weight), 1.0);
@@ -68,6 +80,44 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         }
     }
 
-
+#ifdef SPLIT
     textureStore(view_output, global_id.xy, vec4(sum / max(cum_w, 0.0001), 1.0));
+#else
+    textureStore(view_output, global_id.xy, vec4(sum / max(cum_w, 0.0001) + sharp_color(global_id.xy), 1.0));
+#endif
+}
+
+#ifdef SPLIT
+// adds the sharp light back to the filtered diffuse light, after the last pass
+@compute @workgroup_size(8, 8, 1)
+fn combine(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id.xy >= vec2u(view.viewport.zw)) {
+        return;
+    }
+
+    textureStore(view_output, global_id.xy, vec4(textureLoad(view_input, global_id.xy).rgb + sharp_color(global_id.xy), 1.0));
+}
+#endif
+
+// the light filtered by the passes
+fn diffuse_color(uv: vec2<u32>) -> vec3<f32> {
+#ifdef SPLIT
+    // only the first pass reads the image, the next ones read the diffuse light filtered by the previous pass;
+    // the temporal denoiser may leave the image a bit darker than the specular light
+    if (step_width == 1u) {
+        return max(textureLoad(view_input, uv).rgb - sharp_color(uv), vec3(0.0));
+    }
+    return textureLoad(view_input, uv).rgb;
+#else
+    return textureLoad(view_input, uv).rgb - sharp_color(uv);
+#endif
+}
+
+fn sharp_color(uv: vec2<u32>) -> vec3<f32> {
+#ifdef SPLIT
+    return textureLoad(specular_texture, uv).rgb * saturate(detail_strength);
+#else
+    let detail = saturate(textureLoad(detail_texture, uv).r * detail_strength);
+    return textureLoad(noisy_texture, uv).rgb * detail;
+#endif
 }
@@ -0,0 +1,80 @@
+const BINS: u32 = 64u;
+// the darkest and the brightest pixels are ignored, so small lights and deep shadows don't change the exposure
+const LOW_PERCENTILE: f32 = 0.1;
+const HIGH_PERCENTILE: f32 = 0.9;
+
+struct Histogram {
+    bins: array<atomic<u32>, BINS>,
+    average_ev: f32,
+}
+
+@group(0) @binding(0) var view_input: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(1) var<storage, read_write> histogram: Histogram;
+// x: min EV
+// y: max EV
+@group(0) @binding(2) var<uniform> ev_range: vec4<f32>;
+
+var<workgroup> local_bins: array<atomic<u32>, BINS>;
+
+// every invocation clears and then adds the bin with its index, a workgroup has as many invocations as the bins
+@compute @workgroup_size(8, 8, 1)
+fn build_histogram(@builtin(global_invocation_id) global_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    atomicStore(&local_bins[local_index], 0u);
+    workgroupBarrier();
+
+    if all(global_id.xy < textureDimensions(view_input)) {
+        let color = textureLoad(view_input, global_id.xy).rgb;
+        let luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+        atomicAdd(&local_bins[luminance_bin(luminance)], 1u);
+    }
+
+    workgroupBarrier();
+
+    let count = atomicLoad(&local_bins[local_index]);
+    if count > 0u {
+        atomicAdd(&histogram.bins[local_index], count);
+    }
+}
+
+@compute @workgroup_size(1, 1, 1)
+fn average_histogram() {
+    var total = 0u;
+    for (var i = 0u; i < BINS; i++) {
+        total += atomicLoad(&histogram.bins[i]);
+    }
+
+    let low = f32(total) * LOW_PERCENTILE;
+    let high = f32(total) * HIGH_PERCENTILE;
+    var seen = 0.0;
+    var sum = 0.0;
+    var weight = 0.0;
+
+    for (var i = 0u; i < BINS; i++) {
+        let count = f32(atomicLoad(&histogram.bins[i]));
+        // only the part of the bin between the percentiles is counted
+        let counted = max(min(seen + count, high) - max(seen, low), 0.0);
+        sum += counted * bin_ev(i);
+        weight += counted;
+        seen += count;
+
+        atomicStore(&histogram.bins[i], 0u);
+    }
+
+    if weight > 0.0 {
+        histogram.average_ev = sum / weight;
+    }
+}
+
+fn luminance_bin(luminance: f32) -> u32 {
+    // black pixels (and NaNs) go in the darkest bin
+    if !(luminance > 0.0) {
+        return 0u;
+    }
+
+    let t = saturate((log2(luminance) - ev_range.x) / (ev_range.y - ev_range.x));
+    return min(u32(t * f32(BINS)), BINS - 1u);
+}
+
+fn bin_ev(bin: u32) -> f32 {
+    return ev_range.x + (f32(bin) + 0.5) / f32(BINS) * (ev_range.y - ev_range.x);
+}
@@ -0,0 +1,31 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+@group(0) @binding(0) var screen_texture: texture_2d<f32>;
+@group(0) @binding(1) var screen_sampler: sampler;
+@group(0) @binding(2) var lut_texture: texture_3d<f32>;
+@group(0) @binding(3) var lut_sampler: sampler;
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(screen_texture, screen_sampler, in.uv);
+
+    // sample the centers of the first and last texels, so that 0.0 and 1.0 map exactly to the LUT's corners
+    let lut_size = vec3<f32>(textureDimensions(lut_texture));
+    let encoded = linear_to_srgb(saturate(color.rgb));
+    let uvw = encoded * (lut_size - 1.0) / lut_size + 0.5 / lut_size;
+    let graded = textureSampleLevel(lut_texture, lut_sampler, uvw, 0.0).rgb;
+
+    return vec4(srgb_to_linear(graded), color.a);
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
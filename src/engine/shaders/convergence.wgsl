@@ -0,0 +1,60 @@
+// only one pixel every 4x4 is read, the noise is spread over the whole image so it doesn't need them all
+const DOWNSAMPLE: u32 = 4u;
+// the variances are summed in fixed point, since there are no atomic floats
+const FIXED_POINT_SCALE: f32 = 1024.0;
+
+struct Convergence {
+    sum: atomic<u32>,
+    count: atomic<u32>,
+    mean_variance: f32,
+}
+
+// the alpha is the mean squared relative difference between the frames and the accumulated colors
+@group(0) @binding(0) var accumulation: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(1) var<storage, read_write> convergence: Convergence;
+// x: accumulated frames
+@group(0) @binding(2) var<uniform> settings: vec4<f32>;
+
+// x: sum of the variances
+// y: number of pixels
+var<workgroup> local_sums: array<vec2<f32>, 64>;
+
+@compute @workgroup_size(8, 8, 1)
+fn sum_variance(@builtin(global_invocation_id) global_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let position = global_id.xy * DOWNSAMPLE;
+    var local_sum = vec2(0.0);
+    if all(position < textureDimensions(accumulation)) {
+        // the variance of the accumulated color shrinks with the number of frames, the first frame has nothing
+        // to be compared with, so it's as far from converged as it can be
+        var variance = 1.0;
+        if settings.x > 0.0 {
+            variance = textureLoad(accumulation, position).a / settings.x;
+        }
+        local_sum = vec2(select(1.0, min(variance, 1.0), variance >= 0.0), 1.0);
+    }
+    local_sums[local_index] = local_sum;
+    workgroupBarrier();
+
+    for (var stride = 32u; stride > 0u; stride >>= 1u) {
+        if local_index < stride {
+            local_sums[local_index] += local_sums[local_index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if local_index == 0u && local_sums[0].y > 0.0 {
+        atomicAdd(&convergence.sum, u32(local_sums[0].x * FIXED_POINT_SCALE));
+        atomicAdd(&convergence.count, u32(local_sums[0].y));
+    }
+}
+
+@compute @workgroup_size(1, 1, 1)
+fn average_variance() {
+    let count = atomicLoad(&convergence.count);
+    if count > 0u {
+        convergence.mean_variance = f32(atomicLoad(&convergence.sum)) / FIXED_POINT_SCALE / f32(count);
+    }
+
+    atomicStore(&convergence.sum, 0u);
+    atomicStore(&convergence.count, 0u);
+}
@@ -0,0 +1,34 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var<uniform> view: View;
+@group(0) @binding(1) var view_input: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(2) var view_output: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(3) var<uniform> strength: f32;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2u(view.viewport.zw);
+    if any(global_id.xy >= size) {
+        return;
+    }
+
+    let input = textureLoad(view_input, global_id.xy);
+
+    // the colors of the 8 pixels around this one, without it: a firefly is brighter than all of its neighbors
+    var neighborhood_min = vec3(65504.0);
+    var neighborhood_max = vec3(0.0);
+    for (var d_x = -1; d_x <= 1; d_x += 1) {
+        for (var d_y = -1; d_y <= 1; d_y += 1) {
+            let uv = vec2i(global_id.xy) + vec2i(d_x, d_y);
+            if (d_x == 0 && d_y == 0) || any(uv < vec2i(0)) || any(uv >= vec2i(size)) {
+                continue;
+            }
+            let color = textureLoad(view_input, vec2u(uv)).rgb;
+            neighborhood_min = min(neighborhood_min, color);
+            neighborhood_max = max(neighborhood_max, color);
+        }
+    }
+
+    let clamped = clamp(input.rgb, neighborhood_min, max(neighborhood_min, neighborhood_max));
+    textureStore(view_output, global_id.xy, vec4(mix(input.rgb, clamped, strength), input.a));
+}
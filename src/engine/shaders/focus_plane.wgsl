@@ -0,0 +1,33 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var view_output: texture_storage_2d<rgba16float, read_write>;
+// positions are relative to the camera, so their length is the distance along the ray
+@group(0) @binding(1) var world_position_texture: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(2) var depth_texture: texture_storage_2d<r32float, read>;
+@group(0) @binding(3) var<uniform> tint: vec4<f32>;
+// x: focus distance
+// y: half width of the tinted band
+@group(0) @binding(4) var<uniform> focus: vec4<f32>;
+@group(0) @binding(5) var<uniform> view: View;
+// where the viewport starts in the output, zero when the output is as large as the viewport
+@group(0) @binding(6) var<uniform> output_offset: vec2<u32>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id.xy >= vec2u(view.viewport.zw)) {
+        return;
+    }
+
+    // nothing was hit
+    if textureLoad(depth_texture, global_id.xy).r <= 0.0 {
+        return;
+    }
+
+    // the rays are focused at the same distance from the camera, so the in-focus surface is a sphere around it
+    let distance = length(textureLoad(world_position_texture, global_id.xy).xyz);
+    let weight = (1.0 - smoothstep(0.0, focus.y, abs(distance - focus.x))) * tint.a;
+
+    let output_position = global_id.xy + output_offset;
+    let color = textureLoad(view_output, output_position);
+    textureStore(view_output, output_position, vec4(mix(color.rgb, tint.rgb, weight), color.a));
+}
@@ -0,0 +1,48 @@
+#import bevy_render::view::View
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+const RAY_NO_CULL = 0xFFu;
+
+@group(0) @binding(0) var tlas: acceleration_structure;
+@group(0) @binding(1) var<uniform> view: View;
+@group(0) @binding(2) var composite: texture_2d<f32>;
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef DEPTH
+    @builtin(frag_depth) depth: f32,
+#endif
+}
+
+// the color is traced once per pixel by the compute pipeline, while the primary ray is traced again for every
+// sample so that the depth of MSAA targets has the same edges as the ray traced geometry
+@fragment
+fn fragment(in: FullscreenVertexOutput, @builtin(sample_index) sample_index: u32) -> FragmentOutput {
+    let pixel_position = in.position.xy - view.viewport.xy;
+    let pixel = min(vec2<u32>(pixel_position), vec2<u32>(view.viewport.zw) - vec2(1u));
+
+    var out: FragmentOutput;
+    out.color = textureLoad(composite, pixel, 0);
+
+#ifdef DEPTH
+    let d = pixel_position / view.viewport.zw * 2.0 - 1.0;
+    let origin = view.world_position;
+    let camera_target = view.world_from_clip * vec4(d.x, -d.y, 1.0, 1.0);
+    let direction = normalize((camera_target.xyz / camera_target.w) - origin);
+
+    var rq: ray_query;
+    // the types with glass are built as non-opaque geometry, they hide what's behind them like the opaque ones
+    rayQueryInitialize(&rq, tlas, RayDesc(RAY_FLAG_FORCE_OPAQUE, RAY_NO_CULL, 0.001, 10000.0, origin, direction));
+    rayQueryProceed(&rq);
+    let hit = rayQueryGetCommittedIntersection(&rq);
+
+    // reversed-z: 0.0 is the far plane
+    out.depth = 0.0;
+    if hit.kind != RAY_QUERY_INTERSECTION_NONE {
+        let clip_position = view.clip_from_world * vec4(origin + direction * hit.t, 1.0);
+        out.depth = saturate(clip_position.z / clip_position.w);
+    }
+#endif
+
+    return out;
+}
@@ -0,0 +1,56 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var view_output: texture_storage_2d<rgba16float, read_write>;
+@group(0) @binding(1) var depth_texture: texture_storage_2d<r32float, read>;
+// the light added by a pixel that only sees the sky, w is unused
+@group(0) @binding(2) var<uniform> sun_color: vec4<f32>;
+// toward the sun, w is unused
+@group(0) @binding(3) var<uniform> sun_direction: vec4<f32>;
+// x: decay
+// y: samples
+@group(0) @binding(4) var<uniform> settings: vec4<f32>;
+@group(0) @binding(5) var<uniform> view: View;
+// where the viewport starts in the output, zero when the output is as large as the viewport
+@group(0) @binding(6) var<uniform> output_offset: vec2<u32>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2u(view.viewport.zw);
+    if any(global_id.xy >= size) {
+        return;
+    }
+
+    // the sun is infinitely far away, so only the rotation of the view moves it on the screen
+    let sun_clip = view.clip_from_world * vec4(sun_direction.xyz, 0.0);
+    // the sun is behind the camera
+    if sun_clip.w <= 0.0 {
+        return;
+    }
+    let sun_uv = sun_clip.xy / sun_clip.w * vec2(0.5, -0.5) + vec2(0.5);
+
+    let uv = (vec2<f32>(global_id.xy) + vec2(0.5)) / vec2<f32>(size);
+    let samples = u32(settings.y);
+    let step = (sun_uv - uv) / f32(samples);
+
+    var sample_uv = uv;
+    var weight = 1.0;
+    var scattered = 0.0;
+    for (var i = 0u; i < samples; i++) {
+        sample_uv += step;
+        // what is outside of the screen is unknown, so the shafts fade out as the sun leaves it
+        if any(sample_uv < vec2(0.0)) || any(sample_uv >= vec2(1.0)) {
+            break;
+        }
+
+        // only the pixels where nothing was hit let the light of the sun through
+        if textureLoad(depth_texture, vec2u(sample_uv * vec2<f32>(size))).r <= 0.0 {
+            scattered += weight;
+        }
+        weight *= settings.x;
+    }
+
+    let output_position = global_id.xy + output_offset;
+    let color = textureLoad(view_output, output_position);
+    let light = sun_color.rgb * scattered / f32(samples);
+    textureStore(view_output, output_position, vec4(color.rgb + light, color.a));
+}
@@ -0,0 +1,27 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var view_output: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(1) var view_input: texture_storage_2d<rgba16float, read>;
+@group(0) @binding(2) var<uniform> view: View;
+// where the viewport starts in the output, zero when the output is as large as the viewport
+@group(0) @binding(3) var<uniform> output_offset: vec2<u32>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id.xy >= vec2u(view.viewport.zw)) {
+        return;
+    }
+
+    // the red channel contains the work of the pixel, between 0.0 and 1.0
+    let work = saturate(textureLoad(view_input, global_id.xy).r);
+
+    textureStore(view_output, global_id.xy + output_offset, vec4(color_ramp(work), 1.0));
+}
+
+// blue -> cyan -> green -> yellow -> red
+fn color_ramp(t: f32) -> vec3<f32> {
+    let r = saturate(t * 4.0 - 2.0);
+    let g = saturate(t * 4.0) * saturate(4.0 - t * 4.0);
+    let b = saturate(2.0 - t * 4.0);
+    return vec3(r, g, b);
+}
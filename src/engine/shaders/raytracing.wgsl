@@ -4,8 +4,17 @@ struct Camera {
     aperture: f32,
     focus_distance: f32,
     samples: u32,
-    bounces: u32,
+    diffuse_bounces: u32,
+    specular_bounces: u32,
     temporal_accumulation: u32,
+    seed: u32,
+    terminator_softness: f32,
+    accumulated_frames: u32,
+    // check NEVRShadingMode
+    shading_mode: u32,
+    // the near and far planes of the projection, the rays from the camera are clipped between them
+    near: f32,
+    far: f32,
 }
 
 struct Light {
@@ -14,11 +23,29 @@ struct Light {
     ambient: vec4<f32>,
     direction: vec4<f32>,
     sky_color: vec4<f32>,
+    // rgb: radiance of the sun disk
+    // a: angular radius of the sun
+    sun: vec4<f32>,
+    // rgb: minimum radiance of the sky for indirect rays
+    min_indirect: vec4<f32>,
+    // world position to the texture coordinates of the cookie, check VoxelLight::cookie_projection
+    cookie_projection: mat4x4<f32>,
+    sun_in_skybox: u32,
+    // 1 when the light has a cookie
+    cookie: u32,
 }
 
 struct Object {
     index: u32,
     material_id: u32,
+    // multiplier for the texture coordinates, defaults to 1.0
+    uv_scale: f32,
+    // offset of the slice of material_palette used by the object, NO_MATERIAL_REMAP for the materials of its type
+    material_remap: u32,
+    // VoxelBlockCustomData, ignored by the stock shaders
+    custom_data: vec4<f32>,
+    // VoxelLightChannels, the emitters only light the objects sharing a channel bit with them
+    light_channels: u32,
 }
 
 const MATERIAL_MODEL_LAMBERTIAN: u32 = 0;
@@ -26,13 +53,31 @@ const MATERIAL_MODEL_METALLIC: u32 = 1;
 const MATERIAL_MODEL_DIELECTRIC: u32 = 2;
 const _UNUSED_MATERIAL_MODEL_ISOTROPIC: u32 = 3;
 const MATERIAL_MODEL_DIFFUSE_LIGHT: u32 = 4;
+const MATERIAL_MODEL_THIN_FILM: u32 = 5;
+const MATERIAL_MODEL_PBR: u32 = 6;
+const MATERIAL_MODEL_FLIPBOOK: u32 = 7;
+// resolved to a lambertian material by hit_material
+const MATERIAL_MODEL_GRADIENT: u32 = 8;
+// resolved to a lambertian or a mirror material by glossy_lobe
+const MATERIAL_MODEL_GLOSSY: u32 = 9;
+
+// wavelengths (in nanometers) used to sample the thin film interference for the r, g and b channels
+const THIN_FILM_WAVELENGTHS = vec3(650.0, 510.0, 475.0);
+const PI = 3.14159265;
+// metallic and pbr surfaces rougher than this are blurred by the denoisers like the diffuse ones
+const SHARP_ROUGHNESS: f32 = 0.1;
 
 struct Material {
     diffuse: vec4<f32>,
-    _unused_texture: i32,
+    // first frame of the flipbook materials, axis of the gradient materials, light channels of the diffuse
+    // lights, shadow opacity of the dielectric and thin film materials, unused by the other models
+    texture: i32,
     fuzziness: f32,
     refraction_index: f32,
     material_model: u32,
+    // check VoxelMaterial::with_clearcoat, 0.0 when the material has no coat
+    clearcoat: f32,
+    clearcoat_roughness: f32,
 }
 
 struct HitDesc {
@@ -47,40 +92,152 @@ const RAY_T_MAX = 100000.0f;
 
 const RAY_NO_CULL = 0xFFu;
 
+const NO_OBJECT = 0xFFFFFFFFu;
+
+const SHADING_MODE_CONTACT_ONLY: u32 = 1;
+const SHADING_MODE_BAKED_PROBES: u32 = 2;
+// occluders farther than this from the surface don't cast contact shadows
+const CONTACT_SHADOW_DISTANCE: f32 = 1.0;
+const NO_MATERIAL_REMAP = 0xFFFFFFFFu;
+// the light channels of the camera rays and of the emitters that don't set them
+const ALL_LIGHT_CHANNELS: u32 = 0xffffffffu;
+// shadow rays go through at most this many glass surfaces, the light behind more of them is blocked
+const MAX_SHADOW_LAYERS: u32 = 8u;
+// the refraction index of the lacquer of VoxelMaterial::with_clearcoat
+const CLEARCOAT_REFRACTION_INDEX: f32 = 1.5;
+// check VoxelMaterial::with_shadow_opacity
+const SHADOW_OPACITY_SCALE: f32 = 65535.0;
+// keeps the relative variance of the accumulation (stored in f16) finite when a firefly hits a dark pixel
+const MAX_RELATIVE_VARIANCE: f32 = 1000.0;
+// the largest finite f16, the colors written in the f16 textures are clamped to it so a very bright emitter
+// saturates instead of becoming infinite
+const MAX_F16: f32 = 65504.0;
+
 @group(0) @binding(0) var tlas: acceleration_structure;
 @group(0) @binding(1) var<storage, read> objects: array<Object>;
 @group(0) @binding(2) var<storage, read> indices: array<vec4<u32>>;
 @group(0) @binding(3) var<storage, read> vertices: array<vec4<f32>>;
 @group(0) @binding(4) var<storage, read> normals: array<vec4<f32>>;
-@group(0) @binding(5) var<storage, read> materials: array<Material>;
-@group(0) @binding(6) var<storage, read> material_map: array<u32>;
+// w is the handedness of the bitangent
+@group(0) @binding(5) var<storage, read> tangents: array<vec4<f32>>;
+@group(0) @binding(6) var<storage, read> materials: array<Material>;
+@group(0) @binding(7) var<storage, read> material_map: array<u32>;
+// world transform of the last frame of every TLAS instance
+@group(0) @binding(8) var<storage, read> previous_transforms: array<mat4x4<f32>>;
+// the materials used instead of the ones of the type by the objects with a VoxelMaterialRemap
+@group(0) @binding(9) var<storage, read> material_palette: array<u32>;
+// the ambient cubes of the probes baked by NEVRBakeProbes, 6 colors (+x, -x, +y, -y, +z, -z) per probe
+@group(0) @binding(10) var<storage, read_write> probes: array<vec4<f32>>;
+@group(0) @binding(11) var<uniform> probe_grid: ProbeGrid;
+
+struct ProbeGrid {
+    // xyz: probes on every axis
+    // w: samples baked so far, 0 when there are no probes
+    size: vec4<u32>,
+    min: vec4<f32>,
+    max: vec4<f32>,
+    // x: samples baked before this pass
+    // y: samples of this pass
+    bake: vec4<u32>,
+}
 
 @group(1) @binding(0) var<uniform> camera: Camera;
 @group(1) @binding(1) var view_output: texture_storage_2d<rgba16float, write>;
 @group(1) @binding(2) var<uniform> light: Light;
 @group(1) @binding(3) var<uniform> view: View;
 @group(1) @binding(4) var accumulation: texture_storage_2d<rgba16float, read_write>;
+// clip_from_world of the last frame
+@group(1) @binding(5) var<uniform> previous_clip_from_world: mat4x4<f32>;
+// first pixel of the dispatched tile, check NEVRTuning::max_workgroups_per_dispatch
+@group(1) @binding(6) var<uniform> tile_offset: vec2<u32>;
+// check VoxelLight::cookie, a white image when there is none
+@group(1) @binding(7) var cookie_texture: texture_2d<f32>;
+@group(1) @binding(8) var cookie_sampler: sampler;
+// check VoxelFlipbook, an empty array when there is none
+@group(1) @binding(9) var flipbook_texture: texture_2d_array<f32>;
+@group(1) @binding(10) var flipbook_sampler: sampler;
+@group(1) @binding(11) var<uniform> globals: Globals;
+
+// bevy's GlobalsUniform
+struct Globals {
+    time: f32,
+    delta_time: f32,
+    frame_count: u32,
+}
 
 @group(2) @binding(0) var albedo_texture: texture_storage_2d<rgba16float, write>;
 @group(2) @binding(1) var normal_texture: texture_storage_2d<rgba16float, write>;
 @group(2) @binding(2) var world_position_texture: texture_storage_2d<rgba16float, write>;
+// linear view-space depth, 0.0 where nothing was hit
+@group(2) @binding(3) var depth_texture: texture_storage_2d<r32float, write>;
+// index of the object of the primary hit, NO_OBJECT where nothing was hit
+@group(2) @binding(4) var object_id_texture: texture_storage_2d<r32uint, write>;
+// current uv minus the uv of the same point in the last frame
+@group(2) @binding(5) var motion_vectors_texture: texture_storage_2d<rg32float, write>;
+// how much of the light comes from sharp paths (mirrors, glass and lights seen through them), accumulated like
+// the image, the denoisers don't blur it
+@group(2) @binding(6) var detail_texture: texture_storage_2d<r32float, read_write>;
+// the light of the sharp paths, accumulated like the image, VoxelDenoiser::Split only filters the rest of it
+@group(2) @binding(7) var specular_texture: texture_storage_2d<rgba16float, read_write>;
+
+#ifdef HEATMAP
+// number of rays traced by the invocation
+var<private> heatmap_work: u32 = 0u;
+#endif
 
 #ifdef SKYBOX
 @group(3) @binding(0) var skybox: texture_cube<f32>;
 @group(3) @binding(1) var skybox_sampler: sampler;
+// 1 when the texels are sRGB-encoded, check VoxelSkybox::skybox_is_srgb
+@group(3) @binding(2) var<uniform> skybox_decode_srgb: u32;
+// check SkyboxDistribution
+@group(3) @binding(3) var<storage, read> skybox_distribution: SkyboxDistribution;
+// check VoxelSkybox::layers, the textures can't be indexed so every layer has its own binding
+@group(3) @binding(4) var<uniform> skybox_layers: array<SkyboxLayer, SKYBOX_MAX_LAYERS>;
+@group(3) @binding(5) var skybox_layer_0: texture_cube<f32>;
+@group(3) @binding(6) var skybox_layer_1: texture_cube<f32>;
+@group(3) @binding(7) var skybox_layer_2: texture_cube<f32>;
+
+const SKYBOX_DISTRIBUTION_WIDTH: u32 = 64u;
+const SKYBOX_DISTRIBUTION_HEIGHT: u32 = 32u;
+const SKYBOX_MAX_LAYERS: u32 = 3u;
+const SKYBOX_BLEND_ALPHA: u32 = 1u;
+
+struct SkyboxLayer {
+    // moves a world direction into the space of the layer
+    from_world: mat3x3<f32>,
+    // x: blend mode
+    // y: whether the texels are sRGB-encoded
+    // z: whether the layer is used
+    flags: vec4<u32>,
+}
+
+struct SkyboxDistribution {
+    weights: array<f32, SKYBOX_DISTRIBUTION_WIDTH * SKYBOX_DISTRIBUTION_HEIGHT>,
+    conditional_cdf: array<f32, SKYBOX_DISTRIBUTION_WIDTH * SKYBOX_DISTRIBUTION_HEIGHT>,
+    marginal_cdf: array<f32, SKYBOX_DISTRIBUTION_HEIGHT>,
+    total: f32,
+}
 #endif
 
 @compute @workgroup_size(8, 8, 1)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let global_id = vec3(invocation_id.xy + tile_offset, invocation_id.z);
     if any(global_id.xy >= vec2u(view.viewport.zw)) {
         return;
     }
 
     create_g_buffer(global_id);
 
+    // a path has at most this many bounces in total
+    let max_bounces = max(camera.diffuse_bounces, camera.specular_bounces);
+
     var pixel_color = vec4(0.0);
-    var ray_seed = init_random_seed(init_random_seed(global_id.x, global_id.y), camera.samples * camera.bounces * view.frame_count);
-    var pixel_seed = init_random_seed(camera.samples * camera.bounces, camera.samples * view.frame_count);
+    var detail = 0.0;
+    var specular = vec3(0.0);
+    // with a seed of 0 this is the same as not using a seed
+    var ray_seed = init_random_seed(init_random_seed(global_id.x, global_id.y) ^ camera.seed, camera.samples * max_bounces * view.frame_count);
+    var pixel_seed = init_random_seed((camera.samples * max_bounces) ^ camera.seed, camera.samples * view.frame_count);
 
     for (var i = u32(0); i < camera.samples; i++) {
         let jitter = vec2(random_float(&pixel_seed), random_float(&pixel_seed));
@@ -97,52 +254,265 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         let lens_radius = camera.aperture / 2.0;
         let rand_uv = random_in_unit_disk(&ray_seed);
         let offset_on_lens = camera_right * rand_uv.x + camera_up * rand_uv.y;
-        var origin = pinhole_origin + offset_on_lens * lens_radius;
-        var direction = normalize(focal_point - origin);
-
-        var b = u32(0);
+        let origin = pinhole_origin + offset_on_lens * lens_radius;
+        let direction = normalize(focal_point - origin);
 
         var accumulated_light = vec3(0.0);
-        var throughput = vec3(1.0);
+        var detail_light = vec3(0.0);
+        // the path tracing loop is skipped, only the primary hit is shaded
+        if (camera.shading_mode == SHADING_MODE_CONTACT_ONLY) {
+            accumulated_light = contact_shading(origin, direction, &ray_seed);
+        } else {
+            let gather_probes = camera.shading_mode == SHADING_MODE_BAKED_PROBES && probe_grid.size.w > 0u;
+            accumulated_light = trace_path(origin, direction, &ray_seed, true, gather_probes, &detail_light);
+        }
 
-        loop {
-            if (b == camera.bounces) {
-                break;
-            }
+        // a single NaN or infinite sample would stay in the accumulation forever
+        let color = select(vec3(0.0), accumulated_light, all(is_finite(accumulated_light)));
+        pixel_color += vec4(color, 1.0);
+        detail += select(0.0, saturate(luminance(detail_light) / max(luminance(color), 0.0001)), all(is_finite(detail_light)));
+        specular += select(vec3(0.0), detail_light, all(is_finite(accumulated_light)) && all(is_finite(detail_light)));
+    }
 
-            let hit = trace_ray(origin, direction, 0.001, 10000.0, RAY_FLAG_NONE);
+    pixel_color = pixel_color / f32(camera.samples);
 
-            var scatter = false;
-            if hit.kind != RAY_QUERY_INTERSECTION_NONE {
-                scatter = closest_hit(hit, &ray_seed, &origin, &direction, &accumulated_light, &throughput);
-            } else {
-                scatter = miss(hit, &origin, &direction, &accumulated_light, &throughput);
-            }
+    // the denoisers scale the accumulated image by it, a single frame would make the sharp part flicker
+    detail = detail / f32(camera.samples);
+    if (camera.accumulated_frames > 0 && camera.temporal_accumulation > 0) {
+        let old_detail = textureLoad(detail_texture, global_id.xy).r;
+        detail = (old_detail * f32(camera.accumulated_frames) + detail) / (f32(camera.accumulated_frames) + 1.0);
+    }
+    textureStore(detail_texture, global_id.xy, vec4(detail, 0.0, 0.0, 0.0));
+
+    specular = specular / f32(camera.samples);
+    if (camera.accumulated_frames > 0 && camera.temporal_accumulation > 0) {
+        let old_specular = textureLoad(specular_texture, global_id.xy).rgb;
+        if (all(is_finite(old_specular))) {
+            specular = (old_specular * f32(camera.accumulated_frames) + specular) / (f32(camera.accumulated_frames) + 1.0);
+        }
+    }
+    textureStore(specular_texture, global_id.xy, vec4(min(specular, vec3(MAX_F16)), 1.0));
+
+    // the alpha of the accumulation is the mean of the squared difference between every frame and the colors
+    // accumulated before it, relative to their luminance, which is used to estimate the convergence of the image
+    pixel_color.a = 0.0;
+    if (camera.accumulated_frames > 0 && camera.temporal_accumulation > 0) {
+        var old_color = textureLoad(accumulation, global_id.xy);
+        // the history may have been written before the sample was rejected (e.g. overflowing f16)
+        if (!all(is_finite(old_color.rgb))) {
+            old_color = pixel_color;
+        }
+        let old_luminance = luminance(old_color.rgb);
+        let difference = luminance(pixel_color.rgb) - old_luminance;
+        pixel_color.a = min(difference * difference / max(old_luminance * old_luminance, 0.0001), MAX_RELATIVE_VARIANCE);
+        pixel_color = (old_color * f32(camera.accumulated_frames) + pixel_color) / (f32(camera.accumulated_frames) + 1.0);
+    }
+
+#ifdef HEATMAP
+#ifdef SKYBOX
+    // the g-buffer traces one ray, every bounce traces at most a scattered ray and a shadow ray for the sun and
+    // one for the skybox
+    let max_work = 1u + camera.samples * max_bounces * 3u;
+#else
+    // the g-buffer traces one ray, every bounce traces at most a scattered ray and a shadow ray
+    let max_work = 1u + camera.samples * max_bounces * 2u;
+#endif
+    let work = f32(heatmap_work) / f32(max_work);
+    textureStore(view_output, global_id.xy, vec4(work, 0.0, 0.0, 1.0));
+#else
+    // the accumulation is a running mean, it's never bigger than the brightest sample, only a single sample
+    // brighter than the range of f16 would overflow
+    pixel_color = vec4(min(pixel_color.rgb, vec3(MAX_F16)), pixel_color.a);
+    textureStore(accumulation, global_id.xy, pixel_color);
+    textureStore(view_output, global_id.xy, vec4(pixel_color.rgb, 1.0));
+#endif
+}
+
+// follows a path from the camera (or from a probe while baking) and returns the light it brings back, the light
+// of the sharp part of the path (before it hits a diffuse or rough surface) is added to detail_light
+// gather_probes ends the path at its last diffuse bounce with the light of the baked probes
+fn trace_path(
+    ray_origin: vec3<f32>, ray_direction: vec3<f32>, seed: ptr<function, u32>, from_camera: bool, gather_probes: bool,
+    detail_light: ptr<function, vec3<f32>>
+) -> vec3<f32> {
+    // a path has at most this many bounces in total
+    let max_bounces = max(camera.diffuse_bounces, camera.specular_bounces);
+
+    var origin = ray_origin;
+    var direction = ray_direction;
+
+    var b = u32(0);
+    var diffuse_b = u32(0);
+    var specular_b = u32(0);
+
+    var accumulated_light = vec3(0.0);
+    var throughput = vec3(1.0);
+    // the probes don't see the sun disk, the surfaces lit by them already sample the sun directly
+    var show_sun = from_camera;
+    // pdf of the direction of the last bounce, 0 when the skybox isn't sampled directly at the hit
+    var brdf_pdf = 0.0;
+    // the path hasn't hit a diffuse or rough surface yet, the light it finds is sharp detail
+    var sharp_path = true;
+    // the light channels of the object the path last bounced off, the emitters found next only light it
+    // when they share a channel with it
+    var receiver_channels = ALL_LIGHT_CHANNELS;
+
+    loop {
+        if (b == max_bounces) {
+            break;
+        }
 
-            if (!scatter) {
-                break;
+        // the ray from the camera is clipped by the projection, like the rasterized geometry
+        var clip = vec2(0.001, 10000.0);
+        if (from_camera && b == 0u) {
+            clip = camera_ray_range(direction);
+        }
+        let hit = trace_ray(origin, direction, clip.x, clip.y, RAY_FLAG_NONE);
+
+        var scatter = false;
+        let previous_light = accumulated_light;
+        if hit.kind != RAY_QUERY_INTERSECTION_NONE {
+            var material = hit_material(hit);
+            if (material.clearcoat > 0.0) {
+                material = clearcoat_lobe(material, hit, direction, seed);
+            }
+            if (material.material_model == MATERIAL_MODEL_GLOSSY) {
+                material = glossy_lobe(material, hit, direction, seed);
+            }
+            let diffuse = material.material_model == MATERIAL_MODEL_LAMBERTIAN;
+            // lambertian surfaces already sample the sun directly, also through the glass in the way, hitting
+            // the disk again would count it twice
+            show_sun = !diffuse && (show_sun || !transmits_shadows(material));
+            // the direct light of a diffuse or rough surface is blurred, like everything found after it
+            sharp_path = sharp_path && is_sharp(material);
+            // the last diffuse bounce takes the rest of the path from the probes
+            let last_bounce = diffuse_b + 1u >= camera.diffuse_bounces || b + 1u >= max_bounces;
+            scatter = closest_hit(hit, material, seed, &origin, &direction, &accumulated_light, &throughput, &brdf_pdf, &receiver_channels, gather_probes && diffuse && last_bounce);
+            if (sharp_path) {
+                *detail_light += accumulated_light - previous_light;
             }
 
-            if (0.01 >= max(throughput.r, max(throughput.g, throughput.b))) {
-                break;
+            // diffuse and specular bounces have their own limit, the hit is shaded but the path ends once
+            // the kind of its bounce reached the limit
+            if (diffuse) {
+                diffuse_b += 1u;
+                scatter = scatter && diffuse_b < camera.diffuse_bounces;
+            } else {
+                specular_b += 1u;
+                scatter = scatter && specular_b < camera.specular_bounces;
+            }
+        } else {
+            scatter = miss(hit, &origin, &direction, &accumulated_light, &throughput, show_sun, b > 0u || !from_camera, brdf_pdf);
+            if (sharp_path) {
+                *detail_light += accumulated_light - previous_light;
             }
+        }
 
-            b += 1;
+        if (!scatter) {
+            break;
         }
 
-        let color = accumulated_light;
-        pixel_color += vec4(color, 1.0);
+        if (0.01 >= max(throughput.r, max(throughput.g, throughput.b))) {
+            break;
+        }
+
+        b += 1;
     }
 
-    pixel_color = pixel_color / f32(camera.samples);
+    return accumulated_light;
+}
 
-    if (view.frame_count > 0 && camera.temporal_accumulation > 0) {
-        let old_color = textureLoad(accumulation, global_id.xy);
-        pixel_color = (old_color * f32(view.frame_count) + pixel_color) / (f32(view.frame_count) + 1.0);
+// bakes a pass of the probes of NEVRBakeProbes, every invocation is a face of the ambient cube of a probe
+@compute @workgroup_size(64, 1, 1)
+fn bake_probes(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = probe_grid.size.xyz;
+    let index = invocation_id.x;
+    if (index >= size.x * size.y * size.z * 6u) {
+        return;
     }
 
-    textureStore(accumulation, global_id.xy, pixel_color);
-    textureStore(view_output, global_id.xy, pixel_color);
+    let probe = index / 6u;
+    let face = index % 6u;
+    let cell = vec3(probe % size.x, (probe / size.x) % size.y, probe / (size.x * size.y));
+    // the probes are at the centers of the cells of the grid
+    let position = mix(probe_grid.min.xyz, probe_grid.max.xyz, (vec3<f32>(cell) + 0.5) / vec3<f32>(size));
+    var axis = vec3(0.0);
+    axis[face / 2u] = select(1.0, -1.0, face % 2u == 1u);
+
+    var seed = init_random_seed(index ^ camera.seed, probe_grid.bake.x + 1u);
+    var sum = vec3(0.0);
+    for (var i = 0u; i < probe_grid.bake.y; i++) {
+        // cosine-weighted around the axis, so the mean is the irradiance over pi, like the light a lambertian
+        // surface reflects
+        var direction = axis + random_unit_vector(&seed);
+        if (dot(direction, direction) < 0.0001) {
+            direction = axis;
+        }
+        var detail_light = vec3(0.0);
+        let radiance = trace_path(position, normalize(direction), &seed, false, false, &detail_light);
+        sum += select(vec3(0.0), radiance, all(is_finite(radiance)));
+    }
+
+    let baked = f32(probe_grid.bake.x);
+    let samples = baked + f32(probe_grid.bake.y);
+    let previous = select(vec3(0.0), probes[index].rgb, probe_grid.bake.x > 0u);
+    probes[index] = vec4((previous * baked + sum) / max(samples, 1.0), 1.0);
+}
+
+// the light reaching a surface from the probes around it, trilinearly interpolated between the 8 closest probes
+fn probe_irradiance(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let size = probe_grid.size.xyz;
+    let extent = max(probe_grid.max.xyz - probe_grid.min.xyz, vec3(0.0001));
+    // the positions outside of the bounds use the closest probes
+    let grid_position = clamp((position - probe_grid.min.xyz) / extent * vec3<f32>(size) - 0.5, vec3(0.0), vec3<f32>(size - 1u));
+    let base = vec3<u32>(floor(grid_position));
+    let t = grid_position - vec3<f32>(base);
+
+    var irradiance = vec3(0.0);
+    for (var corner = 0u; corner < 8u; corner++) {
+        let offset = vec3(corner & 1u, (corner >> 1u) & 1u, (corner >> 2u) & 1u);
+        let cell = min(base + offset, size - 1u);
+        let weights = select(1.0 - t, t, offset == vec3(1u));
+        let probe = cell.x + size.x * (cell.y + size.y * cell.z);
+        irradiance += ambient_cube(probe, normal) * weights.x * weights.y * weights.z;
+    }
+
+    return irradiance;
+}
+
+// the light of an ambient cube toward a direction, blended between the faces by the squared components
+fn ambient_cube(probe: u32, normal: vec3<f32>) -> vec3<f32> {
+    let weights = normal * normal;
+    let first = probe * 6u;
+    let x = probes[first + select(0u, 1u, normal.x < 0.0)].rgb;
+    let y = probes[first + select(2u, 3u, normal.y < 0.0)].rgb;
+    let z = probes[first + select(4u, 5u, normal.z < 0.0)].rgb;
+    return weights.x * x + weights.y * y + weights.z * z;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// mirrors, glass and lights keep their detail in the denoisers, see detail_texture
+fn is_sharp(material: Material) -> bool {
+    switch material.material_model {
+        case MATERIAL_MODEL_METALLIC, MATERIAL_MODEL_PBR: {
+            return material.fuzziness < SHARP_ROUGHNESS;
+        }
+        case MATERIAL_MODEL_DIELECTRIC, MATERIAL_MODEL_DIFFUSE_LIGHT, MATERIAL_MODEL_THIN_FILM, MATERIAL_MODEL_FLIPBOOK: {
+            return true;
+        }
+        default: {
+            return false;
+        }
+    }
+}
+
+// false for the NaN and infinite components, checked on the bits since comparisons with NaN may be optimized away
+fn is_finite(value: vec3<f32>) -> vec3<bool> {
+    let exponent = bitcast<vec3<u32>>(value) & vec3(0x7f800000u);
+    return exponent != vec3(0x7f800000u);
 }
 
 fn create_g_buffer(global_id: vec3<u32>) {
@@ -154,17 +524,23 @@ fn create_g_buffer(global_id: vec3<u32>) {
     let camera_target = view.world_from_clip * vec4(d.x, -d.y, 1.0, 1.0);
     var direction = normalize((camera_target.xyz / camera_target.w) - origin);
 
-    let hit = trace_ray(origin, direction, 0.001, 10000.0, RAY_FLAG_CULL_BACK_FACING);
+    // no face culling: mirrored blocks (negative scale) flip the winding of their triangles
+    let clip = camera_ray_range(direction);
+    let hit = trace_ray(origin, direction, clip.x, clip.y, RAY_FLAG_NONE);
 
     var albedo: vec3<f32>;
     var normal: vec3<f32>;
     var world_position: vec3<f32>;
+    var depth = 0.0;
+    var object_id = NO_OBJECT;
+    // nothing was hit: the sky is infinitely far, so only the rotation of the camera moves it
+    var previous_position = vec4(direction, 0.0);
 
     if hit.kind != RAY_QUERY_INTERSECTION_NONE {
         let barycentrics = vec3(1.0 - hit.barycentrics.x - hit.barycentrics.y, hit.barycentrics.x, hit.barycentrics.y);
 
         let object = objects[hit.instance_custom_data];
-        let material = materials[material_map[object.material_id + hit.primitive_index]];
+        let material = hit_material(hit);
         let index = indices[object.index + hit.primitive_index];
         let n0 = normals[index.x].xyz;
         let n1 = normals[index.y].xyz;
@@ -174,47 +550,302 @@ fn create_g_buffer(global_id: vec3<u32>) {
 
         albedo = material.diffuse.rgb;
         world_position = origin.xyz + hit.t * direction.xyz;
-        normal = normalize(mat3x3(hit.object_to_world[0].xyz, hit.object_to_world[1].xyz, hit.object_to_world[2].xyz) * nrm);
+        normal = object_to_world_normal(hit, nrm);
+        depth = dot(world_position - origin, -view.world_from_view[2].xyz);
+        object_id = hit.instance_custom_data;
+
+        // the hit point moved with its instance, find where it was in the last frame
+        let local_position = hit.world_to_object * vec4(world_position, 1.0);
+        previous_position = previous_transforms[hit.instance_index] * vec4(local_position, 1.0);
     }
 
+    let previous_clip_position = previous_clip_from_world * previous_position;
+    let previous_uv = previous_clip_position.xy / previous_clip_position.w * vec2(0.5, -0.5) + 0.5;
+
     textureStore(albedo_texture, global_id.xy, vec4(albedo, 1.0));
     textureStore(normal_texture, global_id.xy, vec4(normal, 1.0));
-    textureStore(world_position_texture, global_id.xy, vec4(world_position, 1.0));
+    // stored relative to the camera: far from the origin absolute positions don't fit in the precision of f16
+    let relative_position = select(vec3(0.0), world_position - origin, hit.kind != RAY_QUERY_INTERSECTION_NONE);
+    textureStore(world_position_texture, global_id.xy, vec4(relative_position, 1.0));
+    textureStore(depth_texture, global_id.xy, vec4(depth, 0.0, 0.0, 0.0));
+    textureStore(object_id_texture, global_id.xy, vec4(object_id, 0u, 0u, 0u));
+    textureStore(motion_vectors_texture, global_id.xy, vec4(in_uv - previous_uv, 0.0, 0.0));
+}
+
+// transforms a normal with the inverse transpose of the instance transform, so that non-uniform and negative
+// scales keep the normal perpendicular to the surface and pointing outwards
+fn object_to_world_normal(hit: RayIntersection, normal: vec3<f32>) -> vec3<f32> {
+    let world_to_object = mat3x3(hit.world_to_object[0], hit.world_to_object[1], hit.world_to_object[2]);
+    return normalize(normal * world_to_object);
+}
+
+// texture coordinates of a hit, projected on the face of the block and scaled by the object's uv_scale
+fn object_uv(hit: RayIntersection, object: Object, world_position: vec3<f32>, object_normal: vec3<f32>) -> vec2<f32> {
+    let local_position = hit.world_to_object * vec4(world_position, 1.0);
+    let axis = abs(object_normal);
+
+    var uv = local_position.xy;
+    if (axis.x >= axis.y && axis.x >= axis.z) {
+        uv = local_position.zy;
+    } else if (axis.y >= axis.z) {
+        uv = local_position.xz;
+    }
+
+    return fract(uv * object.uv_scale);
+}
+
+// moves the origin of shadow rays on the surface described by the vertex normals instead of the flat triangle,
+// based on Hanika 2021 "Hacking the Shadow Terminator"
+fn shadow_terminator_origin(hit: RayIntersection, index: vec4<u32>, barycentrics: vec3<f32>) -> vec3<f32> {
+    let p0 = vertices[index.x].xyz;
+    let p1 = vertices[index.y].xyz;
+    let p2 = vertices[index.z].xyz;
+    let n0 = normals[index.x].xyz;
+    let n1 = normals[index.y].xyz;
+    let n2 = normals[index.z].xyz;
+
+    let p = mat3x3(p0, p1, p2) * barycentrics;
+    let d0 = min(0.0, dot(p - p0, n0));
+    let d1 = min(0.0, dot(p - p1, n1));
+    let d2 = min(0.0, dot(p - p2, n2));
+    let offset = -(barycentrics.x * d0 * n0 + barycentrics.y * d1 * n1 + barycentrics.z * d2 * n2);
+
+    return hit.object_to_world * vec4(p + offset * camera.terminator_softness, 1.0);
+}
+
+// smooths the light falloff where the shading normal and the triangle normal disagree,
+// based on Chiang et al. 2019 "Taming the Shadow Terminator"
+fn shadow_terminator_term(hit: RayIntersection, index: vec4<u32>, shading_normal: vec3<f32>, light_direction: vec3<f32>) -> f32 {
+    let p0 = vertices[index.x].xyz;
+    let p1 = vertices[index.y].xyz;
+    let p2 = vertices[index.z].xyz;
+
+    var geometric_normal = object_to_world_normal(hit, cross(p1 - p0, p2 - p0));
+    if (dot(geometric_normal, shading_normal) < 0.0) {
+        geometric_normal = -geometric_normal;
+    }
+
+    let shading_cos = dot(shading_normal, light_direction) * dot(geometric_normal, shading_normal);
+    let g = clamp(dot(geometric_normal, light_direction) / max(shading_cos, 0.0001), 0.0, 1.0);
+
+    return -(g * g * g) + g * g + g;
+}
+
+// the distances along a ray from the camera between the near and the far planes of the projection, which are
+// perpendicular to the view direction
+fn camera_ray_range(direction: vec3<f32>) -> vec2<f32> {
+    let cos_theta = max(dot(direction, -view.world_from_view[2].xyz), 0.0001);
+    return vec2(camera.near, camera.far) / cos_theta;
 }
 
 fn trace_ray(ray_origin: vec3<f32>, ray_direction: vec3<f32>, ray_t_min: f32, ray_t_max: f32, ray_flag: u32) -> RayIntersection {
-    let ray = RayDesc(ray_flag, RAY_NO_CULL, ray_t_min, ray_t_max, ray_origin, ray_direction);
+#ifdef HEATMAP
+    heatmap_work += 1u;
+#endif
+    // the types with glass are built as non-opaque geometry, naga can't confirm candidate intersections yet so
+    // every ray treats them as opaque
+    let ray = RayDesc(ray_flag | RAY_FLAG_FORCE_OPAQUE, RAY_NO_CULL, ray_t_min, ray_t_max, ray_origin, ray_direction);
     var rq: ray_query;
     rayQueryInitialize(&rq, tlas, ray);
     rayQueryProceed(&rq);
     return rayQueryGetCommittedIntersection(&rq);
 }
 
+// dielectric and thin film surfaces let the light through, tinted by their color
+fn transmits_shadows(material: Material) -> bool {
+    return material.material_model == MATERIAL_MODEL_DIELECTRIC || material.material_model == MATERIAL_MODEL_THIN_FILM;
+}
+
+// how much light goes through a surface toward the light, a negative opacity (the default) is fully transparent
+fn shadow_tint(material: Material) -> vec3<f32> {
+    let opacity = saturate(f32(material.texture) / SHADOW_OPACITY_SCALE);
+    return material.diffuse.rgb * (1.0 - opacity);
+}
+
+// the light reaching a point from a direction, tinted by the glass in the way and zero when an opaque surface
+// blocks it; the rays go straight through the glass, they aren't refracted
+fn shadow_transmittance(origin: vec3<f32>, direction: vec3<f32>, t_max: f32) -> vec3<f32> {
+    var transmittance = vec3(1.0);
+    var ray_origin = origin;
+    var remaining = t_max;
+
+    for (var i = 0u; i < MAX_SHADOW_LAYERS; i++) {
+        let hit = trace_ray(ray_origin, direction, 0.001, remaining, RAY_FLAG_NONE);
+        if (hit.kind == RAY_QUERY_INTERSECTION_NONE) {
+            return transmittance;
+        }
+
+        let material = hit_material(hit);
+        if (!transmits_shadows(material)) {
+            return vec3(0.0);
+        }
+        // a block has two faces along the ray, only the one where the ray enters tints the light
+        if (hit.front_face) {
+            transmittance *= shadow_tint(material);
+        }
+        if (max(transmittance.r, max(transmittance.g, transmittance.b)) <= 0.001) {
+            return vec3(0.0);
+        }
+
+        ray_origin += direction * hit.t;
+        remaining -= hit.t;
+    }
+
+    return vec3(0.0);
+}
+
+// shades a primary ray with the sun, the ambient light and a single short shadow ray, without any bounce
+fn contact_shading(origin: vec3<f32>, direction: vec3<f32>, seed: ptr<function, u32>) -> vec3<f32> {
+    let clip = camera_ray_range(direction);
+    let hit = trace_ray(origin, direction, clip.x, clip.y, RAY_FLAG_NONE);
+
+    if hit.kind == RAY_QUERY_INTERSECTION_NONE {
+        var miss_origin = origin;
+        var miss_direction = direction;
+        var sky = vec3(0.0);
+        var throughput = vec3(1.0);
+        _ = miss(hit, &miss_origin, &miss_direction, &sky, &throughput, true, false, 0.0);
+        return sky;
+    }
+
+    let barycentrics = vec3(1.0 - hit.barycentrics.x - hit.barycentrics.y, hit.barycentrics.x, hit.barycentrics.y);
+
+    let object = objects[hit.instance_custom_data];
+    let material = hit_material(hit);
+    let index = indices[object.index + hit.primitive_index];
+    let n0 = normals[index.x].xyz;
+    let n1 = normals[index.y].xyz;
+    let n2 = normals[index.z].xyz;
+
+    let normal = mat3x3(n0, n1, n2) * barycentrics;
+    let world_normal = object_to_world_normal(hit, normal);
+    let hit_point = origin + hit.t * direction;
+
+    var hit_desc = scatter_fn(material, hit.t, seed, world_normal, direction);
+    if (material.material_model == MATERIAL_MODEL_FLIPBOOK) {
+        hit_desc.color *= flipbook_frame(material, object_uv(hit, object, hit_point, normal));
+    }
+
+    let light_direction = -light.direction.xyz;
+    let sun = max(dot(light_direction, world_normal), 0.0) * light.ambient.y;
+    var shadow = vec3(0.0);
+    if (sun > 0.0) {
+        let shadow_origin = shadow_terminator_origin(hit, index, barycentrics) + world_normal * 0.0001;
+        shadow = shadow_transmittance(shadow_origin, light_direction, CONTACT_SHADOW_DISTANCE);
+    }
+
+    let direct_light = max(sun * shadow * light_cookie(hit_point), vec3(light.ambient.x));
+    return hit_desc.color + material.diffuse.rgb * direct_light;
+}
+
+fn hit_material(hit: RayIntersection) -> Material {
+    let object = objects[hit.instance_custom_data];
+    let material = object_material(object, hit.primitive_index);
+    if (material.material_model == MATERIAL_MODEL_GRADIENT) {
+        return gradient_material(material, hit, object);
+    }
+    return material;
+}
+
+// a gradient is a lambertian surface whose color depends on the position of the hit in the block
+fn gradient_material(material: Material, hit: RayIntersection, object: Object) -> Material {
+    let barycentrics = vec3(1.0 - hit.barycentrics.x - hit.barycentrics.y, hit.barycentrics.x, hit.barycentrics.y);
+    let index = indices[object.index + hit.primitive_index];
+    // the vertices are in the space of the block, from 0.0 to 1.0 on every axis
+    let local_position = mat3x3(vertices[index.x].xyz, vertices[index.y].xyz, vertices[index.z].xyz) * barycentrics;
+    let t = saturate(local_position[clamp(material.texture, 0, 2)]);
+
+    let end_color = vec3(material.diffuse.a, material.fuzziness, material.refraction_index);
+    let color = mix(material.diffuse.rgb, end_color, t);
+    return Material(vec4(color, 1.0), 0, 0.0, 1.0, MATERIAL_MODEL_LAMBERTIAN, material.clearcoat, material.clearcoat_roughness);
+}
+
+// the interpolated normal of a hit in world space
+fn hit_normal(hit: RayIntersection) -> vec3<f32> {
+    let barycentrics = vec3(1.0 - hit.barycentrics.x - hit.barycentrics.y, hit.barycentrics.x, hit.barycentrics.y);
+    let object = objects[hit.instance_custom_data];
+    let index = indices[object.index + hit.primitive_index];
+    let normal = mat3x3(normals[index.x].xyz, normals[index.y].xyz, normals[index.z].xyz) * barycentrics;
+    return object_to_world_normal(hit, normal);
+}
+
+// a coated surface reflects off the coat with the probability of its fresnel reflection, scaled by the strength
+// of the coat, otherwise the hit scatters on the material below; the weight of both lobes is 1.0 since each is
+// picked with the probability of its own share of the light
+fn clearcoat_lobe(material: Material, hit: RayIntersection, direction: vec3<f32>, seed: ptr<function, u32>) -> Material {
+    let cos_theta = min(abs(dot(direction, hit_normal(hit))), 1.0);
+    let reflect_probability = saturate(material.clearcoat) * schlick(cos_theta, CLEARCOAT_REFRACTION_INDEX);
+
+    if (random_float(seed) < reflect_probability) {
+        return Material(vec4(1.0), -1, material.clearcoat_roughness, 1.0, MATERIAL_MODEL_METALLIC, 0.0, 0.0);
+    }
+    var base = material;
+    base.clearcoat = 0.0;
+    return base;
+}
+
+// a glossy surface is a lambertian surface or a perfect mirror, picked for every hit with the reflectivity
+// scaled up toward grazing angles, so that the lambertian lobe keeps the direct light of the sun and the skybox
+fn glossy_lobe(material: Material, hit: RayIntersection, direction: vec3<f32>, seed: ptr<function, u32>) -> Material {
+    let world_normal = hit_normal(hit);
+
+    // schlick's approximation, the reflectivity is the reflection at normal incidence and it also scales the
+    // fresnel term so that a reflectivity of 0.0 is a lambertian surface at every angle
+    let cos_theta = min(abs(dot(direction, world_normal)), 1.0);
+    let reflectivity = saturate(material.fuzziness);
+    let reflect_probability = reflectivity + (1.0 - reflectivity) * reflectivity * pow(1.0 - cos_theta, 5.0);
+
+    if (random_float(seed) < reflect_probability) {
+        return Material(vec4(1.0), -1, 0.0, 1.0, MATERIAL_MODEL_METALLIC, 0.0, 0.0);
+    }
+    return Material(material.diffuse, -1, 0.0, 1.0, MATERIAL_MODEL_LAMBERTIAN, 0.0, 0.0);
+}
+
+fn object_material(object: Object, primitive_index: u32) -> Material {
+    var material_index = material_map[object.material_id + primitive_index];
+    if (object.material_remap != NO_MATERIAL_REMAP) {
+        material_index = material_palette[object.material_remap + material_index];
+    }
+    return materials[material_index];
+}
+
 fn closest_hit(
-    hit: RayIntersection, seed: ptr<function, u32>, origin: ptr<function, vec3<f32>>, direction: ptr<function, vec3<f32>>,
-    accumulated_light: ptr<function, vec3<f32>>, throughput: ptr<function, vec3<f32>>
+    hit: RayIntersection, material: Material, seed: ptr<function, u32>, origin: ptr<function, vec3<f32>>, direction: ptr<function, vec3<f32>>,
+    accumulated_light: ptr<function, vec3<f32>>, throughput: ptr<function, vec3<f32>>, brdf_pdf: ptr<function, f32>,
+    receiver_channels: ptr<function, u32>, gather_probes: bool
 ) -> bool {
     let barycentrics = vec3(1.0 - hit.barycentrics.x - hit.barycentrics.y, hit.barycentrics.x, hit.barycentrics.y);
 
     let object = objects[hit.instance_custom_data];
-    let material = materials[material_map[object.material_id + hit.primitive_index]];
     let index = indices[object.index + hit.primitive_index];
     let n0 = normals[index.x].xyz;
     let n1 = normals[index.y].xyz;
     let n2 = normals[index.z].xyz;
 
     let normal = mat3x3(n0, n1, n2) * barycentrics;
-    let world_normal = normalize(mat3x3(hit.object_to_world[0].xyz, hit.object_to_world[1].xyz, hit.object_to_world[2].xyz) * normal);
+    let world_normal = object_to_world_normal(hit, normal);
 
     var hit_desc = scatter_fn(material, hit.t, seed, world_normal, *direction);
+    if (material.material_model == MATERIAL_MODEL_FLIPBOOK) {
+        let uv = object_uv(hit, object, *origin + hit.t * *direction, normal);
+        hit_desc.color *= flipbook_frame(material, uv);
+    }
+    // light linking, the texture slot of a diffuse light holds its channels (-1 for all of them)
+    if (material.material_model == MATERIAL_MODEL_DIFFUSE_LIGHT && (bitcast<u32>(material.texture) & *receiver_channels) == 0u) {
+        hit_desc.color = vec3(0.0);
+    }
+    *receiver_channels = object.light_channels;
 
     *accumulated_light += hit_desc.color * *throughput;
+    *brdf_pdf = 0.0;
 
     if (material.material_model == MATERIAL_MODEL_LAMBERTIAN) {
         let hit_point = *origin + hit.t * *direction;
+        let shadow_origin = shadow_terminator_origin(hit, index, barycentrics) + world_normal * 0.0001;
+        let flags = RAY_FLAG_TERMINATE_ON_FIRST_HIT;
         let rand1 = random_float(seed);
         let rand2 = random_float(seed);
-        let cos_theta = 1.0 - rand1 * (1.0 - cos(0.00925));
+        let cos_theta = 1.0 - rand1 * (1.0 - cos(light.sun.a));
         let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
         let phi = 2.0 * 3.14 * rand2;
         let up_vector = -light.direction.xyz;
@@ -222,19 +853,46 @@ fn closest_hit(
         let forward_vector = normalize(cross(up_vector, right_vector));
         let basis = mat3x3(right_vector, forward_vector, up_vector);
         let light_direction = basis * vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
-        let light_coefficient = max(light.ambient.y * dot(light_direction, world_normal), light.ambient.x);
+        let terminator = mix(1.0, shadow_terminator_term(hit, index, world_normal, light_direction), camera.terminator_softness);
+        let light_coefficient = max(light.ambient.y * dot(light_direction, world_normal) * terminator, light.ambient.x);
 
         if (light_coefficient > 0.0) {
-            let shadow_origin = hit_point + world_normal * 0.0001;
-            let shadow_direction = light_direction;
-            let flags = RAY_FLAG_TERMINATE_ON_FIRST_HIT | RAY_FLAG_CULL_NO_OPAQUE;
-            let shadow_hit = trace_ray(shadow_origin, shadow_direction, 0.001, 10000.0, flags);
+            // the glass between the surface and the sun tints its light
+            let shadow = shadow_transmittance(shadow_origin, light_direction, 10000.0);
 
-            if (shadow_hit.kind == RAY_QUERY_INTERSECTION_NONE) {
-                let direct_light = hit_desc.albedo * light_coefficient;
+            if (any(shadow > vec3(0.0))) {
+                // the cookie and the glass don't darken the ambient light
+                let direct_light = hit_desc.albedo * max(light_coefficient * shadow * light_cookie(hit_point), vec3(light.ambient.x));
                 *accumulated_light += direct_light * *throughput;
             }
         }
+
+#ifdef SKYBOX
+        // the skybox is sampled by its brightness and the scattered ray by its cosine, both are weighted with
+        // the power heuristic so that bright spots and wide skies converge fast
+        let skybox_sample = sample_skybox(seed);
+        let skybox_direction = skybox_sample.xyz;
+        let cos_theta_skybox = dot(skybox_direction, world_normal);
+
+        if (cos_theta_skybox > 0.0 && skybox_sample.w > 0.0) {
+            let skybox_hit = trace_ray(shadow_origin, skybox_direction, 0.001, 10000.0, flags);
+
+            if (skybox_hit.kind == RAY_QUERY_INTERSECTION_NONE) {
+                let lambertian_pdf = cos_theta_skybox / PI;
+                let weight = power_heuristic(skybox_sample.w, lambertian_pdf);
+                let radiance = skybox_indirect_radiance(skybox_direction);
+                let direct_light = hit_desc.albedo * radiance * lambertian_pdf / skybox_sample.w * weight;
+                *accumulated_light += direct_light * *throughput;
+            }
+        }
+
+        *brdf_pdf = max(dot(hit_desc.scatter_direction, world_normal), 0.0) / PI;
+#endif
+
+        // the indirect light the rest of the path would have found
+        if (gather_probes) {
+            *accumulated_light += hit_desc.albedo * probe_irradiance(hit_point, world_normal) * *throughput;
+        }
     }
 
     *throughput *= hit_desc.albedo;
@@ -247,12 +905,31 @@ fn closest_hit(
 
 fn miss(
     hit: RayIntersection, origin: ptr<function, vec3<f32>>, direction: ptr<function, vec3<f32>>,
-    accumulated_light: ptr<function, vec3<f32>>, throughput: ptr<function, vec3<f32>>
+    accumulated_light: ptr<function, vec3<f32>>, throughput: ptr<function, vec3<f32>>, show_sun: bool, indirect: bool,
+    brdf_pdf: f32
 ) -> bool {
 #ifndef SKYBOX
-    let color = light.sky_color.rgb;
+    var color = light.sky_color.rgb;
+    let sun_enabled = show_sun;
 #else
-    let color = textureSampleLevel(skybox, skybox_sampler, *direction, 0.0).rgb;
+    // the floor only fills the indirect light, the background seen by the camera stays the same
+    var color = select(skybox_radiance(*direction), skybox_indirect_radiance(*direction), indirect);
+    // the skybox was also sampled directly at the last hit, this is the other half of the MIS estimator
+    if (brdf_pdf > 0.0) {
+        color *= power_heuristic(brdf_pdf, skybox_pdf(*direction));
+    }
+    let sun_enabled = show_sun && light.sun_in_skybox != 0u;
+#endif
+
+    if (sun_enabled) {
+        color += sun_disk(*direction);
+    }
+
+#ifndef SKYBOX
+    // the floor only fills the indirect light, the background seen by the camera stays the same
+    if (indirect) {
+        color = max(color, light.min_indirect.rgb);
+    }
 #endif
 
     *accumulated_light += color * *throughput;
@@ -260,6 +937,159 @@ fn miss(
     return false;
 }
 
+#ifdef SKYBOX
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
+
+fn skybox_radiance(direction: vec3<f32>) -> vec3<f32> {
+    let color = textureSampleLevel(skybox, skybox_sampler, direction, 0.0).rgb;
+    var radiance = select(color, srgb_to_linear(color), skybox_decode_srgb != 0u);
+
+    for (var i = 0u; i < SKYBOX_MAX_LAYERS; i++) {
+        let layer = skybox_layers[i];
+        if (layer.flags.z == 0u) {
+            continue;
+        }
+
+        let layer_color = sample_skybox_layer(i, layer.from_world * direction);
+        let layer_radiance = select(layer_color.rgb, srgb_to_linear(layer_color.rgb), layer.flags.y != 0u);
+        if (layer.flags.x == SKYBOX_BLEND_ALPHA) {
+            radiance = mix(radiance, layer_radiance, layer_color.a);
+        } else {
+            radiance += layer_radiance * layer_color.a;
+        }
+    }
+
+    return radiance;
+}
+
+fn sample_skybox_layer(layer: u32, direction: vec3<f32>) -> vec4<f32> {
+    switch layer {
+        case 0u: {
+            return textureSampleLevel(skybox_layer_0, skybox_sampler, direction, 0.0);
+        }
+        case 1u: {
+            return textureSampleLevel(skybox_layer_1, skybox_sampler, direction, 0.0);
+        }
+        default: {
+            return textureSampleLevel(skybox_layer_2, skybox_sampler, direction, 0.0);
+        }
+    }
+}
+
+// radiance of the skybox as seen by the bounces, with VoxelLight::min_indirect as floor
+fn skybox_indirect_radiance(direction: vec3<f32>) -> vec3<f32> {
+    return max(skybox_radiance(direction), light.min_indirect.rgb);
+}
+
+// position of a direction on the equirectangular grid of the distribution, in [0, 1]
+fn skybox_direction_uv(direction: vec3<f32>) -> vec2<f32> {
+    let theta = acos(clamp(direction.y, -1.0, 1.0));
+    var phi = atan2(direction.z, direction.x);
+    if (phi < 0.0) {
+        phi += 2.0 * PI;
+    }
+
+    return vec2(phi / (2.0 * PI), theta / PI);
+}
+
+// pdf of sample_skybox picking the direction, per unit of solid angle
+fn skybox_pdf(direction: vec3<f32>) -> f32 {
+    let uv = skybox_direction_uv(direction);
+    let column = min(u32(uv.x * f32(SKYBOX_DISTRIBUTION_WIDTH)), SKYBOX_DISTRIBUTION_WIDTH - 1u);
+    let row = min(u32(uv.y * f32(SKYBOX_DISTRIBUTION_HEIGHT)), SKYBOX_DISTRIBUTION_HEIGHT - 1u);
+    let sin_theta = sqrt(max(1.0 - direction.y * direction.y, 0.0));
+
+    if (skybox_distribution.total <= 0.0 || sin_theta <= 0.0001) {
+        return 0.0;
+    }
+
+    let weight = skybox_distribution.weights[row * SKYBOX_DISTRIBUTION_WIDTH + column];
+    let cells = f32(SKYBOX_DISTRIBUTION_WIDTH * SKYBOX_DISTRIBUTION_HEIGHT);
+    return weight * cells / skybox_distribution.total / (2.0 * PI * PI * sin_theta);
+}
+
+// picks a direction with a probability proportional to the brightness of the skybox, the pdf is in w
+fn sample_skybox(seed: ptr<function, u32>) -> vec4<f32> {
+    let rand1 = random_float(seed);
+    let rand2 = random_float(seed);
+
+    // first row whose CDF goes over the random number
+    var low = 0u;
+    var high = SKYBOX_DISTRIBUTION_HEIGHT - 1u;
+    while (low < high) {
+        let middle = (low + high) / 2u;
+        if (skybox_distribution.marginal_cdf[middle] > rand1) {
+            high = middle;
+        } else {
+            low = middle + 1u;
+        }
+    }
+    let row = low;
+
+    let start = row * SKYBOX_DISTRIBUTION_WIDTH;
+    low = 0u;
+    high = SKYBOX_DISTRIBUTION_WIDTH - 1u;
+    while (low < high) {
+        let middle = (low + high) / 2u;
+        if (skybox_distribution.conditional_cdf[start + middle] > rand2) {
+            high = middle;
+        } else {
+            low = middle + 1u;
+        }
+    }
+    let column = low;
+
+    // the random numbers are reused to pick a point inside the cell
+    let row_start = select(0.0, skybox_distribution.marginal_cdf[max(row, 1u) - 1u], row > 0u);
+    let row_end = skybox_distribution.marginal_cdf[row];
+    let column_start = select(0.0, skybox_distribution.conditional_cdf[start + max(column, 1u) - 1u], column > 0u);
+    let column_end = skybox_distribution.conditional_cdf[start + column];
+    let v = (f32(row) + saturate((rand1 - row_start) / max(row_end - row_start, 0.000001))) / f32(SKYBOX_DISTRIBUTION_HEIGHT);
+    let u = (f32(column) + saturate((rand2 - column_start) / max(column_end - column_start, 0.000001))) / f32(SKYBOX_DISTRIBUTION_WIDTH);
+
+    let theta = v * PI;
+    let phi = u * 2.0 * PI;
+    let direction = vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+
+    return vec4(direction, skybox_pdf(direction));
+}
+
+// Veach 1997 "Robust Monte Carlo Methods for Light Transport Simulation"
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    return select(0.0, a / (a + b), a + b > 0.0);
+}
+#endif
+
+// attenuation of the direct light at a world position by the texel of the cookie it is projected on
+fn light_cookie(position: vec3<f32>) -> vec3<f32> {
+    if (light.cookie == 0u) {
+        return vec3(1.0);
+    }
+
+    let uv = (light.cookie_projection * vec4(position, 1.0)).xy;
+    return textureSampleLevel(cookie_texture, cookie_sampler, uv, 0.0).rgb;
+}
+
+// radiance of the sun disk in the given direction, with limb darkening towards the edge of the disk
+fn sun_disk(direction: vec3<f32>) -> vec3<f32> {
+    let sun_direction = -normalize(light.direction.xyz);
+    let angle = acos(clamp(dot(normalize(direction), sun_direction), -1.0, 1.0));
+
+    if (angle >= light.sun.a) {
+        return vec3(0.0);
+    }
+
+    let distance = angle / light.sun.a;
+    let limb_darkening = 1.0 - 0.6 * (1.0 - sqrt(1.0 - distance * distance));
+    return light.sun.rgb * limb_darkening;
+}
+
 fn init_random_seed(val0: u32, val1: u32) -> u32 {
     var v0 = val0;
     var v1 = val1;
@@ -312,6 +1142,11 @@ fn random_in_unit_sphere(seed: ptr<function, u32>) -> vec3<f32> {
     return normalize(vec3(1.0));
 }
 
+// uniformly distributed on the sphere, added to a normal it gives a cosine distribution on the hemisphere
+fn random_unit_vector(seed: ptr<function, u32>) -> vec3<f32> {
+    return normalize(random_in_unit_sphere(seed));
+}
+
 fn schlick(cosine: f32, refraction_index: f32) -> f32 {
     var r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
     r0 *= r0;
@@ -321,7 +1156,7 @@ fn schlick(cosine: f32, refraction_index: f32) -> f32 {
 fn scatter_lambertian(material: Material, t: f32, seed: ptr<function, u32>, normal: vec3<f32>, direction: vec3<f32>) -> HitDesc {
     let scatter = dot(direction, normal) < 0.0;
     let color = material.diffuse.rgb;
-    let scatter_direction = normal + random_in_unit_sphere(seed);
+    let scatter_direction = normal + random_unit_vector(seed);
 
     return HitDesc(vec3(0.0), normalize(scatter_direction), scatter, color);
 }
@@ -349,33 +1184,158 @@ fn scatter_dielectric(material: Material, t: f32, seed: ptr<function, u32>, norm
         ni_over_nt = material.refraction_index;
     }
 
-    var cosine = -dot_value;
-    if (dot_value > 0.0) {
-        cosine = material.refraction_index * dot_value;
-    }
+    let cos_theta = min(abs(dot_value), 1.0);
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
 
-    let refracted = refract(direction, outward_normal, ni_over_nt);
+    // past the critical angle the ray can't leave the denser medium and is always reflected (total internal
+    // reflection), refract would return a zero vector
     var reflect_probability = 1.0;
-    if (any(refracted != vec3(0.0))) {
+    if (ni_over_nt * sin_theta <= 1.0) {
+        // Schlick's approximation needs the cosine on the side of the less dense medium, which is the
+        // transmitted ray when leaving the material; its argument must stay in 0..1 or pow returns NaN
+        var cosine = cos_theta;
+        if (dot_value > 0.0) {
+            cosine = sqrt(1.0 - ni_over_nt * ni_over_nt * sin_theta * sin_theta);
+        }
         reflect_probability = schlick(cosine, material.refraction_index);
     }
 
+    let refracted = refract(direction, outward_normal, ni_over_nt);
+
     let color = material.diffuse.rgb;
 
     var scatter_direction = refracted;
-    if (random_float(seed) < reflect_probability) {
+    // right at the critical angle the rounding can still make refract fail
+    if (random_float(seed) < reflect_probability || all(refracted == vec3(0.0))) {
         scatter_direction = reflect(direction, normal);
     }
 
     return HitDesc(vec3(0.0), normalize(scatter_direction), scatter, color);
 }
 
+// texel of the current frame of a flipbook material, the layers past the end of the image are white
+fn flipbook_frame(material: Material, uv: vec2<f32>) -> vec3<f32> {
+    let layers = textureNumLayers(flipbook_texture);
+    let frames = max(u32(material.fuzziness), 1u);
+    let frame = u32(floor(globals.time * material.refraction_index)) % frames;
+    let layer = u32(max(material.texture, 0)) + frame;
+
+    if (layer >= layers) {
+        return vec3(1.0);
+    }
+
+    return textureSampleLevel(flipbook_texture, flipbook_sampler, uv, layer, 0.0).rgb;
+}
+
 fn scatter_diffuse_light(material: Material, t: f32, seed: ptr<function, u32>) -> HitDesc {
     let color = material.diffuse.rgb;
 
     return HitDesc(color, vec3(0.0), false, vec3(0.0));
 }
 
+// thickness is stored in material.fuzziness and the film's refraction index in material.refraction_index
+fn scatter_thin_film(material: Material, t: f32, seed: ptr<function, u32>, normal: vec3<f32>, direction: vec3<f32>) -> HitDesc {
+    let thickness = material.fuzziness;
+    let film_ior = max(material.refraction_index, 1.0);
+
+    let cos_i = min(abs(dot(direction, normal)), 1.0);
+    let sin_t2 = (1.0 - cos_i * cos_i) / (film_ior * film_ior);
+    let cos_t = sqrt(max(1.0 - sin_t2, 0.0));
+
+    // the film is surrounded by air on both sides, so the second interface is the first one reversed
+    let rs12 = (cos_i - film_ior * cos_t) / (cos_i + film_ior * cos_t);
+    let rp12 = (film_ior * cos_i - cos_t) / (film_ior * cos_i + cos_t);
+    let rs23 = -rs12;
+    let rp23 = -rp12;
+
+    let phase = 4.0 * PI * film_ior * thickness * cos_t / THIN_FILM_WAVELENGTHS;
+    let cos_phase = cos(phase);
+
+    let reflectance_s = thin_film_reflectance(rs12, rs23, cos_phase);
+    let reflectance_p = thin_film_reflectance(rp12, rp23, cos_phase);
+    let reflectance = clamp((reflectance_s + reflectance_p) * 0.5, vec3(0.0), vec3(1.0));
+    let reflect_probability = clamp((reflectance.r + reflectance.g + reflectance.b) / 3.0, 0.001, 0.999);
+
+    if (random_float(seed) < reflect_probability) {
+        let color = reflectance / reflect_probability;
+        return HitDesc(vec3(0.0), normalize(reflect(direction, normal)), true, color);
+    }
+
+    // the film is thin enough to not bend the transmitted light
+    let color = material.diffuse.rgb * (vec3(1.0) - reflectance) / (1.0 - reflect_probability);
+    return HitDesc(vec3(0.0), direction, true, color);
+}
+
+// the roughness is stored in material.fuzziness and the metallic factor in material.refraction_index.
+// one lobe is picked at random (GGX specular or diffuse) and its weight is divided by the probability of picking it;
+// the sun isn't sampled directly like for lambertian materials, it's found by the rays hitting its disk
+fn scatter_pbr(material: Material, t: f32, seed: ptr<function, u32>, normal: vec3<f32>, direction: vec3<f32>) -> HitDesc {
+    let base_color = material.diffuse.rgb;
+    let metallic = material.refraction_index;
+    // a perfectly smooth GGX lobe is a delta, which the sampling below can't represent
+    let roughness = max(material.fuzziness, 0.02);
+    let alpha = roughness * roughness;
+
+    let view_direction = -direction;
+    let n_dot_v = dot(normal, view_direction);
+    if (n_dot_v <= 0.0) {
+        return HitDesc(vec3(0.0), vec3(0.0), false, vec3(0.0));
+    }
+
+    let f0 = mix(vec3(0.04), base_color, metallic);
+    let fresnel_view = f0 + (vec3(1.0) - f0) * pow(1.0 - n_dot_v, 5.0);
+    let specular_probability = clamp(max((fresnel_view.r + fresnel_view.g + fresnel_view.b) / 3.0, metallic), 0.05, 0.95);
+
+    if (random_float(seed) < specular_probability) {
+        // samples the half vector from the GGX distribution around the normal
+        let rand1 = random_float(seed);
+        let rand2 = random_float(seed);
+        let cos_theta = sqrt((1.0 - rand1) / (1.0 + (alpha * alpha - 1.0) * rand1));
+        let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+        let phi = 2.0 * PI * rand2;
+
+        var helper = vec3(1.0, 0.0, 0.0);
+        if (abs(normal.x) > 0.9) {
+            helper = vec3(0.0, 1.0, 0.0);
+        }
+        let tangent = normalize(cross(helper, normal));
+        let bitangent = cross(normal, tangent);
+        let half_vector = normalize(tangent * cos(phi) * sin_theta + bitangent * sin(phi) * sin_theta + normal * cos_theta);
+
+        let scatter_direction = reflect(direction, half_vector);
+        let n_dot_l = dot(normal, scatter_direction);
+        if (n_dot_l <= 0.0) {
+            return HitDesc(vec3(0.0), vec3(0.0), false, vec3(0.0));
+        }
+
+        let n_dot_h = max(dot(normal, half_vector), 0.0001);
+        let v_dot_h = max(dot(view_direction, half_vector), 0.0001);
+        let fresnel = f0 + (vec3(1.0) - f0) * pow(1.0 - v_dot_h, 5.0);
+
+        // Smith shadowing with the Schlick-GGX approximation
+        let k = alpha / 2.0;
+        let g_view = n_dot_v / (n_dot_v * (1.0 - k) + k);
+        let g_light = n_dot_l / (n_dot_l * (1.0 - k) + k);
+
+        // the distribution cancels out with the probability of sampling the half vector
+        let color = fresnel * g_view * g_light * v_dot_h / (n_dot_v * n_dot_h) / specular_probability;
+        return HitDesc(vec3(0.0), scatter_direction, true, color);
+    }
+
+    // metals have no diffuse lobe, the light not reflected by dielectrics is scattered like a lambertian surface
+    let color = (vec3(1.0) - fresnel_view) * (1.0 - metallic) * base_color / (1.0 - specular_probability);
+    let scatter_direction = normal + random_unit_vector(seed);
+
+    return HitDesc(vec3(0.0), normalize(scatter_direction), true, color);
+}
+
+// Airy summation of the reflections inside a thin film
+fn thin_film_reflectance(r12: f32, r23: f32, cos_phase: vec3<f32>) -> vec3<f32> {
+    let numerator = r12 * r12 + r23 * r23 + 2.0 * r12 * r23 * cos_phase;
+    let denominator = 1.0 + r12 * r12 * r23 * r23 + 2.0 * r12 * r23 * cos_phase;
+    return numerator / denominator;
+}
+
 fn scatter_fn(material: Material, t: f32, seed: ptr<function, u32>, normal: vec3<f32>, direction: vec3<f32>) -> HitDesc {
     switch (material.material_model) {
         case 0: {
@@ -394,6 +1354,24 @@ fn scatter_fn(material: Material, t: f32, seed: ptr<function, u32>, normal: vec3
             return scatter_diffuse_light(material, t, seed);
         }
 
+        case 5: {
+            return scatter_thin_film(material, t, seed, normal, direction);
+        }
+
+        case 6: {
+            return scatter_pbr(material, t, seed, normal, direction);
+        }
+
+        // the frame of the flipbook is applied by closest_hit, which has the texture coordinates
+        case 7: {
+            return scatter_diffuse_light(material, t, seed);
+        }
+
+        // the paths pick a lobe with glossy_lobe, only the contact shading scatters glossy materials
+        case 9: {
+            return scatter_lambertian(material, t, seed, normal, direction);
+        }
+
         default: {
             return HitDesc(vec3(1.0, 0.0, 1.0), vec3(0.0), false, vec3(0.0));
         }
@@ -9,6 +9,11 @@ const MSIZE: u32 = 15;
 @group(0) @binding(0) var view_output: texture_storage_2d<rgba16float, write>;
 @group(0) @binding(1) var view_input: texture_storage_2d<rgba16float, read>;
 @group(0) @binding(2) var<uniform> view: View;
+// where the viewport starts in the output, zero when the output is as large as the viewport
+@group(0) @binding(3) var<uniform> output_offset: vec2<u32>;
+// how much of every pixel comes from specular paths and emissive surfaces, that part isn't blurred
+@group(0) @binding(4) var detail_texture: texture_storage_2d<r32float, read>;
+@group(0) @binding(5) var<uniform> detail_strength: f32;
 
 @compute @workgroup_size(8, 8, 1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
@@ -16,7 +21,8 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         return;
     }
 
-    let original_color = textureLoad(view_input, global_id.xy).rgb;
+    // the sharp part of the light is taken out before filtering and added back after
+    let original_color = diffuse_color(global_id.xy);
 
     let k_size = (MSIZE - 1) / 2;
     var kernel = array<f32, MSIZE>();
@@ -34,14 +40,23 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let bZ = 1.0 / normpdf(0.0, BSIGMA);
     for (var i = -i32(k_size); i <= i32(k_size); i++) {
         for (var j = -i32(k_size); j <= i32(k_size); j++) {
-            color = textureLoad(view_input, vec2<u32>(vec2<f32>(global_id.xy) + vec2(f32(i), f32(j)))).rgb;
+            color = diffuse_color(vec2<u32>(vec2<f32>(global_id.xy) + vec2(f32(i), f32(j))));
             factor = normpdf3(color - original_color, BSIGMA) * bZ * kernel[u32(i32(k_size) + j)] * kernel[u32(i32(k_size) + i)];
             Z += factor;
             final_color += color * factor;
         }
     }
 
-    textureStore(view_output, global_id.xy, vec4(final_color / Z, 1.0));
+    textureStore(view_output, global_id.xy + output_offset, vec4(final_color / Z + sharp_color(global_id.xy), 1.0));
+}
+
+fn sharp_color(uv: vec2<u32>) -> vec3<f32> {
+    let detail = saturate(textureLoad(detail_texture, uv).r * detail_strength);
+    return textureLoad(view_input, uv).rgb * detail;
+}
+
+fn diffuse_color(uv: vec2<u32>) -> vec3<f32> {
+    return textureLoad(view_input, uv).rgb - sharp_color(uv);
 }
 
 fn normpdf(x: f32, sigma: f32) -> f32 {
@@ -0,0 +1,79 @@
+const WIDTH: u32 = 64u;
+const HEIGHT: u32 = 32u;
+const PI = 3.14159265;
+// keeps every direction reachable, so a black part of the skybox can still be sampled
+const MIN_LUMINANCE = 0.001;
+
+struct SkyboxDistribution {
+    // luminance of every cell times the solid angle it covers, row by row
+    weights: array<f32, WIDTH * HEIGHT>,
+    // normalized CDF of the cells in each row
+    conditional_cdf: array<f32, WIDTH * HEIGHT>,
+    // normalized CDF of the rows
+    marginal_cdf: array<f32, HEIGHT>,
+    total: f32,
+}
+
+@group(0) @binding(0) var skybox: texture_cube<f32>;
+@group(0) @binding(1) var skybox_sampler: sampler;
+// 1 when the texels are sRGB-encoded, check VoxelSkybox::skybox_is_srgb
+@group(0) @binding(2) var<uniform> skybox_decode_srgb: u32;
+@group(0) @binding(3) var<storage, read_write> distribution: SkyboxDistribution;
+
+var<workgroup> row_sums: array<f32, HEIGHT>;
+
+// every invocation builds a row of the equirectangular grid, the first one then builds the CDF of the rows
+@compute @workgroup_size(32, 1, 1)
+fn main(@builtin(local_invocation_index) row: u32) {
+    // a cell covers many texels, a smaller mip averages them instead of picking a single one
+    let face_size = f32(textureDimensions(skybox).x);
+    let max_level = f32(textureNumLevels(skybox) - 1u);
+    let level = clamp(log2(face_size / 16.0), 0.0, max_level);
+
+    let theta = (f32(row) + 0.5) / f32(HEIGHT) * PI;
+    let sin_theta = sin(theta);
+    let cos_theta = cos(theta);
+
+    var sum = 0.0;
+    for (var column = 0u; column < WIDTH; column++) {
+        let phi = (f32(column) + 0.5) / f32(WIDTH) * 2.0 * PI;
+        let direction = vec3(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi));
+
+        var color = textureSampleLevel(skybox, skybox_sampler, direction, level).rgb;
+        if (skybox_decode_srgb != 0u) {
+            color = srgb_to_linear(color);
+        }
+        let luminance = max(dot(color, vec3(0.2126, 0.7152, 0.0722)), 0.0);
+
+        let weight = (luminance + MIN_LUMINANCE) * sin_theta;
+        distribution.weights[row * WIDTH + column] = weight;
+        sum += weight;
+        distribution.conditional_cdf[row * WIDTH + column] = sum;
+    }
+
+    for (var column = 0u; column < WIDTH; column++) {
+        distribution.conditional_cdf[row * WIDTH + column] /= sum;
+    }
+    row_sums[row] = sum;
+
+    workgroupBarrier();
+
+    if (row == 0u) {
+        var total = 0.0;
+        for (var i = 0u; i < HEIGHT; i++) {
+            total += row_sums[i];
+            distribution.marginal_cdf[i] = total;
+        }
+
+        for (var i = 0u; i < HEIGHT; i++) {
+            distribution.marginal_cdf[i] /= total;
+        }
+        distribution.total = total;
+    }
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
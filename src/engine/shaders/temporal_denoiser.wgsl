@@ -0,0 +1,52 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var<uniform> view: View;
+@group(0) @binding(1) var view_input: texture_storage_2d<rgba16float, read>;
+// the result of the last frame, the alpha is 0.0 where it was never written
+@group(0) @binding(2) var history_texture: texture_storage_2d<rgba16float, read>;
+// current uv minus the uv of the same point in the last frame
+@group(0) @binding(3) var motion_vectors_texture: texture_storage_2d<rg32float, read>;
+@group(0) @binding(4) var view_output: texture_storage_2d<rgba16float, write>;
+// x: feedback
+// y: history clamp
+@group(0) @binding(5) var<uniform> settings: vec2<f32>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2u(view.viewport.zw);
+    if any(global_id.xy >= size) {
+        return;
+    }
+
+    let current_color = textureLoad(view_input, global_id.xy).rgb;
+
+    // the colors around the pixel in the current frame, the history outside of them belongs to something that
+    // moved away (or was hidden) and would leave a trail behind moving objects
+    var neighborhood_min = current_color;
+    var neighborhood_max = current_color;
+    for (var d_x = -1; d_x <= 1; d_x += 1) {
+        for (var d_y = -1; d_y <= 1; d_y += 1) {
+            let uv = vec2u(clamp(vec2i(global_id.xy) + vec2i(d_x, d_y), vec2i(0), vec2i(size) - vec2i(1)));
+            let color = textureLoad(view_input, uv).rgb;
+            neighborhood_min = min(neighborhood_min, color);
+            neighborhood_max = max(neighborhood_max, color);
+        }
+    }
+
+    let in_uv = (vec2<f32>(global_id.xy) + vec2(0.5)) / vec2<f32>(size);
+    let previous_uv = in_uv - textureLoad(motion_vectors_texture, global_id.xy).xy;
+
+    var color = current_color;
+    // the point wasn't on screen in the last frame, or there's no last frame yet
+    if all(previous_uv >= vec2(0.0)) && all(previous_uv < vec2(1.0)) {
+        let history = textureLoad(history_texture, vec2u(previous_uv * vec2<f32>(size)));
+
+        if history.a > 0.0 {
+            let clamped_history = clamp(history.rgb, neighborhood_min, neighborhood_max);
+            let history_color = mix(history.rgb, clamped_history, settings.y);
+            color = mix(current_color, history_color, settings.x);
+        }
+    }
+
+    textureStore(view_output, global_id.xy, vec4(color, 1.0));
+}
@@ -1,7 +1,27 @@
 //! Skybox module.
 
-use bevy::prelude::{Handle, Image, Resource};
+use crate::ToBytes;
+use bevy::asset::RenderAssetUsages;
+use bevy::asset::{LoadState, load_embedded_asset};
+use bevy::math::{Mat3, Quat, Vec3};
+use bevy::prelude::{AssetServer, Assets, FromWorld, Handle, Image, Res, ResMut, Resource, World};
 use bevy::render::extract_resource::ExtractResource;
+use bevy::render::render_resource::ShaderType;
+use bevy::render::render_resource::binding_types::{
+    sampler, storage_buffer_sized, texture_cube, uniform_buffer,
+};
+use bevy::render::render_resource::encase::internal::{
+    AlignmentValue, BufferMut, WriteInto, Writer,
+};
+use bevy::render::render_resource::encase::private::{Metadata, SizeValue};
+use bevy::render::render_resource::{
+    BindGroupLayout, BindGroupLayoutEntries, Buffer, BufferDescriptor, BufferUsages,
+    CachedComputePipelineId, ComputePipelineDescriptor, PipelineCache, SamplerBindingType,
+    ShaderStages, TextureDataOrder, TextureDimension, TextureFormat, TextureSampleType,
+    TextureViewDescriptor, TextureViewDimension,
+};
+use bevy::render::renderer::RenderDevice;
+use std::num::NonZeroU64;
 
 /// Skybox resource.
 ///
@@ -9,5 +29,412 @@ use bevy::render::extract_resource::ExtractResource;
 /// An easy way to create a DDS cubemap is to use a panorama image, convert it to 6 images (one for each face)
 /// and use GIMP to export those images as a DDS cubemap.
 /// For GIMP, import the images as layers and rename them as `positive x`, `negative x`, `positive y` and so on.
+/// Six images of the faces (e.g. PNGs) can also be used directly with [VoxelSkybox::from_faces].
+///
+/// Check [VoxelSkybox::skybox_is_srgb] to choose how the texels are decoded.
+///
+/// The skybox lights the scene even without a sun: lambertian surfaces sample its bright parts directly, check
+/// [SkyboxDistribution].
+///
+/// More cubemaps can be composited over the image, e.g. a cloud layer scrolling over a starfield, check
+/// [VoxelSkybox::with_layer].
 #[derive(Resource, ExtractResource, Clone, Debug)]
-pub struct VoxelSkybox(pub Handle<Image>);
+pub struct VoxelSkybox {
+    /// The cubemap image.
+    pub image: Handle<Image>,
+    /// Whether the texels are sRGB-encoded and must be decoded to linear when sampled.
+    ///
+    /// When `None` the choice is made from the format of the image: float (HDR) formats are treated as linear,
+    /// every other format as sRGB.
+    ///
+    /// With GIMP's DDS exporter:
+    /// - `RGBA8` and the `BC1`/`BC3` compressions (DXT1/DXT5) store the colors as they are shown in GIMP, which
+    ///   are sRGB, so this should be `true`;
+    /// - the `RGBA16F`/`RGBA32F` formats store linear colors, so this should be `false`;
+    /// - images imported with a linear precision (`Image > Precision > ... linear light`) and exported as `RGBA8`
+    ///   store linear colors, so this should be `false`.
+    ///
+    /// Formats that are already sRGB on the GPU (like `Rgba8UnormSrgb`) are decoded by the sampler, so they are
+    /// never decoded twice.
+    pub skybox_is_srgb: Option<bool>,
+    /// The layers composited over the image, from the bottom to the top. Defaults to none.
+    ///
+    /// Only the first [VoxelSkybox::MAX_LAYERS] layers are drawn.
+    pub layers: Vec<VoxelSkyboxLayer>,
+    /// The faces [VoxelSkybox::image] is assembled from once they're loaded, `None` when the image is already a
+    /// cubemap. Check [VoxelSkybox::from_faces].
+    pub faces: Option<[Handle<Image>; 6]>,
+}
+
+impl VoxelSkybox {
+    /// The maximum number of layers over the image.
+    pub const MAX_LAYERS: usize = 3;
+
+    pub fn new(image: Handle<Image>) -> Self {
+        Self {
+            image,
+            skybox_is_srgb: None,
+            layers: vec![],
+            faces: None,
+        }
+    }
+
+    /// Creates a skybox from the six images of the faces of a cube, in the order of the layers of a cubemap:
+    /// positive x, negative x, positive y, negative y, positive z and negative z.
+    /// ```rs
+    /// let faces = ["px", "nx", "py", "ny", "pz", "nz"].map(|face| asset_server.load(format!("sky/{face}.png")));
+    /// commands.insert_resource(VoxelSkybox::from_faces(faces, &images));
+    /// ```
+    ///
+    /// The cubemap is assembled by [assemble_skybox_faces] once every face is loaded, until then nothing is
+    /// drawn. The faces must be square 2D images with the same size, format and mip levels and they must keep
+    /// their data in the main world (the default for loaded images), otherwise an error is printed and the
+    /// skybox stays empty.
+    pub fn from_faces(faces: [Handle<Image>; 6], images: &Assets<Image>) -> Self {
+        let mut skybox = Self::new(images.reserve_handle());
+        skybox.faces = Some(faces);
+        skybox
+    }
+
+    pub fn with_srgb(mut self, skybox_is_srgb: bool) -> Self {
+        self.skybox_is_srgb = Some(skybox_is_srgb);
+        self
+    }
+
+    /// Adds a layer on top of the others:
+    /// ```rs
+    /// let skybox = VoxelSkybox::new(asset_server.load("stars.ktx2")).with_layer(
+    ///     VoxelSkyboxLayer::new(asset_server.load("clouds.ktx2"), VoxelSkyboxBlend::Alpha)
+    ///         .with_scroll(Vec3::Y * 0.01),
+    /// );
+    /// ```
+    pub fn with_layer(mut self, layer: VoxelSkyboxLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Whether a layer scrolls, which changes the lighting over time.
+    pub fn is_animated(&self) -> bool {
+        self.layers
+            .iter()
+            .take(Self::MAX_LAYERS)
+            .any(|layer| layer.scroll != Vec3::ZERO)
+    }
+
+    /// Returns whether the shader has to decode the texels of an image with the given format from sRGB to linear.
+    pub fn decode_srgb(&self, format: TextureFormat) -> bool {
+        decode_srgb(self.skybox_is_srgb, format)
+    }
+}
+
+/// How a [VoxelSkyboxLayer] is composited over the layers below it.
+///
+/// Defaults to [VoxelSkyboxBlend::Additive].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoxelSkyboxBlend {
+    /// The color of the layer, scaled by its alpha, is added to the layers below, e.g. for stars or auroras
+    /// over a sky.
+    #[default]
+    Additive = 0,
+    /// The color of the layer covers the layers below as much as its alpha, e.g. for clouds.
+    Alpha = 1,
+}
+
+/// A cubemap composited over a [VoxelSkybox].
+///
+/// The layer is sampled with the sampler of the image of the skybox. Only the image of the skybox is importance
+/// sampled (check [SkyboxDistribution]), the light of the layers is still found by the scattered rays but with
+/// more noise, so the brightest parts of the sky should be in the image of the skybox.
+#[derive(Clone, Debug)]
+pub struct VoxelSkyboxLayer {
+    /// The cubemap image, its alpha is used by the blend mode.
+    pub image: Handle<Image>,
+    /// Whether the texels are sRGB-encoded, check [VoxelSkybox::skybox_is_srgb].
+    pub skybox_is_srgb: Option<bool>,
+    /// How the layer is composited over the layers below it.
+    pub blend: VoxelSkyboxBlend,
+    /// The rotation of the layer. Defaults to identity.
+    pub rotation: Quat,
+    /// How the layer turns over time, as the axis of the rotation scaled by its speed in radians per second.
+    /// Defaults to zero.
+    ///
+    /// **Note:** A scrolling layer changes the lighting of the scene, so the accumulation restarts every frame.
+    pub scroll: Vec3,
+}
+
+impl VoxelSkyboxLayer {
+    pub fn new(image: Handle<Image>, blend: VoxelSkyboxBlend) -> Self {
+        Self {
+            image,
+            skybox_is_srgb: None,
+            blend,
+            rotation: Quat::IDENTITY,
+            scroll: Vec3::ZERO,
+        }
+    }
+
+    pub fn with_srgb(mut self, skybox_is_srgb: bool) -> Self {
+        self.skybox_is_srgb = Some(skybox_is_srgb);
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Quat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scroll(mut self, scroll: Vec3) -> Self {
+        self.scroll = scroll;
+        self
+    }
+
+    /// Returns whether the shader has to decode the texels of an image with the given format from sRGB to linear.
+    pub fn decode_srgb(&self, format: TextureFormat) -> bool {
+        decode_srgb(self.skybox_is_srgb, format)
+    }
+
+    /// The rotation of the layer after `seconds`.
+    pub fn rotation_at(&self, seconds: f32) -> Quat {
+        Quat::from_scaled_axis(self.scroll * seconds) * self.rotation
+    }
+}
+
+fn decode_srgb(skybox_is_srgb: Option<bool>, format: TextureFormat) -> bool {
+    // the sampler already decodes sRGB formats
+    if format.is_srgb() {
+        return false;
+    }
+
+    skybox_is_srgb.unwrap_or(!matches!(
+        format,
+        TextureFormat::R16Float
+            | TextureFormat::R32Float
+            | TextureFormat::Rg16Float
+            | TextureFormat::Rg32Float
+            | TextureFormat::Rgba16Float
+            | TextureFormat::Rgba32Float
+            | TextureFormat::Rgb9e5Ufloat
+            | TextureFormat::Rg11b10Ufloat
+            | TextureFormat::Bc6hRgbUfloat
+            | TextureFormat::Bc6hRgbFloat
+    ))
+}
+
+/// Assembles the cubemap of a [VoxelSkybox] created with [VoxelSkybox::from_faces] once all of its faces are
+/// loaded, copying the faces into the layers of [VoxelSkybox::image].
+///
+/// When a face fails to load the error is printed and the faces are dropped, the skybox stays without an image.
+pub fn assemble_skybox_faces(
+    mut skybox: ResMut<VoxelSkybox>,
+    mut images: ResMut<Assets<Image>>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(faces) = &skybox.faces else {
+        return;
+    };
+    // a face that failed to load never shows up in the assets, the skybox would wait for it forever
+    let failed =
+        faces
+            .iter()
+            .enumerate()
+            .find_map(|(index, face)| match asset_server.load_state(face) {
+                LoadState::Failed(error) => Some((index, error)),
+                _ => None,
+            });
+    if let Some((index, error)) = failed {
+        eprintln!(
+            "Face {index} of the VoxelSkybox failed to load, the skybox can't be assembled: {error}"
+        );
+        skybox.faces = None;
+        return;
+    }
+    let Some(faces) = faces
+        .iter()
+        .map(|face| images.get(face))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+
+    let cubemap = cubemap_from_faces(&faces);
+    let image = skybox.image.clone();
+    skybox.faces = None;
+    match cubemap {
+        Ok(cubemap) => {
+            // the handle was reserved by VoxelSkybox::from_faces, it can't be stale
+            let _ = images.insert(&image, cubemap);
+        }
+        Err(error) => {
+            eprintln!("The faces of the VoxelSkybox can't be assembled into a cubemap: {error}")
+        }
+    }
+}
+
+fn cubemap_from_faces(faces: &[&Image]) -> Result<Image, String> {
+    let first = faces[0];
+    let descriptor = &first.texture_descriptor;
+    let size = descriptor.size;
+    if size.width != size.height
+        || size.depth_or_array_layers != 1
+        || descriptor.dimension != TextureDimension::D2
+    {
+        return Err(format!(
+            "the faces must be square 2D images, the first one is {}x{}x{}",
+            size.width, size.height, size.depth_or_array_layers
+        ));
+    }
+
+    let mut data = Vec::with_capacity(first.data.as_ref().map_or(0, Vec::len) * faces.len());
+    for (index, face) in faces.iter().enumerate() {
+        let face_descriptor = &face.texture_descriptor;
+        if face_descriptor.size != size
+            || face_descriptor.format != descriptor.format
+            || face_descriptor.mip_level_count != descriptor.mip_level_count
+            || face_descriptor.dimension != descriptor.dimension
+        {
+            return Err(format!(
+                "face {index} is a {:?} {}x{}x{} image with {} mip levels, while the first face is a {:?} {}x{}x{} image with {} mip levels",
+                face_descriptor.format,
+                face_descriptor.size.width,
+                face_descriptor.size.height,
+                face_descriptor.size.depth_or_array_layers,
+                face_descriptor.mip_level_count,
+                descriptor.format,
+                size.width,
+                size.height,
+                size.depth_or_array_layers,
+                descriptor.mip_level_count,
+            ));
+        }
+        let Some(face_data) = &face.data else {
+            return Err(format!(
+                "face {index} has no data in the main world, load it with RenderAssetUsages::MAIN_WORLD"
+            ));
+        };
+        // every face is a single layer, so with the mip levels of a layer stored together they're just
+        // concatenated
+        match face.data_order {
+            TextureDataOrder::LayerMajor => data.extend_from_slice(face_data),
+            TextureDataOrder::MipMajor if face_descriptor.mip_level_count == 1 => {
+                data.extend_from_slice(face_data)
+            }
+            TextureDataOrder::MipMajor => {
+                return Err(format!(
+                    "face {index} stores its mip levels in mip-major order, which isn't supported"
+                ));
+            }
+        }
+    }
+
+    let mut cubemap = first.clone();
+    cubemap.data = Some(data);
+    cubemap.data_order = TextureDataOrder::LayerMajor;
+    cubemap.texture_descriptor.size.depth_or_array_layers = 6;
+    cubemap.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::Cube),
+        ..Default::default()
+    });
+    cubemap.asset_usage = RenderAssetUsages::RENDER_WORLD;
+    Ok(cubemap)
+}
+
+/// The layers of a [VoxelSkybox] as written in the uniform of the shader, the unused layers are zeroed.
+#[derive(Default)]
+pub struct RenderSkyboxLayers {
+    /// Moves a world direction into the space of every layer.
+    pub from_world: [Mat3; VoxelSkybox::MAX_LAYERS],
+    /// The blend mode, whether the texels are decoded from sRGB and whether the layer is used, w is unused.
+    pub flags: [[u32; 4]; VoxelSkybox::MAX_LAYERS],
+}
+
+impl ShaderType for RenderSkyboxLayers {
+    type ExtraMetadata = ();
+    const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
+        alignment: AlignmentValue::new(16),
+        has_uniform_min_alignment: false,
+        // a mat3x3 (3 columns padded to 16 bytes) and a vec4<u32> per layer
+        min_size: SizeValue::new(64 * VoxelSkybox::MAX_LAYERS as u64),
+        is_pod: false,
+        extra: (),
+    };
+}
+
+impl WriteInto for RenderSkyboxLayers {
+    fn write_into<B>(&self, writer: &mut Writer<B>)
+    where
+        B: BufferMut,
+    {
+        for (from_world, flags) in self.from_world.iter().zip(&self.flags) {
+            for column in from_world.to_cols_array_2d() {
+                writer.write_slice(column.to_bytes());
+                writer.write_slice(&[0; 4]);
+            }
+            writer.write_slice(flags.to_bytes());
+        }
+    }
+}
+
+/// Width of the grid the skybox is projected on to importance sample it.
+pub const SKYBOX_DISTRIBUTION_WIDTH: u64 = 64;
+/// Height of the grid the skybox is projected on to importance sample it.
+pub const SKYBOX_DISTRIBUTION_HEIGHT: u64 = 32;
+/// Size in bytes of the distribution: the weight and the CDF of every cell, the CDF of every row and the total.
+pub const SKYBOX_DISTRIBUTION_SIZE: u64 =
+    (SKYBOX_DISTRIBUTION_WIDTH * SKYBOX_DISTRIBUTION_HEIGHT * 2 + SKYBOX_DISTRIBUTION_HEIGHT + 1)
+        * 4;
+
+/// The distribution used to importance sample the skybox, rebuilt on the GPU before tracing every frame.
+///
+/// The skybox is projected on an equirectangular grid where every cell has a weight proportional to its
+/// brightness, lambertian surfaces shoot a shadow ray towards a direction picked with these weights and combine
+/// it with the scattered ray through multiple importance sampling, so HDR skyboxes light the scene with less noise.
+#[derive(Resource)]
+pub struct SkyboxDistribution {
+    pub buffer: Buffer,
+    pub pipeline: CachedComputePipelineId,
+    pub bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for SkyboxDistribution {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("voxel_skybox_distribution"),
+            size: SKYBOX_DISTRIBUTION_SIZE,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = render_device.create_bind_group_layout(
+            "voxel_skybox_distribution_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // Skybox texture
+                    texture_cube(TextureSampleType::Float { filterable: true }),
+                    // Sampler
+                    sampler(SamplerBindingType::Filtering),
+                    // Whether to decode the texels from sRGB
+                    uniform_buffer::<u32>(false),
+                    // Distribution
+                    storage_buffer_sized(false, NonZeroU64::new(SKYBOX_DISTRIBUTION_SIZE)),
+                ),
+            ),
+        );
+
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_skybox_distribution_pipeline".into()),
+            layout: vec![bind_group_layout.clone()],
+            shader: load_embedded_asset!(world, "shaders/skybox_distribution.wgsl"),
+            ..Default::default()
+        });
+
+        Self {
+            buffer,
+            pipeline,
+            bind_group_layout,
+        }
+    }
+}
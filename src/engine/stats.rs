@@ -0,0 +1,63 @@
+//! This module contains the statistics about the complexity of the scene.
+
+use bevy::prelude::{Res, ResMut, Resource};
+use std::sync::{Arc, Mutex};
+
+/// Counters describing how complex the scene traced by NEVR is, useful to find out why a scene is slow
+/// (e.g. a type with millions of triangles).
+///
+/// It is updated in the render world while preparing the frame and copied to the main world at the start of
+/// the next frame, so in the main world it's one frame late:
+/// ```rs
+/// fn print_stats(stats: Res<NEVRStats>) {
+///     println!("{} triangles in {} instances", stats.triangles, stats.tlas_instances);
+/// }
+/// ```
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NEVRStats {
+    /// Triangles of the geometry of all the types built so far, every type is counted once however many blocks
+    /// use it.
+    pub triangles: u32,
+    /// The BLASes currently built, one for every visible type (and level of detail).
+    pub blas_count: u32,
+    /// The BLASes waiting to be compacted.
+    pub compaction_queue: u32,
+    /// The instances in the TLAS built in the last frame, one for every block and every instance of a
+    /// [crate::engine::voxel::VoxelBlockInstances].
+    pub tlas_instances: u32,
+    /// The instances of the TLAS written in the last frame, 0 when no block was spawned, moved, hidden, shown
+    /// or despawned. The TLAS is built again only in the frames where some were written.
+    pub tlas_writes: u32,
+    /// The [crate::engine::voxel::VoxelMaterial]s uploaded to the GPU, every material is counted once however
+    /// many voxels use it.
+    pub materials: u32,
+    /// The samples baked so far for every probe of [crate::engine::probes::NEVRBakeProbes], 0 when there are
+    /// no probes.
+    pub probe_samples: u32,
+    /// The types whose geometry (and BLAS) was built in the last frame, 0 in the frames where no type was added
+    /// or changed.
+    pub geometry_rebuilds: u32,
+    /// The pipelines of the ray tracing and the denoisers that are still compiling. It's also 0 before the
+    /// render world sends the first stats, check [NEVRStats::pipelines_ready].
+    pub pipelines_compiling: u32,
+    /// Whether the pipelines of the ray tracing and the denoisers are done compiling. Nothing is rendered in the
+    /// first frames after the app starts, until their shaders are loaded and compiled: it's false until then,
+    /// so it can be used to keep a loading screen up during the warm-up. Defaults to false.
+    pub pipelines_ready: bool,
+}
+
+/// Moves [NEVRStats] from the render world to the main world.
+#[derive(Resource, Clone, Default)]
+pub(crate) struct NEVRStatsChannel(Arc<Mutex<NEVRStats>>);
+
+pub(crate) fn send_stats(stats: Res<NEVRStats>, channel: Res<NEVRStatsChannel>) {
+    *channel.0.lock().unwrap() = *stats;
+}
+
+pub(crate) fn receive_stats(mut stats: ResMut<NEVRStats>, channel: Res<NEVRStatsChannel>) {
+    let received = *channel.0.lock().unwrap();
+    // only written on changes, so systems can use change detection
+    if *stats != received {
+        *stats = received;
+    }
+}
@@ -0,0 +1,151 @@
+//! This module contains the status used to report problems found while rendering.
+
+use crate::engine::stats::NEVRStats;
+use bevy::platform::collections::HashMap;
+use bevy::platform::time::Instant;
+use bevy::prelude::{Res, ResMut, Resource};
+use bevy::render::render_resource::{CachedComputePipelineId, CachedPipelineState, PipelineCache};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A problem that prevented NEVR from rendering a frame (or part of it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NEVRWarning {
+    /// The view uniforms weren't written for this frame.
+    MissingViewUniforms,
+    /// The camera doesn't have a physical viewport size.
+    MissingViewport,
+    /// The bind group with the scene's geometry wasn't created.
+    MissingBindGroup,
+    /// The skybox image isn't loaded on the GPU.
+    MissingSkyboxImage,
+    /// The geometry was built, but one of the buffers needed to render it is missing.
+    MissingGeometryBuffers,
+    /// There are more blocks and instances than the TLAS can hold, the ones over the limit aren't rendered.
+    TooManyInstances,
+    /// More than 90% of the materials the GPU can hold are used.
+    MaterialsNearLimit,
+    /// There are more materials than the GPU can hold, the ones over the limit aren't uploaded.
+    TooManyMaterials,
+    /// The ray tracing shader or the shader of a denoiser failed to compile.
+    InvalidShader,
+    /// A type has more triangles than a BLAS can hold, its blocks aren't rendered.
+    TypeTooLarge,
+    /// A type has voxels outside of its grid or overlapping other voxels, check [VoxelType::validate].
+    ///
+    /// [VoxelType::validate]: crate::engine::voxel::VoxelType::validate
+    InvalidVoxels,
+}
+
+impl NEVRWarning {
+    /// A description of the problem and its likely causes.
+    pub fn message(&self) -> &'static str {
+        match self {
+            NEVRWarning::MissingViewUniforms => {
+                "no view uniforms: the camera may not have been extracted yet or its viewport may be zero-sized"
+            }
+            NEVRWarning::MissingViewport => {
+                "no viewport size: the render target of the camera may be minimized or zero-sized"
+            }
+            NEVRWarning::MissingBindGroup => {
+                "no bind group: no visible block has been prepared yet, check that VoxelBlocks are spawned and that their VoxelTypes and VoxelMaterials are added to the assets"
+            }
+            NEVRWarning::MissingSkyboxImage => {
+                "no skybox image: the image of VoxelSkybox is still loading or isn't a valid cubemap"
+            }
+            NEVRWarning::MissingGeometryBuffers => {
+                "no geometry buffers: the blocks were built but the vertices, indices or materials weren't written, check that the VoxelMaterials used by the VoxelTypes are added to the assets"
+            }
+            NEVRWarning::TooManyInstances => {
+                "too many instances: some blocks aren't rendered because the scene has more blocks and instances than the GPU (or NEVRTuning::max_instances) allows, merge them into fewer VoxelTypes or use chunks"
+            }
+            NEVRWarning::MaterialsNearLimit => {
+                "materials near the limit: more than 90% of the materials the GPU (or NEVRTuning::max_materials) allows are used, reuse the same VoxelMaterial handles instead of adding a new material for every voxel"
+            }
+            NEVRWarning::TooManyMaterials => {
+                "too many materials: some materials aren't uploaded and their voxels use the magenta fallback material because the scene has more materials than the GPU (or NEVRTuning::max_materials) allows, reuse the same VoxelMaterial handles or use material remaps"
+            }
+            NEVRWarning::InvalidShader => {
+                "invalid shader: the ray tracing shader or the shader of a denoiser failed to compile, check the errors logged by the pipeline cache and the contract of NEVRShaderOverride"
+            }
+            NEVRWarning::TypeTooLarge => {
+                "type too large: some VoxelTypes have more triangles than a BLAS of the GPU can hold (12 for every cube, 80 for every sphere) and their blocks aren't rendered, split them into smaller types"
+            }
+            NEVRWarning::InvalidVoxels => {
+                "invalid voxels: some VoxelTypes have voxels outside of [0, size) on some axis, which stick out of the block, or voxels at the same position as others, whose faces are drawn twice, check VoxelType::validate"
+            }
+        }
+    }
+}
+
+/// Collects the problems found while rendering.
+///
+/// Every problem is printed at most once every [NEVRStatus::REPORT_INTERVAL] so that a problem lasting
+/// many frames doesn't flood the console, while it still shows up when it happens again later on.
+/// It is available in the render world.
+#[derive(Resource, Default)]
+pub struct NEVRStatus {
+    reports: Mutex<HashMap<NEVRWarning, Instant>>,
+    last_warning: Mutex<Option<NEVRWarning>>,
+}
+
+impl NEVRStatus {
+    /// Minimum time between two reports of the same problem.
+    pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Reports a problem, it is printed only if it wasn't reported in the last [NEVRStatus::REPORT_INTERVAL].
+    pub fn report(&self, warning: NEVRWarning) {
+        *self.last_warning.lock().unwrap() = Some(warning);
+
+        let mut reports = self.reports.lock().unwrap();
+        let now = Instant::now();
+        let should_print = reports
+            .get(&warning)
+            .is_none_or(|last| now.duration_since(*last) >= Self::REPORT_INTERVAL);
+
+        if should_print {
+            eprintln!("NEVR: {}", warning.message());
+            reports.insert(warning, now);
+        }
+    }
+
+    /// The last problem reported.
+    pub fn last_warning(&self) -> Option<NEVRWarning> {
+        *self.last_warning.lock().unwrap()
+    }
+}
+
+/// The compute pipelines of the ray tracing and denoiser nodes, to follow their compilation.
+///
+/// The pipelines are queued when the nodes are created, but they're compiled only once their shaders are
+/// loaded, which takes a few frames after the app starts. Until then nothing is rendered.
+#[derive(Resource, Default)]
+pub(crate) struct NEVRPipelines(Vec<CachedComputePipelineId>);
+
+impl NEVRPipelines {
+    pub(crate) fn track(&mut self, pipelines: impl IntoIterator<Item = CachedComputePipelineId>) {
+        self.0.extend(pipelines);
+    }
+}
+
+/// Counts the pipelines still compiling into [NEVRStats::pipelines_compiling] (and
+/// [NEVRStats::pipelines_ready]) and reports the ones that failed, so the nodes can skip the frames where their
+/// pipelines aren't ready without printing anything.
+pub(crate) fn check_pipelines(
+    pipelines: Res<NEVRPipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    status: Res<NEVRStatus>,
+    mut stats: ResMut<NEVRStats>,
+) {
+    let mut compiling = 0;
+    for pipeline in &pipelines.0 {
+        match pipeline_cache.get_compute_pipeline_state(*pipeline) {
+            CachedPipelineState::Ok(_) => {}
+            CachedPipelineState::Err(_) => status.report(NEVRWarning::InvalidShader),
+            _ => compiling += 1,
+        }
+    }
+    stats.pipelines_compiling = compiling;
+    // the pipelines are queued when the nodes are created, none are tracked before
+    stats.pipelines_ready = !pipelines.0.is_empty() && compiling == 0;
+}
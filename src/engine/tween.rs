@@ -0,0 +1,98 @@
+//! This module contains helpers to animate materials over time.
+
+use crate::engine::voxel::VoxelMaterial;
+use bevy::color::Mix;
+use bevy::prelude::{Assets, Component, Handle, LinearRgba, Query, Res, ResMut, Time};
+
+/// Animates the diffuse color of a [VoxelMaterial] towards a target color.
+///
+/// Add it to any entity, the material is changed through [Assets] and its color is updated in place on the GPU:
+/// ```rs
+/// // a lamp pulsing between its color and a dim red every 2 seconds
+/// commands.spawn(MaterialColorTween::new(lamp_material, LinearRgba::rgb(2.0, 0.1, 0.1), 2.0).with_ping_pong(true));
+/// ```
+///
+/// The tween starts from the color the material has on the first update. Once it finishes (and it isn't
+/// ping-ponging) the material isn't touched anymore, the component can be removed or restarted with
+/// [MaterialColorTween::restart].
+///
+/// **Note:** all the blocks using the material are animated, use a separate material for blocks that must not be.
+#[derive(Component, Clone, Debug)]
+pub struct MaterialColorTween {
+    /// The animated material.
+    pub material: Handle<VoxelMaterial>,
+    /// The color reached at the end of the tween.
+    pub target: LinearRgba,
+    /// The duration of the tween in seconds.
+    pub duration: f32,
+    /// Goes back and forth between the start and the target color forever. Defaults to false.
+    pub ping_pong: bool,
+    start: Option<LinearRgba>,
+    elapsed: f32,
+}
+
+impl MaterialColorTween {
+    pub fn new(material: Handle<VoxelMaterial>, target: LinearRgba, duration: f32) -> Self {
+        Self {
+            material,
+            target,
+            duration,
+            ping_pong: false,
+            start: None,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn with_ping_pong(mut self, ping_pong: bool) -> Self {
+        self.ping_pong = ping_pong;
+        self
+    }
+
+    /// Whether the tween reached the target color, it's never true when ping-ponging.
+    pub fn finished(&self) -> bool {
+        !self.ping_pong && self.elapsed >= self.duration
+    }
+
+    /// Restarts the tween from the current color of the material.
+    pub fn restart(&mut self) {
+        self.start = None;
+        self.elapsed = 0.0;
+    }
+
+    // the progress between the start (0.0) and the target (1.0)
+    fn progress(&self) -> f32 {
+        if self.duration <= 0.0 {
+            return 1.0;
+        }
+
+        let t = self.elapsed / self.duration;
+        if self.ping_pong {
+            // 0 -> 1 -> 0 every two durations
+            1.0 - (t % 2.0 - 1.0).abs()
+        } else {
+            t.min(1.0)
+        }
+    }
+}
+
+/// Advances every [MaterialColorTween] and writes the color into its material.
+pub fn update_material_color_tweens(
+    time: Res<Time>,
+    mut tweens: Query<&mut MaterialColorTween>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    for mut tween in &mut tweens {
+        if tween.finished() {
+            continue;
+        }
+
+        // the material may still be loading
+        let Some(material) = materials.get_mut(&tween.material) else {
+            continue;
+        };
+
+        let start = *tween.start.get_or_insert(material.diffuse());
+        tween.elapsed += time.delta_secs();
+        material.set_diffuse(start.mix(&tween.target, tween.progress()));
+    }
+}
@@ -0,0 +1,69 @@
+//! This module contains the material mapping used when importing MagicaVoxel (`.vox`) models.
+//!
+//! **Note:** NEVR doesn't ship a `.vox` loader yet, this mapping is what a loader consults to turn the palette
+//! of a model into [VoxelMaterial]s.
+
+use crate::engine::voxel::VoxelMaterial;
+use bevy::platform::collections::HashMap;
+use bevy::prelude::{Assets, Color, Handle, Resource};
+
+/// Maps the palette indices of a `.vox` model to [VoxelMaterial]s.
+///
+/// For every palette index, the mapped material is used if present, otherwise a lambertian material
+/// with the palette color is created. The default mapping is empty, so every voxel is lambertian.
+/// ```rs
+/// // make the voxels with palette index 7 emissive and the ones with index 12 glass
+/// let material_map = VoxMaterialMap::default()
+///     .with(7, VoxelMaterial::new_diffuse_light(Color::WHITE, 5.0))
+///     .with(12, VoxelMaterial::new_dielectric(Color::WHITE, 1.5));
+/// commands.insert_resource(material_map);
+/// ```
+#[derive(Resource, Clone, Default)]
+pub struct VoxMaterialMap {
+    materials: HashMap<u8, VoxelMaterial>,
+}
+
+impl VoxMaterialMap {
+    /// Maps the palette index to the material.
+    pub fn with(mut self, palette_index: u8, material: VoxelMaterial) -> Self {
+        self.insert(palette_index, material);
+        self
+    }
+
+    /// Maps the palette index to the material, replacing the previous mapping if present.
+    pub fn insert(&mut self, palette_index: u8, material: VoxelMaterial) {
+        self.materials.insert(palette_index, material);
+    }
+
+    /// Removes the mapping of the palette index, so it falls back to a lambertian material.
+    pub fn remove(&mut self, palette_index: u8) -> Option<VoxelMaterial> {
+        self.materials.remove(&palette_index)
+    }
+
+    /// Returns the mapped material of the palette index, if present.
+    pub fn get(&self, palette_index: u8) -> Option<&VoxelMaterial> {
+        self.materials.get(&palette_index)
+    }
+
+    /// Returns the material to use for the palette index, falling back to a lambertian material with the
+    /// palette color.
+    pub fn material_for(&self, palette_index: u8, palette_color: Color) -> VoxelMaterial {
+        self.get(palette_index)
+            .cloned()
+            .unwrap_or_else(|| VoxelMaterial::new_lambertian(palette_color))
+    }
+
+    /// Creates the materials for a whole palette, the handle at position `i` is the material of palette index `i`.
+    pub fn palette_materials(
+        &self,
+        palette: &[Color],
+        materials: &mut Assets<VoxelMaterial>,
+    ) -> Vec<Handle<VoxelMaterial>> {
+        palette
+            .iter()
+            .take(u8::MAX as usize + 1)
+            .enumerate()
+            .map(|(index, color)| materials.add(self.material_for(index as u8, *color)))
+            .collect()
+    }
+}
@@ -2,13 +2,18 @@
 
 use crate::ToBytes;
 use bevy::asset::AssetId;
+use bevy::camera::primitives::Aabb;
+use bevy::ecs::change_detection::DetectChanges;
 use bevy::ecs::query::QueryItem;
 use bevy::ecs::system::SystemParamItem;
 use bevy::ecs::system::lifetimeless::SRes;
+use bevy::platform::collections::{HashMap, HashSet};
 use bevy::prelude::{
-    Asset, Color, ColorToComponents, Component, GlobalTransform, Handle, InheritedVisibility,
-    LinearRgba, Transform, TypePath, Vec3, Visibility,
+    Asset, Assets, Color, ColorToComponents, Commands, Component, GlobalTransform, Handle, IVec3,
+    InheritedVisibility, LinearRgba, Mat4, Query, Ref, Reflect, ReflectComponent, Transform,
+    TypePath, Vec3, Vec4, Visibility,
 };
+use bevy::render::Extract;
 use bevy::render::extract_component::ExtractComponent;
 use bevy::render::render_asset::{PrepareAssetError, RenderAsset};
 use bevy::render::render_resource::ShaderType;
@@ -17,6 +22,7 @@ use bevy::render::render_resource::encase::internal::{
 };
 use bevy::render::render_resource::encase::private::{Metadata, SizeValue};
 use bevy::render::renderer::RenderDevice;
+use bevy::render::sync_world::RenderEntity;
 
 /// Describes the model to use for a material, used in [VoxelMaterial].
 pub enum VoxelMaterialModel {
@@ -27,16 +33,59 @@ pub enum VoxelMaterialModel {
     Metallic,
     /// A water/glass-like material, it both reflects and refracts the light.
     /// Water has a refraction index of about 1.33, whilst glass has about 1.5.
+    /// Its shadow is tinted by its color, check [VoxelMaterial::with_shadow_opacity].
     Dielectric,
     /// NOT USED YET
     Isotropic,
     /// An emissive material, could be used for torches, lamps, etc...
     ///
     /// The brightness can be thought as a multiplier of the color, for example if the color is pure white
-    /// and the brightness is 10, then the diffuse value would be RGBA(10.0, 10.0, 10.0, 10.0).
+    /// and the brightness is 10, then the diffuse value would be RGBA(10.0, 10.0, 10.0, 1.0).
     /// A convenient method is provided through [VoxelMaterial::new_diffuse_light] for which you provide a base
     /// color and the brightness separately.
     DiffuseLight,
+    /// A thin transparent film (soap bubbles, oil slicks) that produces an iridescent sheen.
+    ///
+    /// The reflected color depends on the thickness of the film (in nanometers), its refraction index
+    /// and the viewing angle; the diffuse color tints the light transmitted through the film.
+    /// A convenient method is provided through [VoxelMaterial::new_thin_film].
+    ThinFilm,
+    /// A metallic-roughness material following the glTF PBR convention.
+    ///
+    /// It mixes a diffuse lobe and a GGX specular lobe: the base color is the albedo of dielectrics and the
+    /// reflection color of metals, the metallic factor blends between the two and the roughness spreads the
+    /// reflections. A convenient method is provided through [VoxelMaterial::new_pbr].
+    Pbr,
+    /// An emissive material cycling through the frames of [VoxelFlipbook], for screens, fires and other
+    /// flickering lights.
+    ///
+    /// The frame is picked from the time since the app started, the diffuse color tints it like the emission
+    /// of [VoxelMaterialModel::DiffuseLight]. A convenient method is provided through
+    /// [VoxelMaterial::new_flipbook].
+    ///
+    /// [VoxelFlipbook]: crate::engine::flipbook::VoxelFlipbook
+    Flipbook,
+    /// A [VoxelMaterialModel::Lambertian] material whose color fades between two colors along an axis of the
+    /// block, for cheap variations (e.g. grass darker at the bottom) without a material for every voxel.
+    ///
+    /// The color is computed where the block is hit, from the start of the block on the axis to its end.
+    /// A convenient method is provided through [VoxelMaterial::new_gradient].
+    Gradient,
+    /// A [VoxelMaterialModel::Lambertian] surface with a mirror-like coating, for polished floors and other
+    /// semi-reflective surfaces without the cost of tuning a [VoxelMaterialModel::Pbr] material.
+    ///
+    /// Every hit either reflects the ray like a perfect, untinted mirror or scatters it like a lambertian
+    /// surface, the reflection is picked with the probability given by the reflectivity, which grows toward
+    /// grazing angles (Fresnel). A convenient method is provided through [VoxelMaterial::new_glossy].
+    Glossy,
+}
+
+/// The axis of the block along which a [VoxelMaterialModel::Gradient] fades, check [VoxelMaterial::new_gradient].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelGradientAxis {
+    X,
+    Y,
+    Z,
 }
 
 impl From<VoxelMaterialModel> for u32 {
@@ -47,6 +96,11 @@ impl From<VoxelMaterialModel> for u32 {
             VoxelMaterialModel::Dielectric => 2,
             VoxelMaterialModel::Isotropic => 3,
             VoxelMaterialModel::DiffuseLight => 4,
+            VoxelMaterialModel::ThinFilm => 5,
+            VoxelMaterialModel::Pbr => 6,
+            VoxelMaterialModel::Flipbook => 7,
+            VoxelMaterialModel::Gradient => 8,
+            VoxelMaterialModel::Glossy => 9,
         }
     }
 }
@@ -57,7 +111,7 @@ impl From<VoxelMaterialModel> for u32 {
 /// ```rs
 /// let handle = asset_server.add(VoxelMaterial::new_lambertian(VoxelColor::RGBA(1.0, 1.0, 1.0, 1.0)));
 /// ```
-#[derive(Asset, TypePath, Clone, Copy)]
+#[derive(Asset, Reflect, Clone, Copy)]
 #[repr(C)]
 pub struct VoxelMaterial {
     diffuse: LinearRgba,
@@ -65,9 +119,22 @@ pub struct VoxelMaterial {
     fuzziness: f32,
     refraction_index: f32,
     material_model: u32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
 }
 
 impl VoxelMaterial {
+    /// The smallest refraction index accepted by the constructors.
+    pub const MIN_REFRACTION_INDEX: f32 = 0.1;
+    /// The biggest refraction index accepted by the constructors (diamond has about 2.42).
+    pub const MAX_REFRACTION_INDEX: f32 = 10.0;
+    /// The refraction index of water.
+    pub const IOR_WATER: f32 = 1.33;
+    /// The refraction index of common glass.
+    pub const IOR_GLASS: f32 = 1.5;
+    /// The refraction index of diamond.
+    pub const IOR_DIAMOND: f32 = 2.42;
+
     pub fn new(
         diffuse: LinearRgba,
         fuzziness: f32,
@@ -80,6 +147,8 @@ impl VoxelMaterial {
             refraction_index,
             material_model: material_model.into(),
             _diffuse_texture_id: -1,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
         }
     }
 
@@ -97,11 +166,13 @@ impl VoxelMaterial {
 
     /// Creates a new metallic material.
     ///
+    /// The fuzziness is clamped between 0.0 and 1.0, NaN is treated as 0.0.
+    ///
     /// Check [VoxelMaterialModel::Metallic] for more information.
     pub fn new_metallic(diffuse: Color, fuzziness: f32) -> Self {
         Self::new(
             diffuse.to_linear(),
-            fuzziness,
+            sanitize(fuzziness, 0.0, 0.0, 1.0),
             0.0,
             VoxelMaterialModel::Metallic,
         )
@@ -109,28 +180,272 @@ impl VoxelMaterial {
 
     /// Creates a new dielectric material.
     ///
+    /// The refraction index is clamped between [VoxelMaterial::MIN_REFRACTION_INDEX] and
+    /// [VoxelMaterial::MAX_REFRACTION_INDEX], NaN is treated as 1.0 (the refraction index of air).
+    ///
     /// Check [VoxelMaterialModel::Dielectric] for more information.
     pub fn new_dielectric(diffuse: Color, refraction_index: f32) -> Self {
         Self::new(
             diffuse.to_linear(),
             0.0,
-            refraction_index,
+            sanitize(
+                refraction_index,
+                1.0,
+                Self::MIN_REFRACTION_INDEX,
+                Self::MAX_REFRACTION_INDEX,
+            ),
             VoxelMaterialModel::Dielectric,
         )
     }
 
+    /// Creates a new clear dielectric material with the given refraction index, e.g.
+    /// `VoxelMaterial::from_ior(VoxelMaterial::IOR_GLASS)`.
+    ///
+    /// Check [VoxelMaterial::new_dielectric] to tint it.
+    pub fn from_ior(refraction_index: f32) -> Self {
+        Self::new_dielectric(Color::WHITE, refraction_index)
+    }
+
     /// Creates a new emissive material.
     ///
+    /// The emission is RGB-only: `brightness` scales the red, green and blue channels and leaves alpha unchanged.
+    /// It can be bigger than 1.0 for HDR emission, negative values are clamped to 0.0.
+    ///
+    /// The image is accumulated as a running mean in `Rgba16Float` textures, so the brightness doesn't add up
+    /// over the frames, but the light reaching a pixel is clamped to 65504 (the largest value of f16): brighter
+    /// emitters saturate to the same white.
+    ///
     /// Check [VoxelMaterialModel::DiffuseLight] for more information.
     pub fn new_diffuse_light(diffuse: Color, brightness: f32) -> Self {
+        let brightness = brightness.max(0.0);
         let mut diffuse = diffuse.to_linear();
         diffuse.red *= brightness;
         diffuse.green *= brightness;
         diffuse.blue *= brightness;
-        diffuse.alpha *= brightness;
 
         Self::new(diffuse, 0.0, 0.0, VoxelMaterialModel::DiffuseLight)
     }
+
+    /// Creates a new thin film material.
+    ///
+    /// `thickness` is the thickness of the film in nanometers (soap bubbles are usually between 200 and 1000nm),
+    /// `film_ior` is the refraction index of the film (soapy water has about 1.33, oil about 1.45).
+    ///
+    /// **Note:** the thickness is stored in the fuzziness slot of the material.
+    ///
+    /// Negative thicknesses are clamped to 0.0 and the refraction index is clamped like in
+    /// [VoxelMaterial::new_dielectric].
+    ///
+    /// Check [VoxelMaterialModel::ThinFilm] for more information.
+    pub fn new_thin_film(base_color: Color, thickness: f32, film_ior: f32) -> Self {
+        Self::new(
+            base_color.to_linear(),
+            sanitize(thickness, 0.0, 0.0, f32::MAX),
+            sanitize(
+                film_ior,
+                1.0,
+                Self::MIN_REFRACTION_INDEX,
+                Self::MAX_REFRACTION_INDEX,
+            ),
+            VoxelMaterialModel::ThinFilm,
+        )
+    }
+
+    /// Creates a new metallic-roughness material, the factors are the ones found in glTF materials.
+    ///
+    /// `metallic` and `roughness` are clamped between 0.0 and 1.0, NaN is treated as 0.0 for the metallic
+    /// factor and as 1.0 for the roughness.
+    ///
+    /// **Note:** the metallic factor is stored in the refraction index slot and the roughness in the fuzziness
+    /// slot of the material. Textures aren't supported yet, only the factors are used.
+    ///
+    /// Check [VoxelMaterialModel::Pbr] for more information.
+    pub fn new_pbr(base_color: Color, metallic: f32, roughness: f32) -> Self {
+        Self::new(
+            base_color.to_linear(),
+            sanitize(roughness, 1.0, 0.0, 1.0),
+            sanitize(metallic, 0.0, 0.0, 1.0),
+            VoxelMaterialModel::Pbr,
+        )
+    }
+
+    /// Creates a new glossy material, `reflectivity` goes from 0.0 (lambertian) to 1.0 (mirror).
+    /// ```rs
+    /// let floor = VoxelMaterial::new_glossy(Color::srgb(0.6, 0.6, 0.65), 0.3);
+    /// ```
+    ///
+    /// The reflectivity is clamped between 0.0 and 1.0, NaN is treated as 0.0.
+    ///
+    /// **Note:** the reflectivity is stored in the fuzziness slot of the material.
+    ///
+    /// Check [VoxelMaterialModel::Glossy] for more information.
+    pub fn new_glossy(diffuse: Color, reflectivity: f32) -> Self {
+        Self::new(
+            diffuse.to_linear(),
+            sanitize(reflectivity, 0.0, 0.0, 1.0),
+            0.0,
+            VoxelMaterialModel::Glossy,
+        )
+    }
+
+    /// Creates a new emissive material animated with the layers of
+    /// [VoxelFlipbook](crate::engine::flipbook::VoxelFlipbook) from `first_frame` to `first_frame + frames - 1`,
+    /// at `fps` frames per second.
+    ///
+    /// `brightness` scales the tint like in [VoxelMaterial::new_diffuse_light], at least one frame is used and
+    /// negative frame rates are clamped to 0.0 (the first frame is shown).
+    ///
+    /// **Note:** the first frame is stored in the texture slot, the number of frames in the fuzziness slot and
+    /// the frame rate in the refraction index slot of the material. The lighting changes every frame, so while
+    /// a flipbook material exists the temporal accumulation of the cameras is restarted every frame.
+    ///
+    /// Check [VoxelMaterialModel::Flipbook] for more information.
+    pub fn new_flipbook(
+        tint: Color,
+        brightness: f32,
+        first_frame: u32,
+        frames: u32,
+        fps: f32,
+    ) -> Self {
+        let mut material = Self::new_diffuse_light(tint, brightness);
+        material.material_model = VoxelMaterialModel::Flipbook.into();
+        material._diffuse_texture_id = first_frame as i32;
+        material.fuzziness = frames.max(1) as f32;
+        material.refraction_index = sanitize(fps, 0.0, 0.0, f32::MAX);
+        material
+    }
+
+    /// Creates a new gradient material, fading from `start` at the start of `axis` in the block (e.g. the bottom
+    /// for [VoxelGradientAxis::Y]) to `end` at its end.
+    /// ```rs
+    /// let grass = VoxelMaterial::new_gradient(Color::srgb(0.1, 0.3, 0.05), Color::srgb(0.3, 0.7, 0.2), VoxelGradientAxis::Y);
+    /// ```
+    ///
+    /// **Note:** the axis is stored in the texture slot, the end color in the alpha of the diffuse color, the
+    /// fuzziness slot and the refraction index slot of the material, so tweening the color of a gradient
+    /// changes its end color too.
+    ///
+    /// Check [VoxelMaterialModel::Gradient] for more information.
+    pub fn new_gradient(start: Color, end: Color, axis: VoxelGradientAxis) -> Self {
+        let start = start.to_linear();
+        let end = end.to_linear();
+
+        let mut material = Self::new(
+            LinearRgba::new(start.red, start.green, start.blue, end.red),
+            end.green,
+            end.blue,
+            VoxelMaterialModel::Gradient,
+        );
+        material._diffuse_texture_id = axis as i32;
+        material
+    }
+
+    /// Sets the light channels of a [VoxelMaterialModel::DiffuseLight] material, it only lights the blocks
+    /// sharing at least one channel bit with it (check [VoxelLightChannels]). Defaults to all the channels.
+    /// ```rs
+    /// let lamp = VoxelMaterial::new_diffuse_light(Color::WHITE, 10.0).with_light_channels(0b10);
+    /// ```
+    ///
+    /// **Note:** the channels are stored in the texture slot, so other models ignore them and
+    /// [VoxelMaterialModel::Flipbook] materials always light every channel.
+    pub fn with_light_channels(mut self, channels: u32) -> Self {
+        if self.material_model == u32::from(VoxelMaterialModel::DiffuseLight) {
+            self._diffuse_texture_id = channels as i32;
+        }
+        self
+    }
+
+    /// Sets how much a [VoxelMaterialModel::Dielectric] or [VoxelMaterialModel::ThinFilm] material blocks the
+    /// light of the sun, from 0.0 to 1.0. Defaults to 0.0.
+    ///
+    /// The shadows of these materials are tinted by their color: a red glass block casts a red shadow, the
+    /// opacity darkens it toward a black shadow.
+    /// ```rs
+    /// let frosted_glass = VoxelMaterial::new_dielectric(Color::srgb(1.0, 0.2, 0.2), VoxelMaterial::IOR_GLASS)
+    ///     .with_shadow_opacity(0.5);
+    /// ```
+    ///
+    /// **Note:** the opacity is stored in the texture slot, so other models ignore it. The shadow rays go
+    /// straight through the glass without bending (there are no caustics) and through at most 8 surfaces.
+    pub fn with_shadow_opacity(mut self, opacity: f32) -> Self {
+        if self.material_model == u32::from(VoxelMaterialModel::Dielectric)
+            || self.material_model == u32::from(VoxelMaterialModel::ThinFilm)
+        {
+            self._diffuse_texture_id =
+                (sanitize(opacity, 0.0, 0.0, 1.0) * SHADOW_OPACITY_SCALE).round() as i32;
+        }
+        self
+    }
+
+    /// Adds a clear coat over the material, like the varnish of lacquered wood or the clear coat of car paint:
+    /// a thin layer of glass-like lacquer that reflects a bit of the light, more at grazing angles, and lets the
+    /// rest through to the material below.
+    /// ```rs
+    /// let car_paint = VoxelMaterial::new_pbr(Color::srgb(0.6, 0.0, 0.0), 0.5, 0.6).with_clearcoat(1.0, 0.05);
+    /// ```
+    ///
+    /// `strength` scales the reflection of the coat, from 0.0 (no coat) to 1.0 (the Fresnel reflection of a
+    /// coat with a refraction index of 1.5, about 4% of the light head-on and all of it at grazing angles).
+    /// `roughness` blurs the reflection of the coat like the fuzziness of [VoxelMaterialModel::Metallic].
+    /// Both are clamped between 0.0 and 1.0, NaN is treated as 0.0.
+    ///
+    /// Every hit either reflects off the coat (untinted) or scatters on the material below, picked with the
+    /// probability of the reflection, so the coat doesn't add any ray to the path.
+    ///
+    /// **Note:** the coat is only applied over [VoxelMaterialModel::Lambertian], [VoxelMaterialModel::Metallic],
+    /// [VoxelMaterialModel::Pbr], [VoxelMaterialModel::Gradient] and [VoxelMaterialModel::Glossy] materials,
+    /// the other models ignore it. It's stored in two fields of its own, which grew the material on the GPU
+    /// from 32 to 48 bytes: the shaders overriding the embedded one have to add them to their `Material`
+    /// struct, check [NEVRShaderOverride](crate::engine::node::NEVRShaderOverride).
+    pub fn with_clearcoat(mut self, strength: f32, roughness: f32) -> Self {
+        let coated = [
+            VoxelMaterialModel::Lambertian,
+            VoxelMaterialModel::Metallic,
+            VoxelMaterialModel::Pbr,
+            VoxelMaterialModel::Gradient,
+            VoxelMaterialModel::Glossy,
+        ]
+        .map(u32::from);
+        if coated.contains(&self.material_model) {
+            self.clearcoat = sanitize(strength, 0.0, 0.0, 1.0);
+            self.clearcoat_roughness = sanitize(roughness, 0.0, 0.0, 1.0);
+        }
+        self
+    }
+
+    /// Whether the light goes through the material, which is true for [VoxelMaterialModel::Dielectric] and
+    /// [VoxelMaterialModel::ThinFilm]. The BLASes of the types using these materials are built as non-opaque
+    /// geometry.
+    pub fn transmits_light(&self) -> bool {
+        self.material_model == u32::from(VoxelMaterialModel::Dielectric)
+            || self.material_model == u32::from(VoxelMaterialModel::ThinFilm)
+    }
+
+    /// Whether the material is a [VoxelMaterialModel::Flipbook].
+    pub fn is_flipbook(&self) -> bool {
+        self.material_model == u32::from(VoxelMaterialModel::Flipbook)
+    }
+
+    pub fn diffuse(&self) -> LinearRgba {
+        self.diffuse
+    }
+
+    pub fn set_diffuse(&mut self, diffuse: LinearRgba) {
+        self.diffuse = diffuse;
+    }
+}
+
+// the shadow opacity is stored in the texture slot as a fixed-point number, check SHADOW_OPACITY_SCALE in
+// raytracing.wgsl
+const SHADOW_OPACITY_SCALE: f32 = 65535.0;
+
+// clamps a value between min and max, NaN becomes the fallback
+fn sanitize(value: f32, fallback: f32, min: f32, max: f32) -> f32 {
+    if value.is_nan() {
+        fallback
+    } else {
+        value.clamp(min, max)
+    }
 }
 
 impl RenderAsset for VoxelMaterial {
@@ -152,7 +467,7 @@ impl ShaderType for VoxelMaterial {
     const METADATA: Metadata<Self::ExtraMetadata> = Metadata {
         alignment: AlignmentValue::new(16),
         has_uniform_min_alignment: false,
-        min_size: SizeValue::new(32),
+        min_size: SizeValue::new(48),
         is_pod: false,
         extra: (),
     };
@@ -163,11 +478,20 @@ impl WriteInto for VoxelMaterial {
     where
         B: BufferMut,
     {
-        writer.write_slice(self.diffuse.to_f32_array().to_bytes());
+        // NaN and infinite values would poison the accumulation of every pixel seeing the material
+        let diffuse = self
+            .diffuse
+            .to_f32_array()
+            .map(|channel| sanitize(channel, 0.0, 0.0, f32::MAX));
+        writer.write_slice(diffuse.to_bytes());
         writer.write_slice(&self._diffuse_texture_id.to_le_bytes());
-        writer.write_slice(&self.fuzziness.to_le_bytes());
-        writer.write_slice(&self.refraction_index.to_le_bytes());
+        writer.write_slice(&sanitize(self.fuzziness, 0.0, 0.0, f32::MAX).to_le_bytes());
+        writer.write_slice(&sanitize(self.refraction_index, 1.0, 0.0, f32::MAX).to_le_bytes());
         writer.write_slice(&self.material_model.to_le_bytes());
+        writer.write_slice(&sanitize(self.clearcoat, 0.0, 0.0, 1.0).to_le_bytes());
+        writer.write_slice(&sanitize(self.clearcoat_roughness, 0.0, 0.0, 1.0).to_le_bytes());
+        // padding
+        writer.write_slice(&[0; 8]);
     }
 }
 
@@ -203,19 +527,143 @@ impl WriteInto for VoxelMaterial {
 /// commands.spawn((VoxelBlock::new(handle_voxel_type), transform));
 /// ```
 ///
-/// **Note:** if you rotate the block, the bounding volume in the BLAS (used to accelerate ray intersections) is still
-/// axis-aligned (i.e. it doesn't rotate) so if you have multiple rotated blocks one next to each other
-/// you may suffer degraded performance.
-#[derive(Component, Debug)]
+/// The texture coordinates of the block can be tiled through [VoxelBlock::with_uv_scale] (defaults to 1.0):
+/// ```rs
+/// // the texture repeats twice on every face of the block
+/// commands.spawn(VoxelBlock::new(handle_voxel_type).with_uv_scale(2.0));
+/// ```
+///
+/// Negative scales can be used to mirror a block (e.g. `Vec3::new(-1.0, 1.0, 1.0)`), keep in mind that mirroring
+/// flips the winding of the triangles of the block: the shaders overriding the embedded one (check
+/// [NEVRShaderOverride](crate::engine::node::NEVRShaderOverride)) shouldn't cull faces by their winding.
+///
+/// Hiding a block through its [Visibility] is cheap: the block keeps its instance in the TLAS with an empty mask,
+/// so toggling it only updates that instance instead of moving every other block in the TLAS.
+///
+/// **Note:** the BLAS of a type (used to accelerate ray intersections) is built once in the local space of the
+/// block and the rotation is applied by its instance in the TLAS, so the bounding volumes of the voxels stay
+/// tight whatever the rotation is. The bounding box of the whole block in the TLAS is still axis-aligned (i.e.
+/// it doesn't rotate) and grows with the rotation, up to about 1.7 times its size on each axis: many rotated
+/// blocks next to each other overlap more in the TLAS and may degrade the performance a bit.
+#[derive(Component, Reflect, Debug)]
+#[reflect(Component)]
 #[require(Transform, Visibility::Inherited)]
 pub struct VoxelBlock {
     /// The type of the block.
     pub voxel_type: Handle<VoxelType>,
+    /// Multiplier for the texture coordinates of the block, defaults to 1.0.
+    pub uv_scale: f32,
 }
 
 impl VoxelBlock {
     pub fn new(voxel_type: Handle<VoxelType>) -> Self {
-        Self { voxel_type }
+        Self {
+            voxel_type,
+            uv_scale: 1.0,
+        }
+    }
+
+    pub fn with_uv_scale(mut self, uv_scale: f32) -> Self {
+        self.uv_scale = uv_scale;
+        self
+    }
+
+    /// Creates a block with a [Transform] scaled so that every voxel of its type is `voxel_size` units large,
+    /// instead of fitting the whole type in a 1x1x1 block:
+    /// ```rs
+    /// // a type with a size of 4 becomes a 1x1x1 block with 0.25 units large voxels
+    /// if let Some(bundle) = VoxelBlock::from_voxel_size(&voxel_types, handle_voxel_type, 0.25) {
+    ///     commands.spawn(bundle);
+    /// }
+    /// ```
+    ///
+    /// Returns [None] if the type isn't in the assets yet. Change the translation and the rotation of the
+    /// returned transform as usual, check [VoxelType::scale_for_voxel_size] to compute only the scale.
+    pub fn from_voxel_size(
+        voxel_types: &Assets<VoxelType>,
+        voxel_type: Handle<VoxelType>,
+        voxel_size: f32,
+    ) -> Option<(Self, Transform)> {
+        let scale = voxel_types
+            .get(&voxel_type)?
+            .scale_for_voxel_size(voxel_size);
+
+        Some((Self::new(voxel_type), Transform::from_scale(scale)))
+    }
+}
+
+/// Arbitrary data passed to the shader with a [VoxelBlock] or a [VoxelBlockInstances], for game-specific
+/// effects like team colors or damage states.
+///
+/// The data is in the `custom_data` field of the `Object` struct in `raytracing.wgsl`, defaults to zero:
+/// ```rs
+/// commands.spawn((VoxelBlock::new(handle_voxel_type), VoxelBlockCustomData(Vec4::new(1.0, 0.0, 0.0, 0.0))));
+/// ```
+///
+/// **Note:** the stock shaders ignore it, it's meant for forks of the shaders. All the instances of a
+/// [VoxelBlockInstances] share the same data.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq)]
+pub struct VoxelBlockCustomData(pub Vec4);
+
+/// The light channels of a [VoxelBlock] or a [VoxelBlockInstances], a bitmask of up to 32 channels.
+///
+/// An emissive material only lights the blocks sharing at least one channel bit with it (check
+/// [VoxelMaterial::with_light_channels]), for stylized scenes where a lamp lights only some of the blocks:
+/// ```rs
+/// commands.spawn((VoxelBlock::new(handle_voxel_type), VoxelLightChannels(0b10)));
+/// ```
+///
+/// Blocks without the component are in every channel, so everything lights everything by default.
+///
+/// **Note:** the channels apply to the light arriving on the block from the emitters it sees, the emitters are
+/// still visible from every block (e.g. in reflections), and the light bounced by a block keeps lighting the
+/// other blocks. The sun and the skybox light every channel. All the instances of a [VoxelBlockInstances]
+/// share the same channels.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct VoxelLightChannels(pub u32);
+
+impl VoxelLightChannels {
+    /// Every channel.
+    pub const ALL: Self = Self(u32::MAX);
+}
+
+impl Default for VoxelLightChannels {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// Replaces some materials of the type of a [VoxelBlock] or a [VoxelBlockInstances], to reskin a type (e.g. a
+/// damaged variant of a wall) without another type:
+/// ```rs
+/// let remap = VoxelMaterialRemap::default().with_material(wood, burnt_wood);
+/// commands.spawn((VoxelBlock::new(handle_voxel_type), remap));
+/// ```
+///
+/// The block keeps using the geometry and the BLAS of its type, the shader looks up its materials in a palette
+/// instead: every different remap adds a slice with one entry for every material to the palette, blocks with
+/// the same remap share it. All the instances of a [VoxelBlockInstances] share the same remap.
+#[derive(Component, Debug, Clone, Default, PartialEq)]
+pub struct VoxelMaterialRemap {
+    /// Pairs of the original material and the one used instead, the materials not listed are kept.
+    pub materials: Vec<(Handle<VoxelMaterial>, Handle<VoxelMaterial>)>,
+}
+
+impl VoxelMaterialRemap {
+    pub fn with_material(
+        mut self,
+        original: Handle<VoxelMaterial>,
+        replacement: Handle<VoxelMaterial>,
+    ) -> Self {
+        self.materials.push((original, replacement));
+        self
+    }
+
+    fn render_materials(&self) -> Vec<(AssetId<VoxelMaterial>, AssetId<VoxelMaterial>)> {
+        self.materials
+            .iter()
+            .map(|(original, replacement)| (original.id(), replacement.id()))
+            .collect()
     }
 }
 
@@ -223,6 +671,13 @@ impl VoxelBlock {
 #[derive(Component, Debug)]
 pub struct RenderVoxelBlock {
     pub voxel_type: AssetId<VoxelType>,
+    pub uv_scale: f32,
+    /// Check [VoxelMaterialRemap].
+    pub material_remap: Vec<(AssetId<VoxelMaterial>, AssetId<VoxelMaterial>)>,
+    /// Check [VoxelBlockCustomData].
+    pub custom_data: Vec4,
+    /// Check [VoxelLightChannels].
+    pub light_channels: u32,
 }
 
 impl ExtractComponent for VoxelBlock {
@@ -230,16 +685,27 @@ impl ExtractComponent for VoxelBlock {
         &'static VoxelBlock,
         &'static GlobalTransform,
         &'static InheritedVisibility,
+        Option<&'static VoxelBlockCustomData>,
+        Option<&'static VoxelMaterialRemap>,
+        Option<&'static VoxelLightChannels>,
     );
     type QueryFilter = ();
     type Out = (RenderVoxelBlock, GlobalTransform, InheritedVisibility);
 
     fn extract_component(
-        (block, transform, visibility): QueryItem<'_, '_, Self::QueryData>,
+        (block, transform, visibility, custom_data, material_remap, light_channels): QueryItem<
+            '_,
+            '_,
+            Self::QueryData,
+        >,
     ) -> Option<Self::Out> {
         Some((
             RenderVoxelBlock {
                 voxel_type: block.voxel_type.id(),
+                uv_scale: block.uv_scale,
+                material_remap: material_remap.map_or(vec![], VoxelMaterialRemap::render_materials),
+                custom_data: custom_data.map_or(Vec4::ZERO, |data| data.0),
+                light_channels: light_channels.map_or(u32::MAX, |channels| channels.0),
             },
             *transform,
             *visibility,
@@ -247,6 +713,121 @@ impl ExtractComponent for VoxelBlock {
     }
 }
 
+/// Many blocks of the same type sharing one entity, useful for large amounts of blocks like foliage.
+///
+/// Every transform places an instance of the block relative to the [Transform] of the entity:
+/// ```rs
+/// let transforms = (0..100).map(|i| Transform::from_xyz(i as f32 * 2.0, 0.0, 0.0)).collect();
+/// commands.spawn(VoxelBlockInstances::new(handle_voxel_type, transforms));
+/// ```
+///
+/// All the instances share the BLAS of the type and a single object in the shader, and they are extracted to the
+/// render world only when the component or the transform of the entity change. Hiding or showing the entity
+/// only changes the mask of its instances in the TLAS.
+///
+/// **Note:** the instances aren't entities, so they can't be picked, hidden or queried one by one: the
+/// visibility of the entity applies to all of them. Use [VoxelBlock]s for blocks that need that.
+#[derive(Component, Debug, Clone)]
+#[require(Transform, Visibility::Inherited)]
+pub struct VoxelBlockInstances {
+    /// The type of the blocks.
+    pub voxel_type: Handle<VoxelType>,
+    /// The transforms of the instances, relative to the entity.
+    pub transforms: Vec<Transform>,
+    /// Multiplier for the texture coordinates of the blocks, defaults to 1.0.
+    pub uv_scale: f32,
+}
+
+impl VoxelBlockInstances {
+    pub fn new(voxel_type: Handle<VoxelType>, transforms: Vec<Transform>) -> Self {
+        Self {
+            voxel_type,
+            transforms,
+            uv_scale: 1.0,
+        }
+    }
+
+    pub fn with_uv_scale(mut self, uv_scale: f32) -> Self {
+        self.uv_scale = uv_scale;
+        self
+    }
+}
+
+/// Used in the rendering phase to extract all the needed [VoxelBlockInstances].
+#[derive(Component, Debug)]
+pub struct RenderVoxelBlockInstances {
+    pub voxel_type: AssetId<VoxelType>,
+    pub uv_scale: f32,
+    /// Check [VoxelMaterialRemap].
+    pub material_remap: Vec<(AssetId<VoxelMaterial>, AssetId<VoxelMaterial>)>,
+    /// Check [VoxelBlockCustomData].
+    pub custom_data: Vec4,
+    /// Check [VoxelLightChannels].
+    pub light_channels: u32,
+    /// The world transforms of the instances.
+    pub transforms: Vec<Mat4>,
+}
+
+/// Extracts the changed [VoxelBlockInstances], the unchanged ones are kept in the render world.
+pub fn extract_block_instances(
+    mut commands: Commands,
+    query: Extract<
+        Query<(
+            RenderEntity,
+            Ref<VoxelBlockInstances>,
+            Ref<GlobalTransform>,
+            Ref<InheritedVisibility>,
+            Option<Ref<VoxelBlockCustomData>>,
+            Option<Ref<VoxelMaterialRemap>>,
+            Option<Ref<VoxelLightChannels>>,
+        )>,
+    >,
+) {
+    for (entity, instances, transform, visibility, custom_data, material_remap, light_channels) in
+        &query
+    {
+        // removing the custom data, the remap or the channels isn't detected, it's applied with the next change
+        let custom_data_changed = custom_data.as_ref().is_some_and(|data| data.is_changed());
+        let material_remap_changed = material_remap
+            .as_ref()
+            .is_some_and(|remap| remap.is_changed());
+        let light_channels_changed = light_channels
+            .as_ref()
+            .is_some_and(|channels| channels.is_changed());
+        if !instances.is_changed()
+            && !transform.is_changed()
+            && !custom_data_changed
+            && !material_remap_changed
+            && !light_channels_changed
+        {
+            // the hidden instances stay in the TLAS with an empty mask, so showing or hiding them doesn't
+            // extract the transforms again
+            if visibility.is_changed() {
+                commands.entity(entity).insert(*visibility);
+            }
+            continue;
+        }
+
+        let transforms = instances
+            .transforms
+            .iter()
+            .map(|instance| transform.mul_transform(*instance).to_matrix())
+            .collect();
+
+        commands.entity(entity).insert((
+            RenderVoxelBlockInstances {
+                voxel_type: instances.voxel_type.id(),
+                uv_scale: instances.uv_scale,
+                material_remap: material_remap.map_or(vec![], |remap| remap.render_materials()),
+                custom_data: custom_data.map_or(Vec4::ZERO, |data| data.0),
+                light_channels: light_channels.map_or(u32::MAX, |channels| channels.0),
+                transforms,
+            },
+            *visibility,
+        ));
+    }
+}
+
 /// A relative voxel in the type of the block.
 ///
 /// You can think of this as a voxel in the block with the position relative to the block's position, for example
@@ -254,6 +835,9 @@ impl ExtractComponent for VoxelBlock {
 /// Check [VoxelType] for more information.
 ///
 /// Every voxel has its own material, check [VoxelMaterial] and [VoxelMaterialModel] for more information.
+///
+/// Voxels are meant to sit on the integer grid of the type, [RelativeVoxel::at] places them by their integer
+/// coordinates.
 #[derive(Debug, Clone)]
 pub struct RelativeVoxel {
     pub material: Handle<VoxelMaterial>,
@@ -264,6 +848,15 @@ impl RelativeVoxel {
     pub fn new(material: Handle<VoxelMaterial>, position: Vec3) -> Self {
         Self { material, position }
     }
+
+    /// Creates a voxel at the given coordinates of the grid of the type, each one in `[0, size)`:
+    /// ```rs
+    /// let voxels = (0..4).map(|y| RelativeVoxel::at(IVec3::new(0, y, 0), material.clone())).collect();
+    /// let pillar = VoxelType::new(4, voxels);
+    /// ```
+    pub fn at(position: IVec3, material: Handle<VoxelMaterial>) -> Self {
+        Self::new(material, position.as_vec3())
+    }
 }
 
 /// Describes a type of block.
@@ -281,10 +874,44 @@ impl RelativeVoxel {
 /// In the example above, the size is `1` because the largest dimension (either the x-axis, y-axis or z-axis)
 /// is large 1 unit, the position of the `RelativeVoxel` is (0.0, 0.0, 0.0) because it is at that coordinates **inside** the block.
 /// This means that the `RelativeVoxel` is as large as the block and its position is the same as the block.
+///
+/// Every voxel has to be in `[0, size)` on every axis and two voxels can't share the same position: a warning
+/// is printed when creating a type with voxels outside of the grid (they stick out of the block, which breaks
+/// its bounds and the scale helpers) or overlapping (they draw the same faces twice, which flickers).
+///
+/// Large types can have levels of detail, used by the blocks far from the camera, check [VoxelType::with_lod].
+///
+/// The voxels are cubes by default, check [VoxelType::with_shape] to draw them as spheres.
 #[derive(Asset, TypePath, Debug, Clone)]
 pub struct VoxelType {
     size: i32,
     voxels: Vec<RelativeVoxel>,
+    transient: bool,
+    lods: Vec<VoxelTypeLod>,
+    shape: VoxelShape,
+    smooth_normals: bool,
+}
+
+/// The shape the voxels of a [VoxelType] are drawn with, check [VoxelType::with_shape].
+///
+/// Defaults to [VoxelShape::Cube].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum VoxelShape {
+    /// A cube filling the cell of the voxel, made of 12 triangles.
+    #[default]
+    Cube,
+    /// A sphere inscribed in the cell of the voxel, made of 80 triangles (a subdivided icosahedron) with smooth
+    /// normals, e.g. for molecules or bubbles.
+    Sphere,
+}
+
+/// A level of detail of a [VoxelType], check [VoxelType::with_lod].
+#[derive(Debug, Clone)]
+pub struct VoxelTypeLod {
+    /// The type used instead of the original one, usually a [VoxelType::downsample] of it.
+    pub voxel_type: Handle<VoxelType>,
+    /// The distance from the camera, in world units, from which this level is used.
+    pub distance: f32,
 }
 
 impl VoxelType {
@@ -292,9 +919,93 @@ impl VoxelType {
         Self {
             voxels,
             size: size as i32,
+            transient: false,
+            lods: vec![],
+            shape: VoxelShape::Cube,
+            smooth_normals: false,
         }
     }
 
+    /// Counts the voxels outside of `[0, size)` on some axis and the voxels in the same grid cell as a voxel
+    /// before them, returned as `(out_of_range, overlapping)`.
+    ///
+    /// The types are checked when their geometry is built, [NEVRWarning::InvalidVoxels] is reported when either
+    /// isn't 0.
+    ///
+    /// [NEVRWarning::InvalidVoxels]: crate::engine::status::NEVRWarning::InvalidVoxels
+    pub fn validate(&self) -> (usize, usize) {
+        let size = self.size as f32;
+        let mut positions = HashSet::with_capacity(self.voxels.len());
+        let mut out_of_range = 0;
+        let mut overlapping = 0;
+        for voxel in &self.voxels {
+            if voxel.position.cmplt(Vec3::ZERO).any()
+                || voxel.position.cmpge(Vec3::splat(size)).any()
+            {
+                out_of_range += 1;
+            }
+            if !positions.insert(voxel.position.floor().as_ivec3()) {
+                overlapping += 1;
+            }
+        }
+
+        (out_of_range, overlapping)
+    }
+
+    /// Marks the type as transient, a hint for types whose voxels are changed every few frames.
+    ///
+    /// The BLAS of a type is usually compacted some frames after being built: it takes less memory, but the
+    /// compaction costs GPU time and is wasted when the type is rebuilt soon after. The BLAS of a transient type
+    /// is never compacted, so it's faster to rebuild but it can take up to about twice the memory.
+    pub fn transient(mut self) -> Self {
+        self.transient = true;
+        self
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+
+    /// Sets the shape every voxel of the type is drawn with:
+    /// ```rs
+    /// let molecule = voxel_types.add(VoxelType::new(4, atoms).with_shape(VoxelShape::Sphere));
+    /// ```
+    ///
+    /// **Note:** a [VoxelShape::Sphere] has about 7 times the triangles of a [VoxelShape::Cube], so its BLAS takes
+    /// about 7 times the memory and takes longer to build, and the rays are a bit slower to trace: prefer them
+    /// for small or sparse types and keep large solid ones as cubes.
+    /// The bounds, the picking and the levels of detail still use the cells of the voxels.
+    pub fn with_shape(mut self, shape: VoxelShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    pub fn shape(&self) -> VoxelShape {
+        self.shape
+    }
+
+    /// Smooths the normals of the cubes across the neighbouring voxels, for an organic look instead of a blocky
+    /// one. Defaults to `false`.
+    ///
+    /// Every corner of a visible face takes the average of the directions of the visible faces touching that
+    /// corner, in this voxel and in its neighbours: the shading of flat surfaces doesn't change, while the edges
+    /// and the corners are shaded as if they were rounded, so a lone voxel looks like a rounded cube and a
+    /// terrain like rolling hills.
+    /// ```rs
+    /// let hills = voxel_types.add(VoxelType::new(64, terrain).with_smooth_normals(true));
+    /// ```
+    ///
+    /// **Note:** only the shading is smoothed, the silhouettes and the shadows keep the shape of the cubes. The
+    /// [VoxelShape::Sphere] already has smooth normals and ignores it.
+    pub fn with_smooth_normals(mut self, smooth_normals: bool) -> Self {
+        self.smooth_normals = smooth_normals;
+        self
+    }
+
+    pub fn smooth_normals(&self) -> bool {
+        self.smooth_normals
+    }
+
     pub fn size(&self) -> i32 {
         self.size
     }
@@ -302,6 +1013,296 @@ impl VoxelType {
     pub fn voxels(&self) -> &[RelativeVoxel] {
         &self.voxels
     }
+
+    /// Adds a level of detail: the blocks and the instances farther than `distance` from the camera use
+    /// `voxel_type` instead, so distant blocks are traced with fewer triangles.
+    ///
+    /// The distance is measured from the center of every block (or instance) to the closest [VoxelCamera],
+    /// the level with the largest distance below it is used. The level must fit in the same 1x1x1 block,
+    /// like the types returned by [VoxelType::downsample]:
+    /// ```rs
+    /// let lod = voxel_types.add(tree.downsample(4));
+    /// let tree = voxel_types.add(tree.with_lod(lod, 50.0));
+    /// ```
+    /// Check [VoxelType::with_generated_lods] to create the levels at once.
+    ///
+    /// **Note:** the levels only change the geometry that is traced, the picking and the depth use the
+    /// level that was traced too.
+    ///
+    /// [VoxelCamera]: crate::engine::camera::VoxelCamera
+    pub fn with_lod(mut self, voxel_type: Handle<VoxelType>, distance: f32) -> Self {
+        self.lods.push(VoxelTypeLod {
+            voxel_type,
+            distance,
+        });
+        self
+    }
+
+    /// Creates a level of detail for every distance, each one with half the resolution of the previous one,
+    /// and adds them to the assets.
+    ///
+    /// The type is halved only while its size is even, so the levels keep fitting in the same block: the
+    /// remaining distances are ignored once the size is odd.
+    /// ```rs
+    /// // a 64x64x64 type is traced at 32 from 20 units away and at 16 from 60 units away
+    /// let voxel_type = voxel_type.with_generated_lods(&mut voxel_types, &[20.0, 60.0]);
+    /// ```
+    pub fn with_generated_lods(
+        mut self,
+        voxel_types: &mut Assets<VoxelType>,
+        distances: &[f32],
+    ) -> Self {
+        let mut level = self.clone();
+        for &distance in distances {
+            if level.size % 2 != 0 {
+                break;
+            }
+
+            level = level.downsample(2);
+            self = self.with_lod(voxel_types.add(level.clone()), distance);
+        }
+
+        self
+    }
+
+    /// The levels of detail of the type, check [VoxelType::with_lod].
+    pub fn lods(&self) -> &[VoxelTypeLod] {
+        &self.lods
+    }
+
+    /// Creates a type with `factor` times fewer voxels on every axis, with a box filter: every cube of
+    /// `factor`x`factor`x`factor` voxels becomes a single voxel when at least half of it is filled, with the
+    /// most common material in it.
+    ///
+    /// The size is divided by `factor` and rounded up, so the result fits in the same block only when `factor`
+    /// divides the size. The levels of detail of the type aren't copied, the shape and the smooth normals are.
+    pub fn downsample(&self, factor: u32) -> Self {
+        let factor = factor.max(1) as i32;
+        let cell_volume = (factor * factor * factor) as usize;
+
+        // the voxels of every cell and how many voxels use each material, in order of appearance
+        let mut cells = HashMap::<IVec3, Vec<(Handle<VoxelMaterial>, usize)>>::default();
+        let mut order = vec![];
+        for voxel in &self.voxels {
+            let cell = voxel
+                .position
+                .floor()
+                .as_ivec3()
+                .div_euclid(IVec3::splat(factor));
+            let materials = cells.entry(cell).or_insert_with(|| {
+                order.push(cell);
+                vec![]
+            });
+
+            match materials
+                .iter_mut()
+                .find(|(material, _)| *material == voxel.material)
+            {
+                Some((_, count)) => *count += 1,
+                None => materials.push((voxel.material.clone(), 1)),
+            }
+        }
+
+        let voxels = order
+            .into_iter()
+            .filter_map(|cell| {
+                let materials = &cells[&cell];
+                let filled = materials.iter().map(|(_, count)| count).sum::<usize>();
+                if filled * 2 < cell_volume {
+                    return None;
+                }
+
+                // the first material wins the ties
+                let (material, _) = materials
+                    .iter()
+                    .rev()
+                    .max_by_key(|(_, count)| *count)
+                    .unwrap();
+                Some(RelativeVoxel::new(material.clone(), cell.as_vec3()))
+            })
+            .collect();
+
+        Self {
+            size: (self.size + factor - 1) / factor,
+            voxels,
+            transient: self.transient,
+            lods: vec![],
+            shape: self.shape,
+            smooth_normals: self.smooth_normals,
+        }
+    }
+
+    /// The bounding box of the voxels inside the 1x1x1 block, `None` when the type has no voxels.
+    ///
+    /// Types that don't fill their size (e.g. a flat 4x1x4 floor in a type of size 4) are smaller than the block.
+    pub fn bounds(&self) -> Option<Aabb> {
+        let size = 1.0 / self.size as f32;
+        let min = self
+            .voxels
+            .iter()
+            .map(|voxel| voxel.position)
+            .reduce(Vec3::min)?;
+        let max = self
+            .voxels
+            .iter()
+            .map(|voxel| voxel.position + Vec3::ONE)
+            .reduce(Vec3::max)?;
+
+        Some(Aabb::from_min_max(min * size, max * size))
+    }
+
+    /// The scale a block of this type needs for every voxel to be `voxel_size` units large.
+    ///
+    /// The voxels are scaled to fit the largest dimension in a 1x1x1 block, so the scale is the size of the type
+    /// times the size of a voxel (e.g. a type with a size of 4 and 0.25 units large voxels has a scale of 1).
+    pub fn scale_for_voxel_size(&self, voxel_size: f32) -> Vec3 {
+        Vec3::splat(self.size as f32 * voxel_size)
+    }
+
+    /// Bakes several types into a single type, useful for static compound objects (e.g. a table made of legs
+    /// and a top) since one type has a single BLAS instead of one instance for every part.
+    ///
+    /// Every type is placed like a [VoxelBlock] with the given transform, the merged type uses the resolution
+    /// of the smallest voxel and its size is recomputed to fit all the parts, so it's scaled to fit in a 1x1x1
+    /// block: scale the block with [VoxelType::scale_for_voxel_size] to get back the original dimensions. The
+    /// voxels are snapped to the grid by their centers, rotations should be multiples of 90 degrees. Voxels of
+    /// later parts replace the voxels of earlier parts in the same place.
+    ///
+    /// ```rs
+    /// let table = VoxelType::merge(&[
+    ///     (top, Transform::from_xyz(0.0, 1.0, 0.0).with_scale(Vec3::new(2.0, 0.25, 2.0))),
+    ///     (leg.clone(), Transform::from_xyz(0.0, 0.0, 0.0)),
+    ///     (leg, Transform::from_xyz(1.0, 0.0, 1.0)),
+    /// ]);
+    /// ```
+    ///
+    /// **Note:** the parts can't be moved independently after being merged, use separate blocks for that.
+    pub fn merge(parts: &[(VoxelType, Transform)]) -> Self {
+        let voxel_size = parts
+            .iter()
+            .map(|(voxel_type, transform)| {
+                transform.scale.abs().max_element() / voxel_type.size as f32
+            })
+            .fold(f32::INFINITY, f32::min);
+
+        let centers = parts
+            .iter()
+            .flat_map(|(voxel_type, transform)| {
+                let size = voxel_type.size as f32;
+                voxel_type.voxels.iter().map(move |voxel| {
+                    (
+                        transform.transform_point((voxel.position + 0.5) / size),
+                        &voxel.material,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if centers.is_empty() || !voxel_size.is_normal() {
+            return Self::new(1, vec![]);
+        }
+
+        let min = centers
+            .iter()
+            .fold(Vec3::INFINITY, |min, (center, _)| min.min(*center))
+            - voxel_size / 2.0;
+
+        // the index of every occupied position in voxels, to keep the order of the parts
+        let mut occupied = HashMap::<IVec3, usize>::default();
+        let mut voxels: Vec<(IVec3, Handle<VoxelMaterial>)> = Vec::with_capacity(centers.len());
+        for (center, material) in centers {
+            let position = ((center - min) / voxel_size).floor().as_ivec3();
+
+            match occupied.get(&position) {
+                Some(&index) => voxels[index].1 = material.clone(),
+                None => {
+                    occupied.insert(position, voxels.len());
+                    voxels.push((position, material.clone()));
+                }
+            }
+        }
+
+        let size = voxels
+            .iter()
+            .map(|(position, _)| position.max_element() + 1)
+            .max()
+            .unwrap_or(1);
+
+        Self::new(
+            size as u32,
+            voxels
+                .into_iter()
+                .map(|(position, material)| RelativeVoxel::new(material, position.as_vec3()))
+                .collect(),
+        )
+    }
+}
+
+/// Builds a [VoxelType] from voxels with a material or with a plain color.
+///
+/// The voxels with a color get a lambertian [VoxelMaterial], created when the type is built: voxels with the
+/// same color share the same material, so a block with thousands of voxels and a few colors only adds a few
+/// materials.
+/// ```rs
+/// let voxel_type = VoxelTypeBuilder::new(2)
+///     .with_colored_voxel(Vec3::new(0.0, 0.0, 0.0), Color::WHITE)
+///     .with_colored_voxel(Vec3::new(1.0, 0.0, 0.0), Color::WHITE)
+///     .with_voxel(RelativeVoxel::new(glass, Vec3::new(0.0, 1.0, 0.0)))
+///     .build(&mut materials);
+/// let handle = voxel_types.add(voxel_type);
+/// ```
+#[derive(Debug, Clone)]
+pub struct VoxelTypeBuilder {
+    size: u32,
+    voxels: Vec<RelativeVoxel>,
+    colored_voxels: Vec<(Vec3, LinearRgba)>,
+}
+
+impl VoxelTypeBuilder {
+    /// Creates a builder for a type with the given size, check [VoxelType] for more information.
+    pub fn new(size: u32) -> Self {
+        Self {
+            size,
+            voxels: vec![],
+            colored_voxels: vec![],
+        }
+    }
+
+    pub fn with_voxel(mut self, voxel: RelativeVoxel) -> Self {
+        self.add_voxel(voxel);
+        self
+    }
+
+    pub fn with_colored_voxel(mut self, position: Vec3, color: Color) -> Self {
+        self.add_colored_voxel(position, color);
+        self
+    }
+
+    pub fn add_voxel(&mut self, voxel: RelativeVoxel) {
+        self.voxels.push(voxel);
+    }
+
+    /// Adds a voxel with a lambertian material of the given color.
+    pub fn add_colored_voxel(&mut self, position: Vec3, color: Color) {
+        self.colored_voxels.push((position, color.to_linear()));
+    }
+
+    /// Creates the materials of the colored voxels and builds the type.
+    pub fn build(self, materials: &mut Assets<VoxelMaterial>) -> VoxelType {
+        // colors are compared by their bits, so two colors share a material only when they are exactly the same
+        let mut color_materials = HashMap::<[u32; 4], Handle<VoxelMaterial>>::default();
+        let mut voxels = self.voxels;
+
+        for (position, color) in self.colored_voxels {
+            let material = color_materials
+                .entry(color.to_f32_array().map(f32::to_bits))
+                .or_insert_with(|| materials.add(VoxelMaterial::new_lambertian(color.into())))
+                .clone();
+            voxels.push(RelativeVoxel::new(material, position));
+        }
+
+        VoxelType::new(self.size, voxels)
+    }
 }
 
 /// Used in the rendering phase to extracts all needed [VoxelType]s.
@@ -321,3 +1322,151 @@ impl RenderAsset for RenderVoxelType {
         Ok(Self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::render_resource::encase::StorageBuffer;
+
+    // a type of `size` cells across with a voxel in the corner
+    fn voxel_type(size: u32) -> VoxelType {
+        VoxelType::new(
+            size,
+            vec![RelativeVoxel::new(Handle::default(), Vec3::ZERO)],
+        )
+    }
+
+    #[test]
+    fn voxel_size_scales_the_block() {
+        assert_eq!(voxel_type(4).scale_for_voxel_size(0.25), Vec3::ONE);
+        assert_eq!(voxel_type(16).scale_for_voxel_size(0.5), Vec3::splat(8.0));
+    }
+
+    #[test]
+    fn block_from_voxel_size() {
+        let mut voxel_types = Assets::<VoxelType>::default();
+        let handle = voxel_types.add(voxel_type(4));
+
+        let (block, transform) =
+            VoxelBlock::from_voxel_size(&voxel_types, handle.clone(), 0.25).unwrap();
+        assert_eq!(block.voxel_type, handle);
+        assert_eq!(transform.scale, Vec3::ONE);
+
+        assert!(VoxelBlock::from_voxel_size(&voxel_types, Handle::default(), 0.25).is_none());
+    }
+
+    #[test]
+    fn constructors_clamp_the_parameters() {
+        assert_eq!(
+            VoxelMaterial::new_metallic(Color::WHITE, 2.0).fuzziness,
+            1.0
+        );
+        assert_eq!(
+            VoxelMaterial::new_metallic(Color::WHITE, f32::NAN).fuzziness,
+            0.0
+        );
+
+        let dielectric = VoxelMaterial::new_dielectric(Color::WHITE, -1.0);
+        assert_eq!(
+            dielectric.refraction_index,
+            VoxelMaterial::MIN_REFRACTION_INDEX
+        );
+        let dielectric = VoxelMaterial::new_dielectric(Color::WHITE, f32::NAN);
+        assert_eq!(dielectric.refraction_index, 1.0);
+
+        let thin_film = VoxelMaterial::new_thin_film(Color::WHITE, -1.0, 100.0);
+        assert_eq!(thin_film.fuzziness, 0.0);
+        assert_eq!(
+            thin_film.refraction_index,
+            VoxelMaterial::MAX_REFRACTION_INDEX
+        );
+    }
+
+    #[test]
+    fn nan_is_not_uploaded() {
+        let material = VoxelMaterial::new(
+            LinearRgba::new(f32::NAN, f32::INFINITY, 0.5, 1.0),
+            f32::NAN,
+            f32::NAN,
+            VoxelMaterialModel::Lambertian,
+        );
+        let mut buffer = StorageBuffer::new(vec![]);
+        buffer.write(&material).unwrap();
+
+        let floats = buffer.into_inner()[..28]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        // the diffuse color, the texture ID, the fuzziness and the refraction index
+        assert_eq!(floats[..4], [0.0, f32::MAX, 0.5, 1.0]);
+        assert_eq!(floats[5..], [0.0, 1.0]);
+    }
+
+    #[test]
+    fn clearcoat_is_clamped_and_uploaded() {
+        let material = VoxelMaterial::new_lambertian(Color::WHITE).with_clearcoat(2.0, f32::NAN);
+        assert_eq!(
+            [material.clearcoat, material.clearcoat_roughness],
+            [1.0, 0.0]
+        );
+
+        let mut buffer = StorageBuffer::new(vec![]);
+        buffer.write(&material).unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(bytes.len(), 48);
+        // the strength and the roughness of the coat follow the material model
+        let coat = bytes[32..40]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(coat, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn clearcoat_is_ignored_by_transparent_materials() {
+        let dielectric = VoxelMaterial::new_dielectric(Color::WHITE, 1.5).with_clearcoat(1.0, 0.5);
+        assert_eq!(dielectric.clearcoat, 0.0);
+        let thin_film =
+            VoxelMaterial::new_thin_film(Color::WHITE, 1.3, 0.5).with_clearcoat(1.0, 0.5);
+        assert_eq!(thin_film.clearcoat, 0.0);
+    }
+
+    #[test]
+    fn diffuse_light_scales_only_rgb() {
+        let material =
+            VoxelMaterial::new_diffuse_light(Color::linear_rgba(1.0, 0.5, 0.25, 0.5), 10.0);
+        let diffuse = material.diffuse();
+        assert_eq!([diffuse.red, diffuse.green, diffuse.blue], [10.0, 5.0, 2.5]);
+        assert_eq!(diffuse.alpha, 0.5);
+    }
+
+    #[test]
+    fn diffuse_light_clamps_negative_brightness() {
+        let material = VoxelMaterial::new_diffuse_light(Color::WHITE, -1.0);
+        assert_eq!(material.diffuse(), LinearRgba::new(0.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn validate_counts_out_of_range_voxels() {
+        let voxels = [
+            IVec3::new(0, 0, 0),
+            IVec3::new(4, 0, 0),
+            IVec3::new(0, -1, 3),
+        ]
+        .map(|position| RelativeVoxel::at(position, Handle::default()));
+        assert_eq!(VoxelType::new(4, voxels.to_vec()).validate(), (2, 0));
+        assert_eq!(voxel_type(4).validate(), (0, 0));
+    }
+
+    #[test]
+    fn validate_counts_overlapping_voxels() {
+        let voxels = vec![
+            RelativeVoxel::at(IVec3::new(1, 2, 3), Handle::default()),
+            RelativeVoxel::at(IVec3::new(1, 2, 3), Handle::default()),
+            // in the same cell
+            RelativeVoxel::new(Handle::default(), Vec3::new(1.5, 2.5, 3.5)),
+            RelativeVoxel::at(IVec3::new(3, 2, 1), Handle::default()),
+        ];
+        assert_eq!(VoxelType::new(4, voxels).validate(), (0, 2));
+    }
+}
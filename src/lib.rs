@@ -11,7 +11,7 @@
 //!
 //! fn main() {
 //!     App::new()
-//!         .add_plugins((DefaultPlugins, NEVRPlugin))
+//!         .add_plugins((DefaultPlugins, NEVRPlugin::default()))
 //!         .add_systems(Startup, setup)
 //!         .run();
 //! }
@@ -28,7 +28,8 @@
 //!     commands.spawn(VoxelBlock::new(voxel_type));
 //!
 //!     // spawn a new camera with default parameters
-//!     // use Transform to control the position and rotation and Projection to control the projection (perspective vs orthogonal, aspect ratio, etc...)
+//!     // use Transform to control the position and rotation and Projection to control the projection (perspective vs orthogonal, field of view, etc...)
+//!     // the aspect ratio follows the size of the window automatically
 //!     // VoxelCamera has additional parameters that you can check in the documentation
 //!     commands.spawn(VoxelCamera::default());
 //!
@@ -40,50 +41,126 @@
 pub mod engine;
 
 use crate::engine::blas::{BlasManager, compact_blas, prepare_blas};
-use crate::engine::camera::{RayCamera, VoxelCamera};
-use crate::engine::denoiser::{DenoiserPlugin, VoxelDenoiser};
+use crate::engine::camera::{RayCamera, VoxelCamera, prepare_previous_views, update_accumulation};
+use crate::engine::capabilities::NEVRCapabilities;
+use crate::engine::chunk::{NEVRChunkLoader, update_chunks};
+use crate::engine::color_grade::ColorGradePlugin;
+use crate::engine::convergence::ConvergencePlugin;
+use crate::engine::denoiser::{
+    DenoiserPlugin, NEVRFireflyFilter, VoxelDenoiser, VoxelTemporalDenoiser,
+};
+use crate::engine::exposure::AutoExposurePlugin;
+use crate::engine::flipbook::VoxelFlipbook;
+use crate::engine::focus::{
+    FocusPlanePlugin, VoxelAutoFocus, prepare_auto_focus, update_auto_focus,
+};
 use crate::engine::geometry::{GeometryManager, RenderObject, prepare_geometry, prepare_materials};
-use crate::engine::light::{RenderVoxelLight, VoxelLight};
-use crate::engine::node::NEVRNodeRender;
-use crate::engine::skybox::VoxelSkybox;
+use crate::engine::god_rays::GodRaysPlugin;
+use crate::engine::light::{RenderVoxelLight, VoxelLight, VoxelLightOverride};
+use crate::engine::node::{NEVRNodeMode, NEVRNodeRender};
+use crate::engine::probes::{ProbeVolume, RenderProbeGrid, extract_bake_probes, prepare_probes};
+use crate::engine::readback::{
+    NEVRContinuousReadback, NEVRDepthReadback, prepare_continuous_readback, prepare_depth_readback,
+};
+use crate::engine::reference::update_reference_render;
+use crate::engine::settings::{
+    NEVRAccelConfig, NEVRAccumulationSubsteps, NEVRDebugView, NEVRPaused, NEVRSeed,
+    NEVRShadingMode, NEVRTuning,
+};
+use crate::engine::skybox::{
+    RenderSkyboxLayers, SKYBOX_DISTRIBUTION_SIZE, VoxelSkybox, assemble_skybox_faces,
+};
+use crate::engine::stats::{NEVRStats, NEVRStatsChannel, receive_stats, send_stats};
+use crate::engine::status::{NEVRPipelines, NEVRStatus, NEVRWarning, check_pipelines};
+use crate::engine::tween::update_material_color_tweens;
 use crate::engine::voxel::{
-    RenderVoxelBlock, RenderVoxelType, VoxelBlock, VoxelMaterial, VoxelType,
+    RenderVoxelBlock, RenderVoxelBlockInstances, RenderVoxelType, VoxelBlock, VoxelBlockInstances,
+    VoxelMaterial, VoxelType, extract_block_instances,
 };
 use bevy::app::App;
+use bevy::camera::primitives::Aabb;
+use bevy::ecs::change_detection::DetectChanges;
 use bevy::image::ToExtents;
+use bevy::platform::collections::HashMap;
 use bevy::prelude::{
-    AssetApp, Commands, Component, Entity, FromWorld, GlobalTransform, InheritedVisibility,
-    IntoScheduleConfigs, Mat4, Plugin, Query, Res, ResMut, Resource, UVec4, Vec4, With, World,
+    AssetApp, AssetId, Assets, BVec3, Commands, Component, Entity, First, FromWorld,
+    GlobalTransform, Handle, InheritedVisibility, IntoScheduleConfigs, Mat4, Plugin, PostUpdate,
+    Query, Res, ResMut, Resource, TransformSystems, UVec2, UVec4, Update, Vec3, Vec4, With, World,
+    resource_exists,
 };
 use bevy::render::camera::ExtractedCamera;
 use bevy::render::extract_component::ExtractComponentPlugin;
 use bevy::render::extract_resource::ExtractResourcePlugin;
+use bevy::render::globals::GlobalsUniform;
 use bevy::render::render_asset::{RenderAssetPlugin, prepare_assets};
 use bevy::render::render_resource::binding_types::{
-    acceleration_structure, sampler, storage_buffer_read_only, texture_cube, texture_storage_2d,
+    acceleration_structure, sampler, storage_buffer, storage_buffer_read_only,
+    storage_buffer_read_only_sized, texture_2d, texture_2d_array, texture_cube, texture_storage_2d,
     uniform_buffer,
 };
 use bevy::render::render_resource::{
-    AccelerationStructureFlags, AccelerationStructureUpdateMode, BindGroup, BindGroupEntries,
-    BindGroupLayout, BindGroupLayoutEntries, CommandEncoderDescriptor, CreateTlasDescriptor,
+    AccelerationStructureUpdateMode, BindGroup, BindGroupEntries, BindGroupLayout,
+    BindGroupLayoutEntries, Blas, CommandEncoderDescriptor, CreateTlasDescriptor,
     SamplerBindingType, ShaderStages, StorageBuffer, StorageTextureAccess, TextureDescriptor,
-    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TlasInstance,
+    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, Tlas, TlasInstance,
 };
-use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::renderer::{RenderAdapter, RenderDevice, RenderQueue};
 use bevy::render::settings::WgpuFeatures;
+use bevy::render::sync_component::SyncComponentPlugin;
+use bevy::render::sync_world::MainEntity;
 use bevy::render::texture::{CachedTexture, TextureCache};
-use bevy::render::view::ViewUniform;
-use bevy::render::{Render, RenderApp, RenderSystems};
+use bevy::render::view::{ExtractedView, ViewUniform};
+use bevy::render::{ExtractSchedule, Render, RenderApp, RenderSystems};
+use std::borrow::Cow;
+use std::num::NonZeroU64;
 
 /// Default plugin for NEVR.
 ///
 /// Add it to your app to use NEVR:
 /// ```rs
-/// App::new().add_plugins((DefaultPlugins, NEVRPlugin)).run();
+/// App::new().add_plugins((DefaultPlugins, NEVRPlugin::default())).run();
+/// ```
+///
+/// The fields set the starting values of the renderer, the resources they insert can still be changed at
+/// runtime (except [NEVRAccelConfig]). Resources already inserted before the plugin is added are kept:
+/// ```rs
+/// App::new()
+///     .add_plugins((
+///         DefaultPlugins,
+///         NEVRPlugin {
+///             default_denoiser: VoxelDenoiser::Simple,
+///             ..default()
+///         },
+///     ))
+///     .run();
+/// ```
+///
+/// The shaders are loaded and compiled in the background after the app starts, so the first frames are black
+/// until they're ready (usually a fraction of a second, more the first time a driver compiles them).
+/// [NEVRStats::pipelines_ready] is true once they're ready, to keep a loading screen up until then:
+/// ```rs
+/// fn hide_loading_screen(stats: Res<NEVRStats>, mut screen: Single<&mut Visibility, With<LoadingScreen>>) {
+///     if stats.pipelines_ready {
+///         **screen = Visibility::Hidden;
+///     }
+/// }
 /// ```
 ///
 /// **Note:** Bevy default plugins are necessary for NEVR.
-pub struct NEVRPlugin;
+#[derive(Clone, Debug, Default)]
+pub struct NEVRPlugin {
+    /// The [VoxelDenoiser] used until it's changed. Defaults to [VoxelDenoiser::None].
+    pub default_denoiser: VoxelDenoiser,
+    /// The starting [NEVRTuning].
+    pub tuning: NEVRTuning,
+    /// The starting [NEVRShadingMode]. Defaults to [NEVRShadingMode::Full].
+    pub shading_mode: NEVRShadingMode,
+    /// How the acceleration structures are built, check [NEVRAccelConfig].
+    pub accel_config: NEVRAccelConfig,
+    /// Whether [engine::debug_ui::NEVRDebugUiPlugin] is added too. Defaults to false.
+    #[cfg(feature = "egui")]
+    pub debug_ui: bool,
+}
 
 impl NEVRPlugin {
     /// Required device features to support hardware raytracing
@@ -92,6 +169,19 @@ impl NEVRPlugin {
             | WgpuFeatures::EXPERIMENTAL_RAY_QUERY
     }
 
+    // TODO: the software path needs its own traversal shader and a GPU BVH builder (LBVH over Morton codes of
+    //  the GeometryManager triangles, with a CPU builder as a debugging fallback) so that dynamic scenes don't
+    //  require a CPU rebuild every frame; none of this exists yet, so the builder can't be added on its own
+    /// Returns which features of NEVR the device supports.
+    ///
+    /// The result is also available as a resource once the plugin is finished.
+    pub fn available_modes(
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+    ) -> NEVRCapabilities {
+        NEVRCapabilities::from_device(render_device, render_adapter)
+    }
+
     /// Required device features to support software raytracing (does not require hardware support
     /// so it can be used on older GPUs)
     pub fn required_sw_features() -> WgpuFeatures {
@@ -102,25 +192,93 @@ impl NEVRPlugin {
 // TODO: add better checking in the code to avoid bevy/wgpu panics to better inform users of errors in their code
 impl Plugin for NEVRPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((NEVRNodeRender, DenoiserPlugin))
-            .add_plugins(ExtractResourcePlugin::<RenderVoxelLight>::default())
-            .add_plugins(ExtractResourcePlugin::<VoxelSkybox>::default())
-            .add_plugins(RenderAssetPlugin::<VoxelMaterial>::default())
-            .add_plugins(RenderAssetPlugin::<RenderVoxelType>::default())
-            .add_plugins(ExtractComponentPlugin::<VoxelBlock>::default())
-            .add_plugins(ExtractComponentPlugin::<VoxelCamera>::default())
-            .init_asset::<VoxelMaterial>()
-            .init_asset::<VoxelType>()
-            .init_resource::<VoxelLight>();
+        // the resources inserted before the plugin win over its fields
+        if !app.world().contains_resource::<VoxelDenoiser>() {
+            app.insert_resource(self.default_denoiser);
+        }
+        if !app.world().contains_resource::<NEVRTuning>() {
+            app.insert_resource(self.tuning);
+        }
+        if !app.world().contains_resource::<NEVRShadingMode>() {
+            app.insert_resource(self.shading_mode);
+        }
+
+        app.add_plugins((
+            NEVRNodeRender,
+            DenoiserPlugin,
+            FocusPlanePlugin,
+            GodRaysPlugin,
+            ColorGradePlugin,
+            AutoExposurePlugin,
+            ConvergencePlugin,
+        ))
+        .add_plugins(ExtractResourcePlugin::<RenderVoxelLight>::default())
+        .add_plugins(ExtractResourcePlugin::<VoxelSkybox>::default())
+        .add_plugins(ExtractResourcePlugin::<VoxelFlipbook>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRSeed>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRTuning>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRAccumulationSubsteps>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRDebugView>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRShadingMode>::default())
+        .add_plugins(ExtractResourcePlugin::<NEVRPaused>::default())
+        .add_plugins(RenderAssetPlugin::<VoxelMaterial>::default())
+        .add_plugins(RenderAssetPlugin::<RenderVoxelType>::default())
+        .add_plugins(ExtractComponentPlugin::<VoxelBlock>::default())
+        .add_plugins(SyncComponentPlugin::<VoxelBlockInstances>::default())
+        .add_plugins(ExtractComponentPlugin::<VoxelCamera>::default())
+        .add_plugins(ExtractComponentPlugin::<VoxelLightOverride>::default())
+        .add_plugins(ExtractComponentPlugin::<VoxelAutoFocus>::default())
+        .add_plugins(ExtractComponentPlugin::<NEVRContinuousReadback>::default())
+        .add_plugins(ExtractComponentPlugin::<NEVRDepthReadback>::default())
+        .init_asset::<VoxelMaterial>()
+        .init_asset::<VoxelType>()
+        .init_resource::<VoxelLight>()
+        .init_resource::<NEVRSeed>()
+        .init_resource::<NEVRAccumulationSubsteps>()
+        .init_resource::<NEVRDebugView>()
+        .init_resource::<NEVRPaused>()
+        .init_resource::<NEVRStats>()
+        .add_systems(
+            Update,
+            update_chunks.run_if(resource_exists::<NEVRChunkLoader>),
+        )
+        .add_systems(Update, (prepare_auto_focus, update_auto_focus).chain())
+        .add_systems(
+            Update,
+            (prepare_continuous_readback, prepare_depth_readback),
+        )
+        .add_systems(Update, update_material_color_tweens)
+        .add_systems(
+            Update,
+            assemble_skybox_faces.run_if(resource_exists::<VoxelSkybox>),
+        )
+        .add_systems(
+            PostUpdate,
+            (update_accumulation, update_reference_render)
+                .chain()
+                .after(TransformSystems::Propagate),
+        );
+
+        register_types(app);
+
+        #[cfg(feature = "egui")]
+        if self.debug_ui {
+            app.add_plugins(engine::debug_ui::NEVRDebugUiPlugin);
+        }
     }
 
     fn finish(&self, app: &mut App) {
+        let render_world = app.sub_app(RenderApp).world();
+        let capabilities = NEVRPlugin::available_modes(
+            render_world.resource::<RenderDevice>(),
+            render_world.resource::<RenderAdapter>(),
+        );
+        app.insert_resource(capabilities);
+
         let render_app = app.sub_app_mut(RenderApp);
+        render_app.insert_resource(capabilities);
         let render_device = render_app.world().resource::<RenderDevice>();
-        if !render_device
-            .features()
-            .contains(NEVRPlugin::required_hw_features())
-        {
+        if !capabilities.hardware_ray_tracing {
             eprintln!(
                 "Missing features: {}\nIn the future software raytracing may be supported",
                 render_device
@@ -129,14 +287,36 @@ impl Plugin for NEVRPlugin {
             );
             return;
         }
+        // the bind group layouts would fail the validation of wgpu
+        if capabilities.hdr_storage_format.is_none() {
+            eprintln!(
+                "The GPU can't read and write Rgba16Float storage textures, enable the TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES feature if the adapter supports it"
+            );
+            return;
+        }
 
+        if !render_app.world().contains_resource::<NEVRAccelConfig>() {
+            render_app.insert_resource(self.accel_config);
+        }
+
+        let stats_channel = NEVRStatsChannel::default();
         render_app
+            .insert_resource(stats_channel.clone())
+            .init_resource::<NEVRStats>()
+            .init_resource::<NEVRStatus>()
+            .init_resource::<NEVRPipelines>()
             .init_resource::<BlasManager>()
             .init_resource::<GeometryManager>()
             .init_resource::<VoxelBindings>()
+            .init_resource::<ProbeVolume>()
+            .add_systems(
+                ExtractSchedule,
+                (extract_block_instances, extract_bake_probes),
+            )
             .add_systems(
                 Render,
-                prepare_view_target.in_set(RenderSystems::PrepareResources),
+                (prepare_view_target, prepare_previous_views, prepare_probes)
+                    .in_set(RenderSystems::PrepareResources),
             )
             .add_systems(
                 Render,
@@ -164,28 +344,113 @@ impl Plugin for NEVRPlugin {
             )
             .add_systems(
                 Render,
-                prepare_bindings.in_set(RenderSystems::PrepareBindGroups),
+                prepare_bindings
+                    .in_set(RenderSystems::PrepareBindGroups)
+                    .run_if(|paused: Res<NEVRPaused>| !paused.0),
+            )
+            .add_systems(
+                Render,
+                (check_pipelines, send_stats)
+                    .chain()
+                    .in_set(RenderSystems::Cleanup),
             );
+
+        app.insert_resource(stats_channel)
+            .add_systems(First, receive_stats);
     }
 }
 
+/// The world bounding box of all the visible [VoxelBlock]s and [VoxelBlockInstances], `None` when there are
+/// none or their types aren't loaded yet.
+///
+/// Useful to place a camera where it sees the scene, check [VoxelCamera::frame_scene]:
+/// ```rs
+/// fn frame(world: &mut World) {
+///     let Some(bounds) = nevr::scene_bounds(world) else {
+///         return;
+///     };
+///     let mut camera = world.query::<(&mut VoxelCamera, &mut Transform)>();
+///     for (mut camera, mut transform) in camera.iter_mut(world) {
+///         *transform = camera.frame_scene(&bounds, 90.0f32.to_radians());
+///     }
+/// }
+/// ```
+pub fn scene_bounds(world: &World) -> Option<Aabb> {
+    let voxel_types = world.get_resource::<Assets<VoxelType>>()?;
+
+    // the corners of the boxes of the types moved into the world, the box of the scene encloses all of them
+    let corners = |voxel_type: &Handle<VoxelType>, transform: Mat4| {
+        voxel_types
+            .get(voxel_type)
+            .and_then(VoxelType::bounds)
+            .into_iter()
+            .flat_map(move |bounds| {
+                let (min, max) = (Vec3::from(bounds.min()), Vec3::from(bounds.max()));
+                (0..8).map(move |corner| {
+                    let corner = Vec3::select(
+                        BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                        max,
+                        min,
+                    );
+                    transform.transform_point3(corner)
+                })
+            })
+    };
+
+    let mut points = vec![];
+
+    if let Some(mut blocks) =
+        world.try_query::<(&VoxelBlock, &GlobalTransform, &InheritedVisibility)>()
+    {
+        for (block, transform, visibility) in blocks.iter(world) {
+            if visibility.get() {
+                points.extend(corners(&block.voxel_type, transform.to_matrix()));
+            }
+        }
+    }
+
+    if let Some(mut instances) =
+        world.try_query::<(&VoxelBlockInstances, &GlobalTransform, &InheritedVisibility)>()
+    {
+        for (instances, transform, visibility) in instances.iter(world) {
+            if !visibility.get() {
+                continue;
+            }
+
+            for instance in &instances.transforms {
+                let transform = transform.mul_transform(*instance).to_matrix();
+                points.extend(corners(&instances.voxel_type, transform));
+            }
+        }
+    }
+
+    Aabb::enclosing(points)
+}
+
+// the types that can be inspected and edited through reflection, e.g. by bevy-inspector-egui
+fn register_types(app: &mut App) {
+    app.register_type::<VoxelCamera>()
+        .register_type::<VoxelLight>()
+        .register_type::<VoxelBlock>()
+        .register_asset_reflect::<VoxelMaterial>();
+}
+
 /// Trait to convert data to byte slices.
+///
+/// It's implemented for the slices (and through them the arrays and vectors) of every [bytemuck::Pod] type,
+/// the bytes are in the native order of the CPU (little-endian on the usual ones):
+/// ```rs
+/// assert_eq!([1.0f32, 2.0].to_bytes(), [0, 0, 128, 63, 0, 0, 0, 64]);
+/// assert_eq!(vec![1u32, 256].to_bytes(), [1, 0, 0, 0, 0, 1, 0, 0]);
+/// ```
 pub trait ToBytes {
     /// Convert the data representation to a byte slice.
     fn to_bytes(&self) -> &[u8];
 }
 
-impl ToBytes for [f32] {
-    fn to_bytes(&self) -> &[u8] {
-        // SAFETY: f32 always contains 4 u8
-        unsafe { std::slice::from_raw_parts(self.as_ptr() as *const _, self.len() * 4) }
-    }
-}
-
-impl ToBytes for [u32] {
+impl<T: bytemuck::Pod> ToBytes for [T] {
     fn to_bytes(&self) -> &[u8] {
-        // SAFETY: u32 always contains 4 u8
-        unsafe { std::slice::from_raw_parts(self.as_ptr() as *const _, self.len() * 4) }
+        bytemuck::cast_slice(self)
     }
 }
 
@@ -193,6 +458,18 @@ impl ToBytes for [u32] {
 #[derive(Resource)]
 pub struct VoxelBindings {
     pub bind_group: Option<BindGroup>,
+    /// The TLAS bound in [VoxelBindings::bind_group], kept to trace rays outside the compute pipeline.
+    ///
+    /// It's reused between frames while the scene fits in it: only the instances that changed are written and
+    /// it's built again only when one of them did. Every instance of a block keeps its slot in the TLAS while the
+    /// block exists, the slots of the despawned blocks are emptied and reused in the same frame.
+    pub tlas: Option<Tlas>,
+    // the slot of every instance of the TLAS and what it was set to, to find the changed ones
+    tlas_slots: TlasSlots,
+    /// The main world entity of every object, indexed by the object ID.
+    pub object_entities: Vec<MainEntity>,
+    /// The world transforms of the last frame of every entity, used for the motion vectors of moving blocks.
+    previous_transforms: HashMap<MainEntity, Vec<Mat4>>,
     pub bind_group_layouts: [BindGroupLayout; 4],
 }
 
@@ -202,6 +479,10 @@ impl FromWorld for VoxelBindings {
 
         Self {
             bind_group: None,
+            tlas: None,
+            tlas_slots: TlasSlots::default(),
+            object_entities: vec![],
+            previous_transforms: HashMap::default(),
             bind_group_layouts: [
                 render_device.create_bind_group_layout(
                     "voxel_bind_group_layout",
@@ -218,10 +499,20 @@ impl FromWorld for VoxelBindings {
                             storage_buffer_read_only::<Vec4>(false),
                             // Normals
                             storage_buffer_read_only::<Vec4>(false),
+                            // Tangents
+                            storage_buffer_read_only::<Vec4>(false),
                             // Materials
                             storage_buffer_read_only::<VoxelMaterial>(false),
                             // Material Map
                             storage_buffer_read_only::<u32>(false),
+                            // Previous transforms of the TLAS instances
+                            storage_buffer_read_only::<Mat4>(false),
+                            // Material palette of the remapped objects
+                            storage_buffer_read_only::<u32>(false),
+                            // Baked probes
+                            storage_buffer::<Vec4>(false),
+                            // Grid of the probes
+                            uniform_buffer::<RenderProbeGrid>(false),
                         ),
                     ),
                 ),
@@ -230,8 +521,8 @@ impl FromWorld for VoxelBindings {
                     &BindGroupLayoutEntries::sequential(
                         ShaderStages::COMPUTE,
                         (
-                            // Camera
-                            uniform_buffer::<RayCamera>(false),
+                            // Camera, one for every substep
+                            uniform_buffer::<RayCamera>(true),
                             // Texture storage view
                             texture_storage_2d(
                                 TextureFormat::Rgba16Float,
@@ -246,6 +537,20 @@ impl FromWorld for VoxelBindings {
                                 TextureFormat::Rgba16Float,
                                 StorageTextureAccess::ReadWrite,
                             ),
+                            // Previous view
+                            uniform_buffer::<Mat4>(false),
+                            // Offset of the dispatched tile
+                            uniform_buffer::<UVec2>(true),
+                            // Cookie of the light
+                            texture_2d(TextureSampleType::Float { filterable: true }),
+                            // Cookie sampler
+                            sampler(SamplerBindingType::Filtering),
+                            // Flipbook frames
+                            texture_2d_array(TextureSampleType::Float { filterable: true }),
+                            // Flipbook sampler
+                            sampler(SamplerBindingType::Filtering),
+                            // Time
+                            uniform_buffer::<GlobalsUniform>(false),
                         ),
                     ),
                 ),
@@ -269,6 +574,31 @@ impl FromWorld for VoxelBindings {
                                 TextureFormat::Rgba16Float,
                                 StorageTextureAccess::WriteOnly,
                             ),
+                            // Depth
+                            texture_storage_2d(
+                                TextureFormat::R32Float,
+                                StorageTextureAccess::WriteOnly,
+                            ),
+                            // Object ID
+                            texture_storage_2d(
+                                TextureFormat::R32Uint,
+                                StorageTextureAccess::WriteOnly,
+                            ),
+                            // Motion vectors
+                            texture_storage_2d(
+                                TextureFormat::Rg32Float,
+                                StorageTextureAccess::WriteOnly,
+                            ),
+                            // Detail
+                            texture_storage_2d(
+                                TextureFormat::R32Float,
+                                StorageTextureAccess::ReadWrite,
+                            ),
+                            // Specular
+                            texture_storage_2d(
+                                TextureFormat::Rgba16Float,
+                                StorageTextureAccess::ReadWrite,
+                            ),
                         ),
                     ),
                 ),
@@ -281,6 +611,19 @@ impl FromWorld for VoxelBindings {
                             texture_cube(TextureSampleType::Float { filterable: true }),
                             // Sampler
                             sampler(SamplerBindingType::Filtering),
+                            // Whether to decode the texels from sRGB
+                            uniform_buffer::<u32>(false),
+                            // Distribution used to importance sample the skybox
+                            storage_buffer_read_only_sized(
+                                false,
+                                NonZeroU64::new(SKYBOX_DISTRIBUTION_SIZE),
+                            ),
+                            // Layers
+                            uniform_buffer::<RenderSkyboxLayers>(false),
+                            // Layer textures, one for every VoxelSkybox::MAX_LAYERS
+                            texture_cube(TextureSampleType::Float { filterable: true }),
+                            texture_cube(TextureSampleType::Float { filterable: true }),
+                            texture_cube(TextureSampleType::Float { filterable: true }),
                         ),
                     ),
                 ),
@@ -289,11 +632,28 @@ impl FromWorld for VoxelBindings {
     }
 }
 
+impl VoxelBindings {
+    /// Returns the main world entity of an object ID read from [VoxelGBuffer::object_id].
+    ///
+    /// All the instances of a [VoxelBlockInstances] share the same entity.
+    pub fn object_entity(&self, object_id: u32) -> Option<MainEntity> {
+        self.object_entities.get(object_id as usize).copied()
+    }
+}
+
 /// Texture view target used for rendering.
 #[derive(Component)]
 pub struct VoxelViewTarget {
     pub output: CachedTexture,
     pub accumulation: CachedTexture,
+    /// The (denoised) image drawn by the fragment pipeline, only used with [NEVRNodeMode::Fragment].
+    pub composite: Option<CachedTexture>,
+    /// The result of the temporal denoiser in the last frame, only used when [VoxelTemporalDenoiser] is enabled.
+    pub history: Option<CachedTexture>,
+    /// The result of the temporal denoiser in this frame, the input of the other denoisers.
+    pub temporal: Option<CachedTexture>,
+    /// The image without its fireflies, only used when [NEVRFireflyFilter] is enabled.
+    pub firefly: Option<CachedTexture>,
 }
 
 /// Texture views for g-buffer's data (used for denoising)
@@ -301,19 +661,54 @@ pub struct VoxelViewTarget {
 pub struct VoxelGBuffer {
     pub albedo: CachedTexture,
     pub normal: CachedTexture,
+    /// World position of the primary hit relative to the camera, so it keeps its precision far from the origin.
     pub world_position: CachedTexture,
+    /// Linear view-space depth of the primary hit (R32Float), 0.0 where nothing was hit.
+    pub depth: CachedTexture,
+    /// Index of the object of the primary hit (R32Uint), [VoxelGBuffer::NO_OBJECT] where nothing was hit.
+    ///
+    /// Use [VoxelBindings::object_entity] to find the entity the object belongs to.
+    pub object_id: CachedTexture,
+    /// Screen-space motion of the primary hit since the last frame (Rg32Float), in UV coordinates.
+    ///
+    /// The motion is the current UV minus the UV the same point had in the last frame, it includes both the
+    /// motion of the camera and the motion of the blocks.
+    pub motion_vectors: CachedTexture,
+    /// The part of the light of every pixel that comes from specular paths and emissive surfaces (R32Float),
+    /// between 0.0 and 1.0, accumulated like the image. The denoisers keep that part sharp, see
+    /// [VoxelDenoiserDetail](crate::engine::denoiser::VoxelDenoiserDetail).
+    pub detail: CachedTexture,
+    /// The light of every pixel that comes from specular paths and emissive surfaces (Rgba16Float), accumulated
+    /// like the image. [VoxelDenoiser::Split] filters the rest of the light and adds this back unfiltered.
+    pub specular: CachedTexture,
     pub secondary_textures: Vec<CachedTexture>,
 }
 
+impl VoxelGBuffer {
+    /// The object ID written where nothing was hit.
+    pub const NO_OBJECT: u32 = u32::MAX;
+}
+
 fn prepare_view_target(
     query: Query<(Entity, &ExtractedCamera), With<RayCamera>>,
     mut texture_cache: ResMut<TextureCache>,
     render_device: Res<RenderDevice>,
     voxel_denoiser: Res<VoxelDenoiser>,
+    temporal_denoiser: Res<VoxelTemporalDenoiser>,
+    firefly_filter: Res<NEVRFireflyFilter>,
+    node_mode: Res<NEVRNodeMode>,
     mut commands: Commands,
 ) {
     for (entity, camera) in query {
-        let Some(viewport) = camera.physical_viewport_size else {
+        // wgpu doesn't allow zero-sized textures, the passes skip the views without the targets until the
+        // viewport grows back
+        let Some(viewport) = camera
+            .physical_viewport_size
+            .filter(|viewport| viewport.x > 0 && viewport.y > 0)
+        else {
+            commands
+                .entity(entity)
+                .remove::<(VoxelViewTarget, VoxelGBuffer)>();
             continue;
         };
 
@@ -372,6 +767,63 @@ fn prepare_view_target(
             view_formats: &[],
         };
 
+        let depth_descriptor = TextureDescriptor {
+            label: Some("voxel_raytracing_depth"),
+            size: viewport.to_extents(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        };
+
+        let object_id_descriptor = TextureDescriptor {
+            label: Some("voxel_raytracing_object_id"),
+            size: viewport.to_extents(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Uint,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        };
+
+        let motion_vectors_descriptor = TextureDescriptor {
+            label: Some("voxel_raytracing_motion_vectors"),
+            size: viewport.to_extents(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rg32Float,
+            usage: TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        };
+
+        // accumulated like the image, so it keeps its content between frames like the accumulation
+        let detail_descriptor = TextureDescriptor {
+            label: Some("voxel_raytracing_detail"),
+            size: viewport.to_extents(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        };
+
+        // accumulated like the image, so it keeps its content between frames like the accumulation
+        let specular_descriptor = TextureDescriptor {
+            label: Some("voxel_raytracing_specular"),
+            size: viewport.to_extents(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba16Float,
+            usage: TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        };
+
         let secondary_texture_descriptor = TextureDescriptor {
             label: Some("voxel_raytracing_a_trous_secondary_texture"),
             size: viewport.to_extents(),
@@ -383,8 +835,14 @@ fn prepare_view_target(
             view_formats: &[],
         };
 
-        let secondary_textures = if let VoxelDenoiser::ATrous(size) = *voxel_denoiser {
-            let size = (size.get() as f32).log2().floor() as usize + 1;
+        let secondary_textures = if let VoxelDenoiser::ATrous(size) | VoxelDenoiser::Split(size) =
+            *voxel_denoiser
+        {
+            let mut size = (size.get() as f32).log2().floor() as usize + 1;
+            // the split denoiser combines the filtered diffuse light with the specular light in another texture
+            if matches!(*voxel_denoiser, VoxelDenoiser::Split(_)) {
+                size += 1;
+            }
             let mut textures = Vec::with_capacity(size);
 
             for _ in 0..size {
@@ -397,16 +855,86 @@ fn prepare_view_target(
             vec![]
         };
 
+        let composite = (*node_mode == NEVRNodeMode::Fragment).then(|| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some("voxel_raytracing_composite_texture"),
+                    size: viewport.to_extents(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba16Float,
+                    usage: TextureUsages::STORAGE_BINDING
+                        | TextureUsages::TEXTURE_BINDING
+                        | TextureUsages::COPY_DST,
+                    view_formats: &[],
+                },
+            )
+        });
+
+        let (history, temporal) = if temporal_denoiser.enabled {
+            let descriptor = TextureDescriptor {
+                label: Some("voxel_raytracing_temporal_history"),
+                size: viewport.to_extents(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba16Float,
+                usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            };
+
+            (
+                Some(texture_cache.get(&render_device, descriptor.clone())),
+                Some(texture_cache.get(
+                    &render_device,
+                    TextureDescriptor {
+                        label: Some("voxel_raytracing_temporal_output"),
+                        usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+                        ..descriptor
+                    },
+                )),
+            )
+        } else {
+            (None, None)
+        };
+
+        let firefly = firefly_filter.enabled.then(|| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some("voxel_raytracing_firefly_output"),
+                    size: viewport.to_extents(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba16Float,
+                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+                    view_formats: &[],
+                },
+            )
+        });
+
         commands
             .entity(entity)
             .insert(VoxelViewTarget {
                 output: texture_cache.get(&render_device, target_descriptor),
                 accumulation: texture_cache.get(&render_device, accumulation_descriptor),
+                composite,
+                history,
+                temporal,
+                firefly,
             })
             .insert(VoxelGBuffer {
                 albedo: texture_cache.get(&render_device, albedo_descriptor),
                 normal: texture_cache.get(&render_device, normal_descriptor),
                 world_position: texture_cache.get(&render_device, world_position_descriptor),
+                depth: texture_cache.get(&render_device, depth_descriptor),
+                object_id: texture_cache.get(&render_device, object_id_descriptor),
+                motion_vectors: texture_cache.get(&render_device, motion_vectors_descriptor),
+                detail: texture_cache.get(&render_device, detail_descriptor),
+                specular: texture_cache.get(&render_device, specular_descriptor),
                 secondary_textures,
             });
     }
@@ -420,87 +948,325 @@ pub fn prepare_bindings(
     render_queue: Res<RenderQueue>,
     blas_manager: Res<BlasManager>,
     geometry_manager: Res<GeometryManager>,
-    blocks_query: Query<(&RenderVoxelBlock, &GlobalTransform, &InheritedVisibility)>,
+    blocks_query: Query<(
+        &RenderVoxelBlock,
+        &GlobalTransform,
+        &InheritedVisibility,
+        &MainEntity,
+    )>,
+    instances_query: Query<(
+        &RenderVoxelBlockInstances,
+        &InheritedVisibility,
+        &MainEntity,
+    )>,
+    views: Query<&ExtractedView, With<RayCamera>>,
+    tuning: Res<NEVRTuning>,
+    accel_config: Res<NEVRAccelConfig>,
+    status: Res<NEVRStatus>,
+    mut stats: ResMut<NEVRStats>,
+    probe_volume: Res<ProbeVolume>,
 ) {
     voxel_bindings.bind_group = None;
+    // the TLAS is reused when it's still large enough, it's dropped when nothing can be rendered
+    let previous_tlas = voxel_bindings.tlas.take();
+    voxel_bindings.object_entities.clear();
+    stats.tlas_instances = 0;
+    stats.tlas_writes = 0;
 
-    if blocks_query.is_empty() {
-        eprintln!("no blocks");
+    // nothing to render, the node reports the missing bind group
+    if blocks_query.is_empty() && instances_query.is_empty() {
         return;
     }
 
-    let mut tlas = render_device
-        .wgpu_device()
-        .create_tlas(&CreateTlasDescriptor {
-            label: None,
-            flags: AccelerationStructureFlags::PREFER_FAST_TRACE,
-            update_mode: AccelerationStructureUpdateMode::Build,
-            max_instances: blocks_query.iter().len() as u32,
-        });
+    let (
+        Some(vertices),
+        Some(normals),
+        Some(tangents),
+        Some(indices),
+        Some(materials),
+        Some(material_map),
+    ) = (
+        geometry_manager.vertices().buffer(),
+        geometry_manager.normals().buffer(),
+        geometry_manager.tangents().buffer(),
+        geometry_manager.indices().buffer(),
+        geometry_manager.materials().buffer(),
+        geometry_manager.material_map().buffer(),
+    )
+    else {
+        // the buffers are empty until the first visible type is built, which is expected in the first frames
+        if geometry_manager.has_geometry() {
+            status.report(NEVRWarning::MissingGeometryBuffers);
+        }
+        return;
+    };
+
+    let total_instances = blocks_query.iter().len()
+        + instances_query
+            .iter()
+            .map(|(instances, _, _)| instances.transforms.len())
+            .sum::<usize>();
+    // wgpu panics when the TLAS is bigger than the limit, the instances over it are dropped instead
+    let mut instance_limit = render_device.limits().max_tlas_instance_count;
+    if tuning.max_instances > 0 {
+        instance_limit = instance_limit.min(tuning.max_instances);
+    }
+    let max_instances = total_instances.min(instance_limit as usize);
+    // the TLAS grows to the next power of two so that spawning blocks doesn't create a new one every frame, and
+    // it's created again when it's too large or the acceleration structure flags changed
+    let reused_tlas = previous_tlas.filter(|tlas| {
+        let capacity = tlas.get().len();
+        capacity >= max_instances && capacity / 4 <= max_instances && !accel_config.is_changed()
+    });
+    let mut tlas_slots = std::mem::take(&mut voxel_bindings.tlas_slots);
+    let tlas_reused = reused_tlas.is_some();
+    let mut tlas = reused_tlas.unwrap_or_else(|| {
+        tlas_slots.clear();
+        render_device
+            .wgpu_device()
+            .create_tlas(&CreateTlasDescriptor {
+                label: None,
+                flags: accel_config.flags(),
+                update_mode: AccelerationStructureUpdateMode::Build,
+                max_instances: max_instances
+                    .next_power_of_two()
+                    .min(instance_limit as usize)
+                    .max(1) as u32,
+            })
+    });
+    let mut tlas_writes = 0;
+
+    // the instances of the despawned blocks (and the ones removed from a VoxelBlockInstances) free their slots
+    // before the new instances take one, so a block replacing a despawned one doesn't grow the TLAS
+    let live_instances = blocks_query
+        .iter()
+        .map(|(_, _, _, entity)| (*entity, 1))
+        .chain(
+            instances_query
+                .iter()
+                .map(|(instances, _, entity)| (*entity, instances.transforms.len())),
+        )
+        .collect::<HashMap<_, _>>();
+    let mut previous_slots = std::mem::take(&mut tlas_slots.slots);
+    previous_slots.retain(|(entity, i), (slot, _)| {
+        let live = live_instances
+            .get(entity)
+            .is_some_and(|instances| *i < *instances);
+        if !live {
+            *tlas.get_mut_single(*slot).unwrap() = None;
+            tlas_slots.release(*slot);
+            tlas_writes += 1;
+        }
+        live
+    });
+
     let mut objects = StorageBuffer::<Vec<RenderObject>>::default();
+    let mut previous_transforms = StorageBuffer::<Vec<Mat4>>::default();
+    let mut current_transforms = HashMap::default();
+    // the slices of the material palette of every different remap
+    let mut material_palette = StorageBuffer::<Vec<u32>>::default();
+    let mut remap_offsets =
+        HashMap::<&[(AssetId<VoxelMaterial>, AssetId<VoxelMaterial>)], u32>::default();
+
+    // every block is a group with a single transform, every VoxelBlockInstances is a group sharing one object;
+    // the hidden ones keep their instances with an empty mask, so hiding and showing them only changes the mask
+    let blocks = blocks_query
+        .iter()
+        .map(|(block, transform, visibility, entity)| {
+            (
+                *entity,
+                visibility.get(),
+                block.voxel_type,
+                block.uv_scale,
+                block.custom_data,
+                block.light_channels,
+                block.material_remap.as_slice(),
+                Cow::Owned(vec![transform.to_matrix()]),
+            )
+        });
+    let instances = instances_query
+        .iter()
+        .filter(|(instances, _, _)| !instances.transforms.is_empty())
+        .map(|(instances, visibility, entity)| {
+            (
+                *entity,
+                visibility.get(),
+                instances.voxel_type,
+                instances.uv_scale,
+                instances.custom_data,
+                instances.light_channels,
+                instances.material_remap.as_slice(),
+                Cow::Borrowed(instances.transforms.as_slice()),
+            )
+        });
+
+    // the levels of detail are picked by the distance to the closest camera
+    let camera_positions = views
+        .iter()
+        .map(|view| view.world_from_view.translation())
+        .collect::<Vec<_>>();
 
+    let mut object_entities = vec![];
     let mut instance_id = 0;
-    for (block, transform, visible) in blocks_query {
-        if *visible == InheritedVisibility::HIDDEN {
+    'groups: for (
+        entity,
+        visible,
+        voxel_type,
+        uv_scale,
+        custom_data,
+        light_channels,
+        material_remap,
+        transforms,
+    ) in blocks.chain(instances)
+    {
+        if blas_manager.get(&voxel_type).is_none() {
             continue;
         }
-        let voxel_type = block.voxel_type.clone();
-        let Some(blas) = blas_manager.get(&block.voxel_type) else {
-            continue;
-        };
 
-        let Some(id) = geometry_manager.get_object_id(&voxel_type) else {
-            return;
+        let material_remap = if material_remap.is_empty() {
+            RenderObject::NO_MATERIAL_REMAP
+        } else {
+            *remap_offsets.entry(material_remap).or_insert_with(|| {
+                let palette = material_palette.get_mut();
+                let offset = palette.len() as u32;
+                palette.extend(0..geometry_manager.material_count());
+                for (original, replacement) in material_remap {
+                    // the materials are uploaded once they're loaded, until then the original is kept
+                    if let (Some(original), Some(replacement)) = (
+                        geometry_manager.index_of_material(original),
+                        geometry_manager.index_of_material(replacement),
+                    ) {
+                        palette[(offset + original) as usize] = replacement;
+                    }
+                }
+                offset
+            })
         };
 
-        let Some(index_id) = geometry_manager.get_index(id) else {
-            return;
-        };
-        let Some(material_id) = geometry_manager.get_index_material(id) else {
-            return;
-        };
+        // the instances of a group using the same level of detail share an object
+        let mut lod_objects = HashMap::<AssetId<VoxelType>, u32>::default();
+        let entity_previous_transforms = voxel_bindings.previous_transforms.get(&entity);
+        for (i, transform) in transforms.iter().enumerate() {
+            if instance_id == max_instances {
+                status.report(NEVRWarning::TooManyInstances);
+                break 'groups;
+            }
 
-        let transform = transform.to_matrix();
-        *tlas.get_mut_single(instance_id).unwrap() = Some(TlasInstance::new(
-            blas,
-            tlas_transform(&transform),
-            instance_id as u32,
-            0xFF,
-        ));
-        objects.get_mut().push(RenderObject {
-            index: index_id,
-            material_id,
-        });
+            let center = transform.transform_point3(Vec3::splat(0.5));
+            let distance = camera_positions
+                .iter()
+                .map(|position| position.distance(center))
+                .fold(f32::INFINITY, f32::min);
+            let Some((lod_type, blas)) =
+                blas_manager.get_lod(&geometry_manager, &voxel_type, distance)
+            else {
+                continue;
+            };
+
+            let object_index = match lod_objects.get(&lod_type) {
+                Some(object_index) => *object_index,
+                None => {
+                    let Some(id) = geometry_manager.get_object_id(&lod_type) else {
+                        return;
+                    };
+
+                    let Some(index_id) = geometry_manager.get_index(id) else {
+                        return;
+                    };
+                    let Some(material_id) = geometry_manager.get_index_material(id) else {
+                        return;
+                    };
+
+                    // the custom index of the instance is the index of its object, so the shader finds the
+                    // object whatever the position of the instance in the TLAS is
+                    let object_index = objects.get().len() as u32;
+                    objects.get_mut().push(RenderObject {
+                        index: index_id,
+                        material_id,
+                        uv_scale,
+                        material_remap,
+                        custom_data: custom_data.to_array(),
+                        light_channels,
+                    });
+                    object_entities.push(entity);
+                    lod_objects.insert(lod_type, object_index);
+                    object_index
+                }
+            };
+
+            let instance = TlasSlot {
+                blas: blas.clone(),
+                transform: tlas_transform(transform),
+                custom_data: object_index,
+                mask: if visible { 0xFF } else { 0x00 },
+            };
+            let previous = previous_slots.remove(&(entity, i));
+            let slot = match &previous {
+                Some((slot, _)) => *slot,
+                // the slots still held by the instances that aren't visited yet can't be taken
+                None if tlas_slots.slots.len() + previous_slots.len() >= tlas.get().len() => {
+                    status.report(NEVRWarning::TooManyInstances);
+                    break 'groups;
+                }
+                None => tlas_slots.allocate(),
+            };
+            if previous.as_ref().map(|(_, previous)| previous) != Some(&instance) {
+                *tlas.get_mut_single(slot).unwrap() = Some(TlasInstance::new(
+                    &instance.blas,
+                    instance.transform,
+                    instance.custom_data,
+                    instance.mask,
+                ));
+                tlas_writes += 1;
+            }
+            tlas_slots.slots.insert((entity, i), (slot, instance));
+
+            // the previous transforms are indexed by the slot of the instance, the empty slots are never hit;
+            // new blocks and instances don't have a previous transform, they don't move in their first frame
+            let previous_transforms = previous_transforms.get_mut();
+            if previous_transforms.len() <= slot {
+                previous_transforms.resize(slot + 1, Mat4::IDENTITY);
+            }
+            previous_transforms[slot] = entity_previous_transforms
+                .and_then(|previous| previous.get(i))
+                .copied()
+                .unwrap_or(*transform);
+
+            instance_id += 1;
+        }
+
+        current_transforms.insert(entity, transforms.into_owned());
+    }
 
-        instance_id += 1;
+    // a binding can't be empty
+    if material_palette.get().is_empty() {
+        material_palette.get_mut().push(0);
+    }
+
+    // a binding can't be empty
+    if previous_transforms.get().is_empty() {
+        previous_transforms.get_mut().push(Mat4::IDENTITY);
+    }
+
+    // the instances that weren't rendered this frame (their type isn't built yet or they're over the limit)
+    for (slot, _) in previous_slots.into_values() {
+        *tlas.get_mut_single(slot).unwrap() = None;
+        tlas_slots.release(slot);
+        tlas_writes += 1;
     }
 
     objects.write_buffer(&render_device, &render_queue);
-    let Some(vertices) = geometry_manager.vertices().buffer() else {
-        eprintln!("no vertices");
-        return;
-    };
-    let Some(normals) = geometry_manager.normals().buffer() else {
-        eprintln!("no normals");
-        return;
-    };
-    let Some(indices) = geometry_manager.indices().buffer() else {
-        eprintln!("no indices");
-        return;
-    };
-    let Some(materials) = geometry_manager.materials().buffer() else {
-        eprintln!("no materials");
-        return;
-    };
-    let Some(material_map) = geometry_manager.material_map().buffer() else {
-        eprintln!("no material map");
-        return;
-    };
+    previous_transforms.write_buffer(&render_device, &render_queue);
+    material_palette.write_buffer(&render_device, &render_queue);
+    stats.tlas_instances = instance_id as u32;
+    stats.tlas_writes = tlas_writes;
 
-    let mut command_encoder =
-        render_device.create_command_encoder(&CommandEncoderDescriptor::default());
-    command_encoder.build_acceleration_structures([], [&tlas]);
-    render_queue.submit([command_encoder.finish()]);
+    if !tlas_reused || tlas_writes > 0 {
+        let mut command_encoder =
+            render_device.create_command_encoder(&CommandEncoderDescriptor::default());
+        command_encoder.build_acceleration_structures([], [&tlas]);
+        render_queue.submit([command_encoder.finish()]);
+    }
     voxel_bindings.bind_group = Some(render_device.create_bind_group(
         "voxel_bindings",
         &voxel_bindings.bind_group_layouts[0],
@@ -510,10 +1276,54 @@ pub fn prepare_bindings(
             indices.as_entire_binding(),
             vertices.as_entire_binding(),
             normals.as_entire_binding(),
+            tangents.as_entire_binding(),
             materials.as_entire_binding(),
             material_map.as_entire_binding(),
+            previous_transforms.binding().unwrap(),
+            material_palette.binding().unwrap(),
+            probe_volume.buffer.as_entire_binding(),
+            probe_volume.grid.binding().unwrap(),
         )),
     ));
+    voxel_bindings.tlas = Some(tlas);
+    voxel_bindings.tlas_slots = tlas_slots;
+    voxel_bindings.object_entities = object_entities;
+    voxel_bindings.previous_transforms = current_transforms;
+}
+
+// the slots of the TLAS used by the instances of every block, by its main entity and the index of the transform
+#[derive(Default)]
+struct TlasSlots {
+    slots: HashMap<(MainEntity, usize), (usize, TlasSlot)>,
+    // the emptied slots below `len`, taken before the TLAS grows
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl TlasSlots {
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn allocate(&mut self) -> usize {
+        self.free.pop().unwrap_or_else(|| {
+            self.len += 1;
+            self.len - 1
+        })
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.free.push(slot);
+    }
+}
+
+// an instance of the TLAS, TlasInstance can't be compared
+#[derive(PartialEq)]
+struct TlasSlot {
+    blas: Blas,
+    transform: [f32; 12],
+    custom_data: u32,
+    mask: u8,
 }
 
 fn tlas_transform(transform: &Mat4) -> [f32; 12] {
@@ -521,3 +1331,172 @@ fn tlas_transform(transform: &Mat4) -> [f32; 12] {
         .try_into()
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::denoiser::VoxelDenoiserDetail;
+    use bevy::app::TaskPoolPlugin;
+    use bevy::asset::{AssetPlugin, ReflectAsset};
+    use bevy::prelude::{
+        AppTypeRegistry, Color, ReflectComponent, ReflectResource, Transform, Vec3,
+    };
+    use bevy::render::render_resource::encase::{
+        ShaderType, StorageBuffer as EncaseStorageBuffer, internal::WriteInto,
+    };
+    use std::any::TypeId;
+
+    // the types sent to the shaders implement ShaderType and WriteInto by hand, and the contract is:
+    // - `min_size` in ShaderType::METADATA is the size of the matching WGSL struct, including its padding (e.g. a
+    //   `mat3x3` takes 48 bytes and a struct in a storage array is rounded up to its alignment);
+    // - WriteInto::write_into writes every byte of it, padding included, in the order of the WGSL fields.
+    // A write shorter than the struct leaves the last fields to whatever the buffer held, a longer one overwrites
+    // the next element of an array: both would silently corrupt the rendering. The size of the WGSL struct itself
+    // isn't checked, it has to be kept in sync with `min_size` when a field is added.
+    fn check_shader_layout<T: ShaderType + WriteInto>(value: &T) {
+        let name = std::any::type_name::<T>();
+        let min_size = T::min_size().get() as usize;
+        // the bytes that aren't written keep the byte they were filled with, so they differ between the two writes
+        let [zeros, ones] = [0u8, 0xff].map(|fill| {
+            let mut buffer = EncaseStorageBuffer::new(vec![fill; min_size + 64]);
+            buffer
+                .write(value)
+                .unwrap_or_else(|error| panic!("{name} can't be written: {error}"));
+            buffer.into_inner()
+        });
+        let written = zeros
+            .iter()
+            .zip(&ones)
+            .map(|(zero, one)| zero == one)
+            .collect::<Vec<_>>();
+
+        assert!(
+            written[..min_size].iter().all(|written| *written),
+            "{name} writes less than the {min_size} bytes declared by its ShaderType"
+        );
+        assert!(
+            !written[min_size..].iter().any(|written| *written),
+            "{name} writes more than the {min_size} bytes declared by its ShaderType"
+        );
+    }
+
+    #[test]
+    fn ray_camera_layout() {
+        check_shader_layout(&RayCamera::default());
+    }
+
+    #[test]
+    fn voxel_material_layout() {
+        check_shader_layout(&VoxelMaterial::new_lambertian(Color::WHITE));
+    }
+
+    #[test]
+    fn render_voxel_light_layout() {
+        check_shader_layout(&RenderVoxelLight::default());
+    }
+
+    #[test]
+    fn render_object_layout() {
+        check_shader_layout(&<RenderObject as bytemuck::Zeroable>::zeroed());
+    }
+
+    #[test]
+    fn render_probe_grid_layout() {
+        check_shader_layout(&RenderProbeGrid::default());
+    }
+
+    #[test]
+    fn render_skybox_layers_layout() {
+        check_shader_layout(&RenderSkyboxLayers::default());
+    }
+
+    #[test]
+    fn tlas_slots_are_reused() {
+        let mut slots = TlasSlots::default();
+        assert_eq!(
+            [slots.allocate(), slots.allocate(), slots.allocate()],
+            [0, 1, 2]
+        );
+
+        // the emptied slots are taken before the TLAS grows
+        slots.release(1);
+        slots.release(0);
+        assert_eq!(
+            [slots.allocate(), slots.allocate(), slots.allocate()],
+            [0, 1, 3]
+        );
+        assert_eq!(slots.len, 4);
+
+        slots.release(2);
+        slots.clear();
+        assert_eq!(slots.allocate(), 0);
+        assert!(slots.free.is_empty());
+    }
+
+    #[test]
+    fn tlas_transform_keeps_mirroring() {
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0)
+            .with_scale(Vec3::new(-1.0, 1.0, 1.0))
+            .to_matrix();
+        let rows = tlas_transform(&transform);
+
+        // row-major 3x4, the translation is the last column
+        assert_eq!(
+            rows,
+            [-1.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 2.0, 0.0, 0.0, 1.0, 3.0]
+        );
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn to_bytes_is_native_order() {
+        assert_eq!([1.0f32, 2.0].to_bytes(), [0, 0, 128, 63, 0, 0, 0, 64]);
+        assert_eq!(Vec::from([1u32, 256]).to_bytes(), [1, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn types_are_reflected() {
+        // the denoiser types are registered by DenoiserPlugin, whose shaders need the assets
+        let mut app = App::new();
+        app.add_plugins((
+            TaskPoolPlugin::default(),
+            AssetPlugin::default(),
+            DenoiserPlugin,
+        ));
+        register_types(&mut app);
+
+        let registry = app.world().resource::<AppTypeRegistry>().read();
+        assert!(
+            registry
+                .get_type_data::<ReflectComponent>(TypeId::of::<VoxelCamera>())
+                .is_some()
+        );
+        assert!(
+            registry
+                .get_type_data::<ReflectResource>(TypeId::of::<VoxelLight>())
+                .is_some()
+        );
+        assert!(
+            registry
+                .get_type_data::<ReflectComponent>(TypeId::of::<VoxelBlock>())
+                .is_some()
+        );
+        assert!(
+            registry
+                .get_type_data::<ReflectAsset>(TypeId::of::<VoxelMaterial>())
+                .is_some()
+        );
+        for denoiser in [
+            TypeId::of::<VoxelDenoiser>(),
+            TypeId::of::<VoxelDenoiserDetail>(),
+            TypeId::of::<VoxelTemporalDenoiser>(),
+            TypeId::of::<NEVRFireflyFilter>(),
+        ] {
+            assert!(
+                registry
+                    .get_type_data::<ReflectResource>(denoiser)
+                    .is_some()
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Renders with different cameras and checks the images, check [common].
+
+mod common;
+
+use bevy::camera::{Camera, Viewport};
+use bevy::color::ColorToComponents;
+use bevy::prelude::{Color, Transform, UVec2, Vec3, With, default};
+use nevr::engine::camera::VoxelCamera;
+use nevr::engine::voxel::VoxelMaterial;
+
+#[test]
+fn aspect_ratio_follows_the_viewport() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    // a cube seen from the front is a square, it's wider when the 16:9 projection of VoxelCamera is kept
+    let eye = center + Vec3::Z * 2.0;
+    let image = common::render(
+        &mut app,
+        (
+            VoxelCamera::default().with_focus_distance(2.0),
+            VoxelCamera::look_at(eye, center, Vec3::Y),
+        ),
+        UVec2::new(128, 96),
+        4,
+    );
+
+    let size = common::silhouette_size(&image);
+    assert!(size.y > 16, "the voxel isn't visible");
+    assert!(
+        size.x.abs_diff(size.y) <= 2,
+        "the voxel is stretched in a 4:3 viewport: {size}"
+    );
+}
+
+#[test]
+fn viewport_renders_at_its_offset() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    let eye = center + Vec3::new(1.0, 1.0, 2.0);
+    let camera = || {
+        (
+            VoxelCamera::default().with_focus_distance(eye.distance(center)),
+            VoxelCamera::look_at(eye, center, Vec3::Y),
+        )
+    };
+    let size = UVec2::new(64, 48);
+
+    // the same view rendered in a viewport that doesn't start at (0, 0) of a larger target
+    let full = common::render(&mut app, camera(), size, 4);
+    let viewport = common::render_camera(
+        &mut app,
+        camera(),
+        Camera {
+            viewport: Some(Viewport {
+                physical_position: UVec2::new(40, 24),
+                physical_size: size,
+                ..default()
+            }),
+            ..default()
+        },
+        size * 2,
+        4,
+    );
+
+    assert!(
+        common::silhouette_size(&full).y > 0,
+        "the voxel isn't visible"
+    );
+    let difference = common::image_difference(&full, &viewport)
+        .expect("the output doesn't have the size of the viewport");
+    assert!(
+        difference < 0.01,
+        "the viewport differs from the full view by {difference}"
+    );
+}
+
+#[test]
+fn inset_camera_is_drawn_over_the_main_one() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let red = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 0.0)),
+        Transform::default(),
+    );
+    let blue = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(0.0, 0.0, 1.0)),
+        Transform::from_xyz(4.0, 0.0, 0.0),
+    );
+
+    // every camera is close enough to its block to see nothing else, the inset is spawned first so the order
+    // decides which one is drawn on top
+    let size = UVec2::new(64, 48);
+    let inset_position = UVec2::new(40, 4);
+    let inset_size = UVec2::new(20, 16);
+    let image = common::render_target(&mut app, size, 8, |world, target| {
+        world.spawn((
+            VoxelCamera::default(),
+            VoxelCamera::look_at(blue + Vec3::Z * 0.8, blue, Vec3::Y),
+            Camera {
+                target: target.clone(),
+                order: 1,
+                viewport: Some(Viewport {
+                    physical_position: inset_position,
+                    physical_size: inset_size,
+                    ..default()
+                }),
+                ..default()
+            },
+        ));
+        world.spawn((
+            VoxelCamera::default(),
+            VoxelCamera::look_at(red + Vec3::Z * 0.8, red, Vec3::Y),
+            Camera {
+                target,
+                ..default()
+            },
+        ));
+    });
+
+    let color_at = |pixel: UVec2| {
+        image
+            .get_color_at(pixel.x, pixel.y)
+            .unwrap()
+            .to_linear()
+            .to_vec3()
+    };
+    let inset = color_at(inset_position + inset_size / 2);
+    assert!(
+        inset.z > inset.x,
+        "the inset doesn't show the blue block: {inset}"
+    );
+    for pixel in [size / 2, UVec2::new(4, 4), size - 4, UVec2::new(60, 40)] {
+        let main = color_at(pixel);
+        assert!(
+            main.x > main.z,
+            "the main view doesn't show the red block at {pixel}: {main}"
+        );
+    }
+}
+
+#[test]
+fn zero_sized_viewport_recovers() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    // like a minimized window: the viewport has no height for a while, then it's back to the whole target
+    let eye = center + Vec3::new(1.0, 1.0, 2.0);
+    let image = common::render_frames(
+        &mut app,
+        (
+            VoxelCamera::default(),
+            VoxelCamera::look_at(eye, center, Vec3::Y),
+        ),
+        UVec2::new(64, 48),
+        32,
+        |app, frame| {
+            let viewport = (frame < 8).then(|| Viewport {
+                physical_size: UVec2::new(64, 0),
+                ..default()
+            });
+            let world = app.world_mut();
+            let mut cameras = world.query_filtered::<&mut Camera, With<VoxelCamera>>();
+            cameras.single_mut(world).unwrap().viewport = viewport;
+        },
+    );
+    assert!(common::is_finite(&image));
+    assert!(
+        common::silhouette_size(&image).y > 8,
+        "nothing is rendered after the viewport grows back"
+    );
+}
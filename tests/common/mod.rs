@@ -0,0 +1,488 @@
+//! Helpers of the tests rendering with NEVR: a headless app, rendering a scene for some frames and comparing the
+//! result with the golden images in `tests/golden`.
+//!
+//! The tests need a GPU with hardware ray tracing, they are skipped (and pass) on machines without one.
+
+#![allow(dead_code)]
+
+use bevy::DefaultPlugins;
+use bevy::app::{App, PluginGroup};
+use bevy::asset::RenderAssetUsages;
+use bevy::camera::{Camera, RenderTarget};
+use bevy::color::ColorToComponents;
+use bevy::ecs::observer::On;
+use bevy::image::{CompressedImageFormats, Image, ImageSampler, ImageType};
+use bevy::prelude::{
+    Assets, Bundle, Color, Entity, Handle, Transform, UVec2, Vec3, World, default,
+};
+use bevy::render::RenderPlugin;
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages};
+use bevy::render::renderer::{RenderDevice, initialize_renderer};
+use bevy::render::settings::{Backends, RenderResources, WgpuSettings};
+use bevy::tasks::block_on;
+use bevy::window::{ExitCondition, WindowPlugin};
+use bevy::winit::WinitPlugin;
+use nevr::NEVRPlugin;
+use nevr::engine::camera::VoxelAccumulation;
+use nevr::engine::capabilities::NEVRCapabilities;
+use nevr::engine::readback::NEVRContinuousReadback;
+use nevr::engine::settings::NEVRPaused;
+use nevr::engine::stats::NEVRStats;
+use nevr::engine::voxel::{RelativeVoxel, VoxelBlock, VoxelMaterial, VoxelShape, VoxelType};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The updates after which a render gives up, when the pipelines or the readback never complete.
+const MAX_UPDATES: u32 = 1000;
+
+/// The render resources of the default adapter, `None` when there is no adapter.
+pub fn render_resources() -> Option<RenderResources> {
+    // Bevy panics when it doesn't find an adapter
+    let resources = catch_unwind(AssertUnwindSafe(|| {
+        block_on(initialize_renderer(
+            Backends::all(),
+            None,
+            &WgpuSettings::default(),
+        ))
+    }));
+
+    if resources.is_err() {
+        eprintln!("No GPU adapter found, skipping the test");
+    }
+    resources.ok()
+}
+
+/// A headless app with NEVR, `None` when the adapter doesn't support hardware ray tracing.
+///
+/// The pipelines are compiled synchronously, so the scene is rendered from the first frames.
+pub fn headless_app() -> Option<App> {
+    let resources = render_resources()?;
+    if !NEVRCapabilities::from_device(&resources.0, &resources.3).is_supported() {
+        eprintln!("The GPU doesn't support hardware ray tracing, skipping the test");
+        return None;
+    }
+
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: None,
+                exit_condition: ExitCondition::DontExit,
+                close_when_requested: false,
+                ..default()
+            })
+            .set(RenderPlugin {
+                render_creation: resources.into(),
+                synchronous_pipeline_compilation: true,
+                ..default()
+            })
+            .disable::<WinitPlugin>(),
+        NEVRPlugin::default(),
+    ));
+    app.finish();
+    app.cleanup();
+
+    Some(app)
+}
+
+/// Renders the scene of `app` with `camera` into a target of `size` pixels until `frames` frames are
+/// accumulated, and returns the HDR output of the last one.
+///
+/// Rendering is paused after the last frame, so the image is always the same for the same scene and number of
+/// frames.
+pub fn render(app: &mut App, camera: impl Bundle, size: UVec2, frames: u32) -> Image {
+    render_camera(app, camera, Camera::default(), size, frames)
+}
+
+/// Like [render], with the [Camera] of the view (e.g. to set a viewport). Its target is replaced.
+pub fn render_camera(
+    app: &mut App,
+    camera: impl Bundle,
+    camera_component: Camera,
+    size: UVec2,
+    frames: u32,
+) -> Image {
+    let (entity, output) = spawn_camera(app, camera, camera_component, size);
+    reset_accumulation(app, entity);
+    update_until(app, |app| accumulated_frames(app, entity) >= frames);
+    app.world_mut().resource_mut::<NEVRPaused>().0 = true;
+
+    // the readback is a few frames late, the paused frames all show the last one
+    for _ in 0..8 {
+        app.update();
+    }
+
+    take_output(app, entity, output)
+}
+
+/// Renders the scene of `app` with `camera` into a target of `size` pixels for `frames` frames, calling `step`
+/// with the index of the frame before every one of them (e.g. to move a block), and returns the HDR output read
+/// back after the last one.
+///
+/// Rendering isn't paused, the image is a few frames older than the last one like with a window.
+pub fn render_frames(
+    app: &mut App,
+    camera: impl Bundle,
+    size: UVec2,
+    frames: u32,
+    mut step: impl FnMut(&mut App, u32),
+) -> Image {
+    let (entity, output) = spawn_camera(app, camera, Camera::default(), size);
+    for frame in 0..frames {
+        step(app, frame);
+        app.update();
+    }
+
+    take_output(app, entity, output)
+}
+
+/// The last image read back from the camera rendering with [render_frames], to check the output in the middle of
+/// the frames from its `step`.
+pub fn current_output(app: &mut App) -> Image {
+    let world = app.world_mut();
+    let output = world
+        .query::<&NEVRContinuousReadback>()
+        .single(world)
+        .unwrap()
+        .image
+        .clone();
+    world
+        .resource::<Assets<Image>>()
+        .get(&output)
+        .unwrap()
+        .clone()
+}
+
+/// Renders the scene of `app` for `frames` frames with the cameras spawned by `spawn` on a shared target of `size`
+/// pixels, and returns the target once they're all drawn (after tonemapping, in `Rgba8UnormSrgb`).
+///
+/// Unlike [render], the whole target is read back, so the result is what a window would show (e.g. with several
+/// cameras and viewports).
+pub fn render_target(
+    app: &mut App,
+    size: UVec2,
+    frames: u32,
+    spawn: impl FnOnce(&mut World, RenderTarget),
+) -> Image {
+    let world = app.world_mut();
+    let mut target = Image::new_target_texture(size.x, size.y, TextureFormat::Rgba8UnormSrgb);
+    target.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+    let target = world.resource_mut::<Assets<Image>>().add(target);
+
+    world.resource_mut::<NEVRPaused>().0 = false;
+    spawn(world, RenderTarget::Image(target.clone().into()));
+    update_until(app, |app| {
+        let stats = app.world().resource::<NEVRStats>();
+        stats.tlas_instances > 0 && stats.pipelines_ready
+    });
+    for _ in 0..frames {
+        app.update();
+    }
+    app.world_mut().resource_mut::<NEVRPaused>().0 = true;
+
+    let data = Arc::new(Mutex::new(None));
+    let readback_data = data.clone();
+    let readback = app
+        .world_mut()
+        .spawn(Readback::texture(target))
+        .observe(move |event: On<ReadbackComplete>| {
+            *readback_data.lock().unwrap() = Some(event.data.clone());
+        })
+        .id();
+    update_until(app, |_| data.lock().unwrap().is_some());
+    app.world_mut().despawn(readback);
+
+    // the rows of the readback are padded
+    let padded_row_size = RenderDevice::align_copy_bytes_per_row(size.x as usize * 4);
+    let data = data.lock().unwrap().take().unwrap();
+    let pixels = data
+        .chunks_exact(padded_row_size)
+        .flat_map(|row| &row[..size.x as usize * 4])
+        .copied()
+        .collect();
+    Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        pixels,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    )
+}
+
+// spawns the camera rendering to a new target and waits for the pipelines, returns it and its readback image
+fn spawn_camera(
+    app: &mut App,
+    camera: impl Bundle,
+    camera_component: Camera,
+    size: UVec2,
+) -> (Entity, Handle<Image>) {
+    let world = app.world_mut();
+    let mut images = world.resource_mut::<Assets<Image>>();
+    let target = images.add(Image::new_target_texture(
+        size.x,
+        size.y,
+        TextureFormat::Rgba8UnormSrgb,
+    ));
+    let output = images.add(Image::default());
+
+    world.resource_mut::<NEVRPaused>().0 = false;
+    let entity = world
+        .spawn((
+            camera,
+            Camera {
+                target: RenderTarget::Image(target.into()),
+                ..camera_component
+            },
+            NEVRContinuousReadback::new(output.clone()),
+        ))
+        .id();
+
+    // the stats arrive once the render world prepared a frame
+    update_until(app, |app| {
+        let stats = app.world().resource::<NEVRStats>();
+        stats.tlas_instances > 0 && stats.pipelines_ready
+    });
+    (entity, output)
+}
+
+// the last image read back from the camera, which is despawned
+fn take_output(app: &mut App, camera: Entity, output: Handle<Image>) -> Image {
+    let image = app
+        .world()
+        .resource::<Assets<Image>>()
+        .get(&output)
+        .unwrap()
+        .clone();
+    app.world_mut().despawn(camera);
+    image
+}
+
+/// Spawns a block of a single voxel of `material` at `transform` and returns its center in the world.
+pub fn spawn_voxel(app: &mut App, material: VoxelMaterial, transform: Transform) -> Vec3 {
+    spawn_voxel_shape(app, material, VoxelShape::Cube, transform)
+}
+
+/// Like [spawn_voxel], with the [VoxelShape] of the voxel.
+pub fn spawn_voxel_shape(
+    app: &mut App,
+    material: VoxelMaterial,
+    shape: VoxelShape,
+    transform: Transform,
+) -> Vec3 {
+    let material = add_material(app, material);
+    let voxel_type =
+        VoxelType::new(1, vec![RelativeVoxel::new(material, Vec3::ZERO)]).with_shape(shape);
+    spawn_type(app, voxel_type, transform)
+}
+
+/// Adds `material` to the assets of the app.
+pub fn add_material(app: &mut App, material: VoxelMaterial) -> Handle<VoxelMaterial> {
+    app.world_mut()
+        .resource_mut::<Assets<VoxelMaterial>>()
+        .add(material)
+}
+
+/// Spawns a block of `voxel_type` at `transform` and returns the center of its voxels in the world.
+pub fn spawn_type(app: &mut App, voxel_type: VoxelType, transform: Transform) -> Vec3 {
+    let center = Vec3::from(voxel_type.bounds().unwrap().center);
+    let world = app.world_mut();
+    let voxel_type = world.resource_mut::<Assets<VoxelType>>().add(voxel_type);
+    world.spawn((VoxelBlock::new(voxel_type), transform));
+    transform.transform_point(center)
+}
+
+/// The size in pixels of the box around the pixels that differ from the top left one, which is expected to be
+/// the sky.
+pub fn silhouette_size(image: &Image) -> UVec2 {
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    let size = image.size();
+    let mut min = size;
+    let mut max = UVec2::ZERO;
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let color = image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+            if (color - sky).abs().max_element() > 0.05 {
+                min = min.min(UVec2::new(x, y));
+                max = max.max(UVec2::new(x + 1, y + 1));
+            }
+        }
+    }
+    max.saturating_sub(min)
+}
+
+/// The average linear color of the pixels that differ from the top left one, black when there are none.
+pub fn mean_color(image: &Image) -> Vec3 {
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    let size = image.size();
+    let mut sum = Vec3::ZERO;
+    let mut count = 0;
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let color = image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+            if (color - sky).abs().max_element() > 0.05 {
+                sum += color;
+                count += 1;
+            }
+        }
+    }
+    sum / count.max(1) as f32
+}
+
+/// The fraction of the pixels that differ from the top left one which are darker than a tenth of the brightest
+/// of them.
+pub fn dark_fraction(image: &Image) -> f32 {
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    let size = image.size();
+    let mut brightness = vec![];
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let color = image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+            if (color - sky).abs().max_element() > 0.05 {
+                brightness.push(color.max_element());
+            }
+        }
+    }
+
+    let brightest = brightness.iter().copied().fold(0.0, f32::max);
+    let dark = brightness
+        .iter()
+        .filter(|brightness| **brightness < brightest * 0.1)
+        .count();
+    dark as f32 / brightness.len().max(1) as f32
+}
+
+/// Whether every pixel of the image has finite colors.
+pub fn is_finite(image: &Image) -> bool {
+    let size = image.size();
+    (0..size.y).all(|y| {
+        (0..size.x).all(|x| {
+            let color = image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+            color.is_finite()
+        })
+    })
+}
+
+fn update_until(app: &mut App, done: impl Fn(&App) -> bool) {
+    for _ in 0..MAX_UPDATES {
+        app.update();
+        if done(app) {
+            return;
+        }
+    }
+    panic!("The render didn't complete in {MAX_UPDATES} updates");
+}
+
+fn reset_accumulation(app: &mut App, entity: Entity) {
+    app.world_mut()
+        .get_mut::<VoxelAccumulation>(entity)
+        .unwrap()
+        .reset();
+}
+
+fn accumulated_frames(app: &App, entity: Entity) -> u32 {
+    app.world()
+        .get::<VoxelAccumulation>(entity)
+        .unwrap()
+        .frames()
+}
+
+/// The root mean square difference of the linear colors of two images of the same size, `None` when the sizes
+/// differ or the format of an image can't be read.
+pub fn image_difference(a: &Image, b: &Image) -> Option<f32> {
+    if a.size() != b.size() {
+        return None;
+    }
+
+    let UVec2 {
+        x: width,
+        y: height,
+    } = a.size();
+    let mut sum = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let a = a.get_color_at(x, y).ok()?.to_linear();
+            let b = b.get_color_at(x, y).ok()?.to_linear();
+            sum +=
+                (a.red - b.red).powi(2) + (a.green - b.green).powi(2) + (a.blue - b.blue).powi(2);
+        }
+    }
+
+    let channels = (width * height * 3).max(1) as f32;
+    Some((sum / channels).sqrt())
+}
+
+/// Compares `image` with the golden image `tests/golden/{name}.png`, panicking when their difference is above
+/// `tolerance` or when the golden image doesn't exist.
+///
+/// The golden images are recorded from `image` instead when the `NEVR_RECORD_GOLDEN` environment variable is
+/// `1` (e.g. `NEVR_RECORD_GOLDEN=1 cargo test --test golden`): check them and commit them. The images are stored
+/// as 8 bit sRGB, so the HDR colors above 1.0 are clamped.
+pub fn assert_golden(name: &str, image: &Image, tolerance: f32) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.png"));
+    let image = to_srgb(image);
+
+    if std::env::var("NEVR_RECORD_GOLDEN").is_ok_and(|record| record == "1") {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        image
+            .try_into_dynamic()
+            .unwrap()
+            .save(&path)
+            .unwrap_or_else(|error| panic!("Couldn't save {}: {error}", path.display()));
+        eprintln!("Recorded the golden image {}", path.display());
+        return;
+    }
+
+    let bytes = std::fs::read(&path).unwrap_or_else(|error| {
+        panic!(
+            "Couldn't read the golden image {}: {error}, record it with NEVR_RECORD_GOLDEN=1",
+            path.display()
+        )
+    });
+    let golden = Image::from_buffer(
+        &bytes,
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )
+    .unwrap_or_else(|error| panic!("Couldn't load {}: {error}", path.display()));
+
+    let difference = image_difference(&image, &golden)
+        .unwrap_or_else(|| panic!("{name} doesn't have the size of its golden image"));
+    assert!(
+        difference <= tolerance,
+        "{name} differs from its golden image by {difference} (tolerance {tolerance})"
+    );
+}
+
+// the HDR output in a format that can be saved
+fn to_srgb(image: &Image) -> Image {
+    let size = image.size();
+    let mut srgb = Image::new_fill(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let color = image.get_color_at(x, y).unwrap_or(Color::BLACK);
+            srgb.set_color_at(x, y, color).unwrap();
+        }
+    }
+    srgb
+}
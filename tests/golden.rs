@@ -0,0 +1,32 @@
+//! Renders small fixed scenes and compares them with the golden images in `tests/golden`, check [common].
+
+mod common;
+
+use bevy::prelude::{Color, Transform, UVec2, Vec3};
+use nevr::engine::camera::VoxelCamera;
+use nevr::engine::voxel::VoxelMaterial;
+
+#[test]
+fn lambertian_voxel() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    let eye = center + Vec3::new(-2.0, 1.5, 3.0);
+    let image = common::render(
+        &mut app,
+        (
+            VoxelCamera::default().with_focus_distance(eye.distance(center)),
+            VoxelCamera::look_at(eye, center, Vec3::Y),
+        ),
+        UVec2::new(64, 48),
+        16,
+    );
+    common::assert_golden("lambertian_voxel", &image, 0.01);
+}
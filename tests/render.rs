@@ -0,0 +1,1060 @@
+//! Renders scenes that exercise single features of the renderer and checks the images, check [common].
+
+mod common;
+
+use bevy::app::App;
+use bevy::asset::RenderAssetUsages;
+use bevy::color::ColorToComponents;
+use bevy::image::Image;
+use bevy::prelude::{
+    Assets, Color, Entity, IVec3, LinearRgba, PerspectiveProjection, Projection, Quat, Transform,
+    UVec2, Vec3, Visibility, With, default,
+};
+use bevy::render::RenderApp;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use nevr::engine::blas::BlasManager;
+use nevr::engine::camera::VoxelCamera;
+use nevr::engine::denoiser::{VoxelDenoiser, VoxelTemporalDenoiser};
+use nevr::engine::geometry::GeometryManager;
+use nevr::engine::light::VoxelLight;
+use nevr::engine::settings::{NEVRDebugView, NEVRSeed, NEVRTuning};
+use nevr::engine::skybox::VoxelSkybox;
+use nevr::engine::stats::NEVRStats;
+use nevr::engine::status::{NEVRStatus, NEVRWarning};
+use nevr::engine::voxel::{
+    RelativeVoxel, VoxelBlock, VoxelMaterial, VoxelMaterialModel, VoxelShape, VoxelType,
+};
+use std::f32::consts::FRAC_PI_4;
+use std::num::NonZeroU32;
+
+// a camera in front of `center`, looking at it
+fn camera_at(center: Vec3, offset: Vec3) -> (VoxelCamera, Transform) {
+    (
+        VoxelCamera::default().with_focus_distance(offset.length()),
+        VoxelCamera::look_at(center + offset, center, Vec3::Y),
+    )
+}
+
+#[test]
+fn mirrored_block_is_visible() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::from_scale(Vec3::new(-1.0, 1.0, 1.0)),
+    );
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(1.0, 1.0, 2.0)),
+        UVec2::new(64, 48),
+        4,
+    );
+    assert!(
+        common::silhouette_size(&image).y > 8,
+        "the mirrored block vanished"
+    );
+}
+
+#[test]
+fn same_seed_renders_the_same_image() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    let offset = Vec3::new(-2.0, 1.5, 3.0);
+    let size = UVec2::new(64, 48);
+
+    app.insert_resource(NEVRSeed(7));
+    let first = common::render(&mut app, camera_at(center, offset), size, 4);
+    let second = common::render(&mut app, camera_at(center, offset), size, 4);
+    assert_eq!(
+        first.data, second.data,
+        "the same seed rendered different images"
+    );
+
+    app.insert_resource(NEVRSeed(8));
+    let other = common::render(&mut app, camera_at(center, offset), size, 4);
+    assert_ne!(
+        first.data, other.data,
+        "a different seed rendered the same noise"
+    );
+}
+
+#[test]
+fn clearcoat_reflects_untinted_light() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let red = VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 0.0));
+    let offset = Vec3::new(-2.0, 1.5, 3.0);
+    let size = UVec2::new(64, 48);
+    app.insert_resource(NEVRSeed(7));
+
+    let mut render = |material: VoxelMaterial| {
+        let mut blocks = app.world_mut().query_filtered::<Entity, With<VoxelBlock>>();
+        let blocks = blocks.iter(app.world()).collect::<Vec<_>>();
+        for block in blocks {
+            app.world_mut().despawn(block);
+        }
+        let center = common::spawn_voxel(&mut app, material, Transform::default());
+        common::render(&mut app, camera_at(center, offset), size, 8)
+    };
+    let bare = render(red);
+    let no_coat = render(red.with_clearcoat(0.0, 0.0));
+    let coated = render(red.with_clearcoat(1.0, 0.0));
+
+    assert_eq!(
+        bare.data, no_coat.data,
+        "a coat of strength 0 changed the image"
+    );
+    let bare = common::mean_color(&bare);
+    let coated = common::mean_color(&coated);
+    assert!(
+        coated.y > bare.y && coated.z > bare.z,
+        "the coat doesn't reflect the sky: {bare} and {coated}"
+    );
+}
+
+#[test]
+fn terminator_softening_reduces_the_dark_bands() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    // the flat triangles of a sphere with smooth normals, lit by a low sun, show the shadow terminator
+    let center = common::spawn_voxel_shape(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        VoxelShape::Sphere,
+        Transform::default(),
+    );
+    app.world_mut().resource_mut::<VoxelLight>().direction =
+        Vec3::new(-1.0, -0.2, 0.0).normalize().extend(0.0);
+
+    let dark_fraction = |app: &mut App, terminator_softness: f32| {
+        app.insert_resource(NEVRTuning {
+            terminator_softness,
+            ..default()
+        });
+        let image = common::render(
+            app,
+            camera_at(center, Vec3::new(1.5, 0.5, 1.0)),
+            UVec2::new(96, 72),
+            16,
+        );
+        common::dark_fraction(&image)
+    };
+
+    let hard = dark_fraction(&mut app, 0.0);
+    let soft = dark_fraction(&mut app, 1.0);
+    assert!(
+        soft <= hard,
+        "the softened terminator has more dark pixels: {soft} > {hard}"
+    );
+}
+
+#[test]
+fn hidden_block_keeps_the_materials_of_the_others() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(0.0, 1.0, 0.0)),
+        Transform::default(),
+    );
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(0.0, 0.0, 1.0)),
+        Transform::from_xyz(3.0, 0.0, 0.0),
+    );
+    // the middle block is hidden, the last one keeps its own material
+    let mut blocks = app
+        .world_mut()
+        .query_filtered::<(&Transform, &mut Visibility), With<VoxelBlock>>();
+    for (transform, mut visibility) in blocks.iter_mut(app.world_mut()) {
+        if transform.translation.x == 3.0 {
+            *visibility = Visibility::Hidden;
+        }
+    }
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 0.0)),
+        Transform::from_xyz(6.0, 0.0, 0.0),
+    );
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(0.0, 0.5, 1.5)),
+        UVec2::new(64, 48),
+        4,
+    );
+    let color = common::mean_color(&image);
+    assert!(
+        color.x > color.y && color.x > color.z,
+        "the last block isn't red: {color}"
+    );
+}
+
+#[test]
+fn unloaded_material_is_magenta_until_it_loads() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = app
+        .world()
+        .resource::<Assets<VoxelMaterial>>()
+        .reserve_handle();
+    let center = common::spawn_type(
+        &mut app,
+        VoxelType::new(1, vec![RelativeVoxel::new(material.clone(), Vec3::ZERO)]),
+        Transform::default(),
+    );
+    let camera = camera_at(center, Vec3::new(0.0, 0.5, 1.5));
+
+    let image = common::render(&mut app, camera.clone(), UVec2::new(64, 48), 4);
+    let color = common::mean_color(&image);
+    assert!(
+        color.x > color.y && color.z > color.y,
+        "the block without material isn't magenta: {color}"
+    );
+
+    // the type was built with the fallback material, it switches to the material once it's loaded
+    app.world_mut()
+        .resource_mut::<Assets<VoxelMaterial>>()
+        .insert(
+            &material,
+            VoxelMaterial::new_lambertian(Color::srgb(0.0, 1.0, 0.0)),
+        )
+        .unwrap();
+    let image = common::render(&mut app, camera, UVec2::new(64, 48), 4);
+    let color = common::mean_color(&image);
+    assert!(
+        color.y > color.x && color.y > color.z,
+        "the loaded material isn't used: {color}"
+    );
+}
+
+#[test]
+fn blocks_past_the_far_plane_arent_rendered() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    // the block is 10 units in front of the camera
+    let camera = |far: f32| {
+        let (camera, transform) = camera_at(center, Vec3::Z * 10.0);
+        let projection = Projection::Perspective(PerspectiveProjection { far, ..default() });
+        (camera, transform, projection)
+    };
+    let size = UVec2::new(64, 48);
+
+    let clipped = common::render(&mut app, camera(5.0), size, 4);
+    assert_eq!(
+        common::silhouette_size(&clipped),
+        UVec2::ZERO,
+        "the block past the far plane is rendered"
+    );
+    let image = common::render(&mut app, camera(20.0), size, 4);
+    assert!(
+        common::silhouette_size(&image).y > 0,
+        "the block closer than the far plane isn't rendered"
+    );
+}
+
+#[test]
+fn editing_the_voxels_of_a_type_updates_its_blocks() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let white = common::add_material(&mut app, VoxelMaterial::new_lambertian(Color::WHITE));
+    let red = common::add_material(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 0.0)),
+    );
+    let voxel_type = app
+        .world_mut()
+        .resource_mut::<Assets<VoxelType>>()
+        .add(VoxelType::new(
+            1,
+            vec![RelativeVoxel::new(white, Vec3::ZERO)],
+        ));
+    app.world_mut()
+        .spawn((VoxelBlock::new(voxel_type.clone()), Transform::default()));
+    let camera = || camera_at(Vec3::splat(0.5), Vec3::new(1.0, 1.0, 2.0));
+    let size = UVec2::new(64, 48);
+
+    let image = common::render(&mut app, camera(), size, 4);
+    let color = common::mean_color(&image);
+    assert!(
+        color.x - color.z < 0.1,
+        "the block isn't white before the edit: {color}"
+    );
+
+    // the new voxels have 8 times the triangles of the old one, and another material
+    let voxels = (0..8)
+        .map(|i| RelativeVoxel::at(IVec3::new(i & 1, i >> 1 & 1, i >> 2), red.clone()))
+        .collect();
+    *app.world_mut()
+        .resource_mut::<Assets<VoxelType>>()
+        .get_mut(&voxel_type)
+        .unwrap() = VoxelType::new(2, voxels);
+    let image = common::render(&mut app, camera(), size, 4);
+    let color = common::mean_color(&image);
+    assert!(common::is_finite(&image));
+    assert!(
+        color.x > color.y * 2.0 && color.x > color.z * 2.0,
+        "the edited block doesn't use its new voxels: {color}"
+    );
+}
+
+#[test]
+fn late_glass_material_makes_the_blas_non_opaque() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = app
+        .world()
+        .resource::<Assets<VoxelMaterial>>()
+        .reserve_handle();
+    // transient types aren't compacted, so their BLAS only changes when it's built again
+    let voxel_type = app
+        .world_mut()
+        .resource_mut::<Assets<VoxelType>>()
+        .add(VoxelType::new(1, vec![RelativeVoxel::new(material.clone(), Vec3::ZERO)]).transient());
+    app.world_mut()
+        .spawn((VoxelBlock::new(voxel_type.clone()), Transform::default()));
+    common::render(
+        &mut app,
+        camera_at(Vec3::splat(0.5), Vec3::new(1.0, 1.0, 2.0)),
+        UVec2::new(16, 16),
+        1,
+    );
+
+    let render_world = app.sub_app(RenderApp).world();
+    let geometry_manager = render_world.resource::<GeometryManager>();
+    assert!(geometry_manager.is_opaque(&voxel_type.id()));
+    let blas = render_world
+        .resource::<BlasManager>()
+        .get(&voxel_type.id())
+        .cloned()
+        .unwrap();
+
+    // the type was built while the material was loading
+    app.world_mut()
+        .resource_mut::<Assets<VoxelMaterial>>()
+        .insert(&material, VoxelMaterial::from_ior(VoxelMaterial::IOR_GLASS))
+        .unwrap();
+    for _ in 0..4 {
+        app.update();
+    }
+
+    let render_world = app.sub_app(RenderApp).world();
+    assert!(
+        !render_world
+            .resource::<GeometryManager>()
+            .is_opaque(&voxel_type.id()),
+        "the type with a glass material is opaque"
+    );
+    assert!(
+        render_world.resource::<BlasManager>().get(&voxel_type.id()) != Some(&blas),
+        "the BLAS wasn't built again as non-opaque geometry"
+    );
+}
+
+#[test]
+fn denoiser_works_far_from_the_origin() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    app.insert_resource(VoxelDenoiser::ATrous(NonZeroU32::new(3).unwrap()));
+
+    // the same scene near the origin and 100000 units away, the positions stored in 16 bits floats used to
+    // break the edge-stopping of the denoiser
+    let mut render_at = |position: Vec3| {
+        let center = common::spawn_voxel(
+            &mut app,
+            VoxelMaterial::new_lambertian(Color::WHITE),
+            Transform::from_translation(position),
+        );
+        common::render(
+            &mut app,
+            camera_at(center, Vec3::new(-2.0, 1.5, 3.0)),
+            UVec2::new(64, 48),
+            4,
+        )
+    };
+    let near = render_at(Vec3::ZERO);
+    let far = render_at(Vec3::new(100000.0, 0.0, 0.0));
+
+    let difference = common::image_difference(&near, &far).unwrap();
+    assert!(
+        difference < 0.05,
+        "the denoised image far from the origin differs by {difference}"
+    );
+}
+
+#[test]
+fn heatmap_shows_the_work_of_the_pixels() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    app.insert_resource(NEVRDebugView::Heatmap);
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.0, 1.5)),
+        UVec2::new(64, 48),
+        1,
+    );
+
+    // the sky traces a single ray (blue), the voxel bounces (warmer)
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    assert!(sky.z > sky.x, "the sky isn't cheap: {sky}");
+    let voxel = common::mean_color(&image);
+    assert!(
+        voxel.x + voxel.y > sky.x + sky.y,
+        "the voxel isn't warmer than the sky: {voxel}"
+    );
+}
+
+#[test]
+fn hidden_type_has_no_blas() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    app.insert_resource(NEVRTuning {
+        blas_eviction_frames: 0,
+        ..default()
+    });
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.0, 1.5)),
+        UVec2::new(16, 16),
+        1,
+    );
+    assert_eq!(app.world().resource::<NEVRStats>().blas_count, 1);
+
+    let mut blocks = app
+        .world_mut()
+        .query_filtered::<&mut Visibility, With<VoxelBlock>>();
+    for mut visibility in blocks.iter_mut(app.world_mut()) {
+        *visibility = Visibility::Hidden;
+    }
+    // the stats are a frame late
+    for _ in 0..4 {
+        app.update();
+    }
+    assert_eq!(app.world().resource::<NEVRStats>().blas_count, 0);
+}
+
+#[test]
+fn nan_material_doesnt_spread() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new(
+            LinearRgba::new(f32::NAN, 1.0, 1.0, 1.0),
+            f32::NAN,
+            f32::NAN,
+            VoxelMaterialModel::Metallic,
+        ),
+        Transform::from_xyz(1.0, 0.0, 0.0),
+    );
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.5, 2.5)),
+        UVec2::new(64, 48),
+        16,
+    );
+    assert!(common::is_finite(&image), "the accumulation has NaNs");
+    assert!(common::silhouette_size(&image).y > 0);
+}
+
+#[test]
+fn degenerate_block_doesnt_spread_nans() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    // a flat block has no inverse transform, so its normals are NaN
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::from_xyz(1.5, 0.0, 0.0).with_scale(Vec3::new(1.0, 0.0, 1.0)),
+    );
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.5, 2.5)),
+        UVec2::new(64, 48),
+        16,
+    );
+    assert!(common::is_finite(&image), "the accumulation has NaNs");
+}
+
+#[test]
+fn transient_type_isnt_compacted() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = common::add_material(&mut app, VoxelMaterial::new_lambertian(Color::WHITE));
+    let voxel_type = VoxelType::new(1, vec![RelativeVoxel::new(material, Vec3::ZERO)]).transient();
+    common::spawn_type(&mut app, voxel_type, Transform::default());
+
+    // the BLAS is never queued for compaction, from the frame it's built on
+    let mut built = false;
+    for _ in 0..32 {
+        app.update();
+        let stats = app.world().resource::<NEVRStats>();
+        built |= stats.blas_count == 1;
+        assert_eq!(stats.compaction_queue, 0);
+    }
+    assert!(built, "the BLAS wasn't built");
+}
+
+#[test]
+fn small_bright_skybox_lights_with_little_noise() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+
+    // a dim skybox with a single very bright texel above the scene, the only light
+    let world = app.world_mut();
+    world.resource_mut::<VoxelLight>().sun_intensity = 0.0;
+    let mut images = world.resource_mut::<Assets<Image>>();
+    let faces = [0, 1, 2, 3, 4, 5].map(|face| {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: 8,
+                height: 8,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 8],
+            TextureFormat::Rgba16Float,
+            RenderAssetUsages::default(),
+        );
+        for y in 0..8 {
+            for x in 0..8 {
+                image
+                    .set_color_at(x, y, Color::linear_rgb(0.01, 0.01, 0.01))
+                    .unwrap();
+            }
+        }
+        // the positive y face
+        if face == 2 {
+            image
+                .set_color_at(4, 4, Color::linear_rgb(2000.0, 2000.0, 2000.0))
+                .unwrap();
+        }
+        images.add(image)
+    });
+    let skybox = VoxelSkybox::from_faces(faces, &images);
+    world.insert_resource(skybox);
+
+    // two renders of a single frame with different seeds, their difference is the noise: sampling only the
+    // scattered rays misses the bright texel almost every time
+    let render = |app: &mut App, seed: u32| {
+        app.insert_resource(NEVRSeed(seed));
+        common::render(
+            app,
+            camera_at(center, Vec3::new(-1.0, 1.5, 2.0)),
+            UVec2::new(64, 48),
+            1,
+        )
+    };
+    let first = render(&mut app, 1);
+    let second = render(&mut app, 2);
+
+    let brightness = common::mean_color(&first).max_element();
+    let noise = common::image_difference(&first, &second).unwrap();
+    assert!(brightness > 0.0, "the skybox doesn't light the voxel");
+    assert!(
+        noise < brightness,
+        "the skybox lighting is too noisy: {noise} with a brightness of {brightness}"
+    );
+}
+
+#[test]
+fn blocks_over_the_instance_limit_are_dropped() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    app.insert_resource(NEVRTuning {
+        max_instances: 2,
+        ..default()
+    });
+    let material = VoxelMaterial::new_lambertian(Color::WHITE);
+    let mut center = Vec3::ZERO;
+    for x in 0..4 {
+        center = common::spawn_voxel(
+            &mut app,
+            material,
+            Transform::from_xyz(x as f32 * 2.0, 0.0, 0.0),
+        );
+    }
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-3.0, 4.0, 8.0)),
+        UVec2::new(64, 48),
+        1,
+    );
+    assert!(common::is_finite(&image));
+    assert_eq!(app.world().resource::<NEVRStats>().tlas_instances, 2);
+    let status = app.sub_app(RenderApp).world().resource::<NEVRStatus>();
+    assert_eq!(status.last_warning(), Some(NEVRWarning::TooManyInstances));
+}
+
+#[test]
+fn glass_sphere_has_no_black_core() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let glass = common::add_material(&mut app, VoxelMaterial::from_ior(VoxelMaterial::IOR_GLASS));
+    let mut voxels = vec![];
+    for x in 0..8 {
+        for y in 0..8 {
+            for z in 0..8 {
+                let position = IVec3::new(x, y, z);
+                if (position.as_vec3() - Vec3::splat(3.5)).length() < 4.0 {
+                    voxels.push(RelativeVoxel::at(position, glass.clone()));
+                }
+            }
+        }
+    }
+    let center = common::spawn_type(&mut app, VoxelType::new(8, voxels), Transform::default());
+
+    // the rays crossing the sphere refract through many faces, a NaN or a wrong total internal reflection in
+    // the Fresnel term paints its core black
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(0.0, 0.0, 2.0)),
+        UVec2::new(64, 48),
+        16,
+    );
+    let mut core = Vec3::ZERO;
+    for y in 20..28 {
+        for x in 28..36 {
+            core += image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+        }
+    }
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    assert!(
+        core.max_element() / 64.0 > sky.max_element() * 0.1,
+        "the core of the sphere is black: {core} against a sky of {sky}"
+    );
+}
+
+#[test]
+fn history_clamp_reduces_the_trail_of_moving_blocks() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::from_xyz(-1.0, 0.0, 0.0),
+    );
+
+    // the block moves to the right halfway through, what's left where it was is the trail
+    let render = |app: &mut App, history_clamp: f32, moved_at: u32| {
+        app.insert_resource(VoxelTemporalDenoiser::new(0.95, history_clamp));
+        common::render_frames(
+            app,
+            camera_at(Vec3::ZERO, Vec3::new(0.0, 0.0, 5.0)),
+            UVec2::new(64, 48),
+            32,
+            |app, frame| {
+                let x = if frame < moved_at { -1.0 } else { 1.0 };
+                let world = app.world_mut();
+                let mut blocks = world.query_filtered::<&mut Transform, With<VoxelBlock>>();
+                blocks.single_mut(world).unwrap().translation.x = x;
+            },
+        )
+    };
+    let still = render(&mut app, 1.0, 0);
+    let clamped = render(&mut app, 1.0, 16);
+    let still_unclamped = render(&mut app, 0.0, 0);
+    let unclamped = render(&mut app, 0.0, 16);
+
+    let clamped_trail = common::image_difference(&clamped, &still).unwrap();
+    let unclamped_trail = common::image_difference(&unclamped, &still_unclamped).unwrap();
+    assert!(
+        clamped_trail < unclamped_trail * 0.5,
+        "the history clamp doesn't reduce the trail: {clamped_trail} against {unclamped_trail} without it"
+    );
+}
+
+#[test]
+fn blas_of_a_dropped_type_is_freed_after_the_grace_period() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    app.insert_resource(NEVRTuning {
+        blas_eviction_frames: 8,
+        ..default()
+    });
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::default(),
+    );
+    common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.0, 1.5)),
+        UVec2::new(16, 16),
+        1,
+    );
+    assert_eq!(app.world().resource::<NEVRStats>().blas_count, 1);
+
+    let mut blocks = app.world_mut().query_filtered::<Entity, With<VoxelBlock>>();
+    let blocks = blocks.iter(app.world()).collect::<Vec<_>>();
+    for block in blocks {
+        app.world_mut().despawn(block);
+    }
+    for _ in 0..4 {
+        app.update();
+    }
+    assert_eq!(
+        app.world().resource::<NEVRStats>().blas_count,
+        1,
+        "the BLAS was freed before the grace period"
+    );
+    for _ in 0..16 {
+        app.update();
+    }
+    assert_eq!(app.world().resource::<NEVRStats>().blas_count, 0);
+}
+
+#[test]
+fn rotating_blocks_keeps_their_blas() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = common::add_material(&mut app, VoxelMaterial::new_lambertian(Color::WHITE));
+    let voxels = (0..4)
+        .map(|x| RelativeVoxel::at(IVec3::new(x, 0, 0), material.clone()))
+        .collect();
+    let voxel_type = app
+        .world_mut()
+        .resource_mut::<Assets<VoxelType>>()
+        .add(VoxelType::new(4, voxels));
+    for x in [0.0, 0.5] {
+        app.world_mut().spawn((
+            VoxelBlock::new(voxel_type.clone()),
+            Transform::from_xyz(x, 0.0, 0.0).with_rotation(Quat::from_rotation_y(FRAC_PI_4)),
+        ));
+    }
+    common::render(
+        &mut app,
+        camera_at(Vec3::ZERO, Vec3::new(0.0, 2.0, 3.0)),
+        UVec2::new(16, 16),
+        1,
+    );
+
+    // the BLAS is built in the local space of the type, the rotation of the overlapping blocks only changes
+    // their instances in the TLAS
+    let triangles = app.world().resource::<NEVRStats>().triangles;
+    for frame in 0..8 {
+        let mut blocks = app
+            .world_mut()
+            .query_filtered::<&mut Transform, With<VoxelBlock>>();
+        for mut transform in blocks.iter_mut(app.world_mut()) {
+            transform.rotate_y(0.1 * frame as f32);
+        }
+        app.update();
+
+        let stats = app.world().resource::<NEVRStats>();
+        assert_eq!(stats.geometry_rebuilds, 0);
+        assert_eq!(stats.blas_count, 1);
+        assert_eq!(stats.triangles, triangles);
+    }
+}
+
+#[test]
+fn red_glass_casts_a_red_shadow() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let floor_scale = Vec3::new(4.0, 0.1, 4.0);
+    let floor = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::from_scale(floor_scale),
+    );
+    // the sun is straight above, the shadow is right under the glass
+    let voxel_center = floor / floor_scale;
+    let glass = floor + Vec3::Y * 0.85;
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_dielectric(Color::srgb(1.0, 0.1, 0.1), VoxelMaterial::IOR_GLASS),
+        Transform::from_translation(glass - voxel_center * 0.5).with_scale(Vec3::splat(0.5)),
+    );
+
+    // the camera looks at the shadow from above, the glass is higher up in the image
+    let shadow = floor + Vec3::Y * 0.05;
+    let image = common::render(
+        &mut app,
+        (
+            VoxelCamera::default(),
+            VoxelCamera::look_at(shadow + Vec3::new(0.0, 1.5, 2.0), shadow, Vec3::Y),
+        ),
+        UVec2::new(64, 48),
+        16,
+    );
+    let mean = |rows: std::ops::Range<u32>| {
+        let mut color = Vec3::ZERO;
+        for y in rows.clone() {
+            for x in 30..34 {
+                color += image.get_color_at(x, y).unwrap().to_linear().to_vec3();
+            }
+        }
+        color / (rows.len() * 4) as f32
+    };
+    let shadow_color = mean(23..26);
+    let lit_color = mean(40..44);
+    assert!(
+        shadow_color.y < lit_color.y * 0.5,
+        "the glass casts no shadow: {shadow_color} against {lit_color}"
+    );
+    assert!(
+        shadow_color.x > shadow_color.y * 1.5,
+        "the shadow of the red glass isn't red: {shadow_color}"
+    );
+}
+
+#[test]
+fn toggling_a_block_rewrites_only_its_instance() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = common::add_material(&mut app, VoxelMaterial::new_lambertian(Color::WHITE));
+    let voxel_type = app
+        .world_mut()
+        .resource_mut::<Assets<VoxelType>>()
+        .add(VoxelType::new(
+            1,
+            vec![RelativeVoxel::new(material, Vec3::ZERO)],
+        ));
+    let blocks = (0..10_000)
+        .map(|i| {
+            (
+                VoxelBlock::new(voxel_type.clone()),
+                Transform::from_xyz((i % 100) as f32 * 2.0, 0.0, (i / 100) as f32 * 2.0),
+            )
+        })
+        .collect::<Vec<_>>();
+    let toggled = app.world_mut().spawn_batch(blocks).next().unwrap();
+
+    // the stats are a frame or two late, the writes of the toggled block show up after it
+    let mut writes = vec![];
+    common::render_frames(
+        &mut app,
+        camera_at(Vec3::new(100.0, 0.0, 100.0), Vec3::new(0.0, 150.0, 1.0)),
+        UVec2::new(16, 16),
+        32,
+        |app, frame| {
+            writes.push(app.world().resource::<NEVRStats>().tlas_writes);
+            if frame == 16 {
+                *app.world_mut().get_mut::<Visibility>(toggled).unwrap() = Visibility::Hidden;
+            }
+        },
+    );
+    assert!(
+        writes[8..=16].iter().all(|writes| *writes == 0),
+        "the TLAS of a static scene is written: {writes:?}"
+    );
+    assert_eq!(writes[17..].iter().sum::<u32>(), 1, "{writes:?}");
+}
+
+// the pixels that differ from the top left one (the sky) whose color matches `filter`
+fn count_pixels(image: &Image, filter: impl Fn(Vec3) -> bool) -> usize {
+    let sky = image.get_color_at(0, 0).unwrap().to_linear().to_vec3();
+    let size = image.size();
+    (0..size.y)
+        .flat_map(|y| (0..size.x).map(move |x| (x, y)))
+        .map(|(x, y)| image.get_color_at(x, y).unwrap().to_linear().to_vec3())
+        .filter(|color| (*color - sky).abs().max_element() > 0.05 && filter(*color))
+        .count()
+}
+
+#[test]
+fn despawned_block_frees_its_slot() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(1.0, 0.0, 0.0)),
+        Transform::default(),
+    );
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(0.0, 0.0, 1.0)),
+        Transform::from_xyz(2.0, 0.0, 0.0),
+    );
+    let green = common::add_material(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::srgb(0.0, 1.0, 0.0)),
+    );
+    let blue = |color: Vec3| color.z > color.x * 2.0 && color.z > color.y * 2.0;
+    let green_pixels = |color: Vec3| color.y > color.x * 2.0 && color.y > color.z * 2.0;
+
+    // the blue block is despawned at frame 8 and a green one takes its place at frame 16
+    let mut stats = vec![];
+    let mut despawned = None;
+    let image = common::render_frames(
+        &mut app,
+        camera_at(Vec3::new(1.5, 0.5, 0.5), Vec3::new(0.0, 1.0, 4.0)),
+        UVec2::new(64, 48),
+        24,
+        |app, frame| {
+            let frame_stats = app.world().resource::<NEVRStats>();
+            stats.push((frame_stats.tlas_instances, frame_stats.tlas_writes));
+            match frame {
+                8 => {
+                    let world = app.world_mut();
+                    let mut blocks =
+                        world.query_filtered::<(Entity, &Transform), With<VoxelBlock>>();
+                    let (block, _) = blocks
+                        .iter(world)
+                        .find(|(_, transform)| transform.translation.x == 2.0)
+                        .unwrap();
+                    world.despawn(block);
+                }
+                // the readback is 2 to 3 frames late
+                12 => despawned = Some(common::current_output(app)),
+                16 => {
+                    common::spawn_type(
+                        app,
+                        VoxelType::new(1, vec![RelativeVoxel::new(green.clone(), Vec3::ZERO)]),
+                        Transform::from_xyz(2.0, 0.0, 0.0),
+                    );
+                }
+                _ => {}
+            }
+        },
+    );
+
+    // the stats are up to a frame late
+    assert_eq!(stats[8].0, 2, "{stats:?}");
+    assert!(
+        stats[9..=10].iter().any(|(instances, _)| *instances == 1),
+        "the despawned block keeps its instance: {stats:?}"
+    );
+    assert_eq!(
+        count_pixels(&despawned.unwrap(), blue),
+        0,
+        "the despawned block is still rendered"
+    );
+    // the new block takes the freed slot: the TLAS isn't created again, which would write both instances
+    assert_eq!(stats[23].0, 2, "{stats:?}");
+    assert_eq!(
+        stats[17..].iter().map(|(_, writes)| writes).sum::<u32>(),
+        1,
+        "{stats:?}"
+    );
+    assert!(
+        count_pixels(&image, green_pixels) > 0,
+        "the new block isn't rendered"
+    );
+    assert_eq!(count_pixels(&image, blue), 0);
+}
+
+#[test]
+fn large_type_renders() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    let material = common::add_material(&mut app, VoxelMaterial::new_lambertian(Color::WHITE));
+    // 205 379 voxels in a single type
+    let mut voxels = vec![];
+    for x in 0..59 {
+        for y in 0..59 {
+            for z in 0..59 {
+                voxels.push(RelativeVoxel::at(IVec3::new(x, y, z), material.clone()));
+            }
+        }
+    }
+    let center = common::spawn_type(&mut app, VoxelType::new(59, voxels), Transform::default());
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(-1.0, 1.0, 2.0)),
+        UVec2::new(64, 48),
+        1,
+    );
+    assert!(
+        common::silhouette_size(&image).y > 8,
+        "the large type isn't rendered"
+    );
+    assert_eq!(app.world().resource::<NEVRStats>().blas_count, 1);
+    let status = app.sub_app(RenderApp).world().resource::<NEVRStatus>();
+    assert_ne!(status.last_warning(), Some(NEVRWarning::TypeTooLarge));
+}
+
+#[test]
+fn very_bright_emitter_doesnt_overflow() {
+    let Some(mut app) = common::headless_app() else {
+        return;
+    };
+    // far brighter than the largest f16, the accumulation saturates instead of turning infinite
+    let center = common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_diffuse_light(Color::WHITE, 1.0e6),
+        Transform::default(),
+    );
+    common::spawn_voxel(
+        &mut app,
+        VoxelMaterial::new_lambertian(Color::WHITE),
+        Transform::from_xyz(1.5, 0.0, 0.0),
+    );
+
+    let image = common::render(
+        &mut app,
+        camera_at(center, Vec3::new(0.5, 1.0, 3.0)),
+        UVec2::new(64, 48),
+        64,
+    );
+    assert!(common::is_finite(&image), "the accumulation overflowed");
+}